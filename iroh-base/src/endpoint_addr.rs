@@ -52,6 +52,48 @@ pub enum TransportAddr {
     Relay(RelayUrl),
     /// IP based addresses
     Ip(SocketAddr),
+    /// Addresses of custom transports
+    Custom(CustomAddr),
+}
+
+/// The address of an endpoint on a custom transport.
+///
+/// Custom transports are identified by an application chosen `id`, the `data` holds the
+/// transport specific address, e.g. the path of a unix socket.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CustomAddr {
+    id: u64,
+    data: Vec<u8>,
+}
+
+impl CustomAddr {
+    /// Creates a new address for the custom transport with the given `id`.
+    pub fn new(id: u64, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            id,
+            data: data.into(),
+        }
+    }
+
+    /// Returns the id of the custom transport this address belongs to.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the transport specific address data.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl std::fmt::Display for CustomAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.id)?;
+        for byte in &self.data {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
 }
 
 impl EndpointAddr {
@@ -86,6 +128,12 @@ impl EndpointAddr {
         self
     }
 
+    /// Adds a custom transport address.
+    pub fn with_custom_addr(mut self, addr: CustomAddr) -> Self {
+        self.addrs.insert(TransportAddr::Custom(addr));
+        self
+    }
+
     /// Adds a list of addresses.
     pub fn with_addrs(mut self, addrs: impl IntoIterator<Item = TransportAddr>) -> Self {
         for addr in addrs.into_iter() {
@@ -116,6 +164,14 @@ impl EndpointAddr {
             _ => None,
         })
     }
+
+    /// Returns a list of custom transport addresses of this peer.
+    pub fn custom_addrs(&self) -> impl Iterator<Item = &CustomAddr> {
+        self.addrs.iter().filter_map(|addr| match addr {
+            TransportAddr::Custom(addr) => Some(addr),
+            _ => None,
+        })
+    }
 }
 
 impl From<EndpointId> for EndpointAddr {
@@ -139,6 +195,21 @@ mod tests {
         Cool(u16),
     }
 
+    #[test]
+    fn test_custom_addr() {
+        let custom = CustomAddr::new(7, b"/tmp/iroh.sock".to_vec());
+        let addr = EndpointAddr::new(crate::SecretKey::from_bytes(&[0u8; 32]).public())
+            .with_ip_addr("127.0.0.1:9".parse().unwrap())
+            .with_custom_addr(custom.clone());
+        assert_eq!(addr.custom_addrs().collect::<Vec<_>>(), vec![&custom]);
+        assert_eq!(addr.ip_addrs().count(), 1);
+        assert_eq!(custom.to_string(), "7:2f746d702f69726f682e736f636b");
+
+        let ser = postcard::to_stdvec(&addr).unwrap();
+        let back: EndpointAddr = postcard::from_bytes(&ser).unwrap();
+        assert_eq!(addr, back);
+    }
+
     #[test]
     fn test_roundtrip_new_addr_type() {
         let old = vec![
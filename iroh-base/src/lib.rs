@@ -7,12 +7,20 @@
 mod endpoint_addr;
 #[cfg(feature = "key")]
 mod key;
+#[cfg(feature = "key")]
+mod key_file;
 #[cfg(feature = "relay")]
 mod relay_url;
+#[cfg(feature = "ticket")]
+pub mod ticket;
 
 #[cfg(feature = "key")]
-pub use self::endpoint_addr::{EndpointAddr, TransportAddr};
+pub use self::endpoint_addr::{CustomAddr, EndpointAddr, TransportAddr};
 #[cfg(feature = "key")]
 pub use self::key::{EndpointId, KeyParsingError, PublicKey, SecretKey, Signature, SignatureError};
+#[cfg(feature = "key")]
+pub use self::key_file::{SecretKeyDecodeError, SecretKeyFileError, SecretKeyFormat};
 #[cfg(feature = "relay")]
 pub use self::relay_url::{RelayUrl, RelayUrlParseError};
+#[cfg(feature = "ticket")]
+pub use self::ticket::EndpointTicket;
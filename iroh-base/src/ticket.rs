@@ -0,0 +1,369 @@
+//! Tickets to share the addressing information of an endpoint.
+//!
+//! An [`EndpointTicket`] is a compact, copy-pasteable encoding of an [`EndpointAddr`] with an
+//! optional application defined payload.  Tickets are encoded as a kind prefix followed by
+//! the lowercase base32 encoding of a versioned [postcard] encoding, e.g. `endpointabcd...`.
+//! They can also be written as an URI by prepending the `iroh:` scheme.
+//!
+//! [postcard]: https://docs.rs/postcard
+
+use std::{collections::BTreeSet, fmt, net::SocketAddr, str::FromStr};
+
+use n0_error::{e, ensure, stack_error};
+use serde::{Deserialize, Serialize, de};
+
+use crate::{CustomAddr, EndpointAddr, EndpointId, RelayUrl, TransportAddr};
+
+/// The URI scheme of tickets.
+pub const URI_SCHEME: &str = "iroh";
+
+/// Maximum length of the binary encoding of a ticket, in bytes.
+///
+/// The string encoding of a ticket is about 1.6 times as long.
+pub const MAX_TICKET_LEN: usize = 2048;
+
+/// Maximum length of the application payload of a ticket, in bytes.
+pub const MAX_PAYLOAD_LEN: usize = 1024;
+
+/// Error when creating, encoding or decoding a ticket.
+#[stack_error(derive, add_meta)]
+#[allow(missing_docs)]
+#[non_exhaustive]
+pub enum TicketError {
+    #[error("wrong ticket kind, expected {expected}")]
+    Kind { expected: &'static str },
+    #[error("invalid base32 encoding")]
+    Encoding {
+        #[error(std_err)]
+        source: data_encoding::DecodeError,
+    },
+    #[error("invalid ticket data")]
+    Postcard {
+        #[error(std_err)]
+        source: postcard::Error,
+    },
+    #[error("unexpected data after the end of the ticket")]
+    TrailingData,
+    #[error("ticket is longer than {MAX_TICKET_LEN} bytes")]
+    TooLong,
+    #[error("payload is longer than {MAX_PAYLOAD_LEN} bytes")]
+    PayloadTooLong,
+}
+
+/// A ticket to share the addressing information of an endpoint.
+///
+/// This contains an [`EndpointAddr`] and optionally an application defined payload, e.g. to
+/// tell the receiver of the ticket what to ask the endpoint for once connected.
+///
+/// The [`Display`] and [`FromStr`] implementations use the string encoding, e.g.
+/// `endpointabcd...`.  [`EndpointTicket::to_uri`] creates the `iroh:endpointabcd...` URI
+/// form, which is also accepted when parsing.  Serde uses the string encoding for human
+/// readable formats and the binary encoding, see [`EndpointTicket::to_bytes`], otherwise.
+///
+/// Tickets are limited to [`MAX_TICKET_LEN`] bytes in their binary encoding and the
+/// payload is limited to [`MAX_PAYLOAD_LEN`] bytes.
+///
+/// [`Display`]: fmt::Display
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EndpointTicket {
+    addr: EndpointAddr,
+    payload: Vec<u8>,
+}
+
+/// Versioned wire format of [`EndpointTicket`].
+///
+/// New versions must only ever be added as new variants.
+#[derive(Serialize, Deserialize)]
+enum TicketWireFormat {
+    Variant0(Variant0EndpointTicket),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Variant0EndpointTicket {
+    id: EndpointId,
+    addrs: BTreeSet<Variant0TransportAddr>,
+    payload: Vec<u8>,
+}
+
+/// Wire format of [`TransportAddr`], decoupled from the type so that it can evolve freely.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+enum Variant0TransportAddr {
+    Relay(RelayUrl),
+    Ip(SocketAddr),
+    Custom(CustomAddr),
+}
+
+impl EndpointTicket {
+    /// The prefix of the string encoding of endpoint tickets.
+    pub const KIND: &'static str = "endpoint";
+
+    /// Creates a ticket for an [`EndpointAddr`].
+    ///
+    /// Fails if the ticket would be longer than [`MAX_TICKET_LEN`].
+    pub fn new(addr: EndpointAddr) -> Result<Self, TicketError> {
+        Self::with_payload(addr, Vec::new())
+    }
+
+    /// Creates a ticket for an [`EndpointAddr`] with an application defined payload.
+    ///
+    /// Fails if the payload is longer than [`MAX_PAYLOAD_LEN`] or the ticket would be
+    /// longer than [`MAX_TICKET_LEN`].
+    pub fn with_payload(addr: EndpointAddr, payload: Vec<u8>) -> Result<Self, TicketError> {
+        ensure!(
+            payload.len() <= MAX_PAYLOAD_LEN,
+            TicketError::PayloadTooLong
+        );
+        let ticket = Self { addr, payload };
+        ensure!(
+            ticket.to_bytes().len() <= MAX_TICKET_LEN,
+            TicketError::TooLong
+        );
+        Ok(ticket)
+    }
+
+    /// The [`EndpointAddr`] of this ticket.
+    pub fn endpoint_addr(&self) -> &EndpointAddr {
+        &self.addr
+    }
+
+    /// The application defined payload of this ticket, empty if none was set.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Returns the binary encoding of this ticket.
+    ///
+    /// This is the [postcard] encoding of the versioned wire format.
+    ///
+    /// [postcard]: https://docs.rs/postcard
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let addrs = self
+            .addr
+            .addrs
+            .iter()
+            .map(|addr| match addr {
+                TransportAddr::Relay(url) => Variant0TransportAddr::Relay(url.clone()),
+                TransportAddr::Ip(addr) => Variant0TransportAddr::Ip(*addr),
+                TransportAddr::Custom(addr) => Variant0TransportAddr::Custom(addr.clone()),
+            })
+            .collect();
+        let wire = TicketWireFormat::Variant0(Variant0EndpointTicket {
+            id: self.addr.id,
+            addrs,
+            payload: self.payload.clone(),
+        });
+        postcard::to_stdvec(&wire).expect("postcard serialization to vec is infallible")
+    }
+
+    /// Decodes a ticket from its binary encoding.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TicketError> {
+        ensure!(bytes.len() <= MAX_TICKET_LEN, TicketError::TooLong);
+        let (wire, rest) = postcard::take_from_bytes::<TicketWireFormat>(bytes)
+            .map_err(|err| e!(TicketError::Postcard, err))?;
+        ensure!(rest.is_empty(), TicketError::TrailingData);
+        let TicketWireFormat::Variant0(ticket) = wire;
+        ensure!(
+            ticket.payload.len() <= MAX_PAYLOAD_LEN,
+            TicketError::PayloadTooLong
+        );
+        let addrs = ticket.addrs.into_iter().map(|addr| match addr {
+            Variant0TransportAddr::Relay(url) => TransportAddr::Relay(url),
+            Variant0TransportAddr::Ip(addr) => TransportAddr::Ip(addr),
+            Variant0TransportAddr::Custom(addr) => TransportAddr::Custom(addr),
+        });
+        Ok(Self {
+            addr: EndpointAddr::from_parts(ticket.id, addrs),
+            payload: ticket.payload,
+        })
+    }
+
+    /// Returns the `iroh:` URI form of this ticket.
+    pub fn to_uri(&self) -> String {
+        format!("{URI_SCHEME}:{self}")
+    }
+}
+
+impl From<EndpointTicket> for EndpointAddr {
+    fn from(ticket: EndpointTicket) -> Self {
+        ticket.addr
+    }
+}
+
+impl fmt::Display for EndpointTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoded = data_encoding::BASE32_NOPAD.encode(&self.to_bytes());
+        write!(f, "{}{}", Self::KIND, encoded.to_ascii_lowercase())
+    }
+}
+
+/// Parses the string encoding of a ticket, with or without the `iroh:` URI scheme.
+impl FromStr for EndpointTicket {
+    type Err = TicketError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s
+            .strip_prefix(URI_SCHEME)
+            .and_then(|s| s.strip_prefix(':'))
+            .unwrap_or(s);
+        let encoded = s.strip_prefix(Self::KIND).ok_or_else(|| {
+            e!(TicketError::Kind {
+                expected: Self::KIND
+            })
+        })?;
+        // Check the length before decoding to avoid allocating for overly long input.
+        ensure!(
+            data_encoding::BASE32_NOPAD.encode_len(MAX_TICKET_LEN) >= encoded.len(),
+            TicketError::TooLong
+        );
+        let bytes = data_encoding::BASE32_NOPAD
+            .decode(encoded.to_ascii_uppercase().as_bytes())
+            .map_err(|err| e!(TicketError::Encoding, err))?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl Serialize for EndpointTicket {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_bytes(&self.to_bytes())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for EndpointTicket {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            Self::from_str(&s).map_err(de::Error::custom)
+        } else {
+            let bytes = <Vec<u8>>::deserialize(deserializer)?;
+            Self::from_bytes(&bytes).map_err(de::Error::custom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data_encoding::HEXLOWER;
+
+    use super::*;
+    use crate::PublicKey;
+
+    fn make_addr() -> EndpointAddr {
+        let id =
+            PublicKey::from_str("ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b6")
+                .unwrap();
+        EndpointAddr::new(id)
+            .with_relay_url("http://derp.me./".parse().unwrap())
+            .with_ip_addr("127.0.0.1:1024".parse().unwrap())
+    }
+
+    #[test]
+    fn test_ticket_roundtrip() {
+        let ticket = EndpointTicket::new(make_addr()).unwrap();
+        let s = ticket.to_string();
+        assert!(s.starts_with("endpoint"));
+        assert_eq!(s.to_ascii_lowercase(), s);
+        assert_eq!(EndpointTicket::from_str(&s).unwrap(), ticket);
+
+        let uri = ticket.to_uri();
+        assert_eq!(uri, format!("iroh:{s}"));
+        assert_eq!(EndpointTicket::from_str(&uri).unwrap(), ticket);
+
+        let bytes = ticket.to_bytes();
+        assert_eq!(EndpointTicket::from_bytes(&bytes).unwrap(), ticket);
+    }
+
+    #[test]
+    fn test_ticket_payload() {
+        let ticket = EndpointTicket::with_payload(make_addr(), b"hello".to_vec()).unwrap();
+        let parsed = EndpointTicket::from_str(&ticket.to_string()).unwrap();
+        assert_eq!(parsed.payload(), b"hello");
+        assert_eq!(parsed.endpoint_addr(), &make_addr());
+
+        assert!(matches!(
+            EndpointTicket::with_payload(make_addr(), vec![0; MAX_PAYLOAD_LEN + 1]),
+            Err(TicketError::PayloadTooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn test_ticket_limits() {
+        let addr = EndpointAddr::new(make_addr().id).with_addrs(
+            (0..400).map(|port| TransportAddr::Ip(SocketAddr::from(([127, 0, 0, 1], port)))),
+        );
+        assert!(matches!(
+            EndpointTicket::new(addr),
+            Err(TicketError::TooLong { .. })
+        ));
+
+        let too_long = format!("endpoint{}", "a".repeat(4096));
+        assert!(matches!(
+            EndpointTicket::from_str(&too_long),
+            Err(TicketError::TooLong { .. })
+        ));
+
+        let mut bytes = EndpointTicket::new(make_addr()).unwrap().to_bytes();
+        bytes.push(0);
+        assert!(matches!(
+            EndpointTicket::from_bytes(&bytes),
+            Err(TicketError::TrailingData { .. })
+        ));
+    }
+
+    #[test]
+    fn test_ticket_wrong_kind() {
+        let s = EndpointTicket::new(make_addr()).unwrap().to_string();
+        let s = s.replacen("endpoint", "blob", 1);
+        assert!(matches!(
+            EndpointTicket::from_str(&s),
+            Err(TicketError::Kind { .. })
+        ));
+    }
+
+    #[test]
+    fn test_ticket_base32() {
+        let ticket = EndpointTicket::new(make_addr()).unwrap();
+        let bytes = postcard::to_stdvec(&ticket).unwrap();
+        let base32 = data_encoding::BASE32_NOPAD
+            .decode(
+                ticket
+                    .to_string()
+                    .strip_prefix("endpoint")
+                    .unwrap()
+                    .to_ascii_uppercase()
+                    .as_bytes(),
+            )
+            .unwrap();
+        // postcard prefixes the binary encoding with its length.
+        assert_eq!(&bytes[1..], base32.as_slice());
+        let expected = [
+            // version
+            "00",
+            // endpoint id, 32 bytes, see above
+            "ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b6",
+            // two addresses
+            "02",
+            // TransportAddr: Relay
+            "00",
+            // 16 bytes
+            "10",
+            // RelayUrl
+            "687474703a2f2f646572702e6d652e2f",
+            // TransportAddr: IP
+            "01",
+            // IPv4
+            "00",
+            // address, 4 bytes
+            "7f000001",
+            // port, varint encoded
+            "8008",
+            // empty payload
+            "00",
+        ];
+        let expected = HEXLOWER.decode(expected.concat().as_bytes()).unwrap();
+        assert_eq!(base32, expected);
+    }
+}
@@ -24,7 +24,7 @@ use std::{
 };
 
 use data_encoding::HEXLOWER;
-use iroh_base::{PublicKey, RelayUrl};
+use iroh_base::{CustomAddr, PublicKey, RelayUrl, TransportAddr};
 use n0_error::{e, ensure, stack_error};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
@@ -136,13 +136,15 @@ pub struct Pong {
     pub ping_observed_addr: SendAddr,
 }
 
-/// Addresses to which we can send. This is either a UDP or a relay address.
+/// Addresses to which we can send. This is either a UDP, a relay or a custom transport address.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum SendAddr {
     /// UDP, the ip addr.
     Udp(SocketAddr),
     /// Relay Url.
     Relay(RelayUrl),
+    /// Custom transport address.
+    Custom(CustomAddr),
 }
 
 impl SendAddr {
@@ -155,7 +157,7 @@ impl SendAddr {
     pub fn relay_url(&self) -> Option<RelayUrl> {
         match self {
             Self::Relay(url) => Some(url.clone()),
-            Self::Udp(_) => None,
+            Self::Udp(_) | Self::Custom(_) => None,
         }
     }
 }
@@ -165,6 +167,17 @@ impl From<transports::Addr> for SendAddr {
         match addr {
             transports::Addr::Ip(addr) => SendAddr::Udp(addr),
             transports::Addr::Relay(url, _) => SendAddr::Relay(url),
+            transports::Addr::Custom(addr) => SendAddr::Custom(addr),
+        }
+    }
+}
+
+impl From<SendAddr> for TransportAddr {
+    fn from(addr: SendAddr) -> Self {
+        match addr {
+            SendAddr::Udp(addr) => TransportAddr::Ip(addr),
+            SendAddr::Relay(url) => TransportAddr::Relay(url),
+            SendAddr::Custom(addr) => TransportAddr::Custom(addr),
         }
     }
 }
@@ -184,7 +197,7 @@ impl From<RelayUrl> for SendAddr {
 impl PartialEq<SocketAddr> for SendAddr {
     fn eq(&self, other: &SocketAddr) -> bool {
         match self {
-            Self::Relay(_) => false,
+            Self::Relay(_) | Self::Custom(_) => false,
             Self::Udp(addr) => addr.eq(other),
         }
     }
@@ -195,6 +208,7 @@ impl Display for SendAddr {
         match self {
             SendAddr::Relay(id) => write!(f, "Relay({id})"),
             SendAddr::Udp(addr) => write!(f, "UDP({addr})"),
+            SendAddr::Custom(addr) => write!(f, "Custom({addr})"),
         }
     }
 }
@@ -266,6 +280,14 @@ fn send_addr_from_bytes(p: &[u8]) -> Result<SendAddr, ParseError> {
             let u: Url = s.parse().map_err(|_| e!(ParseError::InvalidEncoding))?;
             Ok(SendAddr::Relay(u.into()))
         }
+        2u8 => {
+            let id: [u8; 8] = p
+                .get(1..9)
+                .and_then(|id| id.try_into().ok())
+                .ok_or_else(|| e!(ParseError::TooShort))?;
+            let addr = CustomAddr::new(u64::from_le_bytes(id), &p[9..]);
+            Ok(SendAddr::Custom(addr))
+        }
         _ => Err(e!(ParseError::UnknownFormat)),
     }
 }
@@ -282,6 +304,12 @@ fn send_addr_to_vec(addr: &SendAddr) -> Vec<u8> {
             out.extend_from_slice(&socket_addr_as_bytes(ip));
             out
         }
+        SendAddr::Custom(addr) => {
+            let mut out = vec![2u8];
+            out.extend_from_slice(&addr.id().to_le_bytes());
+            out.extend_from_slice(addr.data());
+            out
+        }
     }
 }
 
@@ -552,6 +580,14 @@ mod tests {
                 }),
                 want: "02 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 00 fe d0 00 00 00 00 00 00 00 00 00 00 00 00 00 12 0a 1a",
             },
+            Test {
+                name: "pong_custom",
+                m: Message::Pong(Pong {
+                    tx_id: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12].into(),
+                    ping_observed_addr: SendAddr::Custom(CustomAddr::new(7, [0xab, 0xcd])),
+                }),
+                want: "02 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 02 07 00 00 00 00 00 00 00 ab cd",
+            },
             Test {
                 name: "call_me_maybe",
                 m: Message::CallMeMaybe(CallMeMaybe {
@@ -123,8 +123,8 @@ use n0_future::{
 use tokio::sync::oneshot;
 use tracing::{Instrument, debug, error_span, warn};
 
-use crate::Endpoint;
 pub use crate::endpoint_info::{EndpointData, EndpointInfo, ParseError, UserData};
+use crate::{Endpoint, endpoint::EndpointEvent};
 
 #[cfg(not(wasm_browser))]
 pub mod dns;
@@ -626,6 +626,10 @@ impl DiscoveryTask {
                         continue;
                     }
                     debug!(%provenance, addr = ?endpoint_addr, "new address found");
+                    ep.event_sender().send(EndpointEvent::DiscoveryResult {
+                        endpoint_addr: endpoint_addr.clone(),
+                        provenance,
+                    });
                     let source = crate::magicsock::Source::Discovery {
                         name: provenance.to_string(),
                     };
@@ -659,14 +663,18 @@ mod tests {
     };
 
     use iroh_base::{EndpointAddr, SecretKey, TransportAddr};
-    use n0_error::{AnyError as Error, Result, StackResultExt};
+    use n0_error::{AnyError as Error, Result, StackResultExt, StdResultExt};
     use quinn::{IdleTimeout, TransportConfig};
     use rand::{CryptoRng, Rng, SeedableRng};
     use tokio_util::task::AbortOnDropHandle;
     use tracing_test::traced_test;
 
     use super::*;
-    use crate::{Endpoint, RelayMode, endpoint::ConnectOptions};
+    use crate::{
+        Endpoint, RelayMode,
+        endpoint::{ConnectOptions, PathSelection},
+        test_utils::run_relay_server,
+    };
 
     type InfoStore = HashMap<EndpointId, (EndpointData, u64)>;
 
@@ -919,6 +927,43 @@ mod tests {
         Ok(())
     }
 
+    /// With [`PathSelection::RelayOnly`] the direct addresses are not published.
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_discovery_relay_only_publishes_relay_url() -> Result {
+        let (relay_map, relay_url, _guard) = run_relay_server().await?;
+        let disco_shared = TestDiscoveryShared::default();
+        let ep = Endpoint::empty_builder(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .path_selection(PathSelection::RelayOnly)
+            .bind()
+            .await?;
+        ep.discovery().add(disco_shared.create_discovery(ep.id()));
+        ep.online().await;
+
+        let data = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let data = disco_shared
+                    .endpoints
+                    .lock()
+                    .unwrap()
+                    .get(&ep.id())
+                    .cloned();
+                if let Some((data, _)) = data.filter(|(data, _)| data.relay_urls().next().is_some())
+                {
+                    break data;
+                }
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .anyerr()?;
+        assert_eq!(data.relay_urls().collect::<Vec<_>>(), vec![&relay_url]);
+        assert_eq!(data.ip_addrs().count(), 0);
+        ep.close().await;
+        Ok(())
+    }
+
     async fn new_endpoint<R: CryptoRng, D: Discovery + 'static, F: FnOnce(&Endpoint) -> D>(
         rng: &mut R,
         create_disco: F,
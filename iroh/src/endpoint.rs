@@ -12,6 +12,7 @@
 //! [module docs]: crate
 
 use std::{
+    collections::BTreeSet,
     net::{SocketAddr, SocketAddrV4, SocketAddrV6},
     sync::Arc,
 };
@@ -19,7 +20,10 @@ use std::{
 use iroh_base::{EndpointAddr, EndpointId, RelayUrl, SecretKey, TransportAddr};
 use iroh_relay::{RelayConfig, RelayMap};
 use n0_error::{e, ensure, stack_error};
-use n0_future::time::Duration;
+use n0_future::{
+    Stream,
+    time::{self, Duration, Instant},
+};
 use n0_watcher::Watcher;
 use tracing::{debug, instrument, trace, warn};
 use url::Url;
@@ -33,24 +37,31 @@ use crate::{
         ConcurrentDiscovery, DiscoveryError, DiscoveryTask, DynIntoDiscovery, IntoDiscovery,
         UserData,
     },
-    endpoint::presets::Preset,
+    endpoint::{bandwidth::BandwidthLimits, presets::Preset},
     magicsock::{self, EndpointIdMappedAddr, Handle},
     metrics::EndpointMetrics,
     net_report::Report,
     tls::{self, DEFAULT_MAX_TLS_TICKETS},
 };
 
+pub mod address_book;
+pub mod bandwidth;
 mod connection;
+mod events;
+pub mod pool;
 pub mod presets;
 mod rtt_actor;
+#[cfg(not(wasm_browser))]
+pub mod session_store;
+pub mod transport;
 
-// Missing still: SendDatagram and ConnectionClose::frame_type's Type.
+// Missing still: ConnectionClose::frame_type's Type.
 pub use quinn::{
     AcceptBi, AcceptUni, AckFrequencyConfig, ApplicationClose, Chunk, ClosedStream,
     ConnectionClose, ConnectionError, ConnectionStats, MtuDiscoveryConfig, OpenBi, OpenUni,
     ReadDatagram, ReadError, ReadExactError, ReadToEndError, RecvStream, ResetError, RetryError,
-    SendDatagramError, SendStream, ServerConfig, StoppedError, StreamId, TransportConfig, VarInt,
-    WeakConnectionHandle, WriteError,
+    SendDatagram, SendDatagramError, SendStream, ServerConfig, StoppedError, StreamId,
+    TransportConfig, VarInt, WeakConnectionHandle, WriteError,
 };
 pub use quinn_proto::{
     FrameStats, PathStats, TransportError, TransportErrorCode, UdpStats, Written,
@@ -61,33 +72,140 @@ pub use quinn_proto::{
     },
 };
 
+#[cfg(not(wasm_browser))]
+pub use self::address_book::FileAddressBook;
+pub use self::address_book::{AddressBook, AddressBookError, AddressBookStore};
 pub use self::connection::{
     Accept, Accepting, AlpnError, AuthenticationError, Connecting, ConnectingError, Connection,
-    Incoming, IncomingZeroRttConnection, OutgoingZeroRttConnection, RemoteEndpointIdError,
-    ZeroRttStatus,
+    DatagramDropPolicy, Incoming, IncomingZeroRttConnection, OutgoingZeroRttConnection,
+    RemoteEndpointIdError, ZeroRttStatus,
 };
+pub use self::events::EndpointEvent;
+pub(crate) use self::events::EventSender;
+#[cfg(not(wasm_browser))]
+pub use self::session_store::FileSessionStore;
 pub use super::magicsock::{
     AddEndpointAddrError, ConnectionType, ControlMsg, DirectAddr, DirectAddrInfo, DirectAddrType,
-    Source,
+    DiscoPingPurpose, HolepunchEvent, HolepunchEventKind, HolepunchTrace, PathInfo, RelayUrlInfo,
+    RemoteInfo, Source,
 };
+pub use rustls::client::ClientSessionStore;
 
 /// The delay to fall back to discovery when direct addresses fail.
 ///
 /// When a connection is attempted with an [`EndpointAddr`] containing direct addresses the
 /// [`Endpoint`] assumes one of those addresses probably works.  If after this delay there
 /// is still no connection the configured [`crate::discovery::Discovery`] will be used however.
-const DISCOVERY_WAIT_PERIOD: Duration = Duration::from_millis(500);
+///
+/// Can be changed per connection attempt with [`ConnectOptions::with_discovery_delay`].
+pub const DISCOVERY_WAIT_PERIOD: Duration = Duration::from_millis(500);
 
 /// Defines the mode of path selection for all traffic flowing through
 /// the endpoint.
-#[cfg(any(test, feature = "test-utils"))]
-#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+///
+/// Set it for the whole endpoint with [`Builder::path_selection`], or for the traffic to a
+/// single remote endpoint with [`Endpoint::set_path_selection`].
+///
+/// It can not be set per connection: all connections to a remote endpoint are sent over
+/// the same network paths, which are chosen per remote endpoint.  A path selection for a
+/// single connection would change the paths of all other connections to the same remote
+/// endpoint as well.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum PathSelection {
     /// Uses all available paths
     #[default]
     All,
     /// Forces all traffic to go exclusively through relays
+    ///
+    /// No hole punching is attempted, so remote endpoints never learn the IP addresses of
+    /// this endpoint from its connections.
     RelayOnly,
+    /// Forces all traffic to go exclusively over direct paths
+    ///
+    /// Application data is never sent via a relay server.  Relay servers are still used to
+    /// coordinate hole punching, so a relay is needed unless the remote endpoint is
+    /// reachable on one of its known direct addresses.
+    DirectOnly,
+}
+
+/// Configuration of port prediction holepunching, see [`Builder::port_prediction`].
+///
+/// NATs with address-and-port-dependent mapping use a new public port for every
+/// destination, so the addresses a remote endpoint learns about us from relay servers never
+/// work for direct connections.  Many such NATs allocate their ports sequentially however.
+/// Once the regular holepunching did not find a direct path, port prediction also pings the
+/// ports next to the known ports of the remote endpoint's public IPv4 addresses, plus some
+/// random ports in case the allocation is not sequential.
+///
+/// The random ports are the probing half of the birthday-paradox approach, which finds a
+/// remote endpoint behind a hard NAT if our own NAT maps ports independently of the
+/// destination.  The other half, opening mappings from many source ports, is not done:
+/// endpoints send all traffic from a single socket per address family, so when both
+/// endpoints are behind hard NATs port prediction only helps with sequential allocation.
+///
+/// A round of predicted pings is bounded to [`PortPrediction::MAX_PINGS`] and only sent once
+/// per [`PortPrediction::interval`] to each remote endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortPrediction {
+    range: u16,
+    random_ports: u16,
+    interval: Duration,
+}
+
+impl Default for PortPrediction {
+    fn default() -> Self {
+        Self {
+            range: 16,
+            random_ports: 32,
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl PortPrediction {
+    /// The maximum number of predicted pings sent to a remote endpoint in one round.
+    pub const MAX_PINGS: usize = 256;
+
+    /// Sets how many ports above and below each known port are pinged.
+    ///
+    /// Defaults to 16.
+    pub fn with_range(mut self, range: u16) -> Self {
+        self.range = range;
+        self
+    }
+
+    /// Sets how many random ports are pinged on each public IPv4 address.
+    ///
+    /// Defaults to 32.
+    pub fn with_random_ports(mut self, random_ports: u16) -> Self {
+        self.random_ports = random_ports;
+        self
+    }
+
+    /// Sets the minimum interval between two rounds of predicted pings to the same remote
+    /// endpoint.
+    ///
+    /// Defaults to 30 seconds.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Returns how many ports above and below each known port are pinged.
+    pub fn range(&self) -> u16 {
+        self.range
+    }
+
+    /// Returns how many random ports are pinged on each public IPv4 address.
+    pub fn random_ports(&self) -> u16 {
+        self.random_ports
+    }
+
+    /// Returns the minimum interval between two rounds of predicted pings.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
 }
 
 /// Builder for [`Endpoint`].
@@ -112,9 +230,17 @@ pub struct Builder {
     insecure_skip_relay_cert_verify: bool,
     addr_v4: Option<SocketAddrV4>,
     addr_v6: Option<SocketAddrV6>,
-    #[cfg(any(test, feature = "test-utils"))]
     path_selection: PathSelection,
+    port_prediction: Option<PortPrediction>,
+    bandwidth_limits: BandwidthLimits,
     max_tls_tickets: usize,
+    tls_session_store: Option<Arc<dyn ClientSessionStore>>,
+    incoming_filter: Option<tls::IncomingFilter>,
+    custom_transports: Vec<Arc<dyn transport::Transport>>,
+    datagram_drop_policy: DatagramDropPolicy,
+    address_book: Option<Arc<dyn AddressBookStore>>,
+    #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+    virtual_host: Option<crate::test_utils::sim::VirtualHost>,
 }
 
 impl Builder {
@@ -153,9 +279,17 @@ impl Builder {
             insecure_skip_relay_cert_verify: false,
             addr_v4: None,
             addr_v6: None,
-            #[cfg(any(test, feature = "test-utils"))]
             path_selection: PathSelection::default(),
+            port_prediction: None,
+            bandwidth_limits: BandwidthLimits::default(),
             max_tls_tickets: DEFAULT_MAX_TLS_TICKETS,
+            tls_session_store: None,
+            incoming_filter: None,
+            custom_transports: Vec::new(),
+            datagram_drop_policy: DatagramDropPolicy::default(),
+            address_book: None,
+            #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+            virtual_host: None,
         }
     }
 
@@ -163,15 +297,37 @@ impl Builder {
 
     /// Binds the magic endpoint.
     pub async fn bind(self) -> Result<Endpoint, BindError> {
+        let mut transport_ids = BTreeSet::new();
+        for transport in &self.custom_transports {
+            let id = transport.id();
+            ensure!(
+                transport_ids.insert(id),
+                BindError::DuplicateTransportId { id }
+            );
+            let size = transport.max_datagram_size();
+            ensure!(
+                size >= transport::MIN_DATAGRAM_SIZE,
+                BindError::TransportDatagramSize { id, size }
+            );
+        }
+
         let mut rng = rand::rng();
         let relay_map = self.relay_mode.relay_map();
         let secret_key = self
             .secret_key
             .unwrap_or_else(move || SecretKey::generate(&mut rng));
+        let mut tls_config = tls::TlsConfig::new(secret_key.clone(), self.max_tls_tickets);
+        if let Some(filter) = self.incoming_filter {
+            tls_config = tls_config.with_incoming_filter(filter);
+        }
+        if let Some(store) = self.tls_session_store {
+            tls_config = tls_config.with_session_store(store);
+        }
         let static_config = StaticConfig {
             transport_config: Arc::new(self.transport_config),
-            tls_config: tls::TlsConfig::new(secret_key.clone(), self.max_tls_tickets),
+            tls_config,
             keylog: self.keylog,
+            datagram_drop_policy: self.datagram_drop_policy,
         };
         let server_config = static_config.create_server_config(self.alpn_protocols);
 
@@ -180,6 +336,21 @@ impl Builder {
 
         let metrics = EndpointMetrics::default();
 
+        let saved_address_book: AddressBook = match &self.address_book {
+            Some(store) => match address_book::load(store.clone()).await {
+                Ok(book) => book
+                    .into_iter()
+                    .filter(|entry| entry.endpoint_id != secret_key.public())
+                    .collect(),
+                Err(err) => {
+                    warn!("failed to load the address book: {err:#}");
+                    AddressBook::new()
+                }
+            },
+            None => AddressBook::new(),
+        };
+        let saved_endpoints = saved_address_book.iter().map(EndpointAddr::from).collect();
+
         let msock_opts = magicsock::Options {
             addr_v4: self.addr_v4,
             addr_v6: self.addr_v6,
@@ -192,8 +363,13 @@ impl Builder {
             server_config,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: self.insecure_skip_relay_cert_verify,
-            #[cfg(any(test, feature = "test-utils"))]
             path_selection: self.path_selection,
+            port_prediction: self.port_prediction,
+            bandwidth_limits: self.bandwidth_limits,
+            custom_transports: self.custom_transports,
+            saved_endpoints,
+            #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+            virtual_host: self.virtual_host,
             metrics,
         };
 
@@ -202,10 +378,13 @@ impl Builder {
         debug!(version = env!("CARGO_PKG_VERSION"), "iroh Endpoint created");
 
         let metrics = msock.metrics.magicsock.clone();
+        let events = msock.events().clone();
         let ep = Endpoint {
             msock,
-            rtt_actor: Arc::new(rtt_actor::RttHandle::new(metrics)),
+            rtt_actor: Arc::new(rtt_actor::RttHandle::new(metrics, events)),
             static_config: Arc::new(static_config),
+            address_book: self.address_book,
+            saved_address_book: Arc::new(std::sync::Mutex::new(saved_address_book)),
         };
 
         // Add discovery mechanisms
@@ -403,14 +582,123 @@ impl Builder {
         self
     }
 
-    /// This implies we only use the relay to communicate
-    /// and do not attempt to do any hole punching.
-    #[cfg(any(test, feature = "test-utils"))]
+    /// Binds the endpoint onto a host of a simulated network.
+    ///
+    /// The host replaces the UDP sockets and relay servers of the endpoint: the endpoint
+    /// sends and receives on the virtual address of the host, learns its public address
+    /// from the network as if it had probed a relay server, and uses the relay server of
+    /// the network as its home relay.  Holepunching works like on a real network.  The
+    /// [`RelayMode`] and the bind addresses are ignored.
+    ///
+    /// May only be used in tests, see [`crate::test_utils::sim`] for details.
+    #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+    pub fn virtual_host(mut self, host: crate::test_utils::sim::VirtualHost) -> Self {
+        self.virtual_host = Some(host);
+        self
+    }
+
+    /// Sets the [`PathSelection`] for all connections of this endpoint.
+    ///
+    /// [`PathSelection::RelayOnly`] implies we only use the relay to communicate
+    /// and do not attempt to do any hole punching.  [`PathSelection::DirectOnly`]
+    /// never sends application data via a relay.
+    ///
+    /// Defaults to [`PathSelection::All`].
     pub fn path_selection(mut self, path_selection: PathSelection) -> Self {
         self.path_selection = path_selection;
         self
     }
 
+    /// Enables port prediction holepunching for remote endpoints behind hard NATs.
+    ///
+    /// This is off by default, as it sends many more pings while holepunching fails.  See
+    /// [`PortPrediction`] for how it works.  The magicsock metrics
+    /// `port_prediction_pings` and `port_prediction_successes` count its pings and the
+    /// direct paths it found.
+    pub fn port_prediction(mut self, port_prediction: PortPrediction) -> Self {
+        self.port_prediction = Some(port_prediction);
+        self
+    }
+
+    /// Limits the bandwidth used by the endpoint.
+    ///
+    /// Limits can be set for all traffic, separately for direct and relayed traffic, and
+    /// for individual remote endpoints.  See the [`bandwidth`] module for details.
+    ///
+    /// By default the bandwidth is not limited.
+    pub fn bandwidth_limits(mut self, limits: BandwidthLimits) -> Self {
+        self.bandwidth_limits = limits;
+        self
+    }
+
+    /// Adds a custom transport to send and receive datagrams on.
+    ///
+    /// The endpoint uses custom transports in addition to its UDP sockets and relay
+    /// servers.  Their addresses are part of the [`EndpointAddr`] of this endpoint, and
+    /// remote endpoints with custom addresses are dialed on the custom transport with the
+    /// matching id.  See the [`transport`] module for details.
+    ///
+    /// Can be called multiple times to add several transports, each with a unique id.
+    /// [`Builder::bind`] fails if ids are not unique, or if a transport can not carry
+    /// datagrams of [`transport::MIN_DATAGRAM_SIZE`] bytes.
+    pub fn add_custom_transport(mut self, transport: impl transport::Transport) -> Self {
+        self.custom_transports.push(Arc::new(transport));
+        self
+    }
+
+    /// Sets what happens to new application datagrams when the datagram send buffer is full.
+    ///
+    /// Applies to [`Connection::send_datagram`] on all connections of this endpoint, while
+    /// [`Connection::send_datagram_wait`] waits for buffer space instead.  The size of the
+    /// buffer is set with [`TransportConfig::datagram_send_buffer_size`].
+    ///
+    /// Defaults to [`DatagramDropPolicy::DropOldest`].
+    pub fn datagram_drop_policy(mut self, policy: DatagramDropPolicy) -> Self {
+        self.datagram_drop_policy = policy;
+        self
+    }
+
+    /// Persists the addresses of remote endpoints in `store` across restarts.
+    ///
+    /// The address book is loaded when the endpoint is bound, making the saved direct
+    /// addresses and relay URLs which were alive within [`address_book::MAX_AGE`] available
+    /// for connecting without waiting for discovery.
+    /// When the endpoint is closed the current [`Endpoint::export_address_book`] is saved
+    /// to the store.  See the [`address_book`] module for details.
+    ///
+    /// The address book is only saved when the endpoint is closed with [`Endpoint::close`]
+    /// or [`Endpoint::close_graceful`], addresses learned since the last save are lost if
+    /// the process exits otherwise.  To save it more often, pass the
+    /// [`Endpoint::export_address_book`] to [`AddressBookStore::save`] periodically.
+    ///
+    /// By default no address book is used.
+    pub fn address_book(mut self, store: impl AddressBookStore) -> Self {
+        self.address_book = Some(Arc::new(store));
+        self
+    }
+
+    /// Only accepts incoming connections from endpoints allowed by `filter`.
+    ///
+    /// The function is called with the [`EndpointId`] of the remote endpoint during the
+    /// TLS handshake of every incoming connection, and should return `true` for endpoints
+    /// that are allowed to connect, and `false` otherwise.  Refused connections fail their
+    /// handshake before a [`Connection`] is created, so they never reach a protocol handler
+    /// and do not use up any connection or stream limits.  The remote endpoint sees its
+    /// connection closed with a TLS error.
+    ///
+    /// Unlike [`AccessLimit`], this applies to all connections of the endpoint, regardless
+    /// of their ALPN.  As resumed TLS sessions would skip the check, setting a filter
+    /// disables session resumption and 0-RTT for incoming connections.
+    ///
+    /// [`AccessLimit`]: crate::protocol::AccessLimit
+    pub fn incoming_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(EndpointId) -> bool + Send + Sync + 'static,
+    {
+        self.incoming_filter = Some(tls::IncomingFilter::new(filter));
+        self
+    }
+
     /// Set the maximum number of TLS tickets to cache.
     ///
     /// Set this to a larger value if you want to do 0rtt connections to a large
@@ -421,6 +709,20 @@ impl Builder {
         self.max_tls_tickets = n;
         self
     }
+
+    /// Sets where the TLS session state of outgoing connections is kept.
+    ///
+    /// The store holds the session tickets used to resume sessions and send 0-RTT data,
+    /// replacing the in-memory cache sized by [`Builder::max_tls_tickets`].  A
+    /// [`FileSessionStore`] additionally remembers the key exchange group of each remote
+    /// endpoint across restarts; see the [`session_store`] module for what can and can not be
+    /// persisted.
+    ///
+    /// [`session_store`]: crate::endpoint::session_store
+    pub fn tls_session_store(mut self, store: impl ClientSessionStore + 'static) -> Self {
+        self.tls_session_store = Some(Arc::new(store));
+        self
+    }
 }
 
 /// Configuration for a [`quinn::Endpoint`] that cannot be changed at runtime.
@@ -429,6 +731,7 @@ struct StaticConfig {
     tls_config: tls::TlsConfig,
     transport_config: Arc<quinn::TransportConfig>,
     keylog: bool,
+    datagram_drop_policy: DatagramDropPolicy,
 }
 
 impl StaticConfig {
@@ -477,6 +780,10 @@ pub struct Endpoint {
     rtt_actor: Arc<rtt_actor::RttHandle>,
     /// Configuration structs for quinn, holds the transport config, certificate setup, secret key etc.
     static_config: Arc<StaticConfig>,
+    /// Where the address book is saved on close, if configured.
+    address_book: Option<Arc<dyn AddressBookStore>>,
+    /// The address book as loaded from, or last saved to, the store.
+    saved_address_book: Arc<std::sync::Mutex<AddressBook>>,
 }
 
 #[allow(missing_docs)]
@@ -494,12 +801,16 @@ pub enum ConnectWithOptsError {
         #[error(std_err)]
         source: quinn_proto::ConnectError,
     },
+    #[error("Timed out while looking up the remote's addresses")]
+    Timeout,
 }
 
 #[allow(missing_docs)]
 #[stack_error(derive, add_meta, from_sources)]
 #[non_exhaustive]
 pub enum ConnectError {
+    #[error("At least one ALPN is required")]
+    NoAlpn {},
     #[error(transparent)]
     Connect { source: ConnectWithOptsError },
     #[error(transparent)]
@@ -523,6 +834,10 @@ pub enum BindError {
     Discovery {
         source: crate::discovery::IntoDiscoveryError,
     },
+    #[error("Custom transport id {id} is used by more than one transport")]
+    DuplicateTransportId { id: u64 },
+    #[error("Custom transport {id} carries datagrams of at most {size} bytes, QUIC requires 1200")]
+    TransportDatagramSize { id: u64, size: usize },
 }
 
 #[allow(missing_docs)]
@@ -535,6 +850,8 @@ pub enum GetMappingAddressError {
     Discover { source: DiscoveryError },
     #[error("No addressing information found")]
     NoAddress,
+    #[error("No addressing information found and discovery is disabled")]
+    DiscoveryDisabled,
 }
 
 impl Endpoint {
@@ -663,7 +980,10 @@ impl Endpoint {
         alpn: &[u8],
         options: ConnectOptions,
     ) -> Result<Connecting, ConnectWithOptsError> {
-        let endpoint_addr: EndpointAddr = endpoint_addr.into();
+        let deadline = options
+            .connect_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let mut endpoint_addr: EndpointAddr = endpoint_addr.into();
         tracing::Span::current().record(
             "remote",
             tracing::field::display(endpoint_addr.id.fmt_short()),
@@ -675,6 +995,12 @@ impl Endpoint {
             ConnectWithOptsError::SelfConnect
         );
 
+        if let Some(relay_url) = options.relay_url {
+            endpoint_addr
+                .addrs
+                .retain(|addr| !matches!(addr, TransportAddr::Relay(_)));
+            endpoint_addr.addrs.insert(TransportAddr::Relay(relay_url));
+        }
         if !endpoint_addr.is_empty() {
             self.add_endpoint_addr(endpoint_addr.clone(), Source::App)?;
         }
@@ -686,9 +1012,18 @@ impl Endpoint {
         // address.  Start discovery for this endpoint if it's enabled and we have no valid or
         // verified address information for this endpoint.  Dropping the discovery cancels any
         // still running task.
-        let (mapped_addr, _discovery_drop_guard) = self
-            .get_mapping_addr_and_maybe_start_discovery(endpoint_addr)
-            .await?;
+        let discovery_delay = options.discovery_delay.unwrap_or(DISCOVERY_WAIT_PERIOD);
+        let get_mapping_addr = self.get_mapping_addr_and_maybe_start_discovery(
+            endpoint_addr,
+            options.discovery,
+            discovery_delay,
+        );
+        let (mapped_addr, _discovery_drop_guard) = match options.connect_timeout {
+            Some(timeout) => time::timeout(timeout, get_mapping_addr)
+                .await
+                .map_err(|_| e!(ConnectWithOptsError::Timeout))??,
+            None => get_mapping_addr.await?,
+        };
 
         let transport_config = options
             .transport_config
@@ -726,10 +1061,45 @@ impl Endpoint {
             connect,
             self.clone(),
             endpoint_id,
+            alpn.to_vec(),
             _discovery_drop_guard,
+            deadline,
         ))
     }
 
+    /// Connects to a remote [`Endpoint`], offering several versions of a protocol.
+    ///
+    /// The [ALPN] identifiers in `alpns` are offered in order of preference: the first one
+    /// is used as the main ALPN of [`Endpoint::connect_with_opts`], the others are offered
+    /// with [`ConnectOptions::with_additional_alpns`], before any additional ALPNs already
+    /// set on `options`.  Note that it is the accept side which selects the ALPN.
+    ///
+    /// The ALPN negotiated with the remote is available from [`Connection::alpn`].
+    ///
+    /// Returns [`ConnectError::NoAlpn`] if `alpns` is empty.
+    ///
+    /// [ALPN]: https://en.wikipedia.org/wiki/Application-Layer_Protocol_Negotiation
+    pub async fn connect_with_alpns(
+        &self,
+        endpoint_addr: impl Into<EndpointAddr>,
+        alpns: &[&[u8]],
+        mut options: ConnectOptions,
+    ) -> Result<Connection, ConnectError> {
+        let Some((alpn, fallbacks)) = alpns.split_first() else {
+            return Err(e!(ConnectError::NoAlpn));
+        };
+        let additional = std::mem::take(&mut options.additional_alpns);
+        options.additional_alpns = fallbacks.iter().map(|a| a.to_vec()).collect();
+        options.additional_alpns.extend(additional);
+        let connecting = self.connect_with_opts(endpoint_addr, alpn, options).await?;
+        let conn = connecting.await?;
+        debug!(
+            alpn = %String::from_utf8_lossy(conn.alpn()),
+            "Connection established."
+        );
+        Ok(conn)
+    }
+
     /// Accepts an incoming connection on the endpoint.
     ///
     /// Only connections with the ALPNs configured in [`Builder::alpns`] will be accepted.
@@ -782,6 +1152,31 @@ impl Endpoint {
         self.msock.add_endpoint_addr(endpoint_addr, source)
     }
 
+    /// Sets the [`PathSelection`] for all traffic to a remote endpoint.
+    ///
+    /// This overrides [`Builder::path_selection`] for the given remote endpoint.  All
+    /// connections to a remote endpoint share their network paths, so this applies to
+    /// existing connections as well as to future ones.  Passing `None` removes the override
+    /// again.
+    ///
+    /// The override only affects how this endpoint sends to the remote endpoint.  A
+    /// [`PathSelection::RelayOnly`] override does not hide our direct addresses from the
+    /// remote endpoint: unless [`Builder::path_selection`] is [`PathSelection::RelayOnly`]
+    /// as well, they are still published via discovery, and pings the remote endpoint sends
+    /// to them are still answered.
+    ///
+    /// Overrides are kept until they are removed, also when the state of an inactive remote
+    /// endpoint is pruned, so that a remote endpoint which is contacted again is still
+    /// handled as configured.  Remove overrides which are no longer needed by passing
+    /// `None`, to not keep them around forever.
+    pub fn set_path_selection(
+        &self,
+        endpoint_id: EndpointId,
+        path_selection: Option<PathSelection>,
+    ) {
+        self.msock.set_path_selection(endpoint_id, path_selection);
+    }
+
     // # Getter methods for properties of this Endpoint itself.
 
     /// Returns the secret_key of this endpoint.
@@ -845,15 +1240,17 @@ impl Endpoint {
     /// - the endpoint connects to a relay server
     /// - the endpoint changes its preferred relay server
     /// - more addresses are discovered for this endpoint
+    /// - the addresses of a custom transport change
     ///
     /// [`RelayUrl`]: crate::RelayUrl
     #[cfg(not(wasm_browser))]
     pub fn watch_addr(&self) -> impl n0_watcher::Watcher<Value = EndpointAddr> + use<> {
         let watch_addrs = self.msock.ip_addrs();
         let watch_relay = self.msock.home_relay();
+        let watch_custom = self.msock.custom_addrs();
         let endpoint_id = self.id();
 
-        watch_addrs.or(watch_relay).map(move |(addrs, relays)| {
+        (watch_addrs, watch_relay, watch_custom).map(move |(addrs, relays, custom_addrs)| {
             debug_assert!(!addrs.is_empty(), "direct addresses must never be empty");
 
             EndpointAddr::from_parts(
@@ -861,7 +1258,8 @@ impl Endpoint {
                 relays
                     .into_iter()
                     .map(TransportAddr::Relay)
-                    .chain(addrs.into_iter().map(|x| TransportAddr::Ip(x.addr))),
+                    .chain(addrs.into_iter().map(|x| TransportAddr::Ip(x.addr)))
+                    .chain(custom_addrs.into_iter().map(TransportAddr::Custom)),
             )
         })
     }
@@ -877,10 +1275,19 @@ impl Endpoint {
         // for the home relay instead. This makes the `EndpointAddr` have *some* way
         // of connecting to us.
         let watch_relay = self.msock.home_relay();
+        let watch_custom = self.msock.custom_addrs();
         let endpoint_id = self.id();
-        watch_relay.map(move |mut relays| {
-            EndpointAddr::from_parts(endpoint_id, relays.into_iter().map(TransportAddr::Relay))
-        })
+        watch_relay
+            .or(watch_custom)
+            .map(move |(relays, custom_addrs)| {
+                EndpointAddr::from_parts(
+                    endpoint_id,
+                    relays
+                        .into_iter()
+                        .map(TransportAddr::Relay)
+                        .chain(custom_addrs.into_iter().map(TransportAddr::Custom)),
+                )
+            })
     }
 
     /// A convenience method that waits for the endpoint to be considered "online".
@@ -986,6 +1393,69 @@ impl Endpoint {
         self.msock.latency(endpoint_id)
     }
 
+    /// Returns information about the remote endpoint identified by `endpoint_id`.
+    ///
+    /// The returned [`RemoteInfo`] is a snapshot of everything this endpoint knows about the
+    /// network paths to the remote endpoint: each known direct address together with how it
+    /// was learned, when it was last alive and the latencies measured on it, as well as the
+    /// relay path.  This is useful for diagnostics, e.g. to understand why a connection is
+    /// still using the relay.
+    ///
+    /// Will return `None` if we do not have any address information for the given `endpoint_id`.
+    pub fn remote_info(&self, endpoint_id: EndpointId) -> Option<RemoteInfo> {
+        self.msock.remote_info(endpoint_id)
+    }
+
+    /// Returns the recent holepunching activity towards the remote endpoint `endpoint_id`.
+    ///
+    /// The [`HolepunchTrace`] lists the call-me-maybe messages sent and received, the pings
+    /// sent to each candidate address with their pongs or timeouts, and the changes of the
+    /// path used.  This helps to understand why a connection stays on the relay, and can be
+    /// serialized to JSON for bug reports.  Only a bounded number of recent events is kept.
+    ///
+    /// Will return `None` if we do not have any address information for the given `endpoint_id`.
+    pub fn holepunch_trace(&self, endpoint_id: EndpointId) -> Option<HolepunchTrace> {
+        self.msock.holepunch_trace(endpoint_id)
+    }
+
+    /// Returns information about all remote endpoints known to this endpoint.
+    ///
+    /// See [`Endpoint::remote_info`] for details.  Endpoints which have been inactive for a
+    /// while are pruned regularly, so this does not return every endpoint ever contacted.
+    pub fn remote_infos(&self) -> Vec<RemoteInfo> {
+        self.msock.list_remote_infos()
+    }
+
+    /// Returns the validated addresses of all remote endpoints known to this endpoint.
+    ///
+    /// The [`AddressBook`] contains the direct addresses on which the remote endpoints
+    /// answered, and their relay URLs, each with the time they were last alive.  It can be
+    /// saved and passed to [`Builder::address_book`] to connect directly to these endpoints
+    /// after a restart.
+    ///
+    /// The address book loaded from the [`Builder::address_book`] store is merged into the
+    /// result, keeping the saved addresses of remote endpoints which were not contacted
+    /// since.  Addresses older than [`address_book::MAX_AGE`] are removed.
+    pub fn export_address_book(&self) -> AddressBook {
+        let mut book = self.saved_address_book.lock().expect("poisoned").clone();
+        book.merge(AddressBook::from_remote_infos(self.remote_infos()));
+        book.prune(address_book::MAX_AGE);
+        book
+    }
+
+    /// Returns a stream of connectivity events of this endpoint.
+    ///
+    /// The stream yields an [`EndpointEvent`] whenever a connection is opened or closed,
+    /// the path to a remote endpoint switches between relay and direct, the home relay
+    /// changes, a new net report is available or a discovery service found addressing
+    /// information for a remote endpoint.
+    ///
+    /// Only events emitted after this call are yielded.  A subscriber which does not keep
+    /// up with the events skips the oldest ones it missed.
+    pub fn events(&self) -> impl Stream<Item = EndpointEvent> + Unpin + use<> {
+        self.msock.events().subscribe()
+    }
+
     /// Returns the DNS resolver used in this [`Endpoint`].
     ///
     /// See [`Builder::dns_resolver`].
@@ -1188,10 +1658,34 @@ impl Endpoint {
             return;
         }
 
+        self.save_address_book().await;
         tracing::debug!("Connections closed");
         self.msock.close().await;
     }
 
+    /// Closes the endpoint after letting open connections finish, bounded by `timeout`.
+    ///
+    /// Unlike [`Endpoint::close`], which immediately closes all connections, this:
+    ///
+    /// - Stops accepting new connections right away.  Connection attempts from remote
+    ///   endpoints are refused from now on.
+    /// - Waits for all open connections to be closed, by either side, and drained.
+    /// - Hands all datagrams still queued for relay servers to the relay connections.
+    /// - Closes the endpoint like [`Endpoint::close`] does, closing all connections which
+    ///   are still open once `timeout` elapsed.
+    ///
+    /// This is useful for restarting servers without cutting off in-flight transfers: the
+    /// protocols are expected to close their connections once they are done with them.
+    pub async fn close_graceful(&self, timeout: Duration) {
+        if self.is_closed() {
+            return;
+        }
+
+        tracing::debug!(?timeout, "Closing gracefully");
+        self.msock.close_graceful(timeout).await;
+        self.save_address_book().await;
+    }
+
     /// Check if this endpoint is still alive, or already closed.
     pub fn is_closed(&self) -> bool {
         self.msock.is_closed()
@@ -1199,6 +1693,17 @@ impl Endpoint {
 
     // # Remaining private methods
 
+    /// Saves the address book to the configured store, if any.
+    async fn save_address_book(&self) {
+        if let Some(store) = &self.address_book {
+            let book = self.export_address_book();
+            *self.saved_address_book.lock().expect("poisoned") = book.clone();
+            if let Err(err) = address_book::save(store.clone(), book).await {
+                warn!("failed to save the address book: {err:#}");
+            }
+        }
+    }
+
     /// Checks if the given `EndpointId` needs discovery.
     pub(crate) fn needs_discovery(&self, endpoint_id: EndpointId, max_age: Duration) -> bool {
         match self.msock.remote_info(endpoint_id) {
@@ -1229,6 +1734,8 @@ impl Endpoint {
     /// 1) we do not have discovery enabled
     /// 2) we have discovery enabled, but already have at least one verified, unexpired
     ///    addresses for this `endpoint_id`
+    /// 3) `mode` is [`DiscoveryMode::Disabled`], or [`DiscoveryMode::IfNoAddress`] and we
+    ///    have some address for this `endpoint_id`
     ///
     /// # Errors
     ///
@@ -1238,6 +1745,8 @@ impl Endpoint {
     async fn get_mapping_addr_and_maybe_start_discovery(
         &self,
         endpoint_addr: EndpointAddr,
+        mode: DiscoveryMode,
+        delay: Duration,
     ) -> Result<(EndpointIdMappedAddr, Option<DiscoveryTask>), GetMappingAddressError> {
         let endpoint_id = endpoint_addr.id;
 
@@ -1249,6 +1758,7 @@ impl Endpoint {
             None
         };
         match addr {
+            Some(addr) if mode != DiscoveryMode::Enabled => Ok((addr, None)),
             Some(addr) => {
                 // We have some way of dialing this endpoint, but that doesn't actually mean
                 // we can actually connect to any of these addresses.
@@ -1257,13 +1767,16 @@ impl Endpoint {
                 // If the user provided addresses in this connect call, we will add a delay
                 // followed by a recheck before starting the discovery, to give the magicsocket a
                 // chance to test the newly provided addresses.
-                let delay = (!endpoint_addr.is_empty()).then_some(DISCOVERY_WAIT_PERIOD);
+                let delay = (!endpoint_addr.is_empty()).then_some(delay);
                 let discovery = DiscoveryTask::maybe_start_after_delay(self, endpoint_id, delay)
                     .ok()
                     .flatten();
                 Ok((addr, discovery))
             }
 
+            None if mode == DiscoveryMode::Disabled => {
+                Err(e!(GetMappingAddressError::DiscoveryDisabled))
+            }
             None => {
                 // We have no known addresses or relay URLs for this endpoint.
                 // So, we start a discovery task and wait for the first result to arrive, and
@@ -1285,6 +1798,11 @@ impl Endpoint {
         }
     }
 
+    /// Returns the sender for the connectivity events of this endpoint.
+    pub(crate) fn event_sender(&self) -> &EventSender {
+        self.msock.events()
+    }
+
     #[cfg(test)]
     pub(crate) fn magic_sock(&self) -> Handle {
         self.msock.clone()
@@ -1295,11 +1813,38 @@ impl Endpoint {
     }
 }
 
+/// When to use the discovery services of the endpoint during a connection attempt.
+///
+/// Set with [`ConnectOptions::with_discovery`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DiscoveryMode {
+    /// Runs discovery unless the remote endpoint was recently reachable on a known path
+    ///
+    /// If addresses were passed to the connection attempt, discovery only starts if none of
+    /// them worked after the [discovery delay].
+    ///
+    /// [discovery delay]: ConnectOptions::with_discovery_delay
+    #[default]
+    Enabled,
+    /// Only runs discovery if no address of the remote endpoint is known
+    IfNoAddress,
+    /// Never runs discovery
+    ///
+    /// The connection attempt fails if no address of the remote endpoint is known.  This
+    /// makes sure no discovery service learns about the connection attempt.
+    Disabled,
+}
+
 /// Options for the [`Endpoint::connect_with_opts`] function.
 #[derive(Default, Debug, Clone)]
 pub struct ConnectOptions {
     transport_config: Option<Arc<TransportConfig>>,
     additional_alpns: Vec<Vec<u8>>,
+    connect_timeout: Option<Duration>,
+    discovery: DiscoveryMode,
+    discovery_delay: Option<Duration>,
+    relay_url: Option<RelayUrl>,
 }
 
 impl ConnectOptions {
@@ -1341,6 +1886,49 @@ impl ConnectOptions {
         self.additional_alpns = alpns;
         self
     }
+
+    /// Sets a timeout for the whole connection attempt.
+    ///
+    /// The timeout covers waiting for discovery to find addresses of the remote endpoint as
+    /// well as the handshake.  Once it elapses, [`Endpoint::connect_with_opts`] fails with
+    /// [`ConnectWithOptsError::Timeout`] or the returned [`Connecting`] fails with
+    /// [`ConnectingError::Timeout`].  A 0-RTT connection is not affected by the timeout once
+    /// created with [`Connecting::into_0rtt`].
+    ///
+    /// Without a timeout the connection attempt fails once the QUIC idle timeout of the
+    /// [`TransportConfig`] elapsed without reaching the remote endpoint, or once discovery
+    /// gave up.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets when to use the discovery services for this connection attempt.
+    ///
+    /// Defaults to [`DiscoveryMode::Enabled`].
+    pub fn with_discovery(mut self, mode: DiscoveryMode) -> Self {
+        self.discovery = mode;
+        self
+    }
+
+    /// Sets how long to try the addresses passed to the connection attempt before falling
+    /// back to discovery.
+    ///
+    /// Defaults to [`DISCOVERY_WAIT_PERIOD`].
+    pub fn with_discovery_delay(mut self, delay: Duration) -> Self {
+        self.discovery_delay = Some(delay);
+        self
+    }
+
+    /// Uses `relay_url` as the relay server of the remote endpoint.
+    ///
+    /// Replaces any relay URL of the [`EndpointAddr`] passed to the connection attempt.  As
+    /// with any address passed to a connection attempt, the relay server is then used for
+    /// all traffic to the remote endpoint until other address information replaces it.
+    pub fn with_relay_url(mut self, relay_url: RelayUrl) -> Self {
+        self.relay_url = Some(relay_url);
+        self
+    }
 }
 
 /// Read a proxy url from the environment, in this order
@@ -1441,9 +2029,16 @@ fn is_cgi() -> bool {
 // https://github.com/n0-computer/iroh/issues/1183
 #[cfg(test)]
 mod tests {
-    use std::time::{Duration, Instant};
+    use std::{
+        collections::{BTreeMap, HashMap},
+        io,
+        net::Ipv4Addr,
+        sync::Arc,
+        task::{Context, Poll},
+        time::{Duration, Instant},
+    };
 
-    use iroh_base::{EndpointAddr, EndpointId, SecretKey, TransportAddr};
+    use iroh_base::{CustomAddr, EndpointAddr, EndpointId, RelayUrl, SecretKey, TransportAddr};
     use n0_error::{AnyError as Error, Result, StdResultExt};
     use n0_future::{BufferedStreamExt, StreamExt, stream, task::AbortOnDropHandle};
     use n0_watcher::Watcher;
@@ -1456,9 +2051,17 @@ mod tests {
     use crate::{
         RelayMode,
         discovery::static_provider::StaticProvider,
-        endpoint::{ConnectOptions, Connection, ConnectionType},
+        endpoint::{
+            AddressBook, AddressBookStore, ConnectOptions, ConnectWithOptsError, ConnectingError,
+            Connection, ConnectionType, DiscoveryMode, EndpointEvent, FileAddressBook,
+            FileSessionStore, GetMappingAddressError, HolepunchEventKind, HolepunchTrace,
+            PathSelection, Source,
+            address_book::{AddressBookEntry, SavedDirectAddr},
+            bandwidth::{BandwidthLimits, RateLimit, TrafficLimits},
+        },
         protocol::{AcceptError, ProtocolHandler, Router},
         test_utils::{run_relay_server, run_relay_server_with},
+        tls::DEFAULT_MAX_TLS_TICKETS,
     };
 
     const TEST_ALPN: &[u8] = b"n0/iroh/test";
@@ -2038,6 +2641,832 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_remote_info() -> Result {
+        let client = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.addr();
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.anyerr()?;
+            let conn = incoming.await.anyerr()?;
+            conn.closed().await;
+            Ok::<_, Error>(())
+        });
+
+        assert!(client.remote_info(server_addr.id).is_none());
+        assert!(client.remote_infos().is_empty());
+
+        let conn = client.connect(server_addr.clone(), TEST_ALPN).await?;
+        let info = client
+            .remote_info(server_addr.id)
+            .expect("connected endpoint is known");
+        assert_eq!(info.endpoint_id, server_addr.id);
+        assert!(info.relay_url.is_none());
+        assert!(info.has_send_address());
+        for addr in server_addr.ip_addrs() {
+            let addr_info = info
+                .addrs
+                .iter()
+                .find(|info| info.addr == *addr)
+                .expect("dialed address is known");
+            assert!(addr_info.sources.contains_key(&Source::App));
+        }
+        assert_eq!(
+            client
+                .remote_infos()
+                .into_iter()
+                .map(|info| info.endpoint_id)
+                .collect::<Vec<_>>(),
+            vec![server_addr.id]
+        );
+
+        conn.close(0u32.into(), b"done");
+        server_task.await.anyerr()??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_close_graceful() -> Result {
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.addr();
+        let client = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.anyerr()?.await.anyerr() }
+        });
+        let conn = client.connect(server_addr.clone(), TEST_ALPN).await?;
+        let server_conn = accept_task.await.anyerr()??;
+
+        // Start closing while the connection is still in use.
+        let close_start = Instant::now();
+        let closing = tokio::spawn({
+            let server = server.clone();
+            async move { server.close_graceful(Duration::from_secs(10)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!server.is_closed());
+
+        // New connections are refused.
+        let client2 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let res = tokio::time::timeout(
+            Duration::from_secs(1),
+            client2.connect(server_addr.clone(), TEST_ALPN),
+        )
+        .await;
+        assert!(!matches!(res, Ok(Ok(_))));
+
+        // The open connection keeps working.
+        let mut send = conn.open_uni().await.anyerr()?;
+        send.write_all(b"hello").await.anyerr()?;
+        send.finish().anyerr()?;
+        let mut recv = server_conn.accept_uni().await.anyerr()?;
+        let msg = recv.read_to_end(100).await.anyerr()?;
+        assert_eq!(msg, b"hello");
+
+        server_conn.close(0u32.into(), b"done");
+        closing.await.anyerr()?;
+        assert!(close_start.elapsed() < Duration::from_secs(10));
+        assert!(server.is_closed());
+
+        // Connections which are not closed are closed once the timeout elapsed.
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.anyerr()?.await.anyerr() }
+        });
+        let conn = client.connect(server.addr(), TEST_ALPN).await?;
+        let _server_conn = accept_task.await.anyerr()??;
+        server.close_graceful(Duration::from_millis(200)).await;
+        assert!(server.is_closed());
+        conn.closed().await;
+
+        tokio::join!(client.close(), client2.close());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_path_selection() -> Result {
+        let (relay_map, _relay_url, _guard) = run_relay_server().await?;
+        let server = Endpoint::empty_builder(RelayMode::Custom(relay_map.clone()))
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await?;
+        server.online().await;
+        let server_addr = server.addr();
+        let server_task = tokio::spawn(async move {
+            for _ in 0..2 {
+                let incoming = server.accept().await.anyerr()?;
+                let conn = incoming.await.anyerr()?;
+                let (mut send, mut recv) = conn.accept_bi().await.anyerr()?;
+                let msg = recv.read_to_end(100).await.anyerr()?;
+                send.write_all(&msg).await.anyerr()?;
+                send.finish().anyerr()?;
+                conn.closed().await;
+            }
+            Ok::<_, Error>(())
+        });
+
+        async fn echo(conn: &Connection) -> Result {
+            let (mut send, mut recv) = conn.open_bi().await.anyerr()?;
+            send.write_all(b"hello").await.anyerr()?;
+            send.finish().anyerr()?;
+            let msg = recv.read_to_end(100).await.anyerr()?;
+            assert_eq!(msg, b"hello");
+            Ok(())
+        }
+
+        // Relay only, set for a single remote endpoint.
+        let client = Endpoint::empty_builder(RelayMode::Custom(relay_map.clone()))
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await?;
+        client.set_path_selection(server_addr.id, Some(PathSelection::RelayOnly));
+        let conn = client.connect(server_addr.clone(), TEST_ALPN).await?;
+        echo(&conn).await?;
+        let mut conn_type = client.conn_type(server_addr.id).expect("known endpoint");
+        assert!(matches!(conn_type.get(), ConnectionType::Relay(_)));
+        conn.close(0u32.into(), b"done");
+        client.close().await;
+
+        // Direct only, set for the whole endpoint.
+        let client = Endpoint::empty_builder(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .path_selection(PathSelection::DirectOnly)
+            .bind()
+            .await?;
+        let conn = client.connect(server_addr.clone(), TEST_ALPN).await?;
+        echo(&conn).await?;
+        let mut conn_type = client.conn_type(server_addr.id).expect("known endpoint");
+        assert!(matches!(conn_type.get(), ConnectionType::Direct(_)));
+        conn.close(0u32.into(), b"done");
+        client.close().await;
+
+        server_task.await.anyerr()??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_connect_options() -> Result {
+        let disco = StaticProvider::new();
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.addr();
+        let server_id = server_addr.id;
+        disco.add_endpoint_info(server_addr.clone());
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.anyerr()?;
+            let conn = incoming.await.anyerr()?;
+            conn.closed().await;
+            Ok::<_, Error>(())
+        });
+        let client = Endpoint::empty_builder(RelayMode::Disabled)
+            .discovery(disco)
+            .bind()
+            .await?;
+
+        // Without discovery there is no address to dial.
+        let options = ConnectOptions::new().with_discovery(DiscoveryMode::Disabled);
+        let res = client
+            .connect_with_opts(server_id, TEST_ALPN, options)
+            .await;
+        assert!(matches!(
+            res,
+            Err(ConnectWithOptsError::NoAddress {
+                source: GetMappingAddressError::DiscoveryDisabled { .. },
+                ..
+            })
+        ));
+
+        // The relay URL override is used alongside the direct addresses.
+        let relay_url: RelayUrl = "https://relay.example.org".parse().anyerr()?;
+        let options = ConnectOptions::new()
+            .with_discovery(DiscoveryMode::IfNoAddress)
+            .with_relay_url(relay_url.clone());
+        let conn = client
+            .connect_with_opts(server_addr, TEST_ALPN, options)
+            .await?
+            .await
+            .anyerr()?;
+        let info = client.remote_info(server_id).expect("connected endpoint");
+        assert_eq!(info.relay_url.map(|info| info.relay_url), Some(relay_url));
+        conn.close(0u32.into(), b"done");
+        server_task.await.anyerr()??;
+
+        // Connecting to an unresponsive endpoint times out.
+        let unresponsive = SecretKey::generate(&mut rand::rng()).public();
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).anyerr()?;
+        let addr = EndpointAddr::new(unresponsive).with_ip_addr(socket.local_addr().anyerr()?);
+        let options = ConnectOptions::new().with_connect_timeout(Duration::from_millis(500));
+        let start = Instant::now();
+        let res = client
+            .connect_with_opts(addr, TEST_ALPN, options)
+            .await?
+            .await;
+        assert!(matches!(res, Err(ConnectingError::Timeout { .. })));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        client.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_incoming_filter() -> Result {
+        let allowed = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let refused = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let allowed_id = allowed.id();
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .incoming_filter(move |remote| remote == allowed_id)
+            .bind()
+            .await?;
+        let server_addr = server.addr();
+        let server_task = tokio::spawn(async move {
+            let mut accepted = Vec::new();
+            for _ in 0..3 {
+                let incoming = server.accept().await.anyerr()?;
+                if let Ok(conn) = incoming.await {
+                    accepted.push(conn.remote_id());
+                    let mut send = conn.open_uni().await.anyerr()?;
+                    send.write_all(b"hello").await.anyerr()?;
+                    send.finish().anyerr()?;
+                    conn.closed().await;
+                }
+            }
+            Ok::<_, Error>(accepted)
+        });
+
+        // The client completes its side of the handshake before the server verifies it,
+        // so the refusal may only show as the connection being closed.
+        if let Ok(conn) = refused.connect(server_addr.clone(), TEST_ALPN).await {
+            let reason = conn.closed().await;
+            assert!(matches!(reason, ConnectionError::ConnectionClosed(_)));
+        }
+        let conn = allowed.connect(server_addr.clone(), TEST_ALPN).await?;
+        let mut recv = conn.accept_uni().await.anyerr()?;
+        assert_eq!(recv.read_to_end(100).await.anyerr()?, b"hello");
+        conn.close(0u32.into(), b"done");
+
+        // The server issued no session ticket, so the second connection can not skip the
+        // filter by resuming the first session with 0-RTT.
+        let connecting = allowed
+            .connect_with_opts(server_addr, TEST_ALPN, ConnectOptions::new())
+            .await?;
+        let Err(connecting) = connecting.into_0rtt() else {
+            panic!("0-RTT connection with an incoming filter");
+        };
+        let conn = connecting.await.anyerr()?;
+        let mut recv = conn.accept_uni().await.anyerr()?;
+        assert_eq!(recv.read_to_end(100).await.anyerr()?, b"hello");
+        conn.close(0u32.into(), b"done");
+        assert_eq!(server_task.await.anyerr()??, vec![allowed_id, allowed_id]);
+
+        tokio::join!(allowed.close(), refused.close());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_tls_session_store() -> Result {
+        let dir = tempfile::tempdir().anyerr()?;
+        let path = dir.path().join("tls_sessions.json");
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.addr();
+        let server_task = tokio::spawn(async move {
+            for _ in 0..2 {
+                let incoming = server.accept().await.anyerr()?;
+                let conn = incoming.await.anyerr()?;
+                let mut send = conn.open_uni().await.anyerr()?;
+                send.write_all(b"hello").await.anyerr()?;
+                send.finish().anyerr()?;
+                conn.closed().await;
+            }
+            Ok::<_, Error>(())
+        });
+
+        let client = Endpoint::empty_builder(RelayMode::Disabled)
+            .tls_session_store(FileSessionStore::new(&path, DEFAULT_MAX_TLS_TICKETS))
+            .bind()
+            .await?;
+        let conn = client.connect(server_addr.clone(), TEST_ALPN).await?;
+        let mut recv = conn.accept_uni().await.anyerr()?;
+        assert_eq!(recv.read_to_end(100).await.anyerr()?, b"hello");
+        conn.close(0u32.into(), b"done");
+
+        // The session ticket is kept in memory, so the second connection can use 0-RTT.
+        let connecting = client
+            .connect_with_opts(server_addr, TEST_ALPN, ConnectOptions::new())
+            .await?;
+        let Ok(conn) = connecting.into_0rtt() else {
+            panic!("no 0-RTT connection with a session ticket");
+        };
+        let mut recv = conn.accept_uni().await.anyerr()?;
+        assert_eq!(recv.read_to_end(100).await.anyerr()?, b"hello");
+        conn.close(0u32.into(), b"done");
+        server_task.await.anyerr()??;
+
+        client.close().await;
+
+        // The key exchange group chosen by the server was saved.
+        let saved: BTreeMap<String, u16> =
+            serde_json::from_slice(&std::fs::read(&path).anyerr()?).anyerr()?;
+        assert_eq!(saved.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_bandwidth_limits() -> Result {
+        const RATE: u64 = 256 * 1024;
+        const DATA_LEN: usize = 512 * 1024;
+
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.addr();
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.anyerr()?;
+            let conn = incoming.await.anyerr()?;
+            let mut recv = conn.accept_uni().await.anyerr()?;
+            let data = recv.read_to_end(DATA_LEN).await.anyerr()?;
+            assert_eq!(data.len(), DATA_LEN);
+            conn.close(0u32.into(), b"done");
+            Ok::<_, Error>(())
+        });
+
+        let limits = BandwidthLimits::new()
+            .with_direct(TrafficLimits::new().with_upload(RateLimit::bytes_per_second(RATE)));
+        let client = Endpoint::empty_builder(RelayMode::Disabled)
+            .bandwidth_limits(limits)
+            .bind()
+            .await?;
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+
+        let start = Instant::now();
+        let mut send = conn.open_uni().await.anyerr()?;
+        send.write_all(&vec![0u8; DATA_LEN]).await.anyerr()?;
+        send.finish().anyerr()?;
+        conn.closed().await;
+        let elapsed = start.elapsed();
+        server_task.await.anyerr()??;
+
+        // The initial burst allows for a little less than the full two seconds.
+        assert!(elapsed > Duration::from_millis(1500), "{elapsed:?}");
+        assert!(client.metrics().magicsock.send_data_throttled.get() > 0);
+        // Polling a throttled transmit again does not count it again.
+        let sent = client.metrics().magicsock.send_data.get();
+        assert!(sent < DATA_LEN as u64 * 11 / 10, "{sent}");
+        client.close().await;
+        Ok(())
+    }
+
+    /// A [`Transport`] passing datagrams between the transports of a [`TestNetwork`].
+    ///
+    /// [`Transport`]: super::transport::Transport
+    #[derive(Debug)]
+    struct TestTransport {
+        addr: CustomAddr,
+        local_addrs: n0_watcher::Watchable<Vec<CustomAddr>>,
+        network: TestNetwork,
+        inbox: std::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<(Vec<u8>, CustomAddr)>>,
+        max_datagram_size: usize,
+    }
+
+    type Inboxes = HashMap<CustomAddr, tokio::sync::mpsc::UnboundedSender<(Vec<u8>, CustomAddr)>>;
+
+    #[derive(Debug, Clone, Default)]
+    struct TestNetwork(Arc<std::sync::Mutex<Inboxes>>);
+
+    impl TestNetwork {
+        const TRANSPORT_ID: u64 = 42;
+
+        fn transport(&self, name: &str) -> TestTransport {
+            let addr = CustomAddr::new(Self::TRANSPORT_ID, name.as_bytes());
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            self.0.lock().unwrap().insert(addr.clone(), tx);
+            TestTransport {
+                local_addrs: n0_watcher::Watchable::new(vec![addr.clone()]),
+                addr,
+                network: self.clone(),
+                inbox: std::sync::Mutex::new(rx),
+                max_datagram_size: 1500,
+            }
+        }
+    }
+
+    impl super::transport::Transport for TestTransport {
+        fn id(&self) -> u64 {
+            TestNetwork::TRANSPORT_ID
+        }
+
+        fn local_addrs(&self) -> n0_watcher::Direct<Vec<CustomAddr>> {
+            self.local_addrs.watch()
+        }
+
+        fn max_datagram_size(&self) -> usize {
+            self.max_datagram_size
+        }
+
+        fn poll_send(
+            &self,
+            _cx: &mut Context<'_>,
+            dst: &CustomAddr,
+            datagram: &[u8],
+        ) -> Poll<io::Result<()>> {
+            if let Some(inbox) = self.network.0.lock().unwrap().get(dst) {
+                inbox.send((datagram.to_vec(), self.addr.clone())).ok();
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_recv(
+            &self,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<(usize, CustomAddr)>> {
+            let mut inbox = self.inbox.lock().unwrap();
+            match inbox.poll_recv(cx) {
+                Poll::Ready(Some((datagram, src))) => {
+                    let len = datagram.len().min(buf.len());
+                    buf[..len].copy_from_slice(&datagram[..len]);
+                    Poll::Ready(Ok((len, src)))
+                }
+                Poll::Ready(None) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_custom_transport_validation() {
+        let network = TestNetwork::default();
+        let res = Endpoint::empty_builder(RelayMode::Disabled)
+            .add_custom_transport(network.transport("a"))
+            .add_custom_transport(network.transport("b"))
+            .bind()
+            .await;
+        assert!(matches!(
+            res,
+            Err(super::BindError::DuplicateTransportId {
+                id: TestNetwork::TRANSPORT_ID,
+                ..
+            })
+        ));
+
+        let mut transport = network.transport("c");
+        transport.max_datagram_size = 1199;
+        let res = Endpoint::empty_builder(RelayMode::Disabled)
+            .add_custom_transport(transport)
+            .bind()
+            .await;
+        assert!(matches!(
+            res,
+            Err(super::BindError::TransportDatagramSize { size: 1199, .. })
+        ));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_custom_transport() -> Result {
+        const TIMEOUT: Duration = Duration::from_secs(10);
+        let network = TestNetwork::default();
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .add_custom_transport(network.transport("server"))
+            .bind()
+            .await?;
+        let client = Endpoint::empty_builder(RelayMode::Disabled)
+            .add_custom_transport(network.transport("client"))
+            .bind()
+            .await?;
+
+        let server_custom_addr = CustomAddr::new(TestNetwork::TRANSPORT_ID, b"server".as_slice());
+        assert!(
+            server
+                .addr()
+                .custom_addrs()
+                .any(|addr| *addr == server_custom_addr)
+        );
+        let server_id = server.id();
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.anyerr()?;
+            let conn = incoming.await.anyerr()?;
+            let (mut send, mut recv) = conn.accept_bi().await.anyerr()?;
+            let data = recv.read_to_end(100).await.anyerr()?;
+            send.write_all(&data).await.anyerr()?;
+            send.finish().anyerr()?;
+            conn.closed().await;
+            server.close().await;
+            Ok::<_, Error>(())
+        });
+
+        // The client only knows the custom address of the server.
+        let server_addr = EndpointAddr::new(server_id).with_custom_addr(server_custom_addr);
+        let conn = tokio::time::timeout(TIMEOUT, client.connect(server_addr, TEST_ALPN))
+            .await
+            .anyerr()??;
+        let (mut send, mut recv) = conn.open_bi().await.anyerr()?;
+        send.write_all(b"hello custom transport").await.anyerr()?;
+        send.finish().anyerr()?;
+        let echo = recv.read_to_end(100).await.anyerr()?;
+        assert_eq!(echo, b"hello custom transport");
+
+        let mut conn_type = client
+            .conn_type(server_id)
+            .expect("known endpoint")
+            .stream();
+        tokio::time::timeout(TIMEOUT, async {
+            while let Some(conn_type) = conn_type.next().await {
+                if matches!(conn_type, ConnectionType::Custom(_)) {
+                    break;
+                }
+            }
+        })
+        .await
+        .anyerr()?;
+        assert!(client.metrics().magicsock.send_custom.get() > 0);
+
+        conn.close(0u32.into(), b"done");
+        server_task.await.anyerr()??;
+        client.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_address_book() -> Result {
+        const TIMEOUT: Duration = Duration::from_secs(10);
+        let dir = tempfile::tempdir().anyerr()?;
+        let book_path = dir.path().join("address_book.json");
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.addr();
+        let server_id = server.id();
+        let server_task = tokio::spawn(async move {
+            for _ in 0..2 {
+                let incoming = server.accept().await.anyerr()?;
+                let conn = incoming.await.anyerr()?;
+                let (mut send, mut recv) = conn.accept_bi().await.anyerr()?;
+                let data = recv.read_to_end(100).await.anyerr()?;
+                send.write_all(&data).await.anyerr()?;
+                send.finish().anyerr()?;
+                conn.closed().await;
+            }
+            server.close().await;
+            Ok::<_, Error>(())
+        });
+
+        async fn echo(conn: &Connection) -> Result {
+            let (mut send, mut recv) = conn.open_bi().await.anyerr()?;
+            send.write_all(b"hello").await.anyerr()?;
+            send.finish().anyerr()?;
+            let echo = recv.read_to_end(100).await.anyerr()?;
+            assert_eq!(echo, b"hello");
+            Ok(())
+        }
+
+        // The first client learns the direct address of the server and saves it on close.
+        let client = Endpoint::empty_builder(RelayMode::Disabled)
+            .address_book(FileAddressBook::new(&book_path))
+            .bind()
+            .await?;
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        echo(&conn).await?;
+        let mut conn_type = client
+            .conn_type(server_id)
+            .expect("known endpoint")
+            .stream();
+        tokio::time::timeout(TIMEOUT, async {
+            while let Some(conn_type) = conn_type.next().await {
+                if matches!(conn_type, ConnectionType::Direct(_)) {
+                    break;
+                }
+            }
+        })
+        .await
+        .anyerr()?;
+        let exported = client.export_address_book();
+        let entry = exported
+            .get(&server_id)
+            .expect("server is in the address book");
+        assert!(!entry.direct_addrs.is_empty());
+        let direct_addrs: Vec<_> = entry.direct_addrs.iter().map(|a| a.addr).collect();
+        conn.close(0u32.into(), b"done");
+        client.close().await;
+
+        let saved = FileAddressBook::new(&book_path).load()?;
+        let entry = saved
+            .get(&server_id)
+            .expect("server is in the address book");
+        let saved_addrs: Vec<_> = entry.direct_addrs.iter().map(|a| a.addr).collect();
+        assert_eq!(saved_addrs, direct_addrs);
+
+        // A restarted client connects with only the id of the server.
+        let client = Endpoint::empty_builder(RelayMode::Disabled)
+            .address_book(FileAddressBook::new(&book_path))
+            .bind()
+            .await?;
+        let conn = tokio::time::timeout(
+            TIMEOUT,
+            client.connect(EndpointAddr::new(server_id), TEST_ALPN),
+        )
+        .await
+        .anyerr()??;
+        echo(&conn).await?;
+        conn.close(0u32.into(), b"done");
+
+        server_task.await.anyerr()??;
+        client.close().await;
+        Ok(())
+    }
+
+    /// Saved addresses of remote endpoints which were not contacted are saved again.
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_address_book_keeps_uncontacted() -> Result {
+        let dir = tempfile::tempdir().anyerr()?;
+        let store = FileAddressBook::new(dir.path().join("address_book.json"));
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0);
+        let last_alive = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .anyerr()?
+            .as_micros() as u64;
+        let entry = AddressBookEntry {
+            endpoint_id: SecretKey::generate(&mut rng).public(),
+            relay_url: None,
+            relay_last_alive: None,
+            direct_addrs: vec![SavedDirectAddr {
+                addr: "192.0.2.1:1234".parse().unwrap(),
+                last_alive,
+            }],
+        };
+        let book: AddressBook = [entry.clone()].into_iter().collect();
+        store.save(&book)?;
+
+        let ep = Endpoint::empty_builder(RelayMode::Disabled)
+            .address_book(store.clone())
+            .bind()
+            .await?;
+        assert_eq!(ep.export_address_book(), book);
+        ep.close().await;
+
+        assert_eq!(store.load()?.get(&entry.endpoint_id), Some(&entry));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_holepunch_trace() -> Result {
+        const TIMEOUT: Duration = Duration::from_secs(10);
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let client = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let server_addr = server.addr();
+        let server_id = server.id();
+        assert!(client.holepunch_trace(server_id).is_none());
+
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.anyerr()?;
+            let conn = incoming.await.anyerr()?;
+            conn.closed().await;
+            server.close().await;
+            Ok::<_, Error>(())
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let mut conn_type = client
+            .conn_type(server_id)
+            .expect("known endpoint")
+            .stream();
+        tokio::time::timeout(TIMEOUT, async {
+            while let Some(conn_type) = conn_type.next().await {
+                if matches!(conn_type, ConnectionType::Direct(_)) {
+                    break;
+                }
+            }
+        })
+        .await
+        .anyerr()?;
+
+        let trace = client.holepunch_trace(server_id).expect("known endpoint");
+        assert_eq!(trace.endpoint_id, server_id);
+        let kinds: Vec<_> = trace.events.iter().map(|event| &event.kind).collect();
+        assert!(
+            kinds
+                .iter()
+                .any(|kind| matches!(kind, HolepunchEventKind::PingSent { .. }))
+        );
+        assert!(
+            kinds
+                .iter()
+                .any(|kind| matches!(kind, HolepunchEventKind::PongReceived { .. }))
+        );
+        assert!(kinds.iter().any(|kind| matches!(
+            kind,
+            HolepunchEventKind::PathChanged {
+                current: ConnectionType::Direct(_),
+                ..
+            }
+        )));
+
+        let json = serde_json::to_string(&trace).anyerr()?;
+        let decoded: HolepunchTrace = serde_json::from_str(&json).anyerr()?;
+        assert_eq!(decoded, trace);
+
+        conn.close(0u32.into(), b"done");
+        server_task.await.anyerr()??;
+        client.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_events() -> Result {
+        let client = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.addr();
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.anyerr()?;
+            let conn = incoming.await.anyerr()?;
+            conn.closed().await;
+            Ok::<_, Error>(())
+        });
+
+        let mut events = client.events();
+        let conn = client.connect(server_addr.clone(), TEST_ALPN).await?;
+
+        let mut opened = false;
+        let mut became_direct = false;
+        while !(opened && became_direct) {
+            let event = tokio::time::timeout(Duration::from_secs(10), events.next())
+                .await
+                .anyerr()?
+                .expect("endpoint alive");
+            match event {
+                EndpointEvent::ConnectionOpened { remote_id, alpn } => {
+                    assert_eq!(remote_id, server_addr.id);
+                    assert_eq!(alpn, TEST_ALPN);
+                    opened = true;
+                }
+                EndpointEvent::PathChanged {
+                    remote_id,
+                    current: ConnectionType::Direct(_),
+                    ..
+                } => {
+                    assert_eq!(remote_id, server_addr.id);
+                    became_direct = true;
+                }
+                _ => {}
+            }
+        }
+
+        conn.close(0u32.into(), b"done");
+        drop(conn);
+        server_task.await.anyerr()??;
+
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), events.next())
+                .await
+                .anyerr()?
+                .expect("endpoint alive");
+            if let EndpointEvent::ConnectionClosed { remote_id, alpn } = event {
+                assert_eq!(remote_id, server_addr.id);
+                assert_eq!(alpn, TEST_ALPN);
+                break;
+            }
+        }
+        Ok(())
+    }
+
     #[cfg_attr(target_os = "windows", ignore = "flaky")]
     #[tokio::test]
     #[traced_test]
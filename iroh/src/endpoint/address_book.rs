@@ -0,0 +1,496 @@
+//! Persisting the known addresses of remote endpoints across restarts.
+//!
+//! An endpoint learns the addresses of remote endpoints from discovery, from the
+//! [`EndpointAddr`]s it is given and from holepunching.  [`Endpoint::export_address_book`]
+//! takes a snapshot of the validated direct addresses and relay URLs of all known remote
+//! endpoints as an [`AddressBook`].
+//!
+//! An [`AddressBookStore`] configured with [`Builder::address_book`] is loaded when the
+//! endpoint is bound, and the exported address book is saved to it when the endpoint is
+//! closed.  Reloaded addresses are added with [`Source::Saved`], so a restarted endpoint can
+//! connect directly to the remote endpoints it knew without waiting for discovery.
+//! The exported address book includes the loaded one, so the saved addresses of remote
+//! endpoints which were not contacted are kept until they are older than [`MAX_AGE`].
+//! Addresses which were last alive more than [`MAX_AGE`] ago are neither used nor saved.
+//!
+//! [`FileAddressBook`] stores the address book as JSON in a file.
+//!
+//! [`Endpoint::export_address_book`]: crate::endpoint::Endpoint::export_address_book
+//! [`Builder::address_book`]: crate::endpoint::Builder::address_book
+//! [`Source::Saved`]: crate::endpoint::Source::Saved
+
+#[cfg(not(wasm_browser))]
+use std::path::{Path, PathBuf};
+use std::{fmt::Debug, net::SocketAddr, sync::Arc};
+
+use iroh_base::{EndpointAddr, EndpointId, RelayUrl};
+use n0_error::{AnyError, e, stack_error};
+use n0_future::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+
+use crate::endpoint::{ControlMsg, RemoteInfo};
+
+/// The maximum age of the addresses used when loading an [`AddressBook`].
+///
+/// See [`AddressBook::prune`].
+pub const MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// Loads and saves an [`AddressBook`].
+///
+/// The store is loaded once when the endpoint is bound, and saved to when the endpoint is
+/// closed.  Outside of browsers both run on a thread for blocking operations, so
+/// implementations may use blocking I/O.
+pub trait AddressBookStore: Debug + Send + Sync + 'static {
+    /// Loads the saved address book.
+    ///
+    /// A store which has never been saved to returns an empty [`AddressBook`].
+    fn load(&self) -> Result<AddressBook, AddressBookError>;
+
+    /// Saves the address book, replacing the previously saved one.
+    fn save(&self, book: &AddressBook) -> Result<(), AddressBookError>;
+}
+
+/// Errors from loading or saving an [`AddressBook`].
+#[allow(missing_docs)]
+#[stack_error(derive, add_meta)]
+#[non_exhaustive]
+pub enum AddressBookError {
+    #[error("Failed to access the address book")]
+    Io {
+        #[error(std_err)]
+        source: std::io::Error,
+    },
+    #[error("Failed to encode or decode the address book")]
+    Encoding {
+        #[error(std_err)]
+        source: serde_json::Error,
+    },
+    #[error("Address book store error")]
+    User { source: AnyError },
+}
+
+impl AddressBookError {
+    /// Creates a new user error from an arbitrary error type.
+    #[track_caller]
+    pub fn from_err<T: std::error::Error + Send + Sync + 'static>(source: T) -> Self {
+        e!(AddressBookError::User {
+            source: AnyError::from_std(source)
+        })
+    }
+}
+
+/// The known addresses of remote endpoints.
+///
+/// Timestamps are in microseconds since the unix epoch, like the
+/// [`DiscoveryItem::last_updated`] timestamps.
+///
+/// [`DiscoveryItem::last_updated`]: crate::discovery::DiscoveryItem::last_updated
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressBook {
+    entries: Vec<AddressBookEntry>,
+}
+
+impl AddressBook {
+    /// Creates an empty address book.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an entry, replacing any entry for the same endpoint.
+    pub fn insert(&mut self, entry: AddressBookEntry) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|e| e.endpoint_id == entry.endpoint_id)
+        {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+    }
+
+    /// Returns the entry for an endpoint, if any.
+    pub fn get(&self, endpoint_id: &EndpointId) -> Option<&AddressBookEntry> {
+        self.entries.iter().find(|e| &e.endpoint_id == endpoint_id)
+    }
+
+    /// Returns an iterator over all entries.
+    pub fn iter(&self) -> impl Iterator<Item = &AddressBookEntry> {
+        self.entries.iter()
+    }
+
+    /// Returns the number of endpoints in the address book.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the address book has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Merges the entries of `other` into this address book.
+    ///
+    /// The direct addresses of an endpoint in both address books are combined, keeping the
+    /// newer `last_alive` of addresses in both.  Of two relay URLs the one which was alive
+    /// more recently is kept, preferring the one from `other` if neither was ever alive.
+    pub fn merge(&mut self, other: AddressBook) {
+        for entry in other {
+            match self
+                .entries
+                .iter_mut()
+                .find(|e| e.endpoint_id == entry.endpoint_id)
+            {
+                Some(existing) => existing.merge(entry),
+                None => self.entries.push(entry),
+            }
+        }
+    }
+
+    /// Removes all addresses which were last alive longer than `max_age` ago.
+    ///
+    /// Relay URLs which were never alive are removed as well.  Entries left without any
+    /// address are removed.
+    pub fn prune(&mut self, max_age: Duration) {
+        let cutoff = to_micros(SystemTime::now()).saturating_sub(max_age.as_micros() as u64);
+        for entry in &mut self.entries {
+            entry.direct_addrs.retain(|addr| addr.last_alive >= cutoff);
+            if entry.relay_last_alive.is_none_or(|t| t < cutoff) {
+                entry.relay_url = None;
+                entry.relay_last_alive = None;
+            }
+        }
+        self.entries
+            .retain(|e| e.relay_url.is_some() || !e.direct_addrs.is_empty());
+    }
+
+    /// Creates the address book from the state of the remote endpoints.
+    pub(super) fn from_remote_infos(infos: impl IntoIterator<Item = RemoteInfo>) -> Self {
+        let now = SystemTime::now();
+        let timestamp = |elapsed: Duration| to_micros(now.checked_sub(elapsed).unwrap_or(now));
+        let entries = infos
+            .into_iter()
+            .filter_map(|info| {
+                let direct_addrs: Vec<_> = info
+                    .addrs
+                    .iter()
+                    .filter_map(|addr| {
+                        // Only paths which were confirmed by the remote are validated.
+                        let last_pong = addr
+                            .last_control
+                            .filter(|(_, msg)| *msg == ControlMsg::Pong)
+                            .map(|(elapsed, _)| elapsed);
+                        let last_alive = match (last_pong, addr.last_payload) {
+                            (Some(a), Some(b)) => a.min(b),
+                            (a, b) => a.or(b)?,
+                        };
+                        Some(SavedDirectAddr {
+                            addr: addr.addr,
+                            last_alive: timestamp(last_alive),
+                        })
+                    })
+                    .collect();
+                let (relay_url, relay_last_alive) = match info.relay_url {
+                    Some(relay) => (Some(relay.relay_url), relay.last_alive.map(timestamp)),
+                    None => (None, None),
+                };
+                if relay_url.is_none() && direct_addrs.is_empty() {
+                    return None;
+                }
+                Some(AddressBookEntry {
+                    endpoint_id: info.endpoint_id,
+                    relay_url,
+                    relay_last_alive,
+                    direct_addrs,
+                })
+            })
+            .collect();
+        Self { entries }
+    }
+}
+
+impl IntoIterator for AddressBook {
+    type Item = AddressBookEntry;
+    type IntoIter = std::vec::IntoIter<AddressBookEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl FromIterator<AddressBookEntry> for AddressBook {
+    fn from_iter<T: IntoIterator<Item = AddressBookEntry>>(iter: T) -> Self {
+        let mut book = Self::new();
+        for entry in iter {
+            book.insert(entry);
+        }
+        book
+    }
+}
+
+/// The saved addresses of a single remote endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressBookEntry {
+    /// The remote endpoint.
+    pub endpoint_id: EndpointId,
+    /// The home relay of the remote endpoint.
+    pub relay_url: Option<RelayUrl>,
+    /// When the relay path last received data from the remote endpoint, if ever.
+    pub relay_last_alive: Option<u64>,
+    /// The direct addresses which were validated by the remote endpoint.
+    pub direct_addrs: Vec<SavedDirectAddr>,
+}
+
+impl AddressBookEntry {
+    fn merge(&mut self, other: AddressBookEntry) {
+        for addr in other.direct_addrs {
+            match self.direct_addrs.iter_mut().find(|a| a.addr == addr.addr) {
+                Some(existing) => existing.last_alive = existing.last_alive.max(addr.last_alive),
+                None => self.direct_addrs.push(addr),
+            }
+        }
+        if other.relay_url.is_some() && other.relay_last_alive >= self.relay_last_alive {
+            self.relay_url = other.relay_url;
+            self.relay_last_alive = other.relay_last_alive;
+        }
+    }
+}
+
+impl From<&AddressBookEntry> for EndpointAddr {
+    fn from(entry: &AddressBookEntry) -> Self {
+        let mut addr = EndpointAddr::new(entry.endpoint_id);
+        if let Some(relay_url) = &entry.relay_url {
+            addr = addr.with_relay_url(relay_url.clone());
+        }
+        for direct in &entry.direct_addrs {
+            addr = addr.with_ip_addr(direct.addr);
+        }
+        addr
+    }
+}
+
+/// A validated direct address of a remote endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedDirectAddr {
+    /// The UDP address of the remote endpoint.
+    pub addr: SocketAddr,
+    /// When the address last received a payload or pong from the remote endpoint.
+    pub last_alive: u64,
+}
+
+/// An [`AddressBookStore`] keeping the address book as JSON in a file.
+///
+/// Saving writes a temporary file next to the address book and renames it, so an
+/// interrupted save never leaves a truncated address book behind.
+#[cfg(not(wasm_browser))]
+#[derive(Debug, Clone)]
+pub struct FileAddressBook {
+    path: PathBuf,
+}
+
+#[cfg(not(wasm_browser))]
+impl FileAddressBook {
+    /// Creates a store for the address book at `path`.
+    ///
+    /// The file is created on the first save.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Returns the path of the address book file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(not(wasm_browser))]
+impl AddressBookStore for FileAddressBook {
+    fn load(&self) -> Result<AddressBook, AddressBookError> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(AddressBook::default());
+            }
+            Err(err) => return Err(e!(AddressBookError::Io, err)),
+        };
+        serde_json::from_slice(&data).map_err(|err| e!(AddressBookError::Encoding, err))
+    }
+
+    fn save(&self, book: &AddressBook) -> Result<(), AddressBookError> {
+        let data =
+            serde_json::to_vec_pretty(book).map_err(|err| e!(AddressBookError::Encoding, err))?;
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, data).map_err(|err| e!(AddressBookError::Io, err))?;
+        std::fs::rename(&tmp_path, &self.path).map_err(|err| e!(AddressBookError::Io, err))
+    }
+}
+
+/// Loads the address book from `store`, pruned to addresses younger than [`MAX_AGE`].
+pub(super) async fn load(
+    store: Arc<dyn AddressBookStore>,
+) -> Result<AddressBook, AddressBookError> {
+    let mut book = run_blocking(move || store.load()).await?;
+    book.prune(MAX_AGE);
+    Ok(book)
+}
+
+/// Saves `book` to `store`.
+pub(super) async fn save(
+    store: Arc<dyn AddressBookStore>,
+    book: AddressBook,
+) -> Result<(), AddressBookError> {
+    run_blocking(move || store.save(&book)).await
+}
+
+/// Runs `f` on a thread for blocking operations, in browsers it is run right away.
+async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, AddressBookError> + Send + 'static,
+) -> Result<T, AddressBookError> {
+    #[cfg(not(wasm_browser))]
+    {
+        tokio::task::spawn_blocking(f)
+            .await
+            .map_err(AddressBookError::from_err)?
+    }
+    #[cfg(wasm_browser)]
+    {
+        f()
+    }
+}
+
+fn to_micros(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+#[cfg(all(test, not(wasm_browser)))]
+mod tests {
+    use iroh_base::SecretKey;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn entry(seed: u64, last_alive: u64) -> AddressBookEntry {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+        AddressBookEntry {
+            endpoint_id: SecretKey::generate(&mut rng).public(),
+            relay_url: Some("https://relay.example.com".parse().unwrap()),
+            relay_last_alive: None,
+            direct_addrs: vec![SavedDirectAddr {
+                addr: "192.0.2.1:1234".parse().unwrap(),
+                last_alive,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_file_address_book_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileAddressBook::new(dir.path().join("address_book.json"));
+
+        // Nothing saved yet.
+        assert!(store.load().unwrap().is_empty());
+
+        let book: AddressBook = [entry(0, 1), entry(1, 2)].into_iter().collect();
+        store.save(&book).unwrap();
+        assert_eq!(store.load().unwrap(), book);
+
+        // Saving again replaces the address book.
+        let book: AddressBook = [entry(2, 3)].into_iter().collect();
+        store.save(&book).unwrap();
+        assert_eq!(store.load().unwrap(), book);
+    }
+
+    #[tokio::test]
+    async fn test_load_prunes() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileAddressBook::new(dir.path().join("address_book.json"));
+        let now = to_micros(SystemTime::now());
+        let fresh = entry(0, now);
+        let mut stale = entry(1, now - MAX_AGE.as_micros() as u64 - 1);
+        stale.relay_last_alive = Some(now);
+        let book: AddressBook = [fresh.clone(), stale.clone()].into_iter().collect();
+        store.save(&book).unwrap();
+
+        let loaded = load(Arc::new(store)).await.unwrap();
+        stale.direct_addrs.clear();
+        let mut fresh_direct = fresh;
+        fresh_direct.relay_url = None;
+        let expected: AddressBook = [fresh_direct, stale].into_iter().collect();
+        assert_eq!(loaded, expected);
+    }
+
+    #[test]
+    fn test_address_book_prune() {
+        let now = to_micros(SystemTime::now());
+        let hour = Duration::from_secs(60 * 60).as_micros() as u64;
+        let mut fresh = entry(0, now);
+        fresh.relay_last_alive = Some(now);
+        let mut stale = entry(1, now - 2 * hour);
+        stale.relay_last_alive = Some(now);
+        let mut dead = entry(2, now - 2 * hour);
+        dead.relay_last_alive = Some(now - 2 * hour);
+        let mut never_alive = entry(3, now);
+        let mut book: AddressBook = [fresh.clone(), stale.clone(), dead, never_alive.clone()]
+            .into_iter()
+            .collect();
+
+        book.prune(Duration::from_secs(60 * 60));
+
+        // The stale direct address is gone, but its relay URL is still fresh.
+        stale.direct_addrs.clear();
+        // A relay URL which was never alive ages out right away.
+        never_alive.relay_url = None;
+        let expected: AddressBook = [fresh, stale, never_alive].into_iter().collect();
+        assert_eq!(book, expected);
+    }
+
+    #[test]
+    fn test_address_book_merge() {
+        let relay_a: RelayUrl = "https://relay-a.example.com".parse().unwrap();
+        let relay_b: RelayUrl = "https://relay-b.example.com".parse().unwrap();
+        let mut saved = entry(0, 10);
+        saved.relay_url = Some(relay_a.clone());
+        saved.relay_last_alive = Some(20);
+        let uncontacted = entry(1, 10);
+        let mut book: AddressBook = [saved.clone(), uncontacted.clone()].into_iter().collect();
+
+        // The same endpoint was contacted again, on a new address and a never alive relay.
+        let mut current = saved.clone();
+        current.relay_url = Some(relay_b.clone());
+        current.relay_last_alive = None;
+        current.direct_addrs = vec![
+            SavedDirectAddr {
+                addr: "192.0.2.1:1234".parse().unwrap(),
+                last_alive: 5,
+            },
+            SavedDirectAddr {
+                addr: "192.0.2.2:1234".parse().unwrap(),
+                last_alive: 30,
+            },
+        ];
+        book.merge([current.clone()].into_iter().collect());
+
+        let merged = book.get(&saved.endpoint_id).unwrap();
+        assert_eq!(merged.relay_url, Some(relay_a));
+        assert_eq!(merged.relay_last_alive, Some(20));
+        assert_eq!(
+            merged.direct_addrs,
+            [
+                SavedDirectAddr {
+                    addr: "192.0.2.1:1234".parse().unwrap(),
+                    last_alive: 10,
+                },
+                current.direct_addrs[1],
+            ]
+        );
+        assert_eq!(book.get(&uncontacted.endpoint_id), Some(&uncontacted));
+
+        // A relay which was alive more recently replaces the saved one.
+        current.relay_last_alive = Some(40);
+        book.merge([current].into_iter().collect());
+        let merged = book.get(&saved.endpoint_id).unwrap();
+        assert_eq!(merged.relay_url, Some(relay_b));
+        assert_eq!(merged.relay_last_alive, Some(40));
+    }
+}
@@ -0,0 +1,182 @@
+//! Bandwidth limits for the traffic of an endpoint.
+//!
+//! Limits are configured with [`Builder::bandwidth_limits`] and enforced with token buckets
+//! on the QUIC packets sent and received by the endpoint.  A packet has to fit into every
+//! limit which applies to it: the limit for all traffic, the limit for its kind of path
+//! (direct or relayed) and the limit configured for its remote endpoint, if any.
+//!
+//! Outgoing packets exceeding a limit are delayed until the bucket refilled, which makes
+//! QUIC's congestion control settle on the configured rate.  Incoming packets exceeding a
+//! limit are dropped, causing the sender to back off.  Hole punching messages are never
+//! limited.
+//!
+//! The number of throttled bytes is tracked in the `send_data_throttled` and
+//! `recv_data_throttled` metrics of the endpoint.
+//!
+//! [`Builder::bandwidth_limits`]: crate::endpoint::Builder::bandwidth_limits
+
+use std::collections::HashMap;
+
+use iroh_base::EndpointId;
+
+/// The minimum burst size of a [`RateLimit`] created with [`RateLimit::bytes_per_second`].
+const MIN_BURST: u64 = 16 * 1024;
+
+/// A rate limit in bytes per second, with an allowed burst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RateLimit {
+    bytes_per_second: u64,
+    burst: u64,
+}
+
+impl RateLimit {
+    /// Creates a limit of `rate` bytes per second.
+    ///
+    /// The burst size defaults to a tenth of a second worth of traffic, but at least 16 KiB.
+    /// A rate of zero is treated as one byte per second.
+    pub fn bytes_per_second(rate: u64) -> Self {
+        let bytes_per_second = rate.max(1);
+        Self {
+            bytes_per_second,
+            burst: (bytes_per_second / 10).max(MIN_BURST),
+        }
+    }
+
+    /// Sets the number of bytes which may be transferred at once after a quiet period.
+    ///
+    /// Packets larger than the burst size are still let through once the bucket is full.
+    pub fn with_burst(mut self, burst: u64) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    /// Returns the rate of this limit in bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Returns the burst size of this limit in bytes.
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+}
+
+/// Upload and download limits for one kind of traffic.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TrafficLimits {
+    upload: Option<RateLimit>,
+    download: Option<RateLimit>,
+}
+
+impl TrafficLimits {
+    /// Creates limits which do not limit anything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the traffic sent.
+    pub fn with_upload(mut self, limit: RateLimit) -> Self {
+        self.upload = Some(limit);
+        self
+    }
+
+    /// Limits the traffic received.
+    pub fn with_download(mut self, limit: RateLimit) -> Self {
+        self.download = Some(limit);
+        self
+    }
+
+    /// Returns the limit for sent traffic, if any.
+    pub fn upload(&self) -> Option<RateLimit> {
+        self.upload
+    }
+
+    /// Returns the limit for received traffic, if any.
+    pub fn download(&self) -> Option<RateLimit> {
+        self.download
+    }
+
+    /// Returns `true` if neither direction is limited.
+    pub fn is_unlimited(&self) -> bool {
+        self.upload.is_none() && self.download.is_none()
+    }
+}
+
+/// The bandwidth limits of an endpoint.
+///
+/// See the [module documentation](self) for how the limits are applied.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BandwidthLimits {
+    total: TrafficLimits,
+    direct: TrafficLimits,
+    relay: TrafficLimits,
+    remotes: HashMap<EndpointId, TrafficLimits>,
+}
+
+impl BandwidthLimits {
+    /// Creates limits which do not limit anything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits all traffic of the endpoint.
+    pub fn with_total(mut self, limits: TrafficLimits) -> Self {
+        self.total = limits;
+        self
+    }
+
+    /// Limits the traffic sent and received over direct paths.
+    ///
+    /// Traffic on custom transports counts as direct traffic.
+    pub fn with_direct(mut self, limits: TrafficLimits) -> Self {
+        self.direct = limits;
+        self
+    }
+
+    /// Limits the traffic sent and received via relay servers.
+    pub fn with_relay(mut self, limits: TrafficLimits) -> Self {
+        self.relay = limits;
+        self
+    }
+
+    /// Limits the traffic exchanged with the remote endpoint `endpoint_id`.
+    ///
+    /// The limit covers all paths to the remote endpoint together.
+    pub fn with_remote(mut self, endpoint_id: EndpointId, limits: TrafficLimits) -> Self {
+        self.remotes.insert(endpoint_id, limits);
+        self
+    }
+
+    /// Returns the limits for all traffic.
+    pub fn total(&self) -> TrafficLimits {
+        self.total
+    }
+
+    /// Returns the limits for traffic over direct paths.
+    pub fn direct(&self) -> TrafficLimits {
+        self.direct
+    }
+
+    /// Returns the limits for traffic via relay servers.
+    pub fn relay(&self) -> TrafficLimits {
+        self.relay
+    }
+
+    /// Returns the limits for the traffic exchanged with `endpoint_id`, if any.
+    pub fn remote(&self, endpoint_id: &EndpointId) -> Option<TrafficLimits> {
+        self.remotes.get(endpoint_id).copied()
+    }
+
+    /// Returns an iterator over all remote endpoints with limits.
+    pub fn remotes(&self) -> impl Iterator<Item = (&EndpointId, &TrafficLimits)> {
+        self.remotes.iter()
+    }
+
+    /// Returns `true` if no traffic is limited.
+    pub fn is_unlimited(&self) -> bool {
+        self.total.is_unlimited()
+            && self.direct.is_unlimited()
+            && self.relay.is_unlimited()
+            && self.remotes.values().all(TrafficLimits::is_unlimited)
+    }
+}
@@ -25,20 +25,27 @@ use std::{
     task::Poll,
 };
 
+use bytes::Bytes;
+
 use ed25519_dalek::{VerifyingKey, pkcs8::DecodePublicKey};
 use futures_util::{FutureExt, future::Shared};
 use iroh_base::EndpointId;
 use n0_error::{e, stack_error};
-use n0_future::time::Duration;
-use n0_watcher::Watcher;
+use n0_future::time::{self, Duration, Instant};
+use n0_watcher::{Watchable, Watcher};
 use pin_project::pin_project;
 use quinn::{
     AcceptBi, AcceptUni, ConnectionError, ConnectionStats, OpenBi, OpenUni, ReadDatagram,
-    RetryError, SendDatagramError, ServerConfig, VarInt,
+    RetryError, SendDatagram, SendDatagramError, ServerConfig, VarInt,
 };
 use tracing::warn;
 
-use crate::{Endpoint, discovery::DiscoveryTask, endpoint::rtt_actor::RttMessage};
+use crate::{
+    Endpoint,
+    discovery::DiscoveryTask,
+    endpoint::{PathInfo, rtt_actor::RttMessage},
+    metrics::MagicsockMetrics,
+};
 
 /// Future produced by [`Endpoint::accept`].
 #[derive(derive_more::Debug)]
@@ -174,11 +181,16 @@ impl Future for IncomingFuture {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Err(err)) => Poll::Ready(Err(err.into())),
             Poll::Ready(Ok(inner)) => {
-                let conn = match conn_from_quinn_conn(inner) {
+                let conn = match conn_from_quinn_conn(inner, this.ep) {
                     Ok(conn) => conn,
                     Err(err) => return Poll::Ready(Err(err.into())),
                 };
-                try_send_rtt_msg(conn.quinn_connection(), this.ep, conn.remote_id());
+                try_send_rtt_msg(
+                    conn.quinn_connection(),
+                    this.ep,
+                    conn.remote_id(),
+                    conn.alpn(),
+                );
                 Poll::Ready(Ok(conn))
             }
         }
@@ -225,13 +237,24 @@ pub enum AuthenticationError {
 ///
 /// Returns a [`AuthenticationError`] if the handshake data has
 /// not completed, or if no alpn was set by the remote node.
-fn conn_from_quinn_conn(conn: quinn::Connection) -> Result<Connection, AuthenticationError> {
+fn conn_from_quinn_conn(
+    conn: quinn::Connection,
+    ep: &Endpoint,
+) -> Result<Connection, AuthenticationError> {
     if let Some(reason) = conn.close_reason() {
         return Err(e!(AuthenticationError::ConnectionError { source: reason }));
     }
+    let remote_id = remote_id_from_quinn_conn(&conn)?;
+    // The magic socket knows about every endpoint we completed a handshake with.
+    let paths = ep
+        .msock
+        .path_info(remote_id)
+        .unwrap_or_else(|| Watchable::new(PathInfo::default()).watch());
     Ok(Connection {
-        remote_id: remote_id_from_quinn_conn(&conn)?,
+        remote_id,
         alpn: alpn_from_quinn_conn(&conn).ok_or_else(|| e!(AuthenticationError::NoAlpn))?,
+        paths,
+        datagrams: DatagramSender::new(ep),
         inner: conn,
     })
 }
@@ -289,9 +312,13 @@ pub struct Connecting {
     ep: Endpoint,
     /// `Some(remote_id)` if this is an outgoing connection, `None` if this is an incoming conn
     remote_endpoint_id: EndpointId,
+    /// The preferred ALPN this connection attempt offers.
+    alpn: Vec<u8>,
     /// We run discovery as long as we haven't established a connection yet.
     #[debug("Option<DiscoveryTask>")]
     _discovery_drop_guard: Option<DiscoveryTask>,
+    /// Fails the connection attempt once elapsed.
+    timeout: Option<Pin<Box<time::Sleep>>>,
 }
 
 /// In-progress connection attempt future
@@ -329,6 +356,8 @@ pub enum ConnectingError {
     },
     #[error("Failure finalizing the handshake")]
     HandshakeFailure { source: AuthenticationError },
+    #[error("Timed out while connecting")]
+    Timeout,
 }
 
 impl Connecting {
@@ -336,13 +365,17 @@ impl Connecting {
         inner: quinn::Connecting,
         ep: Endpoint,
         remote_endpoint_id: EndpointId,
+        alpn: Vec<u8>,
         _discovery_drop_guard: Option<DiscoveryTask>,
+        deadline: Option<Instant>,
     ) -> Self {
         Self {
             inner,
             ep,
             remote_endpoint_id,
+            alpn,
             _discovery_drop_guard,
+            timeout: deadline.map(|deadline| Box::pin(time::sleep_until(deadline))),
         }
     }
 
@@ -390,10 +423,12 @@ impl Connecting {
                 // in our `Connection`, thus `try_send_rtt_msg` won't be able to pick up
                 // `Connection::remote_endpoint_id`.
                 // Instead, we provide `self.remote_endpoint_id` here - we know it in advance,
-                // after all.
-                try_send_rtt_msg(&inner, &self.ep, self.remote_endpoint_id);
+                // after all.  The same goes for the ALPN, where we report the one we asked for
+                // first.
+                try_send_rtt_msg(&inner, &self.ep, self.remote_endpoint_id, &self.alpn);
                 Ok(OutgoingZeroRttConnection {
                     inner,
+                    ep: self.ep,
                     accepted: ZeroRttAccepted {
                         inner: zrtt_accepted,
                         _discovery_drop_guard: self._discovery_drop_guard,
@@ -405,7 +440,9 @@ impl Connecting {
                 inner,
                 ep: self.ep,
                 remote_endpoint_id: self.remote_endpoint_id,
+                alpn: self.alpn,
                 _discovery_drop_guard: self._discovery_drop_guard,
+                timeout: self.timeout,
             }),
         }
     }
@@ -432,17 +469,32 @@ impl Future for Connecting {
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
         match this.inner.poll(cx) {
-            Poll::Pending => Poll::Pending,
+            Poll::Pending => {
+                let timed_out = this
+                    .timeout
+                    .as_mut()
+                    .is_some_and(|timeout| timeout.as_mut().poll(cx).is_ready());
+                if timed_out {
+                    Poll::Ready(Err(e!(ConnectingError::Timeout)))
+                } else {
+                    Poll::Pending
+                }
+            }
             Poll::Ready(Err(err)) => Poll::Ready(Err(err.into())),
             Poll::Ready(Ok(inner)) => {
-                let conn = match conn_from_quinn_conn(inner) {
+                let conn = match conn_from_quinn_conn(inner, this.ep) {
                     Ok(conn) => conn,
                     Err(err) => {
                         return Poll::Ready(Err(err.into()));
                     }
                 };
 
-                try_send_rtt_msg(conn.quinn_connection(), this.ep, conn.remote_id());
+                try_send_rtt_msg(
+                    conn.quinn_connection(),
+                    this.ep,
+                    conn.remote_id(),
+                    conn.alpn(),
+                );
                 Poll::Ready(Ok(conn))
             }
         }
@@ -492,6 +544,7 @@ impl Accepting {
                 _discovery_drop_guard: None,
             },
             inner,
+            ep: self.ep,
         }
     }
 
@@ -515,12 +568,17 @@ impl Future for Accepting {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Err(err)) => Poll::Ready(Err(err.into())),
             Poll::Ready(Ok(inner)) => {
-                let conn = match conn_from_quinn_conn(inner) {
+                let conn = match conn_from_quinn_conn(inner, this.ep) {
                     Ok(conn) => conn,
                     Err(err) => return Poll::Ready(Err(err.into())),
                 };
 
-                try_send_rtt_msg(conn.quinn_connection(), this.ep, conn.remote_id());
+                try_send_rtt_msg(
+                    conn.quinn_connection(),
+                    this.ep,
+                    conn.remote_id(),
+                    conn.alpn(),
+                );
                 Poll::Ready(Ok(conn))
             }
         }
@@ -566,6 +624,7 @@ impl Future for ZeroRttAccepted {
 #[derive(Debug, Clone)]
 pub struct OutgoingZeroRttConnection {
     inner: quinn::Connection,
+    ep: Endpoint,
     accepted: Shared<ZeroRttAccepted>,
 }
 
@@ -603,7 +662,7 @@ impl OutgoingZeroRttConnection {
     /// modified iroh endpoint or with a plain QUIC client.
     pub async fn handshake_completed(&self) -> Result<ZeroRttStatus, AuthenticationError> {
         let accepted = self.accepted.clone().await;
-        let conn = conn_from_quinn_conn(self.inner.clone())?;
+        let conn = conn_from_quinn_conn(self.inner.clone(), &self.ep)?;
 
         Ok(match accepted {
             true => ZeroRttStatus::Accepted(conn),
@@ -720,25 +779,33 @@ impl OutgoingZeroRttConnection {
     /// Application datagrams are a low-level primitive. They may be lost or delivered out
     /// of order, and `data` must both fit inside a single QUIC packet and be smaller than
     /// the maximum dictated by the peer.
+    ///
+    /// If the outgoing datagram buffer is full, the [`DatagramDropPolicy`] of the endpoint
+    /// decides whether older datagrams are dropped or `data` is discarded, like a datagram
+    /// lost on the network.  See [`Builder::datagram_drop_policy`].
+    ///
+    /// Returns `false` if `data` was discarded by [`DatagramDropPolicy::RejectNewest`].
+    ///
+    /// [`Builder::datagram_drop_policy`]: crate::endpoint::Builder::datagram_drop_policy
+    #[inline]
+    pub fn send_datagram(&self, data: bytes::Bytes) -> Result<bool, SendDatagramError> {
+        DatagramSender::new(&self.ep).send(&self.inner, data)
+    }
+
+    /// Transmits `data` as an unreliable, unordered application datagram, waiting for
+    /// buffer space.
+    ///
+    /// Unlike [`send_datagram`], this method will wait for buffer space during congestion
+    /// conditions, which effectively prioritizes old datagrams over new datagrams.  Use it
+    /// to apply backpressure to the producer of the datagrams.
+    ///
+    /// See [`send_datagram`] for details.
+    ///
+    /// [`send_datagram`]: OutgoingZeroRttConnection::send_datagram
     #[inline]
-    pub fn send_datagram(&self, data: bytes::Bytes) -> Result<(), SendDatagramError> {
-        self.inner.send_datagram(data)
-    }
-
-    // TODO: It seems `SendDatagram` is not yet exposed by quinn.  This has been fixed
-    //       upstream and will be in the next release.
-    // /// Transmits `data` as an unreliable, unordered application datagram
-    // ///
-    // /// Unlike [`send_datagram()`], this method will wait for buffer space during congestion
-    // /// conditions, which effectively prioritizes old datagrams over new datagrams.
-    // ///
-    // /// See [`send_datagram()`] for details.
-    // ///
-    // /// [`send_datagram()`]: Connection::send_datagram
-    // #[inline]
-    // pub fn send_datagram_wait(&self, data: bytes::Bytes) -> SendDatagram<'_> {
-    //     self.inner.send_datagram_wait(data)
-    // }
+    pub fn send_datagram_wait(&self, data: bytes::Bytes) -> SendDatagram<'_> {
+        self.inner.send_datagram_wait(data)
+    }
 
     /// Computes the maximum size of datagrams that may be passed to [`send_datagram`].
     ///
@@ -901,6 +968,7 @@ impl OutgoingZeroRttConnection {
 #[derive(Debug)]
 pub struct IncomingZeroRttConnection {
     inner: quinn::Connection,
+    ep: Endpoint,
     accepted: ZeroRttAccepted,
 }
 
@@ -919,7 +987,7 @@ impl IncomingZeroRttConnection {
     /// modified iroh endpoint or with a plain QUIC client.
     pub async fn handshake_completed(self) -> Result<Connection, AuthenticationError> {
         self.accepted.await;
-        conn_from_quinn_conn(self.inner)
+        conn_from_quinn_conn(self.inner, &self.ep)
     }
 
     /// Initiates a new outgoing unidirectional stream.
@@ -1031,25 +1099,33 @@ impl IncomingZeroRttConnection {
     /// Application datagrams are a low-level primitive. They may be lost or delivered out
     /// of order, and `data` must both fit inside a single QUIC packet and be smaller than
     /// the maximum dictated by the peer.
+    ///
+    /// If the outgoing datagram buffer is full, the [`DatagramDropPolicy`] of the endpoint
+    /// decides whether older datagrams are dropped or `data` is discarded, like a datagram
+    /// lost on the network.  See [`Builder::datagram_drop_policy`].
+    ///
+    /// Returns `false` if `data` was discarded by [`DatagramDropPolicy::RejectNewest`].
+    ///
+    /// [`Builder::datagram_drop_policy`]: crate::endpoint::Builder::datagram_drop_policy
     #[inline]
-    pub fn send_datagram(&self, data: bytes::Bytes) -> Result<(), SendDatagramError> {
-        self.inner.send_datagram(data)
-    }
-
-    // TODO: It seems `SendDatagram` is not yet exposed by quinn.  This has been fixed
-    //       upstream and will be in the next release.
-    // /// Transmits `data` as an unreliable, unordered application datagram
-    // ///
-    // /// Unlike [`send_datagram()`], this method will wait for buffer space during congestion
-    // /// conditions, which effectively prioritizes old datagrams over new datagrams.
-    // ///
-    // /// See [`send_datagram()`] for details.
-    // ///
-    // /// [`send_datagram()`]: Connection::send_datagram
-    // #[inline]
-    // pub fn send_datagram_wait(&self, data: bytes::Bytes) -> SendDatagram<'_> {
-    //     self.inner.send_datagram_wait(data)
-    // }
+    pub fn send_datagram(&self, data: bytes::Bytes) -> Result<bool, SendDatagramError> {
+        DatagramSender::new(&self.ep).send(&self.inner, data)
+    }
+
+    /// Transmits `data` as an unreliable, unordered application datagram, waiting for
+    /// buffer space.
+    ///
+    /// Unlike [`send_datagram`], this method will wait for buffer space during congestion
+    /// conditions, which effectively prioritizes old datagrams over new datagrams.  Use it
+    /// to apply backpressure to the producer of the datagrams.
+    ///
+    /// See [`send_datagram`] for details.
+    ///
+    /// [`send_datagram`]: IncomingZeroRttConnection::send_datagram
+    #[inline]
+    pub fn send_datagram_wait(&self, data: bytes::Bytes) -> SendDatagram<'_> {
+        self.inner.send_datagram_wait(data)
+    }
 
     /// Computes the maximum size of datagrams that may be passed to [`send_datagram`].
     ///
@@ -1209,6 +1285,9 @@ pub struct Connection {
     inner: quinn::Connection,
     remote_id: EndpointId,
     alpn: Vec<u8>,
+    #[debug(skip)]
+    paths: n0_watcher::Direct<PathInfo>,
+    datagrams: DatagramSender,
 }
 
 #[allow(missing_docs)]
@@ -1330,25 +1409,33 @@ impl Connection {
     /// Application datagrams are a low-level primitive. They may be lost or delivered out
     /// of order, and `data` must both fit inside a single QUIC packet and be smaller than
     /// the maximum dictated by the peer.
+    ///
+    /// If the outgoing datagram buffer is full, the [`DatagramDropPolicy`] of the endpoint
+    /// decides whether older datagrams are dropped or `data` is discarded, like a datagram
+    /// lost on the network.  See [`Builder::datagram_drop_policy`].
+    ///
+    /// Returns `false` if `data` was discarded by [`DatagramDropPolicy::RejectNewest`].
+    ///
+    /// [`Builder::datagram_drop_policy`]: crate::endpoint::Builder::datagram_drop_policy
+    #[inline]
+    pub fn send_datagram(&self, data: bytes::Bytes) -> Result<bool, SendDatagramError> {
+        self.datagrams.send(&self.inner, data)
+    }
+
+    /// Transmits `data` as an unreliable, unordered application datagram, waiting for
+    /// buffer space.
+    ///
+    /// Unlike [`send_datagram`], this method will wait for buffer space during congestion
+    /// conditions, which effectively prioritizes old datagrams over new datagrams.  Use it
+    /// to apply backpressure to the producer of the datagrams.
+    ///
+    /// See [`send_datagram`] for details.
+    ///
+    /// [`send_datagram`]: Connection::send_datagram
     #[inline]
-    pub fn send_datagram(&self, data: bytes::Bytes) -> Result<(), SendDatagramError> {
-        self.inner.send_datagram(data)
-    }
-
-    // TODO: It seems `SendDatagram` is not yet exposed by quinn.  This has been fixed
-    //       upstream and will be in the next release.
-    // /// Transmits `data` as an unreliable, unordered application datagram
-    // ///
-    // /// Unlike [`send_datagram()`], this method will wait for buffer space during congestion
-    // /// conditions, which effectively prioritizes old datagrams over new datagrams.
-    // ///
-    // /// See [`send_datagram()`] for details.
-    // ///
-    // /// [`send_datagram()`]: Connection::send_datagram
-    // #[inline]
-    // pub fn send_datagram_wait(&self, data: bytes::Bytes) -> SendDatagram<'_> {
-    //     self.inner.send_datagram_wait(data)
-    // }
+    pub fn send_datagram_wait(&self, data: bytes::Bytes) -> SendDatagram<'_> {
+        self.inner.send_datagram_wait(data)
+    }
 
     /// Computes the maximum size of datagrams that may be passed to [`send_datagram`].
     ///
@@ -1390,6 +1477,17 @@ impl Connection {
         self.inner.stats()
     }
 
+    /// Returns a [`Watcher`] for the network path this connection currently uses.
+    ///
+    /// The [`PathInfo`] tells whether data is sent directly or via a relay server, using
+    /// which socket address or relay URL, the latency of the path and since when it is
+    /// used.  All connections to the same remote endpoint share their network paths.
+    ///
+    /// [`Watcher`]: n0_watcher::Watcher
+    pub fn paths(&self) -> n0_watcher::Direct<PathInfo> {
+        self.paths.clone()
+    }
+
     /// Current state of the congestion control algorithm, for debugging purposes.
     #[inline]
     pub fn congestion_state(&self) -> Box<dyn quinn_proto::congestion::Controller> {
@@ -1490,19 +1588,89 @@ impl Connection {
     }
 }
 
+/// What to do with a new application datagram when the outgoing datagram buffer is full.
+///
+/// Set it for all connections of an endpoint with [`Builder::datagram_drop_policy`].  The
+/// [`MagicsockMetrics`] of the endpoint count how often the buffer was full.
+///
+/// [`Builder::datagram_drop_policy`]: crate::endpoint::Builder::datagram_drop_policy
+/// [`MagicsockMetrics`]: crate::metrics::MagicsockMetrics
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DatagramDropPolicy {
+    /// Drops the oldest buffered datagrams to make room for the new datagram.
+    ///
+    /// This favours fresh data, e.g. for real-time media.
+    ///
+    /// The number of dropped datagrams is not known, sends while the buffer was full are
+    /// counted in `send_datagrams_buffer_full` instead.  This overestimates the datagrams
+    /// sent while dropping older ones, e.g. when the buffer was filled exactly to its size.
+    #[default]
+    DropOldest,
+    /// Discards the new datagram, keeping the buffered ones.
+    ///
+    /// `send_datagram` returns `false` for a discarded datagram.  Counted in
+    /// `send_datagrams_rejected`.
+    RejectNewest,
+}
+
+/// Sends application datagrams according to the [`DatagramDropPolicy`] of an endpoint.
+#[derive(Debug, Clone)]
+struct DatagramSender {
+    policy: DatagramDropPolicy,
+    metrics: Arc<MagicsockMetrics>,
+}
+
+impl DatagramSender {
+    fn new(ep: &Endpoint) -> Self {
+        Self {
+            policy: ep.static_config.datagram_drop_policy,
+            metrics: ep.metrics().magicsock.clone(),
+        }
+    }
+
+    fn send(&self, conn: &quinn::Connection, data: Bytes) -> Result<bool, SendDatagramError> {
+        // The buffer space is the buffer size minus the buffered bytes, saturating at zero.
+        let space = conn.datagram_send_buffer_space();
+        match self.policy {
+            DatagramDropPolicy::DropOldest => {
+                // Before buffering a datagram quinn drops the oldest datagrams for as long as
+                // more bytes than the buffer size are buffered.  With space left nothing is
+                // dropped.  Without, how many bytes are buffered beyond the buffer size is
+                // not exposed, so neither is whether and how many datagrams are dropped.
+                conn.send_datagram(data)?;
+                if space == 0 {
+                    self.metrics.send_datagrams_buffer_full.inc();
+                }
+                Ok(true)
+            }
+            DatagramDropPolicy::RejectNewest => {
+                if space < data.len() {
+                    self.metrics.send_datagrams_rejected.inc();
+                    return Ok(false);
+                }
+                conn.send_datagram(data)?;
+                Ok(true)
+            }
+        }
+    }
+}
+
 /// Try send a message to the rtt-actor.
 ///
 /// If we can't notify the actor that will impact performance a little, but we can still
 /// function.
-fn try_send_rtt_msg(conn: &quinn::Connection, ep: &Endpoint, remote_id: EndpointId) {
+fn try_send_rtt_msg(conn: &quinn::Connection, ep: &Endpoint, remote_id: EndpointId, alpn: &[u8]) {
     let Some(conn_type_changes) = ep.conn_type(remote_id) else {
         warn!(?conn, "failed to create conn_type stream");
         return;
     };
     let rtt_msg = RttMessage::NewConnection {
         connection: conn.weak_handle(),
+        liveness: conn.weak_handle(),
         conn_type_changes: conn_type_changes.stream(),
         endpoint_id: remote_id,
+        alpn: alpn.to_vec(),
     };
     if let Err(err) = ep.rtt_actor.msg_tx.try_send(rtt_msg) {
         warn!(?conn, "rtt-actor not reachable: {err:#}");
@@ -1513,14 +1681,17 @@ fn try_send_rtt_msg(conn: &quinn::Connection, ep: &Endpoint, remote_id: Endpoint
 mod tests {
     use iroh_base::{EndpointAddr, SecretKey};
     use n0_error::{Result, StackResultExt, StdResultExt};
+    use n0_future::time::Duration;
+    use n0_watcher::Watcher;
     use rand::SeedableRng;
     use tracing::{Instrument, info_span, trace_span};
     use tracing_test::traced_test;
 
     use super::Endpoint;
+    use super::{Bytes, DatagramDropPolicy};
     use crate::{
         RelayMode,
-        endpoint::{ConnectOptions, Incoming, ZeroRttStatus},
+        endpoint::{ConnectOptions, ConnectionType, Incoming, TransportConfig, ZeroRttStatus},
     };
 
     const TEST_ALPN: &[u8] = b"n0/iroh/test";
@@ -1730,4 +1901,114 @@ mod tests {
         tokio::join!(client.close(), server.close());
         Ok(())
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_connection_paths() -> Result {
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.addr();
+        let server_task = tokio::spawn(async move {
+            let conn = server.accept().await.anyerr()?.await.anyerr()?;
+            conn.closed().await;
+            Ok::<_, n0_error::AnyError>(())
+        });
+
+        let client = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let mut paths = conn.paths();
+        let path = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let path = paths.get();
+                if path.is_direct() {
+                    break path;
+                }
+                paths.updated().await.expect("endpoint alive");
+            }
+        })
+        .await
+        .anyerr()?;
+        assert!(!path.is_relay());
+        assert!(matches!(path.conn_type, ConnectionType::Direct(_)));
+
+        conn.close(0u32.into(), b"done");
+        server_task.await.anyerr()??;
+        client.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_datagram_drop_policy() -> Result {
+        async fn run(policy: DatagramDropPolicy) -> Result<(Vec<bool>, Vec<Bytes>, u64, u64)> {
+            let server = Endpoint::empty_builder(RelayMode::Disabled)
+                .alpns(vec![TEST_ALPN.to_vec()])
+                .bind()
+                .await?;
+            let server_addr = server.addr();
+            let server_task = tokio::spawn(async move {
+                let conn = server.accept().await.anyerr()?.await.anyerr()?;
+                let mut received = Vec::new();
+                while let Ok(datagram) = conn.read_datagram().await {
+                    received.push(datagram);
+                }
+                server.close().await;
+                Ok::<_, n0_error::AnyError>(received)
+            });
+
+            let mut transport_config = TransportConfig::default();
+            transport_config.datagram_send_buffer_size(100);
+            let client = Endpoint::empty_builder(RelayMode::Disabled)
+                .transport_config(transport_config)
+                .datagram_drop_policy(policy)
+                .bind()
+                .await?;
+            let conn = client.connect(server_addr, TEST_ALPN).await?;
+
+            // Nothing is transmitted before we yield, so the buffer overflows.  The second
+            // datagram does not fit into the remaining space, but quinn only drops the first
+            // datagram when the third one is sent.
+            let mut sent = Vec::new();
+            for datagram in [b"first", b"secnd", b"third"] {
+                sent.push(
+                    conn.send_datagram(Bytes::from(datagram.repeat(12)))
+                        .anyerr()?,
+                );
+            }
+            conn.send_datagram_wait(Bytes::from(b"waits".repeat(12)))
+                .await
+                .anyerr()?;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            conn.close(0u32.into(), b"done");
+
+            let received = server_task.await.anyerr()??;
+            let metrics = &client.metrics().magicsock;
+            let counts = (
+                metrics.send_datagrams_buffer_full.get(),
+                metrics.send_datagrams_rejected.get(),
+            );
+            client.close().await;
+            Ok((sent, received, counts.0, counts.1))
+        }
+
+        let (sent, received, buffer_full, rejected) = run(DatagramDropPolicy::DropOldest).await?;
+        assert_eq!(sent, [true, true, true]);
+        assert_eq!(
+            received,
+            [
+                b"secnd".repeat(12),
+                b"third".repeat(12),
+                b"waits".repeat(12)
+            ]
+        );
+        assert_eq!((buffer_full, rejected), (1, 0));
+
+        let (sent, received, buffer_full, rejected) = run(DatagramDropPolicy::RejectNewest).await?;
+        assert_eq!(sent, [true, false, false]);
+        assert_eq!(received, [b"first".repeat(12), b"waits".repeat(12)]);
+        assert_eq!((buffer_full, rejected), (0, 2));
+        Ok(())
+    }
 }
@@ -0,0 +1,129 @@
+//! Endpoint-wide connectivity events.
+//!
+//! The [`Endpoint`] emits an [`EndpointEvent`] whenever something noteworthy about its
+//! connectivity changes: connections opening and closing, the network path to a remote
+//! endpoint switching between relay and direct, the home relay changing, a new net report
+//! becoming available and discovery services finding addresses for remote endpoints.
+//!
+//! Use [`Endpoint::events`] to subscribe to these events.
+//!
+//! [`Endpoint`]: crate::Endpoint
+//! [`Endpoint::events`]: crate::Endpoint::events
+
+use iroh_base::{EndpointAddr, EndpointId, RelayUrl};
+use n0_future::{Stream, StreamExt};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{BroadcastStream, errors::BroadcastStreamRecvError};
+use tracing::debug;
+
+use crate::{magicsock::ConnectionType, net_report::Report};
+
+/// Number of events buffered for each subscriber before it starts to lag behind.
+///
+/// A subscriber that falls behind by more than this many events misses the oldest ones.
+const EVENTS_CAPACITY: usize = 256;
+
+/// An event about the connectivity of an [`Endpoint`].
+///
+/// Subscribe to these events with [`Endpoint::events`].
+///
+/// [`Endpoint`]: crate::Endpoint
+/// [`Endpoint::events`]: crate::Endpoint::events
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EndpointEvent {
+    /// A connection to a remote endpoint completed its handshake.
+    ///
+    /// Outgoing 0-RTT connections are reported as soon as they are created with
+    /// [`Connecting::into_0rtt`], before the handshake completed.
+    ///
+    /// [`Connecting::into_0rtt`]: crate::endpoint::Connecting::into_0rtt
+    ConnectionOpened {
+        /// The [`EndpointId`] of the remote endpoint.
+        remote_id: EndpointId,
+        /// The ALPN of this connection.
+        ///
+        /// This is the negotiated ALPN, except for outgoing 0-RTT connections where the
+        /// handshake did not select one yet.  Those report the first ALPN offered.
+        alpn: Vec<u8>,
+    },
+    /// A connection to a remote endpoint was closed.
+    ///
+    /// This is emitted once the connection has been fully drained and all of its resources
+    /// were released, which may be a little while after the connection was closed.  The
+    /// endpoint checks for such connections once a second, so the event can be delayed by
+    /// up to another second.
+    ConnectionClosed {
+        /// The [`EndpointId`] of the remote endpoint.
+        remote_id: EndpointId,
+        /// The ALPN of this connection, as reported by
+        /// [`EndpointEvent::ConnectionOpened`].
+        alpn: Vec<u8>,
+    },
+    /// The network path used to send data to a remote endpoint changed.
+    ///
+    /// This is emitted for example when a connection that was using the relay server
+    /// switched to a direct path after holepunching succeeded, or fell back to the relay
+    /// server when the direct path stopped working.
+    PathChanged {
+        /// The [`EndpointId`] of the remote endpoint.
+        remote_id: EndpointId,
+        /// The path previously used.
+        previous: ConnectionType,
+        /// The path now used.
+        current: ConnectionType,
+    },
+    /// The home relay of this endpoint changed.
+    HomeRelayChanged {
+        /// The previous home relay, if any.
+        previous: Option<RelayUrl>,
+        /// The new home relay, if any.
+        current: Option<RelayUrl>,
+    },
+    /// A new net report was produced.
+    NetReportUpdated {
+        /// The new report.
+        report: Box<Report>,
+    },
+    /// A discovery service found addressing information for a remote endpoint.
+    DiscoveryResult {
+        /// The addressing information that was found.
+        endpoint_addr: EndpointAddr,
+        /// The name of the discovery service that found the information.
+        provenance: &'static str,
+    },
+}
+
+/// Sends [`EndpointEvent`]s to all subscribers.
+///
+/// Sending is cheap when there are no subscribers, the event is simply dropped.
+#[derive(Debug, Clone)]
+pub(crate) struct EventSender(broadcast::Sender<EndpointEvent>);
+
+impl Default for EventSender {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENTS_CAPACITY);
+        Self(sender)
+    }
+}
+
+impl EventSender {
+    /// Sends an event to all current subscribers.
+    pub(crate) fn send(&self, event: EndpointEvent) {
+        // Only fails if there are no subscribers, in which case nobody is interested.
+        self.0.send(event).ok();
+    }
+
+    /// Returns a stream of all events sent after this call.
+    ///
+    /// Subscribers that fall too far behind skip the events they missed.
+    pub(crate) fn subscribe(&self) -> impl Stream<Item = EndpointEvent> + Unpin + use<> {
+        BroadcastStream::new(self.0.subscribe()).filter_map(|res| match res {
+            Ok(event) => Some(event),
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+                debug!("endpoint events subscriber lagged, skipped {n} events");
+                None
+            }
+        })
+    }
+}
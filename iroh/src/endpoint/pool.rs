@@ -0,0 +1,442 @@
+//! A pool of connections to reuse for repeated connects to the same remote endpoint.
+//!
+//! Calling [`Endpoint::connect`] for every request creates a new QUIC connection each time,
+//! even though QUIC connections are cheap to multiplex many streams over.  A
+//! [`ConnectionPool`] instead hands out a shared [`Connection`] per remote [`EndpointId`] and
+//! ALPN, dialing a new connection only when there is none or the previous one was closed.
+//!
+//! The pool only ever holds a handle to its connections: releasing a connection, because it
+//! was idle for too long or because the pool is full, does not close it while other handles
+//! are still in use.  The connection is closed once the last handle is dropped, as usual.
+//!
+//! [`Endpoint::connect`]: crate::Endpoint::connect
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+
+use iroh_base::{EndpointAddr, EndpointId};
+use iroh_metrics::{Counter, Gauge, MetricsGroup};
+use n0_future::{
+    task::{self, AbortOnDropHandle},
+    time::{self, Duration, Instant},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use tracing::{Instrument, debug, info_span};
+
+use super::{ConnectError, ConnectOptions, Connection, Endpoint};
+
+/// The default time after which an unused connection is released by the pool.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The default maximum number of connections held by the pool.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// Metrics of a [`ConnectionPool`].
+#[derive(Debug, Serialize, Deserialize, MetricsGroup)]
+#[non_exhaustive]
+#[metrics(name = "connection_pool", default)]
+pub struct Metrics {
+    /// Number of connections dialed by the pool.
+    pub connections_opened: Counter,
+    /// Number of times an existing connection was handed out.
+    pub connections_reused: Counter,
+    /// Number of failed connection attempts.
+    pub connect_errors: Counter,
+    /// Number of pooled connections which were closed.
+    pub connections_closed: Counter,
+    /// Number of connections released because they were not used for the idle timeout.
+    pub idle_timeouts: Counter,
+    /// Number of connections released to make room for a new connection.
+    pub evictions: Counter,
+    /// Number of connections currently held by the pool.
+    pub pool_size: Gauge,
+}
+
+/// Options for a [`ConnectionPool`].
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    idle_timeout: Duration,
+    max_connections: usize,
+    connect_options: ConnectOptions,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            connect_options: ConnectOptions::default(),
+        }
+    }
+}
+
+impl PoolOptions {
+    /// Creates the default pool options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the time after which a connection which was not handed out is released.
+    ///
+    /// Defaults to [`DEFAULT_IDLE_TIMEOUT`].
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets the maximum number of connections held by the pool.
+    ///
+    /// When the pool is full the least recently used connection is released to make room
+    /// for a new one.  Defaults to [`DEFAULT_MAX_CONNECTIONS`].
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections.max(1);
+        self
+    }
+
+    /// Sets the [`ConnectOptions`] used to dial new connections.
+    pub fn with_connect_options(mut self, connect_options: ConnectOptions) -> Self {
+        self.connect_options = connect_options;
+        self
+    }
+}
+
+/// A pool of connections, keyed by remote [`EndpointId`] and ALPN.
+///
+/// See the [module docs](self) for details.
+///
+/// Cloning the pool is cheap, all clones share the same connections.
+#[derive(Debug, Clone)]
+pub struct ConnectionPool {
+    inner: Arc<Inner>,
+}
+
+type Key = (EndpointId, Vec<u8>);
+
+#[derive(Debug)]
+struct Inner {
+    endpoint: Endpoint,
+    options: PoolOptions,
+    entries: Mutex<HashMap<Key, Entry>>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// The connection, set once the first caller finished dialing.
+    conn: Arc<OnceCell<Connection>>,
+    /// When the connection was last handed out.
+    last_used: Instant,
+    /// Task releasing the connection once it is closed or idle.
+    _watcher: Option<AbortOnDropHandle<()>>,
+}
+
+/// Why a connection was removed from the pool.
+#[derive(Debug, Clone, Copy)]
+enum Release {
+    Closed,
+    Idle,
+    Failed,
+}
+
+impl ConnectionPool {
+    /// Creates a new, empty pool dialing connections from `endpoint`.
+    pub fn new(endpoint: Endpoint, options: PoolOptions) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                endpoint,
+                options,
+                entries: Default::default(),
+                metrics: Default::default(),
+            }),
+        }
+    }
+
+    /// Returns a connection to the remote endpoint for the given ALPN.
+    ///
+    /// If the pool holds a connection for this remote endpoint and ALPN which is not closed
+    /// it is returned, otherwise a new connection is dialed using
+    /// [`Endpoint::connect_with_opts`].  Concurrent calls for the same remote endpoint and
+    /// ALPN wait for the same connection attempt.
+    pub async fn get_or_connect(
+        &self,
+        endpoint_addr: impl Into<EndpointAddr>,
+        alpn: &[u8],
+    ) -> Result<Connection, ConnectError> {
+        let endpoint_addr = endpoint_addr.into();
+        let key = (endpoint_addr.id, alpn.to_vec());
+        let cell = self.inner.checkout(&key);
+        if let Some(conn) = cell.get() {
+            self.inner.metrics.connections_reused.inc();
+            return Ok(conn.clone());
+        }
+
+        let mut dialed = false;
+        let res = cell
+            .get_or_try_init(|| {
+                dialed = true;
+                self.inner.connect(endpoint_addr, alpn)
+            })
+            .await;
+        match res {
+            Ok(conn) => {
+                if dialed {
+                    self.inner.metrics.connections_opened.inc();
+                    self.inner.spawn_watcher(key, &cell, conn.clone());
+                } else {
+                    self.inner.metrics.connections_reused.inc();
+                }
+                Ok(conn.clone())
+            }
+            Err(err) => {
+                self.inner.metrics.connect_errors.inc();
+                self.inner.release(&key, &cell, Release::Failed);
+                Err(err)
+            }
+        }
+    }
+
+    /// Returns the number of connections currently held by the pool.
+    ///
+    /// This includes connections which are still being dialed.
+    pub fn len(&self) -> usize {
+        self.inner.entries.lock().expect("poisoned").len()
+    }
+
+    /// Returns `true` if the pool holds no connections.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Releases all connections held by the pool.
+    ///
+    /// Connections still in use elsewhere are not closed.
+    pub fn clear(&self) {
+        self.inner.entries.lock().expect("poisoned").clear();
+        self.inner.metrics.pool_size.set(0);
+    }
+
+    /// Returns the metrics of this pool.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.inner.metrics
+    }
+}
+
+impl Inner {
+    /// Returns the connection slot for `key`, creating it if needed.
+    ///
+    /// Closed connections are dropped so that a new connection is dialed.
+    fn checkout(&self, key: &Key) -> Arc<OnceCell<Connection>> {
+        let mut entries = self.entries.lock().expect("poisoned");
+        if entries
+            .get(key)
+            .and_then(|entry| entry.conn.get())
+            .is_some_and(|conn| conn.close_reason().is_some())
+        {
+            entries.remove(key);
+            self.metrics.connections_closed.inc();
+        }
+        if !entries.contains_key(key) && entries.len() >= self.options.max_connections {
+            let lru = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(lru) = lru {
+                debug!(remote = %lru.0.fmt_short(), "pool full, releasing connection");
+                entries.remove(&lru);
+                self.metrics.evictions.inc();
+            }
+        }
+        let entry = entries.entry(key.clone()).or_insert_with(|| Entry {
+            conn: Default::default(),
+            last_used: Instant::now(),
+            _watcher: None,
+        });
+        entry.last_used = Instant::now();
+        let conn = entry.conn.clone();
+        self.metrics.pool_size.set(entries.len() as i64);
+        conn
+    }
+
+    async fn connect(
+        &self,
+        endpoint_addr: EndpointAddr,
+        alpn: &[u8],
+    ) -> Result<Connection, ConnectError> {
+        let connecting = self
+            .endpoint
+            .connect_with_opts(endpoint_addr, alpn, self.options.connect_options.clone())
+            .await?;
+        let conn = connecting.await?;
+        Ok(conn)
+    }
+
+    /// Starts the task releasing a freshly dialed connection once closed or idle.
+    fn spawn_watcher(
+        self: &Arc<Self>,
+        key: Key,
+        cell: &Arc<OnceCell<Connection>>,
+        conn: Connection,
+    ) {
+        let span = info_span!("pool-watcher", remote = %key.0.fmt_short());
+        let handle = task::spawn(
+            watch_connection(Arc::downgrade(self), key.clone(), cell.clone(), conn)
+                .instrument(span),
+        );
+        let mut entries = self.entries.lock().expect("poisoned");
+        match entries.get_mut(&key) {
+            Some(entry) if Arc::ptr_eq(&entry.conn, cell) => {
+                entry._watcher = Some(AbortOnDropHandle::new(handle));
+            }
+            _ => {
+                // The entry was released while dialing, the pool does not keep the connection.
+                handle.abort();
+            }
+        }
+    }
+
+    /// Removes the entry for `key` if it still holds the connection slot `cell`.
+    fn release(&self, key: &Key, cell: &Arc<OnceCell<Connection>>, reason: Release) {
+        let mut entries = self.entries.lock().expect("poisoned");
+        if !entries
+            .get(key)
+            .is_some_and(|entry| Arc::ptr_eq(&entry.conn, cell))
+        {
+            return;
+        }
+        debug!(remote = %key.0.fmt_short(), ?reason, "releasing connection");
+        // This may drop the watcher handle of the calling task, which is fine as it does
+        // not await anything after releasing.
+        entries.remove(key);
+        match reason {
+            Release::Closed => {
+                self.metrics.connections_closed.inc();
+            }
+            Release::Idle => {
+                self.metrics.idle_timeouts.inc();
+            }
+            Release::Failed => {}
+        }
+        self.metrics.pool_size.set(entries.len() as i64);
+    }
+
+    /// Returns when the connection in `cell` becomes idle, or `None` if it was released.
+    fn idle_deadline(&self, key: &Key, cell: &Arc<OnceCell<Connection>>) -> Option<Instant> {
+        let entries = self.entries.lock().expect("poisoned");
+        let entry = entries.get(key)?;
+        Arc::ptr_eq(&entry.conn, cell).then(|| entry.last_used + self.options.idle_timeout)
+    }
+}
+
+/// Releases a pooled connection once it is closed or was not handed out for too long.
+async fn watch_connection(
+    inner: Weak<Inner>,
+    key: Key,
+    cell: Arc<OnceCell<Connection>>,
+    conn: Connection,
+) {
+    loop {
+        let Some(deadline) = inner
+            .upgrade()
+            .and_then(|inner| inner.idle_deadline(&key, &cell))
+        else {
+            return;
+        };
+        tokio::select! {
+            _ = conn.closed() => {
+                if let Some(inner) = inner.upgrade() {
+                    inner.release(&key, &cell, Release::Closed);
+                }
+                return;
+            }
+            _ = time::sleep_until(deadline) => {
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                if inner
+                    .idle_deadline(&key, &cell)
+                    .is_some_and(|deadline| deadline <= Instant::now())
+                {
+                    inner.release(&key, &cell, Release::Idle);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use n0_error::{AnyError as Error, Result, StdResultExt};
+    use n0_future::time::Duration;
+    use tracing_test::traced_test;
+
+    use super::{ConnectionPool, PoolOptions};
+    use crate::{Endpoint, RelayMode};
+
+    const TEST_ALPN: &[u8] = b"n0/iroh/test";
+
+    async fn server() -> Result<(Endpoint, n0_future::task::AbortOnDropHandle<Result>)> {
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let ep = server.clone();
+        let task = tokio::spawn(async move {
+            while let Some(incoming) = ep.accept().await {
+                let conn = incoming.await.anyerr()?;
+                tokio::spawn(async move { conn.closed().await });
+            }
+            Ok::<_, Error>(())
+        });
+        Ok((server, n0_future::task::AbortOnDropHandle::new(task)))
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_pool_reuse_and_redial() -> Result {
+        let (server, _task) = server().await?;
+        let client = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let pool = ConnectionPool::new(client, PoolOptions::new());
+
+        let conn1 = pool.get_or_connect(server.addr(), TEST_ALPN).await?;
+        let conn2 = pool.get_or_connect(server.addr(), TEST_ALPN).await?;
+        assert_eq!(conn1.stable_id(), conn2.stable_id());
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.metrics().connections_opened.get(), 1);
+        assert_eq!(pool.metrics().connections_reused.get(), 1);
+
+        conn1.close(0u32.into(), b"done");
+        let conn3 = pool.get_or_connect(server.addr(), TEST_ALPN).await?;
+        assert_ne!(conn1.stable_id(), conn3.stable_id());
+        assert_eq!(pool.metrics().connections_opened.get(), 2);
+        assert_eq!(pool.metrics().connections_closed.get(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_pool_idle_timeout_and_max_size() -> Result {
+        let (server1, _task1) = server().await?;
+        let (server2, _task2) = server().await?;
+        let client = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let pool = ConnectionPool::new(
+            client,
+            PoolOptions::new()
+                .with_idle_timeout(Duration::from_millis(200))
+                .with_max_connections(1),
+        );
+
+        pool.get_or_connect(server1.addr(), TEST_ALPN).await?;
+        pool.get_or_connect(server2.addr(), TEST_ALPN).await?;
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.metrics().evictions.get(), 1);
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(pool.is_empty());
+        assert_eq!(pool.metrics().idle_timeouts.get(), 1);
+        Ok(())
+    }
+}
@@ -6,11 +6,19 @@ use iroh_base::EndpointId;
 use n0_future::{
     MergeUnbounded, Stream, StreamExt,
     task::{self, AbortOnDropHandle},
+    time::{self, Duration},
 };
 use tokio::sync::mpsc;
 use tracing::{Instrument, debug, info_span};
 
-use crate::{magicsock::ConnectionType, metrics::MagicsockMetrics};
+use crate::{
+    endpoint::{EndpointEvent, EventSender},
+    magicsock::ConnectionType,
+    metrics::MagicsockMetrics,
+};
+
+/// How often the actor checks whether the connections it knows about are gone.
+const CLOSED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub(super) struct RttHandle {
@@ -20,10 +28,12 @@ pub(super) struct RttHandle {
 }
 
 impl RttHandle {
-    pub(super) fn new(metrics: Arc<MagicsockMetrics>) -> Self {
+    pub(super) fn new(metrics: Arc<MagicsockMetrics>, events: EventSender) -> Self {
         let mut actor = RttActor {
             connection_events: Default::default(),
+            open_connections: Default::default(),
             metrics,
+            events,
         };
         let (msg_tx, msg_rx) = mpsc::channel(16);
         let handle = task::spawn(
@@ -46,10 +56,14 @@ pub(super) enum RttMessage {
     NewConnection {
         /// The connection.
         connection: quinn::WeakConnectionHandle,
+        /// A second handle to the connection, used to notice when it is gone.
+        liveness: quinn::WeakConnectionHandle,
         /// Path changes for this connection from the magic socket.
         conn_type_changes: n0_watcher::Stream<n0_watcher::Direct<ConnectionType>>,
         /// For reporting-only, the Endpoint ID of this connection.
         endpoint_id: EndpointId,
+        /// For reporting-only, the ALPN of this connection.
+        alpn: Vec<u8>,
     },
 }
 
@@ -62,7 +76,18 @@ struct RttActor {
     /// Stream of connection type changes.
     #[debug("MergeUnbounded<WatcherStream<ConnectionType>>")]
     connection_events: MergeUnbounded<MappedStream>,
+    /// Connections which have not yet been reported as closed.
+    open_connections: Vec<OpenConnection>,
     metrics: Arc<MagicsockMetrics>,
+    events: EventSender,
+}
+
+/// A connection for which we emit [`EndpointEvent::ConnectionClosed`] once it is gone.
+#[derive(Debug)]
+struct OpenConnection {
+    connection: quinn::WeakConnectionHandle,
+    endpoint_id: EndpointId,
+    alpn: Vec<u8>,
 }
 
 #[derive(Debug)]
@@ -121,6 +146,7 @@ impl RttActor {
     ///
     /// The main loop will finish when the sender is dropped.
     async fn run(&mut self, mut msg_rx: mpsc::Receiver<RttMessage>) {
+        let mut closed_check = time::interval(CLOSED_CHECK_INTERVAL);
         loop {
             tokio::select! {
                 biased;
@@ -135,6 +161,9 @@ impl RttActor {
                         self.metrics.connection_became_direct.inc();
                     }
                 }
+                _ = closed_check.tick(), if !self.open_connections.is_empty() => {
+                    self.handle_closed_check();
+                }
             }
         }
         debug!("rtt-actor finished");
@@ -145,10 +174,21 @@ impl RttActor {
         match msg {
             RttMessage::NewConnection {
                 connection,
+                liveness,
                 conn_type_changes,
                 endpoint_id,
+                alpn,
             } => {
                 self.handle_new_connection(connection, conn_type_changes, endpoint_id);
+                self.events.send(EndpointEvent::ConnectionOpened {
+                    remote_id: endpoint_id,
+                    alpn: alpn.clone(),
+                });
+                self.open_connections.push(OpenConnection {
+                    connection: liveness,
+                    endpoint_id,
+                    alpn,
+                });
             }
         }
     }
@@ -168,4 +208,19 @@ impl RttActor {
         });
         self.metrics.connection_handshake_success.inc();
     }
+
+    /// Reports all connections which are gone as closed and stops tracking them.
+    fn handle_closed_check(&mut self) {
+        let events = &self.events;
+        self.open_connections.retain(|conn| {
+            if conn.connection.is_alive() {
+                return true;
+            }
+            events.send(EndpointEvent::ConnectionClosed {
+                remote_id: conn.endpoint_id,
+                alpn: conn.alpn.clone(),
+            });
+            false
+        });
+    }
 }
@@ -0,0 +1,191 @@
+//! Storing the TLS session state of outgoing connections.
+//!
+//! An endpoint keeps the TLS session tickets it receives from remote endpoints in a
+//! [`ClientSessionStore`], so later connections to them can resume the session and send
+//! 0-RTT data.  By default this is an in-memory cache holding [`Builder::max_tls_tickets`]
+//! tickets, a different store can be set with [`Builder::tls_session_store`].
+//!
+//! rustls neither exposes an encoding of the TLS 1.3 session tickets it hands to the store
+//! nor a way to construct them, so tickets can only be kept in memory: after a restart the
+//! first connection to a remote endpoint can not use 0-RTT.  What rustls does expose is the
+//! key exchange group each remote endpoint chose, which [`FileSessionStore`] persists in a
+//! file.  With it the first connection after a restart sends a key share the remote endpoint
+//! accepts, instead of needing an extra round trip when the remote endpoint does not support
+//! the default group.
+//!
+//! [`Builder::max_tls_tickets`]: crate::endpoint::Builder::max_tls_tickets
+//! [`Builder::tls_session_store`]: crate::endpoint::Builder::tls_session_store
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+pub use rustls::client::ClientSessionStore;
+use rustls::{
+    NamedGroup,
+    client::{ClientSessionMemoryCache, Tls12ClientSessionValue, Tls13ClientSessionValue},
+    pki_types::ServerName,
+};
+use tracing::warn;
+
+/// A [`ClientSessionStore`] which persists the key exchange groups of remote endpoints in a
+/// file.
+///
+/// Session tickets are kept in memory only, see the [module documentation](self) for why.
+/// The file is read when the store is created and rewritten whenever a remote endpoint
+/// chose a different key exchange group than before, which is rare.  Writing the file
+/// happens during the TLS handshake and uses blocking I/O; failures are logged and
+/// otherwise ignored.
+pub struct FileSessionStore {
+    path: PathBuf,
+    max_entries: usize,
+    kx_hints: Mutex<BTreeMap<String, u16>>,
+    tickets: ClientSessionMemoryCache,
+}
+
+impl fmt::Debug for FileSessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The tickets are key material, only show the file.
+        f.debug_struct("FileSessionStore")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileSessionStore {
+    /// Creates a store keeping the key exchange groups in the file at `path`.
+    ///
+    /// `max_entries` bounds the number of remote endpoints whose key exchange group is
+    /// kept, as well as the number of session tickets kept in memory, like
+    /// [`Builder::max_tls_tickets`] does for the default store.  Tickets are kept for one
+    /// remote endpoint per 8 entries.  A missing or unreadable file starts out empty.
+    ///
+    /// [`Builder::max_tls_tickets`]: crate::endpoint::Builder::max_tls_tickets
+    pub fn new(path: impl Into<PathBuf>, max_entries: usize) -> Self {
+        let path = path.into();
+        let kx_hints = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+                warn!(path = %path.display(), "invalid TLS session store: {err:#}");
+                BTreeMap::new()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                warn!(path = %path.display(), "failed to read TLS session store: {err:#}");
+                BTreeMap::new()
+            }
+        };
+        Self {
+            path,
+            max_entries,
+            kx_hints: Mutex::new(kx_hints),
+            tickets: ClientSessionMemoryCache::new(max_entries),
+        }
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn save(&self, kx_hints: &BTreeMap<String, u16>) -> std::io::Result<()> {
+        let data = serde_json::to_vec_pretty(kx_hints)?;
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, data)?;
+        std::fs::rename(&tmp_path, &self.path)
+    }
+}
+
+impl ClientSessionStore for FileSessionStore {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        let mut kx_hints = self.kx_hints.lock().expect("poisoned");
+        let group = u16::from(group);
+        let server_name = server_name.to_str().into_owned();
+        if kx_hints.get(&server_name) == Some(&group) {
+            return;
+        }
+        if !kx_hints.contains_key(&server_name) && kx_hints.len() >= self.max_entries {
+            // Which entry goes does not matter much, the hint only saves a round trip.
+            kx_hints.pop_first();
+        }
+        if self.max_entries > 0 {
+            kx_hints.insert(server_name, group);
+        }
+        if let Err(err) = self.save(&kx_hints) {
+            warn!(path = %self.path.display(), "failed to save TLS session store: {err:#}");
+        }
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        self.kx_hints
+            .lock()
+            .expect("poisoned")
+            .get(server_name.to_str().as_ref())
+            .map(|group| NamedGroup::from(*group))
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.tickets.set_tls12_session(server_name, value)
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        self.tickets.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.tickets.remove_tls12_session(server_name)
+    }
+
+    fn insert_tls13_ticket(
+        &self,
+        server_name: ServerName<'static>,
+        value: Tls13ClientSessionValue,
+    ) {
+        self.tickets.insert_tls13_ticket(server_name, value)
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        server_name: &ServerName<'static>,
+    ) -> Option<Tls13ClientSessionValue> {
+        self.tickets.take_tls13_ticket(server_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_session_store_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tls_sessions.json");
+        let name = |n: &str| ServerName::try_from(n.to_string()).unwrap();
+
+        let store = FileSessionStore::new(&path, 2);
+        assert_eq!(store.kx_hint(&name("a.iroh.invalid")), None);
+        store.set_kx_hint(name("a.iroh.invalid"), NamedGroup::secp256r1);
+        store.set_kx_hint(name("b.iroh.invalid"), NamedGroup::X25519);
+
+        let store = FileSessionStore::new(&path, 2);
+        assert_eq!(
+            store.kx_hint(&name("a.iroh.invalid")),
+            Some(NamedGroup::secp256r1)
+        );
+        assert_eq!(
+            store.kx_hint(&name("b.iroh.invalid")),
+            Some(NamedGroup::X25519)
+        );
+
+        // The number of entries is bounded.
+        store.set_kx_hint(name("c.iroh.invalid"), NamedGroup::X25519);
+        let store = FileSessionStore::new(&path, 2);
+        assert_eq!(store.kx_hint(&name("a.iroh.invalid")), None);
+        assert_eq!(
+            store.kx_hint(&name("c.iroh.invalid")),
+            Some(NamedGroup::X25519)
+        );
+    }
+}
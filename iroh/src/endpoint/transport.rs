@@ -0,0 +1,79 @@
+//! Custom transports carrying the datagrams of an endpoint.
+//!
+//! Besides UDP sockets and relay servers an endpoint can send its QUIC datagrams over any
+//! number of custom transports, e.g. a unix socket or a serial link.  Custom transports are
+//! registered with [`Builder::add_custom_transport`].
+//!
+//! Each custom transport has a unique id, and its addresses are [`CustomAddr`]s carrying this
+//! id.  The local addresses of a transport are published to discovery like all other
+//! addresses of the endpoint, and custom addresses in an [`EndpointAddr`] are used to dial the
+//! remote endpoint.  Custom paths are validated with the same ping messages as direct UDP
+//! paths: a valid UDP path is preferred, then a valid custom path, and otherwise custom paths
+//! are tried alongside the relay.  With [`PathSelection::RelayOnly`] custom transports are
+//! not used.
+//!
+//! [`Builder::add_custom_transport`]: crate::endpoint::Builder::add_custom_transport
+//! [`EndpointAddr`]: crate::EndpointAddr
+//! [`PathSelection::RelayOnly`]: crate::endpoint::PathSelection::RelayOnly
+
+use std::{
+    fmt::Debug,
+    io,
+    task::{Context, Poll},
+};
+
+pub use iroh_base::CustomAddr;
+
+/// The minimum [`Transport::max_datagram_size`] of a custom transport.
+///
+/// QUIC requires paths to carry datagrams of at least 1200 bytes.
+pub const MIN_DATAGRAM_SIZE: usize = 1200;
+
+/// A transport sending and receiving datagrams for an endpoint.
+///
+/// Transports are shared between the send and receive paths of the endpoint, so all methods
+/// take `&self`.  Like [`tokio::net::UdpSocket::poll_recv_from`], only the waker of the most
+/// recent call to each of [`Transport::poll_send`] and [`Transport::poll_recv`] needs to be
+/// woken up.
+///
+/// Datagrams may be dropped or reordered, QUIC takes care of recovering from that.
+///
+/// [`tokio::net::UdpSocket::poll_recv_from`]: https://docs.rs/tokio/latest/tokio/net/struct.UdpSocket.html#method.poll_recv_from
+pub trait Transport: Debug + Send + Sync + 'static {
+    /// Returns the id of this transport.
+    ///
+    /// Only addresses with this id are sent on this transport.  The ids of all custom
+    /// transports of an endpoint must be unique.
+    fn id(&self) -> u64;
+
+    /// Returns a watcher for the addresses at which this transport can be reached.
+    fn local_addrs(&self) -> n0_watcher::Direct<Vec<CustomAddr>>;
+
+    /// Returns the maximum size of a datagram this transport can carry.
+    ///
+    /// QUIC requires at least [`MIN_DATAGRAM_SIZE`] bytes.  Larger datagrams are dropped before they are handed
+    /// to the transport, which lets QUIC's path MTU discovery settle below this size.
+    fn max_datagram_size(&self) -> usize;
+
+    /// Sends a single datagram to `dst`.
+    ///
+    /// Returning [`Poll::Pending`] applies backpressure to the endpoint.  Errors are logged
+    /// and the datagram is treated as lost.
+    fn poll_send(
+        &self,
+        cx: &mut Context<'_>,
+        dst: &CustomAddr,
+        datagram: &[u8],
+    ) -> Poll<io::Result<()>>;
+
+    /// Receives a single datagram into `buf`.
+    ///
+    /// Returns the length of the datagram and the address of the sender.  The sender's
+    /// address must be the one it can be reached at, i.e. one of the local addresses of the
+    /// remote transport.
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, CustomAddr)>>;
+}
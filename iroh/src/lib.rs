@@ -273,8 +273,8 @@ pub mod protocol;
 
 pub use endpoint::{Endpoint, RelayMode};
 pub use iroh_base::{
-    EndpointAddr, EndpointId, KeyParsingError, PublicKey, RelayUrl, RelayUrlParseError, SecretKey,
-    Signature, SignatureError, TransportAddr,
+    CustomAddr, EndpointAddr, EndpointId, EndpointTicket, KeyParsingError, PublicKey, RelayUrl,
+    RelayUrlParseError, SecretKey, Signature, SignatureError, TransportAddr,
 };
 pub use iroh_relay::{RelayConfig, RelayMap, endpoint_info};
 pub use n0_watcher::Watcher;
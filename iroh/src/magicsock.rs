@@ -30,7 +30,9 @@ use std::{
 
 use bytes::Bytes;
 use data_encoding::HEXLOWER;
-use iroh_base::{EndpointAddr, EndpointId, PublicKey, RelayUrl, SecretKey, TransportAddr};
+use iroh_base::{
+    CustomAddr, EndpointAddr, EndpointId, PublicKey, RelayUrl, SecretKey, TransportAddr,
+};
 use iroh_relay::{RelayConfig, RelayMap};
 use n0_error::{e, stack_error};
 use n0_future::{
@@ -44,7 +46,7 @@ use netwatch::{UdpSocket, ip::LocalAddresses};
 use quinn::{AsyncUdpSocket, ServerConfig};
 use rand::Rng;
 use smallvec::SmallVec;
-use tokio::sync::{Mutex as AsyncMutex, mpsc};
+use tokio::sync::{Mutex as AsyncMutex, mpsc, oneshot};
 use tokio_util::sync::CancellationToken;
 use tracing::{
     Instrument, Level, debug, error, event, info, info_span, instrument, trace, trace_span, warn,
@@ -57,20 +59,26 @@ use self::transports::IpTransport;
 use self::{
     endpoint_map::{EndpointMap, PingAction, PingRole, SendPing},
     metrics::Metrics as MagicsockMetrics,
-    transports::{RelayActorConfig, RelayTransport, Transports, UdpSender},
+    transports::{
+        CustomTransport, Direction, RateLimiter, RelayActorConfig, RelayTransport, Transports,
+        UdpSender,
+    },
 };
 #[cfg(not(wasm_browser))]
 use crate::dns::DnsResolver;
-#[cfg(any(test, feature = "test-utils"))]
-use crate::endpoint::PathSelection;
 #[cfg(not(wasm_browser))]
 use crate::net_report::{IpMappedAddr, QuicConfig};
+#[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+use crate::test_utils::sim::VirtualHost;
 use crate::{
     defaults::timeouts::NET_REPORT_TIMEOUT,
     disco::{self, SendAddr, TransactionId},
     discovery::{ConcurrentDiscovery, Discovery, EndpointData, UserData},
+    endpoint::{
+        EndpointEvent, EventSender, PathSelection, PortPrediction, bandwidth::BandwidthLimits,
+        transport::Transport,
+    },
     key::{DecryptionError, SharedSecret, public_ed_box, secret_ed_box},
-    magicsock::endpoint_map::RemoteInfo,
     metrics::EndpointMetrics,
     net_report::{self, IfStateDetails, IpMappedAddresses, Report},
 };
@@ -83,7 +91,10 @@ pub(crate) mod transports;
 pub use endpoint_map::Source;
 
 pub use self::{
-    endpoint_map::{ConnectionType, ControlMsg, DirectAddrInfo},
+    endpoint_map::{
+        ConnectionType, ControlMsg, DirectAddrInfo, DiscoPingPurpose, HolepunchEvent,
+        HolepunchEventKind, HolepunchTrace, PathInfo, RelayUrlInfo, RemoteInfo,
+    },
     metrics::Metrics,
 };
 
@@ -134,9 +145,25 @@ pub(crate) struct Options {
     pub(crate) insecure_skip_relay_cert_verify: bool,
 
     /// Configuration for what path selection to use
-    #[cfg(any(test, feature = "test-utils"))]
     pub(crate) path_selection: PathSelection,
 
+    /// Port prediction holepunching, if enabled.
+    pub(crate) port_prediction: Option<PortPrediction>,
+
+    /// Bandwidth limits for the traffic of the endpoint
+    pub(crate) bandwidth_limits: BandwidthLimits,
+
+    /// Custom transports to send and receive datagrams on, in addition to UDP and relays.
+    #[debug("{} custom transports", custom_transports.len())]
+    pub(crate) custom_transports: Vec<Arc<dyn Transport>>,
+
+    /// Addresses of remote endpoints loaded from an address book.
+    pub(crate) saved_endpoints: Vec<EndpointAddr>,
+
+    /// A simulated host to use instead of UDP sockets and relay servers.
+    #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+    pub(crate) virtual_host: Option<VirtualHost>,
+
     pub(crate) metrics: EndpointMetrics,
 }
 
@@ -198,6 +225,8 @@ pub(crate) struct MagicSock {
     #[cfg(not(wasm_browser))]
     dns_resolver: DnsResolver,
     relay_map: RelayMap,
+    /// Enforces the configured bandwidth limits.
+    rate_limiter: RateLimiter,
 
     /// Disco
     disco: DiscoState,
@@ -207,9 +236,15 @@ pub(crate) struct MagicSock {
     discovery: ConcurrentDiscovery,
     /// Optional user-defined discover data.
     discovery_user_data: RwLock<Option<UserData>>,
+    /// The endpoint-wide [`PathSelection`].
+    ///
+    /// With [`PathSelection::RelayOnly`] our direct addresses are not published.
+    path_selection: PathSelection,
 
     /// Metrics
     pub(crate) metrics: EndpointMetrics,
+    /// Sender for the endpoint-wide connectivity events.
+    events: EventSender,
 }
 
 #[allow(missing_docs)]
@@ -230,6 +265,11 @@ impl MagicSock {
         Handle::new(opts).await
     }
 
+    /// Returns the sender for the endpoint-wide connectivity events.
+    pub(crate) fn events(&self) -> &EventSender {
+        &self.events
+    }
+
     /// Returns the relay endpoint we are connected to, that has the best latency.
     ///
     /// If `None`, then we are not connected to any relay endpoints.
@@ -275,7 +315,6 @@ impl MagicSock {
     }
 
     /// Return the [`RemoteInfo`]s of all endpoints in the endpoint map.
-    #[cfg(test)]
     pub(crate) fn list_remote_infos(&self) -> Vec<RemoteInfo> {
         self.endpoint_map.list_remote_infos(Instant::now())
     }
@@ -285,6 +324,11 @@ impl MagicSock {
         self.endpoint_map.remote_info(endpoint_id)
     }
 
+    /// Return the [`HolepunchTrace`] for a single endpoint in the endpoint map.
+    pub(crate) fn holepunch_trace(&self, endpoint_id: EndpointId) -> Option<HolepunchTrace> {
+        self.endpoint_map.holepunch_trace(endpoint_id)
+    }
+
     pub(crate) async fn insert_relay(
         &self,
         relay: RelayUrl,
@@ -363,6 +407,22 @@ impl MagicSock {
         })
     }
 
+    /// Watch for changes to the addresses of our custom transports.
+    pub(crate) fn custom_addrs(&self) -> impl Watcher<Value = Vec<CustomAddr>> + use<> {
+        self.local_addrs_watch.clone().map(|addrs| {
+            addrs
+                .into_iter()
+                .filter_map(|addr| {
+                    if let transports::Addr::Custom(addr) = addr {
+                        Some(addr)
+                    } else {
+                        None
+                    }
+                })
+                .collect()
+        })
+    }
+
     /// Returns a [`n0_watcher::Direct`] that reports the [`ConnectionType`] we have to the
     /// given `endpoint_id`.
     ///
@@ -384,6 +444,29 @@ impl MagicSock {
         self.endpoint_map.latency(endpoint_id)
     }
 
+    /// Returns a [`n0_watcher::Direct`] reporting the path used to send to `endpoint_id`.
+    ///
+    /// Will return `None` if there is no address information known about the
+    /// given `endpoint_id`.
+    pub(crate) fn path_info(
+        &self,
+        endpoint_id: EndpointId,
+    ) -> Option<n0_watcher::Direct<PathInfo>> {
+        self.endpoint_map.path_info(endpoint_id)
+    }
+
+    /// Overrides the [`PathSelection`] for all traffic to the given endpoint.
+    ///
+    /// `None` restores the endpoint-wide [`PathSelection`].
+    pub(crate) fn set_path_selection(
+        &self,
+        endpoint_id: EndpointId,
+        path_selection: Option<PathSelection>,
+    ) {
+        self.endpoint_map
+            .set_path_selection(endpoint_id, path_selection)
+    }
+
     /// Returns the socket address which can be used by the QUIC layer to dial this endpoint.
     pub(crate) fn get_mapping_addr(&self, endpoint_id: EndpointId) -> Option<EndpointIdMappedAddr> {
         self.endpoint_map
@@ -404,6 +487,14 @@ impl MagicSock {
                 pruned += 1;
             }
         }
+        for my_addr in self.local_addr() {
+            if let transports::Addr::Custom(my_addr) = my_addr {
+                if addr.addrs.remove(&TransportAddr::Custom(my_addr.clone())) {
+                    warn!( endpoint_id=%addr.id.fmt_short(), %my_addr, %source, "not adding our addr for endpoint");
+                    pruned += 1;
+                }
+            }
+        }
         if !addr.is_empty() {
             let have_ipv6 = self.ipv6_reported.load(Ordering::Relaxed);
             self.endpoint_map
@@ -494,7 +585,7 @@ impl MagicSock {
         &self,
         udp_sender: &UdpSender,
         transmit: &quinn_udp::Transmit,
-    ) -> io::Result<SmallVec<[transports::Addr; 3]>> {
+    ) -> io::Result<(Option<EndpointId>, SmallVec<[transports::Addr; 3]>)> {
         self.metrics
             .magicsock
             .send_data
@@ -511,6 +602,7 @@ impl MagicSock {
             ));
         }
 
+        let mut remote = None;
         let mut active_paths = SmallVec::<[_; 3]>::new();
 
         match MappedAddr::from(transmit.destination) {
@@ -531,8 +623,10 @@ impl MagicSock {
                     dest,
                     self.ipv6_reported.load(Ordering::Relaxed),
                     &self.metrics.magicsock,
+                    &self.events,
                 ) {
-                    Some((endpoint_id, udp_addr, relay_url, ping_actions)) => {
+                    Some((endpoint_id, udp_addr, relay_url, custom_addr, ping_actions)) => {
+                        remote = Some(endpoint_id);
                         if !ping_actions.is_empty() {
                             self.try_send_ping_actions(udp_sender, ping_actions).ok();
                         }
@@ -542,6 +636,9 @@ impl MagicSock {
                         if let Some(url) = relay_url {
                             active_paths.push(transports::Addr::Relay(url, endpoint_id));
                         }
+                        if let Some(addr) = custom_addr {
+                            active_paths.push(transports::Addr::Custom(addr));
+                        }
                     }
                     None => {
                         error!(%dest, "no EndpointState for mapped address");
@@ -570,7 +667,7 @@ impl MagicSock {
             }
         }
 
-        Ok(active_paths)
+        Ok((remote, active_paths))
     }
 
     /// Process datagrams received from UDP sockets.
@@ -664,6 +761,12 @@ impl MagicSock {
                                 .recv_data_relay
                                 .inc_by(datagram.len() as _);
                         }
+                        transports::Addr::Custom(..) => {
+                            self.metrics
+                                .magicsock
+                                .recv_data_custom
+                                .inc_by(datagram.len() as _);
+                        }
                     }
 
                     quic_datagram_count += 1;
@@ -672,6 +775,7 @@ impl MagicSock {
             }
 
             if buf_contains_quic_datagrams {
+                let mut remote = None;
                 match source_addr {
                     #[cfg(wasm_browser)]
                     transports::Addr::Ip(_addr) => {
@@ -709,6 +813,7 @@ impl MagicSock {
                                 }
                             }
                             Some((endpoint_id, quic_mapped_addr)) => {
+                                remote = Some(endpoint_id);
                                 trace!(
                                     src = %addr,
                                     endpoint = %endpoint_id.fmt_short(),
@@ -726,8 +831,50 @@ impl MagicSock {
                         let quic_mapped_addr =
                             self.endpoint_map.receive_relay(src_url, *src_endpoint);
                         quinn_meta.addr = quic_mapped_addr.private_socket_addr();
+                        remote = Some(*src_endpoint);
+                    }
+                    transports::Addr::Custom(addr) => {
+                        // Custom transport
+                        match self.endpoint_map.receive_custom(addr) {
+                            None => {
+                                warn!(
+                                    src = %addr,
+                                    count = %quic_datagram_count,
+                                    len = quinn_meta.len,
+                                    "custom recv quic packets: no endpoint state found, skipping",
+                                );
+                                quinn_meta.len = 0;
+                            }
+                            Some((endpoint_id, quic_mapped_addr)) => {
+                                remote = Some(endpoint_id);
+                                trace!(
+                                    src = %addr,
+                                    endpoint = %endpoint_id.fmt_short(),
+                                    count = %quic_datagram_count,
+                                    len = quinn_meta.len,
+                                    "custom recv quic packets",
+                                );
+                                quic_packets_total += quic_datagram_count;
+                                quinn_meta.addr = quic_mapped_addr.private_socket_addr();
+                            }
+                        }
                     }
                 }
+
+                if quinn_meta.len > 0
+                    && self
+                        .rate_limiter
+                        .try_acquire(Direction::Recv, source_addr, remote, quinn_meta.len)
+                        .is_err()
+                {
+                    // Drop the packets, making the sender back off.
+                    trace!(src = ?source_addr, len = quinn_meta.len, "UDP recv: throttled");
+                    self.metrics
+                        .magicsock
+                        .recv_data_throttled
+                        .inc_by(quinn_meta.len as _);
+                    quinn_meta.len = 0;
+                }
             } else {
                 // If all datagrams in this buf are DISCO, set len to zero to make
                 // Quinn skip the buf completely.
@@ -908,8 +1055,14 @@ impl MagicSock {
         if sent {
             let msg_sender = self.actor_sender.clone();
             trace!(%dst, tx = %HEXLOWER.encode(&tx_id), ?purpose, "ping sent (queued)");
-            self.endpoint_map
-                .notify_ping_sent(id, dst, tx_id, purpose, msg_sender);
+            self.endpoint_map.notify_ping_sent(
+                id,
+                dst,
+                tx_id,
+                purpose,
+                msg_sender,
+                &self.metrics.magicsock,
+            );
         } else {
             warn!(dst = ?dst, tx = %HEXLOWER.encode(&tx_id), ?purpose, "failed to send ping: queues full");
         }
@@ -977,8 +1130,14 @@ impl MagicSock {
                         .await?;
                     debug!(%dst, tx = %HEXLOWER.encode(&tx_id), ?purpose, "ping sent");
                     let msg_sender = self.actor_sender.clone();
-                    self.endpoint_map
-                        .notify_ping_sent(id, dst, tx_id, purpose, msg_sender);
+                    self.endpoint_map.notify_ping_sent(
+                        id,
+                        dst,
+                        tx_id,
+                        purpose,
+                        msg_sender,
+                        &self.metrics.magicsock,
+                    );
                 }
             }
         }
@@ -996,6 +1155,7 @@ impl MagicSock {
         let dst = match dst {
             SendAddr::Udp(addr) => transports::Addr::Ip(addr),
             SendAddr::Relay(url) => transports::Addr::Relay(url, dst_key),
+            SendAddr::Custom(addr) => transports::Addr::Custom(addr),
         };
 
         trace!(?dst, %msg, "send disco message (UDP)");
@@ -1089,8 +1249,14 @@ impl MagicSock {
                     self.try_send_disco_message(sender, dst.clone(), dst_endpoint, msg)?;
                     debug!(%dst, tx = %HEXLOWER.encode(&tx_id), ?purpose, "ping sent");
                     let msg_sender = self.actor_sender.clone();
-                    self.endpoint_map
-                        .notify_ping_sent(id, dst, tx_id, purpose, msg_sender);
+                    self.endpoint_map.notify_ping_sent(
+                        id,
+                        dst,
+                        tx_id,
+                        purpose,
+                        msg_sender,
+                        &self.metrics.magicsock,
+                    );
                 }
             }
         }
@@ -1108,6 +1274,7 @@ impl MagicSock {
         let dst = match dst {
             SendAddr::Udp(addr) => transports::Addr::Ip(addr),
             SendAddr::Relay(url) => transports::Addr::Relay(url, dst_key),
+            SendAddr::Custom(addr) => transports::Addr::Custom(addr),
         };
 
         trace!(?dst, %msg, "send disco message (UDP)");
@@ -1146,11 +1313,19 @@ impl MagicSock {
     /// Called whenever our addresses or home relay endpoint changes.
     fn publish_my_addr(&self) {
         let relay_url = self.my_relay();
-        let mut addrs: BTreeSet<_> = self
-            .direct_addrs
-            .sockaddrs()
-            .map(TransportAddr::Ip)
-            .collect();
+        let mut addrs: BTreeSet<_> = if self.path_selection == PathSelection::RelayOnly {
+            // Remote endpoints are not supposed to learn our direct addresses.
+            BTreeSet::new()
+        } else {
+            self.direct_addrs
+                .sockaddrs()
+                .map(TransportAddr::Ip)
+                .chain(self.local_addr().into_iter().filter_map(|addr| match addr {
+                    transports::Addr::Custom(addr) => Some(TransportAddr::Custom(addr)),
+                    _ => None,
+                }))
+                .collect()
+        };
 
         let user_data = self
             .discovery_user_data
@@ -1213,6 +1388,9 @@ struct DirectAddrUpdateState {
     net_reporter: Arc<AsyncMutex<net_report::Client>>,
     relay_map: RelayMap,
     run_done: mpsc::Sender<()>,
+    /// The simulated host to report about instead of running net reports.
+    #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+    virtual_host: Option<VirtualHost>,
 }
 
 #[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
@@ -1241,6 +1419,9 @@ impl DirectAddrUpdateState {
         net_reporter: Arc<AsyncMutex<net_report::Client>>,
         relay_map: RelayMap,
         run_done: mpsc::Sender<()>,
+        #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))] virtual_host: Option<
+            VirtualHost,
+        >,
     ) -> Self {
         DirectAddrUpdateState {
             want_update: Default::default(),
@@ -1250,6 +1431,8 @@ impl DirectAddrUpdateState {
             msock,
             relay_map,
             run_done,
+            #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+            virtual_host,
         }
     }
 
@@ -1296,6 +1479,15 @@ impl DirectAddrUpdateState {
             debug!("skipping net_report, socket is shutting down");
             return;
         }
+        #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+        if let Some(ref host) = self.virtual_host {
+            debug!("skipping net_report, using the simulated network");
+            self.msock
+                .net_report
+                .set((Some(host.net_report()), why))
+                .ok();
+            return;
+        }
         if self.relay_map.is_empty() {
             debug!("skipping net_report, empty RelayMap");
             self.msock.net_report.set((None, why)).ok();
@@ -1314,6 +1506,9 @@ impl DirectAddrUpdateState {
                 );
                 match fut.await {
                     Ok(report) => {
+                        msock.events.send(EndpointEvent::NetReportUpdated {
+                            report: Box::new(report.clone()),
+                        });
                         msock.net_report.set((Some(report), why)).ok();
                     }
                     Err(time::Elapsed { .. }) => {
@@ -1361,8 +1556,13 @@ impl Handle {
             server_config,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify,
-            #[cfg(any(test, feature = "test-utils"))]
             path_selection,
+            port_prediction,
+            bandwidth_limits,
+            custom_transports,
+            saved_endpoints,
+            #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+            virtual_host,
             metrics,
         } = opts;
 
@@ -1370,7 +1570,13 @@ impl Handle {
 
         let addr_v4 = addr_v4.unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
 
-        #[cfg(not(wasm_browser))]
+        #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+        let (ip_transports, port_mapper) = match virtual_host {
+            Some(ref host) => bind_virtual(host, &metrics),
+            None => bind_ip(addr_v4, addr_v6, &metrics)
+                .map_err(|err| e!(CreateHandleError::BindSockets, err))?,
+        };
+        #[cfg(all(not(any(test, feature = "test-utils")), not(wasm_browser)))]
         let (ip_transports, port_mapper) = bind_ip(addr_v4, addr_v6, &metrics)
             .map_err(|err| e!(CreateHandleError::BindSockets, err))?;
 
@@ -1382,42 +1588,57 @@ impl Handle {
 
         // load the endpoint data
         let endpoint_map = EndpointMap::load_from_vec(
-            Vec::new(),
-            #[cfg(any(test, feature = "test-utils"))]
+            saved_endpoints,
             path_selection,
+            port_prediction,
             ipv6_reported,
             &metrics.magicsock,
         );
 
         let my_relay = Watchable::new(None);
         let ipv6_reported = Arc::new(AtomicBool::new(ipv6_reported));
+        let events = EventSender::default();
 
         let shutdown_token = CancellationToken::new();
 
-        let relay_transport = RelayTransport::new(
-            RelayActorConfig {
-                my_relay: my_relay.clone(),
-                secret_key: secret_key.clone(),
-                #[cfg(not(wasm_browser))]
-                dns_resolver: dns_resolver.clone(),
-                proxy_url: proxy_url.clone(),
-                ipv6_reported: ipv6_reported.clone(),
-                #[cfg(any(test, feature = "test-utils"))]
-                insecure_skip_relay_cert_verify,
-                metrics: metrics.magicsock.clone(),
-            },
-            shutdown_token.child_token(),
-        );
+        let relay_config = RelayActorConfig {
+            my_relay: my_relay.clone(),
+            secret_key: secret_key.clone(),
+            #[cfg(not(wasm_browser))]
+            dns_resolver: dns_resolver.clone(),
+            proxy_url: proxy_url.clone(),
+            ipv6_reported: ipv6_reported.clone(),
+            #[cfg(any(test, feature = "test-utils"))]
+            insecure_skip_relay_cert_verify,
+            metrics: metrics.magicsock.clone(),
+            events: events.clone(),
+        };
+        #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+        let relay_transport = match virtual_host {
+            Some(ref host) => RelayTransport::new_virtual(
+                host.clone(),
+                secret_key.public(),
+                my_relay.clone(),
+                shutdown_token.child_token(),
+            ),
+            None => RelayTransport::new(relay_config, shutdown_token.child_token()),
+        };
+        #[cfg(not(all(any(test, feature = "test-utils"), not(wasm_browser))))]
+        let relay_transport = RelayTransport::new(relay_config, shutdown_token.child_token());
         let relay_transports = vec![relay_transport];
+        let custom_transports = custom_transports
+            .into_iter()
+            .map(|transport| CustomTransport::new(transport, metrics.magicsock.clone()))
+            .collect();
 
         let secret_encryption_key = secret_ed_box(&secret_key);
         #[cfg(not(wasm_browser))]
         let ipv6 = ip_transports.iter().any(|t| t.bind_addr().is_ipv6());
 
         #[cfg(not(wasm_browser))]
-        let transports = Transports::new(ip_transports, relay_transports);
+        let transports = Transports::new(ip_transports, relay_transports, custom_transports);
         #[cfg(wasm_browser)]
-        let transports = Transports::new(relay_transports);
+        let transports = Transports::new(relay_transports, custom_transports);
 
         let (disco, disco_receiver) = DiscoState::new(secret_encryption_key);
 
@@ -1432,12 +1653,15 @@ impl Handle {
             ip_mapped_addrs: ip_mapped_addrs.clone(),
             discovery,
             relay_map: relay_map.clone(),
+            rate_limiter: RateLimiter::new(&bandwidth_limits),
             discovery_user_data: RwLock::new(discovery_user_data),
+            path_selection,
             direct_addrs: DiscoveredDirectAddrs::default(),
             net_report: Watchable::new((None, UpdateReason::None)),
             #[cfg(not(wasm_browser))]
             dns_resolver: dns_resolver.clone(),
             metrics: metrics.clone(),
+            events,
             local_addrs_watch: transports.local_addrs_watch(),
             #[cfg(not(wasm_browser))]
             ip_bind_addrs: transports.ip_bind_addrs(),
@@ -1515,6 +1739,8 @@ impl Handle {
             Arc::new(AsyncMutex::new(net_reporter)),
             relay_map,
             direct_addr_done_tx,
+            #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+            virtual_host,
         );
 
         let netmon_watcher = network_monitor.interface_state();
@@ -1557,6 +1783,43 @@ impl Handle {
         &self.endpoint
     }
 
+    /// Closes the connection gracefully.
+    ///
+    /// New connections are refused right away.  Then this waits for all open connections
+    /// to be closed and drained and for datagrams queued for relay servers to be handed to
+    /// the relay connections, both bound by `timeout`.  Finally the magic socket is closed
+    /// like with [`Handle::close`].
+    pub(crate) async fn close_graceful(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        self.endpoint.set_server_config(None);
+
+        if time::timeout(timeout, self.endpoint.wait_idle())
+            .await
+            .is_err()
+        {
+            debug!(
+                open_connections = self.endpoint.open_connections(),
+                "timed out waiting for connections to close",
+            );
+        }
+
+        let (done, flushed) = oneshot::channel();
+        if self
+            .msock
+            .actor_sender
+            .send(ActorMessage::FlushRelayDatagrams(done))
+            .await
+            .is_ok()
+        {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if time::timeout(remaining, flushed).await.is_err() {
+                debug!("timed out flushing relay datagrams");
+            }
+        }
+
+        self.close().await;
+    }
+
     /// Closes the connection.
     ///
     /// Only the first close does anything. Any later closes return nil.
@@ -1616,7 +1879,7 @@ impl Handle {
     }
 }
 
-fn default_quic_client_config() -> rustls::ClientConfig {
+pub(crate) fn default_quic_client_config() -> rustls::ClientConfig {
     // create a client config for the endpoint to use for QUIC address discovery
     let root_store =
         rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
@@ -1771,6 +2034,8 @@ enum ActorMessage {
     NetworkChange,
     ScheduleDirectAddrUpdate(UpdateReason, Option<(EndpointId, RelayUrl)>),
     RelayMapChange,
+    /// Hands all datagrams queued for relay servers to the relay connections.
+    FlushRelayDatagrams(oneshot::Sender<()>),
     #[cfg(test)]
     ForceNetworkChange(bool),
 }
@@ -1844,6 +2109,26 @@ fn bind_ip(
     Ok((ip, port_mapper))
 }
 
+/// Binds onto a simulated host, with a port mapper which does not probe the real network.
+#[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+fn bind_virtual(
+    host: &VirtualHost,
+    metrics: &EndpointMetrics,
+) -> (Vec<IpTransport>, portmapper::Client) {
+    let config = portmapper::Config {
+        enable_upnp: false,
+        enable_pcp: false,
+        enable_nat_pmp: false,
+        protocol: portmapper::Protocol::Udp,
+    };
+    let port_mapper = portmapper::Client::with_metrics(config, metrics.portmapper.clone());
+    let ip = vec![IpTransport::new_virtual(
+        host.clone(),
+        metrics.magicsock.clone(),
+    )];
+    (ip, port_mapper)
+}
+
 impl Actor {
     async fn run(
         mut self,
@@ -2068,6 +2353,13 @@ impl Actor {
             ActorMessage::RelayMapChange => {
                 self.handle_relay_map_change();
             }
+            ActorMessage::FlushRelayDatagrams(done) => {
+                let flushed = self.network_change_sender.flush_relay_datagrams();
+                task::spawn(async move {
+                    flushed.await;
+                    done.send(()).ok();
+                });
+            }
             #[cfg(test)]
             ActorMessage::ForceNetworkChange(is_major) => {
                 self.handle_network_change(is_major).await;
@@ -2557,8 +2849,12 @@ mod tests {
             server_config,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
-            #[cfg(any(test, feature = "test-utils"))]
             path_selection: PathSelection::default(),
+            port_prediction: None,
+            bandwidth_limits: Default::default(),
+            custom_transports: Vec::new(),
+            saved_endpoints: Vec::new(),
+            virtual_host: None,
             discovery_user_data: None,
             metrics: Default::default(),
         }
@@ -3091,6 +3387,11 @@ mod tests {
             server_config,
             insecure_skip_relay_cert_verify: false,
             path_selection: PathSelection::default(),
+            port_prediction: None,
+            bandwidth_limits: Default::default(),
+            custom_transports: Vec::new(),
+            saved_endpoints: Vec::new(),
+            virtual_host: None,
             metrics: Default::default(),
         };
         let msock = MagicSock::spawn(opts).await?;
@@ -6,24 +6,30 @@ use std::{
     time::Duration,
 };
 
-use iroh_base::{EndpointAddr, EndpointId, PublicKey, RelayUrl};
+use iroh_base::{CustomAddr, EndpointAddr, EndpointId, PublicKey, RelayUrl};
 use n0_future::time::Instant;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info, instrument, trace, warn};
 
 use self::endpoint_state::{EndpointState, Options, PingHandled};
 use super::{ActorMessage, EndpointIdMappedAddr, metrics::Metrics, transports};
-use crate::disco::{CallMeMaybe, Pong, SendAddr, TransactionId};
-#[cfg(any(test, feature = "test-utils"))]
-use crate::endpoint::PathSelection;
+use crate::{
+    disco::{CallMeMaybe, Pong, SendAddr, TransactionId},
+    endpoint::{EventSender, PathSelection, PortPrediction},
+};
 
 mod endpoint_state;
+mod holepunch_trace;
 mod path_state;
 mod path_validity;
 mod udp_paths;
 
-pub use endpoint_state::{ConnectionType, ControlMsg, DirectAddrInfo};
-pub(super) use endpoint_state::{DiscoPingPurpose, PingAction, PingRole, RemoteInfo, SendPing};
+pub use endpoint_state::{
+    ConnectionType, ControlMsg, DirectAddrInfo, DiscoPingPurpose, PathInfo, RelayUrlInfo,
+    RemoteInfo,
+};
+pub(super) use endpoint_state::{PingAction, PingRole, SendPing};
+pub use holepunch_trace::{HolepunchEvent, HolepunchEventKind, HolepunchTrace};
 
 /// Number of endpoints that are inactive for which we keep info about. This limit is enforced
 /// periodically via [`EndpointMap::prune_inactive`].
@@ -56,10 +62,13 @@ pub(super) struct EndpointMapInner {
     by_endpoint_key: HashMap<EndpointId, usize>,
     by_ip_port: HashMap<IpPort, usize>,
     by_quic_mapped_addr: HashMap<EndpointIdMappedAddr, usize>,
+    by_custom_addr: HashMap<CustomAddr, usize>,
     by_id: HashMap<usize, EndpointState>,
     next_id: usize,
-    #[cfg(any(test, feature = "test-utils"))]
     path_selection: PathSelection,
+    /// [`PathSelection`]s overriding `path_selection` for individual remote endpoints.
+    path_selection_overrides: HashMap<EndpointId, PathSelection>,
+    port_prediction: Option<PortPrediction>,
 }
 
 /// Identifier to look up a [`EndpointState`] in the [`EndpointMap`].
@@ -72,6 +81,7 @@ enum EndpointStateKey {
     EndpointId(EndpointId),
     EndpointIdMappedAddr(EndpointIdMappedAddr),
     IpPort(IpPort),
+    CustomAddr(CustomAddr),
 }
 
 /// The origin or *source* through which an address associated with a remote endpoint
@@ -100,6 +110,8 @@ pub enum Source {
     Udp,
     /// An endpoint communicated with us first via relay.
     Relay,
+    /// An endpoint communicated with us first via a custom transport.
+    Custom,
     /// Application layer added the address directly.
     App,
     /// The address was discovered by a discovery service.
@@ -120,14 +132,15 @@ impl EndpointMap {
     /// Create a new [`EndpointMap`] from a list of [`EndpointAddr`]s.
     pub(super) fn load_from_vec(
         endpoints: Vec<EndpointAddr>,
-        #[cfg(any(test, feature = "test-utils"))] path_selection: PathSelection,
+        path_selection: PathSelection,
+        port_prediction: Option<PortPrediction>,
         have_ipv6: bool,
         metrics: &Metrics,
     ) -> Self {
         Self::from_inner(EndpointMapInner::load_from_vec(
             endpoints,
-            #[cfg(any(test, feature = "test-utils"))]
             path_selection,
+            port_prediction,
             have_ipv6,
             metrics,
         ))
@@ -179,6 +192,13 @@ impl EndpointMap {
             .receive_relay(relay_url, src)
     }
 
+    pub(super) fn receive_custom(
+        &self,
+        addr: &CustomAddr,
+    ) -> Option<(PublicKey, EndpointIdMappedAddr)> {
+        self.inner.lock().expect("poisoned").receive_custom(addr)
+    }
+
     pub(super) fn notify_ping_sent(
         &self,
         id: usize,
@@ -186,6 +206,7 @@ impl EndpointMap {
         tx_id: TransactionId,
         purpose: DiscoPingPurpose,
         msg_sender: tokio::sync::mpsc::Sender<ActorMessage>,
+        metrics: &Metrics,
     ) {
         if let Some(ep) = self
             .inner
@@ -193,7 +214,7 @@ impl EndpointMap {
             .expect("poisoned")
             .get_mut(EndpointStateKey::Idx(id))
         {
-            ep.ping_sent(dst, tx_id, purpose, msg_sender);
+            ep.ping_sent(dst, tx_id, purpose, msg_sender, metrics);
         }
     }
 
@@ -265,18 +286,21 @@ impl EndpointMap {
         addr: EndpointIdMappedAddr,
         have_ipv6: bool,
         metrics: &Metrics,
+        events: &EventSender,
     ) -> Option<(
         PublicKey,
         Option<SocketAddr>,
         Option<RelayUrl>,
+        Option<CustomAddr>,
         Vec<PingAction>,
     )> {
         let mut inner = self.inner.lock().expect("poisoned");
         let ep = inner.get_mut(EndpointStateKey::EndpointIdMappedAddr(addr))?;
         let public_key = *ep.public_key();
         trace!(dest = %addr, endpoint_id = %public_key.fmt_short(), "dst mapped to EndpointId");
-        let (udp_addr, relay_url, ping_actions) = ep.get_send_addrs(have_ipv6, metrics);
-        Some((public_key, udp_addr, relay_url, ping_actions))
+        let (udp_addr, relay_url, custom_addr, ping_actions) =
+            ep.get_send_addrs(have_ipv6, metrics, events);
+        Some((public_key, udp_addr, relay_url, custom_addr, ping_actions))
     }
 
     pub(super) fn reset_endpoint_states(&self, metrics: &Metrics) {
@@ -296,7 +320,6 @@ impl EndpointMap {
     }
 
     /// Returns the [`RemoteInfo`]s for each endpoint in the endpoint map.
-    #[cfg(test)]
     pub(super) fn list_remote_infos(&self, now: Instant) -> Vec<RemoteInfo> {
         // NOTE: calls to this method will often call `into_iter` (or similar methods). Note that
         // we can't avoid `collect` here since it would hold a lock for an indefinite time. Even if
@@ -326,6 +349,37 @@ impl EndpointMap {
         self.inner.lock().expect("poisoned").latency(endpoint_id)
     }
 
+    /// Returns a [`n0_watcher::Direct`] for the given endpoint's [`PathInfo`].
+    ///
+    /// Will return `None` if there is not an entry in the [`EndpointMap`] for
+    /// the `endpoint_id`
+    pub(super) fn path_info(
+        &self,
+        endpoint_id: EndpointId,
+    ) -> Option<n0_watcher::Direct<PathInfo>> {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .get(EndpointStateKey::EndpointId(endpoint_id))
+            .map(|ep| ep.path_info())
+    }
+
+    /// Overrides the [`PathSelection`] for the endpoint identified by [`EndpointId`].
+    ///
+    /// `None` removes the override again.  The override also applies if the endpoint is
+    /// only added to the [`EndpointMap`] later, and is not removed when the endpoint is
+    /// pruned.
+    pub(super) fn set_path_selection(
+        &self,
+        endpoint_id: EndpointId,
+        path_selection: Option<PathSelection>,
+    ) {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .set_path_selection(endpoint_id, path_selection)
+    }
+
     /// Get the [`RemoteInfo`]s for the endpoint identified by [`EndpointId`].
     pub(super) fn remote_info(&self, endpoint_id: EndpointId) -> Option<RemoteInfo> {
         self.inner
@@ -334,6 +388,15 @@ impl EndpointMap {
             .remote_info(endpoint_id)
     }
 
+    /// Get the [`HolepunchTrace`] for the endpoint identified by [`EndpointId`].
+    pub(super) fn holepunch_trace(&self, endpoint_id: EndpointId) -> Option<HolepunchTrace> {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .get(EndpointStateKey::EndpointId(endpoint_id))
+            .map(|ep| ep.holepunch_trace(Instant::now()))
+    }
+
     /// Prunes endpoints without recent activity so that at most [`MAX_INACTIVE_ENDPOINTS`] are kept.
     pub(super) fn prune_inactive(&self) {
         self.inner.lock().expect("poisoned").prune_inactive();
@@ -351,13 +414,14 @@ impl EndpointMapInner {
     /// Create a new [`EndpointMap`] from a list of [`EndpointAddr`]s.
     fn load_from_vec(
         endpoints: Vec<EndpointAddr>,
-        #[cfg(any(test, feature = "test-utils"))] path_selection: PathSelection,
+        path_selection: PathSelection,
+        port_prediction: Option<PortPrediction>,
         have_ipv6: bool,
         metrics: &Metrics,
     ) -> Self {
         let mut me = Self {
-            #[cfg(any(test, feature = "test-utils"))]
             path_selection,
+            port_prediction,
             ..Default::default()
         };
         for endpoint_addr in endpoints {
@@ -366,6 +430,34 @@ impl EndpointMapInner {
         me
     }
 
+    fn set_path_selection(
+        &mut self,
+        endpoint_id: EndpointId,
+        path_selection: Option<PathSelection>,
+    ) {
+        match path_selection {
+            Some(path_selection) => {
+                self.path_selection_overrides
+                    .insert(endpoint_id, path_selection);
+            }
+            None => {
+                self.path_selection_overrides.remove(&endpoint_id);
+            }
+        }
+        let path_selection = self.path_selection_for(endpoint_id);
+        if let Some(ep) = self.get_mut(EndpointStateKey::EndpointId(endpoint_id)) {
+            ep.set_path_selection(path_selection);
+        }
+    }
+
+    /// Returns the [`PathSelection`] used for the given remote endpoint.
+    fn path_selection_for(&self, endpoint_id: EndpointId) -> PathSelection {
+        self.path_selection_overrides
+            .get(&endpoint_id)
+            .copied()
+            .unwrap_or(self.path_selection)
+    }
+
     /// Add the contact information for an endpoint.
     #[instrument(skip_all, fields(endpoint = %endpoint_addr.id.fmt_short()))]
     fn add_endpoint_addr(
@@ -378,20 +470,21 @@ impl EndpointMapInner {
         let source0 = source.clone();
         let endpoint_id = endpoint_addr.id;
         let relay_url = endpoint_addr.relay_urls().next().cloned();
-        #[cfg(any(test, feature = "test-utils"))]
-        let path_selection = self.path_selection;
+        let path_selection = self.path_selection_for(endpoint_id);
+        let port_prediction = self.port_prediction;
         let endpoint_state =
             self.get_or_insert_with(EndpointStateKey::EndpointId(endpoint_id), || Options {
                 endpoint_id,
                 relay_url,
                 active: false,
                 source,
-                #[cfg(any(test, feature = "test-utils"))]
                 path_selection,
+                port_prediction,
             });
         endpoint_state.update_from_endpoint_addr(
             endpoint_addr.relay_urls().next(),
             endpoint_addr.ip_addrs().copied(),
+            endpoint_addr.custom_addrs().cloned(),
             source0,
             have_ipv6,
             metrics,
@@ -400,6 +493,9 @@ impl EndpointMapInner {
         for addr in endpoint_addr.ip_addrs() {
             self.set_endpoint_state_for_ip_port(*addr, id);
         }
+        for addr in endpoint_addr.custom_addrs() {
+            self.by_custom_addr.insert(addr.clone(), id);
+        }
     }
 
     /// Prunes direct addresses from endpoints that claim to share an address we know points to us.
@@ -419,7 +515,7 @@ impl EndpointMapInner {
             if let Entry::Occupied(mut entry) = self.by_id.entry(id) {
                 let endpoint = entry.get_mut();
                 endpoint.remove_direct_addr(&ipp, now, why);
-                if endpoint.ip_addrs().count() == 0 {
+                if endpoint.ip_addrs().count() == 0 && endpoint.custom_addrs().count() == 0 {
                     let endpoint_id = endpoint.public_key();
                     let mapped_addr = endpoint.quic_mapped_addr();
                     self.by_endpoint_key.remove(endpoint_id);
@@ -441,6 +537,7 @@ impl EndpointMapInner {
                 self.by_quic_mapped_addr.get(&addr).copied()
             }
             EndpointStateKey::IpPort(ipp) => self.by_ip_port.get(&ipp).copied(),
+            EndpointStateKey::CustomAddr(addr) => self.by_custom_addr.get(&addr).copied(),
         }
     }
 
@@ -484,10 +581,23 @@ impl EndpointMapInner {
         ))
     }
 
+    /// Marks the endpoint we believe to be at the custom `addr` as recently used.
+    fn receive_custom(&mut self, addr: &CustomAddr) -> Option<(EndpointId, EndpointIdMappedAddr)> {
+        let Some(endpoint_state) = self.get_mut(EndpointStateKey::CustomAddr(addr.clone())) else {
+            trace!(src=%addr, "receive_custom: no endpoint_state found for addr, ignore");
+            return None;
+        };
+        endpoint_state.receive_custom(addr, Instant::now());
+        Some((
+            *endpoint_state.public_key(),
+            *endpoint_state.quic_mapped_addr(),
+        ))
+    }
+
     #[instrument(skip_all, fields(src = %src.fmt_short()))]
     fn receive_relay(&mut self, relay_url: &RelayUrl, src: EndpointId) -> EndpointIdMappedAddr {
-        #[cfg(any(test, feature = "test-utils"))]
-        let path_selection = self.path_selection;
+        let path_selection = self.path_selection_for(src);
+        let port_prediction = self.port_prediction;
         let endpoint_state = self.get_or_insert_with(EndpointStateKey::EndpointId(src), || {
             trace!("packets from unknown endpoint, insert into endpoint map");
             Options {
@@ -495,15 +605,14 @@ impl EndpointMapInner {
                 relay_url: Some(relay_url.clone()),
                 active: true,
                 source: Source::Relay,
-                #[cfg(any(test, feature = "test-utils"))]
                 path_selection,
+                port_prediction,
             }
         });
         endpoint_state.receive_relay(relay_url, src, Instant::now());
         *endpoint_state.quic_mapped_addr()
     }
 
-    #[cfg(test)]
     fn endpoint_states(&self) -> impl Iterator<Item = (&usize, &EndpointState)> {
         self.by_id.iter()
     }
@@ -513,7 +622,6 @@ impl EndpointMapInner {
     }
 
     /// Get the [`RemoteInfo`]s for all endpoints.
-    #[cfg(test)]
     fn remote_infos_iter(&self, now: Instant) -> impl Iterator<Item = RemoteInfo> + '_ {
         self.endpoint_states().map(move |(_, ep)| ep.info(now))
     }
@@ -595,29 +703,34 @@ impl EndpointMapInner {
         src: SendAddr,
         tx_id: TransactionId,
     ) -> PingHandled {
-        #[cfg(any(test, feature = "test-utils"))]
-        let path_selection = self.path_selection;
+        let path_selection = self.path_selection_for(sender);
+        let port_prediction = self.port_prediction;
         let endpoint_state = self.get_or_insert_with(EndpointStateKey::EndpointId(sender), || {
             debug!("received ping: endpoint unknown, add to endpoint map");
-            let source = if src.is_relay() {
-                Source::Relay
-            } else {
-                Source::Udp
+            let source = match src {
+                SendAddr::Udp(_) => Source::Udp,
+                SendAddr::Relay(_) => Source::Relay,
+                SendAddr::Custom(_) => Source::Custom,
             };
             Options {
                 endpoint_id: sender,
                 relay_url: src.relay_url(),
                 active: true,
                 source,
-                #[cfg(any(test, feature = "test-utils"))]
                 path_selection,
+                port_prediction,
             }
         });
 
+        let id = endpoint_state.id();
         let handled = endpoint_state.handle_ping(src.clone(), tx_id);
-        if let SendAddr::Udp(ref addr) = src {
-            if matches!(handled.role, PingRole::NewPath) {
-                self.set_endpoint_key_for_ip_port(*addr, &sender);
+        if matches!(handled.role, PingRole::NewPath) {
+            match src {
+                SendAddr::Udp(addr) => self.set_endpoint_key_for_ip_port(addr, &sender),
+                SendAddr::Custom(addr) => {
+                    self.by_custom_addr.insert(addr, id);
+                }
+                SendAddr::Relay(_) => {}
             }
         }
         handled
@@ -710,6 +823,9 @@ impl EndpointMapInner {
             for ip_port in ep.ip_addrs() {
                 self.by_ip_port.remove(&ip_port);
             }
+            for addr in ep.custom_addrs() {
+                self.by_custom_addr.remove(addr);
+            }
 
             self.by_quic_mapped_addr.remove(ep.quic_mapped_addr());
         }
@@ -820,6 +936,7 @@ mod tests {
         let loaded_endpoint_map = EndpointMap::load_from_vec(
             addrs.clone(),
             PathSelection::default(),
+            None,
             true,
             &Default::default(),
         );
@@ -865,6 +982,7 @@ mod tests {
                     name: "test".into(),
                 },
                 path_selection: PathSelection::default(),
+                port_prediction: None,
             })
             .id();
 
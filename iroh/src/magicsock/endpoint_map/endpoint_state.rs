@@ -1,12 +1,13 @@
 use std::{
-    collections::{BTreeSet, HashMap, btree_map::Entry},
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, btree_map::Entry},
     hash::Hash,
-    net::{IpAddr, SocketAddr},
-    sync::atomic::AtomicBool,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Mutex, atomic::AtomicBool},
 };
 
 use data_encoding::HEXLOWER;
-use iroh_base::{EndpointAddr, EndpointId, PublicKey, RelayUrl, TransportAddr};
+use iroh_base::{CustomAddr, EndpointAddr, EndpointId, PublicKey, RelayUrl, TransportAddr};
 use n0_future::{
     task::{self, AbortOnDropHandle},
     time::{self, Duration, Instant},
@@ -18,13 +19,13 @@ use tracing::{Level, debug, event, info, instrument, trace, warn};
 
 use super::{
     IpPort, Source,
+    holepunch_trace::{HolepunchEventKind, HolepunchLog, HolepunchTrace},
     path_state::{PathState, summarize_endpoint_paths},
     udp_paths::{EndpointUdpPaths, UdpSendAddr},
 };
-#[cfg(any(test, feature = "test-utils"))]
-use crate::endpoint::PathSelection;
 use crate::{
     disco::{self, SendAddr, TransactionId},
+    endpoint::{EndpointEvent, EventSender, PathSelection, PortPrediction},
     magicsock::{
         ActorMessage, EndpointIdMappedAddr, HEARTBEAT_INTERVAL, MagicsockMetrics,
         endpoint_map::path_validity::PathValidity,
@@ -116,6 +117,8 @@ pub(super) struct EndpointState {
     /// The fallback/bootstrap path, if non-zero (non-zero for well-behaved clients).
     relay_url: Option<(RelayUrl, PathState)>,
     udp_paths: EndpointUdpPaths,
+    /// The paths to this endpoint over custom transports.
+    custom_paths: BTreeMap<CustomAddr, PathState>,
     sent_pings: HashMap<TransactionId, SentPing>,
     /// Last time this endpoint was used.
     ///
@@ -136,13 +139,23 @@ pub(super) struct EndpointState {
     last_call_me_maybe: Option<Instant>,
     /// The type of connection we have to the endpoint, either direct, relay, mixed, or none.
     conn_type: Watchable<ConnectionType>,
+    /// The path currently used to send to the endpoint, together with its latency.
+    path_info: Watchable<PathInfo>,
     /// Whether the conn_type was ever observed to be `Direct` at some point.
     ///
     /// Used for metric reporting.
     has_been_direct: AtomicBool,
     /// Configuration for what path selection to use
-    #[cfg(any(test, feature = "test-utils"))]
     path_selection: PathSelection,
+    /// Recent holepunching activity, for diagnostics.
+    ///
+    /// Behind a lock as the path changes are recorded when sending, which only has shared
+    /// access.
+    holepunch_log: Mutex<HolepunchLog>,
+    /// Port prediction holepunching, if enabled.
+    port_prediction: Option<PortPrediction>,
+    /// Last time we sent a round of predicted pings.
+    last_port_prediction: Option<Instant>,
 }
 
 /// Options for creating a new [`EndpointState`].
@@ -153,8 +166,8 @@ pub(super) struct Options {
     /// Is this endpoint currently active (sending data)?
     pub(super) active: bool,
     pub(super) source: super::Source,
-    #[cfg(any(test, feature = "test-utils"))]
     pub(super) path_selection: PathSelection,
+    pub(super) port_prediction: Option<PortPrediction>,
 }
 
 impl EndpointState {
@@ -187,13 +200,17 @@ impl EndpointState {
                 )
             }),
             udp_paths: EndpointUdpPaths::new(),
+            custom_paths: BTreeMap::new(),
             sent_pings: HashMap::new(),
             last_used: options.active.then(Instant::now),
             last_call_me_maybe: None,
             conn_type: Watchable::new(ConnectionType::None),
+            path_info: Watchable::new(PathInfo::default()),
             has_been_direct: AtomicBool::new(false),
-            #[cfg(any(test, feature = "test-utils"))]
             path_selection: options.path_selection,
+            holepunch_log: Default::default(),
+            port_prediction: options.port_prediction,
+            last_port_prediction: None,
         }
     }
 
@@ -213,6 +230,41 @@ impl EndpointState {
         self.conn_type.watch()
     }
 
+    pub(super) fn path_info(&self) -> n0_watcher::Direct<PathInfo> {
+        self.path_info.watch()
+    }
+
+    /// Returns the recorded holepunching activity towards this endpoint.
+    pub(super) fn holepunch_trace(&self, now: Instant) -> HolepunchTrace {
+        self.holepunch_log
+            .lock()
+            .expect("poisoned")
+            .trace(self.endpoint_id, now)
+    }
+
+    fn record_holepunch_event(&self, kind: HolepunchEventKind) {
+        self.holepunch_log.lock().expect("poisoned").record(kind);
+    }
+
+    /// Updates the [`PathInfo`] from the current connection type and latency.
+    fn update_path_info(&self) {
+        let conn_type = self.conn_type.get();
+        let latency = self.latency();
+        let prev = self.path_info.get();
+        let since = if prev.conn_type == conn_type {
+            prev.since
+        } else {
+            Instant::now()
+        };
+        self.path_info
+            .set(PathInfo {
+                conn_type,
+                latency,
+                since,
+            })
+            .ok();
+    }
+
     pub(super) fn latency(&self) -> Option<Duration> {
         match self.conn_type.get() {
             ConnectionType::Direct(addr) => self
@@ -225,6 +277,10 @@ impl EndpointState {
                 .as_ref()
                 .filter(|(relay_url, _)| relay_url == url)
                 .and_then(|(_, state)| state.latency()),
+            ConnectionType::Custom(ref addr) => self
+                .custom_paths
+                .get(addr)
+                .and_then(|state| state.latency()),
             ConnectionType::Mixed(addr, ref url) => {
                 let addr_latency = self
                     .udp_paths
@@ -254,6 +310,7 @@ impl EndpointState {
             .map(|(addr, path_state)| DirectAddrInfo {
                 addr: SocketAddr::from(*addr),
                 latency: path_state.validity.latency(),
+                recent_latencies: path_state.validity.latency_history(),
                 last_control: path_state.last_control_msg(now),
                 last_payload: path_state
                     .last_payload_msg
@@ -274,59 +331,123 @@ impl EndpointState {
             endpoint_id: self.endpoint_id,
             relay_url: self.relay_url.clone().map(|r| r.into()),
             addrs,
+            custom_addrs: self.custom_paths.keys().cloned().collect(),
             conn_type,
             latency,
             last_used: self.last_used.map(|instant| now.duration_since(instant)),
         }
     }
 
+    /// Sets the [`PathSelection`] used for all traffic to this endpoint.
+    pub(super) fn set_path_selection(&mut self, path_selection: PathSelection) {
+        if self.path_selection != path_selection {
+            debug!(?path_selection, "path selection changed");
+            self.path_selection = path_selection;
+        }
+    }
+
     /// Returns the relay url of this endpoint
     pub(super) fn relay_url(&self) -> Option<RelayUrl> {
         self.relay_url.as_ref().map(|(url, _state)| url.clone())
     }
 
+    /// Returns the best path over a custom transport, and whether it is valid.
+    ///
+    /// Prefers the lowest latency valid path, then the lowest latency outdated path.
+    fn custom_send_addr(&self, now: Instant) -> Option<(CustomAddr, bool)> {
+        self.custom_paths
+            .iter()
+            .max_by_key(|(_addr, path)| {
+                (
+                    path.validity.latency_if_valid(now).map(Reverse),
+                    path.validity.latency_if_outdated(now).map(Reverse),
+                )
+            })
+            .map(|(addr, path)| (addr.clone(), path.validity.is_valid(now)))
+    }
+
     /// Returns the address(es) that should be used for sending the next packet.
     ///
-    /// This may return to send on one, both or no paths.
+    /// This may return to send on any combination of paths, or on no path at all.
     fn addr_for_send(
         &self,
         have_ipv6: bool,
         metrics: &MagicsockMetrics,
-    ) -> (Option<SocketAddr>, Option<RelayUrl>) {
-        #[cfg(any(test, feature = "test-utils"))]
-        if self.path_selection == PathSelection::RelayOnly {
-            debug!(
-                "in `RelayOnly` mode, giving the relay address as the only viable address for this endpoint"
-            );
-            return (None, self.relay_url());
-        }
-        let (best_addr, relay_url) = match self.udp_paths.send_addr(have_ipv6) {
-            UdpSendAddr::Valid(addr) => {
-                // If we have a valid address we use it.
-                trace!(%addr, ?have_ipv6, "UdpSendAddr is valid, use it");
-                (Some(*addr), None)
-            }
-            UdpSendAddr::Outdated(addr) => {
-                // If the address is outdated we use it, but send via relay at the same time.
-                // We also send disco pings so that it will become valid again if it still
-                // works (i.e. we don't need to holepunch again).
-                trace!(%addr, ?have_ipv6, "UdpSendAddr is outdated, use it together with relay");
-                (Some(*addr), self.relay_url())
-            }
-            UdpSendAddr::Unconfirmed(addr) => {
-                trace!(%addr, ?have_ipv6, "UdpSendAddr is unconfirmed, use it together with relay");
-                (Some(*addr), self.relay_url())
-            }
-            UdpSendAddr::None => {
-                trace!(?have_ipv6, "No UdpSendAddr, use relay");
+        events: &EventSender,
+    ) -> (Option<SocketAddr>, Option<RelayUrl>, Option<CustomAddr>) {
+        let (mut best_addr, mut relay_url) = match self.path_selection {
+            PathSelection::RelayOnly => {
+                debug!(
+                    "in `RelayOnly` mode, giving the relay address as the only viable address for this endpoint"
+                );
                 (None, self.relay_url())
             }
+            PathSelection::DirectOnly => match self.udp_paths.send_addr(have_ipv6) {
+                UdpSendAddr::Valid(addr)
+                | UdpSendAddr::Outdated(addr)
+                | UdpSendAddr::Unconfirmed(addr) => {
+                    trace!(%addr, ?have_ipv6, "in `DirectOnly` mode, use UdpSendAddr");
+                    (Some(*addr), None)
+                }
+                UdpSendAddr::None => {
+                    debug!("in `DirectOnly` mode, no viable address for this endpoint");
+                    (None, None)
+                }
+            },
+            PathSelection::All => match self.udp_paths.send_addr(have_ipv6) {
+                UdpSendAddr::Valid(addr) => {
+                    // If we have a valid address we use it.
+                    trace!(%addr, ?have_ipv6, "UdpSendAddr is valid, use it");
+                    (Some(*addr), None)
+                }
+                UdpSendAddr::Outdated(addr) => {
+                    // If the address is outdated we use it, but send via relay at the same time.
+                    // We also send disco pings so that it will become valid again if it still
+                    // works (i.e. we don't need to holepunch again).
+                    trace!(%addr, ?have_ipv6, "UdpSendAddr is outdated, use it together with relay");
+                    (Some(*addr), self.relay_url())
+                }
+                UdpSendAddr::Unconfirmed(addr) => {
+                    trace!(%addr, ?have_ipv6, "UdpSendAddr is unconfirmed, use it together with relay");
+                    (Some(*addr), self.relay_url())
+                }
+                UdpSendAddr::None => {
+                    trace!(?have_ipv6, "No UdpSendAddr, use relay");
+                    (None, self.relay_url())
+                }
+            },
+        };
+        let custom_addr = match self.path_selection {
+            PathSelection::RelayOnly => None,
+            PathSelection::DirectOnly | PathSelection::All => {
+                if matches!(self.udp_paths.send_addr(have_ipv6), UdpSendAddr::Valid(_)) {
+                    // A valid UDP path is preferred over any custom path.
+                    None
+                } else {
+                    match self.custom_send_addr(Instant::now()) {
+                        Some((addr, true)) => {
+                            trace!(%addr, "custom path is valid, use it");
+                            best_addr = None;
+                            relay_url = None;
+                            Some(addr)
+                        }
+                        Some((addr, false)) => {
+                            trace!(%addr, "custom path is not confirmed, use it together with others");
+                            Some(addr)
+                        }
+                        None => None,
+                    }
+                }
+            }
         };
         let typ = match (best_addr, relay_url.clone()) {
             (Some(best_addr), Some(relay_url)) => ConnectionType::Mixed(best_addr, relay_url),
             (Some(best_addr), None) => ConnectionType::Direct(best_addr),
             (None, Some(relay_url)) => ConnectionType::Relay(relay_url),
-            (None, None) => ConnectionType::None,
+            (None, None) => match custom_addr {
+                Some(ref addr) => ConnectionType::Custom(addr.clone()),
+                None => ConnectionType::None,
+            },
         };
         if matches!(&typ, ConnectionType::Direct(_)) {
             let before = self
@@ -345,6 +466,16 @@ impl EndpointState {
                 conn_type = ?typ,
             );
             info!(%typ, "new connection type");
+            self.update_path_info();
+            self.record_holepunch_event(HolepunchEventKind::PathChanged {
+                previous: prev_typ.clone(),
+                current: typ.clone(),
+            });
+            events.send(EndpointEvent::PathChanged {
+                remote_id: self.endpoint_id,
+                previous: prev_typ.clone(),
+                current: typ.clone(),
+            });
 
             // Update some metrics
             match (prev_typ, typ) {
@@ -375,7 +506,7 @@ impl EndpointState {
                 _ => (),
             }
         }
-        (best_addr, relay_url)
+        (best_addr, relay_url, custom_addr)
     }
 
     /// Removes a direct address for this endpoint.
@@ -454,6 +585,11 @@ impl EndpointState {
     ) {
         if let Some(sp) = self.sent_pings.remove(&txid) {
             debug!(tx = %HEXLOWER.encode(&txid), addr = %sp.to, "pong not received in timeout");
+            if sp.purpose != DiscoPingPurpose::PortPrediction {
+                self.record_holepunch_event(HolepunchEventKind::PingTimeout {
+                    dst: sp.to.clone().into(),
+                });
+            }
             match sp.to {
                 SendAddr::Udp(addr) => {
                     if let Some(path_state) =
@@ -485,20 +621,32 @@ impl EndpointState {
                         }
                     }
                 }
+                SendAddr::Custom(ref addr) => {
+                    if let Some(path_state) = self.custom_paths.get_mut(addr) {
+                        path_state.last_ping = None;
+                        let consider_alive = path_state
+                            .last_alive()
+                            .map(|last_alive| last_alive.elapsed() <= PING_TIMEOUT_DURATION)
+                            .unwrap_or(false);
+                        if !consider_alive {
+                            path_state.validity = PathValidity::empty();
+                            metrics.path_ping_failures.inc();
+                        }
+                    }
+                }
             }
         }
     }
 
     #[must_use = "pings must be handled"]
     fn start_ping(&self, dst: SendAddr, purpose: DiscoPingPurpose) -> Option<SendPing> {
-        #[cfg(any(test, feature = "test-utils"))]
         if self.path_selection == PathSelection::RelayOnly && !dst.is_relay() {
             // don't attempt any hole punching in relay only mode
             warn!("in `RelayOnly` mode, ignoring request to start a hole punching attempt.");
             return None;
         }
         #[cfg(wasm_browser)]
-        if !dst.is_relay() {
+        if matches!(dst, SendAddr::Udp(_)) {
             return None; // Similar to `RelayOnly` mode, we don't send UDP pings for hole-punching.
         }
 
@@ -529,6 +677,7 @@ impl EndpointState {
         tx_id: TransactionId,
         purpose: DiscoPingPurpose,
         sender: mpsc::Sender<ActorMessage>,
+        metrics: &MagicsockMetrics,
     ) {
         trace!(%to, tx = %HEXLOWER.encode(&tx_id), ?purpose, "record ping sent");
 
@@ -550,11 +699,27 @@ impl EndpointState {
                     }
                 }
             }
+            SendAddr::Custom(ref addr) => {
+                if let Some(st) = self.custom_paths.get_mut(addr) {
+                    st.last_ping.replace(now);
+                    st.validity.record_ping_sent();
+                    path_found = true
+                }
+            }
         }
-        if !path_found {
+        if purpose == DiscoPingPurpose::PortPrediction {
+            // Predicted addresses only become paths once they answer.
+            metrics.port_prediction_pings.inc();
+        } else if !path_found {
             // Shouldn't happen. But don't ping an endpoint that's not active for us.
             warn!(%to, ?purpose, "unexpected attempt to ping no longer live path");
             return;
+        } else if purpose != DiscoPingPurpose::StayinAlive {
+            // Heartbeats would quickly push the holepunching out of the trace.
+            self.record_holepunch_event(HolepunchEventKind::PingSent {
+                dst: to.clone().into(),
+                purpose,
+            });
         }
 
         let id = self.id;
@@ -608,8 +773,14 @@ impl EndpointState {
         // accepts the connection.
         let mut msgs = self.send_pings(now);
 
-        if let Some(url) = self.relay_url() {
+        if self.path_selection == PathSelection::RelayOnly {
+            // call-me-maybe messages would share our direct addresses
+            debug!("in `RelayOnly` mode, not sending call-me-maybe");
+        } else if let Some(url) = self.relay_url() {
             debug!(%url, "queue call-me-maybe");
+            self.record_holepunch_event(HolepunchEventKind::CallMeMaybeSent {
+                relay_url: url.clone(),
+            });
             msgs.push(PingAction::SendCallMeMaybe {
                 relay_url: url,
                 dst_endpoint: self.endpoint_id,
@@ -644,7 +815,6 @@ impl EndpointState {
             }
         }
 
-        #[cfg(any(test, feature = "test-utils"))]
         if self.path_selection == PathSelection::RelayOnly {
             warn!("in `RelayOnly` mode, ignoring request to respond to a hole punching attempt.");
             return ping_msgs;
@@ -655,10 +825,15 @@ impl EndpointState {
         self.udp_paths
             .paths()
             .iter()
-            .filter_map(|(ipp, state)| state.needs_ping(&now).then_some(*ipp))
-            .filter_map(|ipp| {
-                self.start_ping(SendAddr::Udp(ipp.into()), DiscoPingPurpose::Discovery)
-            })
+            .filter(|(_ipp, state)| state.needs_ping(&now))
+            .map(|(ipp, _state)| SendAddr::Udp((*ipp).into()))
+            .chain(
+                self.custom_paths
+                    .iter()
+                    .filter(|(_addr, state)| state.needs_ping(&now))
+                    .map(|(addr, _state)| SendAddr::Custom(addr.clone())),
+            )
+            .filter_map(|dst| self.start_ping(dst, DiscoPingPurpose::Discovery))
             .for_each(|msg| {
                 use std::fmt::Write;
                 write!(&mut ping_dsts, " {} ", msg.dst).ok();
@@ -671,14 +846,66 @@ impl EndpointState {
             paths = %summarize_endpoint_paths(self.udp_paths.paths()),
             "sending pings to endpoint",
         );
+        let predicted = self.port_prediction_round(now);
+        ping_msgs.extend(
+            predicted
+                .into_iter()
+                .filter_map(|addr| {
+                    self.start_ping(SendAddr::Udp(addr), DiscoPingPurpose::PortPrediction)
+                })
+                .map(PingAction::SendPing),
+        );
         self.last_full_ping.replace(now);
         ping_msgs
     }
 
+    /// Returns the addresses to ping in a round of port prediction, if one is due.
+    ///
+    /// A round is due if [`PortPrediction`] is enabled, there is no direct path yet although
+    /// the previous full ping was long enough ago to have found one, and the previous round
+    /// was more than [`PortPrediction::interval`] ago.
+    fn port_prediction_round(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let Some(config) = self.port_prediction else {
+            return Vec::new();
+        };
+        if !matches!(
+            self.udp_paths.send_addr(true),
+            UdpSendAddr::None | UdpSendAddr::Unconfirmed(_) | UdpSendAddr::Outdated(_)
+        ) {
+            return Vec::new();
+        }
+        let regular_failed = self
+            .last_full_ping
+            .is_some_and(|last| now.duration_since(last) >= PING_TIMEOUT_DURATION);
+        let recent_round = self
+            .last_port_prediction
+            .is_some_and(|last| now.duration_since(last) < config.interval());
+        if !regular_failed || recent_round {
+            return Vec::new();
+        }
+
+        let known = self
+            .udp_paths
+            .paths()
+            .keys()
+            .map(|ipp| SocketAddr::from(*ipp))
+            .collect();
+        let addrs = predict_addrs(&known, &config, &mut rand::rng());
+        if !addrs.is_empty() {
+            debug!(pings = addrs.len(), "starting port prediction round");
+            self.last_port_prediction = Some(now);
+            self.record_holepunch_event(HolepunchEventKind::PortPredictionStarted {
+                pings: addrs.len(),
+            });
+        }
+        addrs
+    }
+
     pub(super) fn update_from_endpoint_addr(
         &mut self,
         new_relay_url: Option<&RelayUrl>,
         new_addrs: impl Iterator<Item = SocketAddr>,
+        new_custom_addrs: impl Iterator<Item = CustomAddr>,
         source: super::Source,
         have_ipv6: bool,
         metrics: &MagicsockMetrics,
@@ -730,6 +957,23 @@ impl EndpointState {
         drop(access);
         let paths = summarize_endpoint_paths(self.udp_paths.paths());
         debug!(new = ?new_addrs_list , %paths, "added new direct paths for endpoint");
+
+        for addr in new_custom_addrs {
+            self.custom_paths
+                .entry(addr.clone())
+                .and_modify(|path_state| {
+                    path_state.add_source(source.clone(), now);
+                })
+                .or_insert_with(|| {
+                    debug!(%addr, "added new custom path for endpoint");
+                    PathState::new(
+                        self.endpoint_id,
+                        SendAddr::Custom(addr),
+                        source.clone(),
+                        now,
+                    )
+                });
+        }
     }
 
     /// Handle a received Disco Ping.
@@ -743,6 +987,16 @@ impl EndpointState {
     /// from `self` via ep.
     pub(super) fn handle_ping(&mut self, path: SendAddr, tx_id: TransactionId) -> PingHandled {
         let now = Instant::now();
+        // Pings on the validated best path are the heartbeats of the remote endpoint.
+        let is_heartbeat = matches!(
+            (self.udp_paths.send_addr(true), &path),
+            (UdpSendAddr::Valid(best), SendAddr::Udp(addr)) if best == addr
+        );
+        if !is_heartbeat {
+            self.record_holepunch_event(HolepunchEventKind::PingReceived {
+                src: path.clone().into(),
+            });
+        }
 
         let role = match path {
             SendAddr::Udp(addr) => {
@@ -796,6 +1050,20 @@ impl EndpointState {
                     }
                 }
             }
+            SendAddr::Custom(ref addr) => match self.custom_paths.entry(addr.clone()) {
+                Entry::Occupied(mut occupied) => occupied.get_mut().handle_ping(tx_id, now),
+                Entry::Vacant(vacant) => {
+                    info!(%addr, "new custom addr for endpoint");
+                    vacant.insert(PathState::with_ping(
+                        self.endpoint_id,
+                        path.clone(),
+                        tx_id,
+                        Source::Custom,
+                        now,
+                    ));
+                    PingRole::NewPath
+                }
+            },
         };
         event!(
             target: "iroh::_events::ping::recv",
@@ -811,11 +1079,15 @@ impl EndpointState {
         }
 
         // if the endpoint does not yet have a best_addr
-        let needs_ping_back = if matches!(path, SendAddr::Udp(_))
-            && matches!(
+        let needs_ping_back = match path {
+            SendAddr::Udp(_) => matches!(
                 self.udp_paths.send_addr(true),
                 UdpSendAddr::None | UdpSendAddr::Unconfirmed(_) | UdpSendAddr::Outdated(_)
-            ) {
+            ),
+            SendAddr::Custom(_) => !matches!(self.custom_send_addr(now), Some((_, true))),
+            SendAddr::Relay(_) => false,
+        };
+        let needs_ping_back = if needs_ping_back {
             // We also need to send a ping to make this path available to us as well.  This
             // is always sent together with a pong.  So in the worst case the pong gets lost
             // and this ping does not.  In that case we ping-pong until both sides have
@@ -932,6 +1204,30 @@ impl EndpointState {
                     latency = %latency.as_millis(),
                     "received pong",
                 );
+                if sp.purpose != DiscoPingPurpose::StayinAlive {
+                    self.record_holepunch_event(HolepunchEventKind::PongReceived {
+                        src: src.clone().into(),
+                        ping_dst: sp.to.clone().into(),
+                        observed_addr: m.ping_observed_addr.clone().into(),
+                        latency,
+                    });
+                }
+
+                if let (SendAddr::Udp(addr), DiscoPingPurpose::PortPrediction) = (&src, sp.purpose)
+                {
+                    if let Entry::Vacant(entry) =
+                        self.udp_paths.access_mut(now).paths().entry((*addr).into())
+                    {
+                        info!(%addr, "new direct addr for endpoint from port prediction");
+                        metrics.port_prediction_successes.inc();
+                        entry.insert(PathState::new(
+                            self.endpoint_id,
+                            src.clone(),
+                            Source::Udp,
+                            now,
+                        ));
+                    }
+                }
 
                 match src {
                     SendAddr::Udp(addr) => {
@@ -983,6 +1279,23 @@ impl EndpointState {
                             );
                         }
                     },
+                    SendAddr::Custom(ref addr) => match self.custom_paths.get_mut(addr) {
+                        None => {
+                            warn!("ignoring pong: no state for src addr");
+                            return endpoint_map_insert;
+                        }
+                        Some(st) => {
+                            st.add_pong_reply(
+                                PongReply {
+                                    latency,
+                                    pong_at: now,
+                                    from: src,
+                                    pong_src: m.ping_observed_addr.clone(),
+                                },
+                                metrics,
+                            );
+                        }
+                    },
                 }
 
                 // Promote this pong response to our current best address if it's lower latency.
@@ -990,6 +1303,7 @@ impl EndpointState {
                 if let SendAddr::Udp(_to) = sp.to {
                     debug_assert!(!is_relay, "mismatching relay & udp");
                 }
+                self.update_path_info();
 
                 endpoint_map_insert
             }
@@ -1013,6 +1327,9 @@ impl EndpointState {
     ) -> Vec<PingAction> {
         let now = Instant::now();
         let mut call_me_maybe_ipps = BTreeSet::new();
+        self.record_holepunch_event(HolepunchEventKind::CallMeMaybeReceived {
+            addrs: m.my_numbers.clone(),
+        });
 
         let mut guard = self.udp_paths.access_mut(now);
 
@@ -1106,6 +1423,19 @@ impl EndpointState {
         self.last_used = Some(now);
     }
 
+    /// Marks this endpoint as having received a payload message on a custom transport.
+    pub(super) fn receive_custom(&mut self, addr: &CustomAddr, now: Instant) {
+        let Some(state) = self.custom_paths.get_mut(addr) else {
+            debug_assert!(
+                false,
+                "endpoint map inconsistency by_custom_addr <-> custom addr"
+            );
+            return;
+        };
+        state.receive_payload(now);
+        self.last_used = Some(now);
+    }
+
     pub(super) fn last_ping(&self, addr: &SendAddr) -> Option<Instant> {
         match addr {
             SendAddr::Udp(addr) => self
@@ -1118,6 +1448,7 @@ impl EndpointState {
                 .as_ref()
                 .filter(|(home_url, _state)| home_url == url)
                 .and_then(|(_home_url, state)| state.last_ping),
+            SendAddr::Custom(addr) => self.custom_paths.get(addr).and_then(|ep| ep.last_ping),
         }
     }
 
@@ -1140,6 +1471,12 @@ impl EndpointState {
             return Vec::new();
         }
 
+        // The best addr is only re-evaluated when the paths change, which does not happen
+        // when a path silently stops working.  Pick up its expired trust here.
+        if self.udp_paths.access_mut(now).has_best_addr_changed() {
+            debug!(best_addr = ?self.udp_paths.send_addr(have_ipv6), "best addr changed");
+        }
+
         // If we do not have an optimal addr, send pings to all known places.
         if self.want_call_me_maybe(&now, have_ipv6) {
             debug!("sending a call-me-maybe");
@@ -1147,8 +1484,14 @@ impl EndpointState {
         }
 
         // Send heartbeat ping to keep the current addr going as long as we need it.
-        if let Some(udp_addr) = self.udp_paths.send_addr(have_ipv6).get_addr() {
-            let elapsed = self.last_ping(&SendAddr::Udp(udp_addr)).map(|l| now - l);
+        let heartbeat_addr = match self.udp_paths.send_addr(have_ipv6).get_addr() {
+            Some(udp_addr) => Some(SendAddr::Udp(udp_addr)),
+            None => self
+                .custom_send_addr(now)
+                .map(|(addr, _valid)| SendAddr::Custom(addr)),
+        };
+        if let Some(send_addr) = heartbeat_addr {
+            let elapsed = self.last_ping(&send_addr).map(|l| now - l);
             // Send a ping if the last ping is older than 2 seconds.
             let needs_ping = match elapsed {
                 Some(e) => e >= STAYIN_ALIVE_MIN_ELAPSED,
@@ -1157,13 +1500,11 @@ impl EndpointState {
 
             if needs_ping {
                 debug!(
-                    dst = %udp_addr,
+                    dst = %send_addr,
                     since_last_ping=?elapsed,
                     "send stayin alive ping",
                 );
-                if let Some(msg) =
-                    self.start_ping(SendAddr::Udp(udp_addr), DiscoPingPurpose::StayinAlive)
-                {
+                if let Some(msg) = self.start_ping(send_addr, DiscoPingPurpose::StayinAlive) {
                     return vec![PingAction::SendPing(msg)];
                 }
             }
@@ -1182,14 +1523,20 @@ impl EndpointState {
         &mut self,
         have_ipv6: bool,
         metrics: &MagicsockMetrics,
-    ) -> (Option<SocketAddr>, Option<RelayUrl>, Vec<PingAction>) {
+        events: &EventSender,
+    ) -> (
+        Option<SocketAddr>,
+        Option<RelayUrl>,
+        Option<CustomAddr>,
+        Vec<PingAction>,
+    ) {
         let now = Instant::now();
         let prev = self.last_used.replace(now);
         if prev.is_none() {
             // this is the first time we are trying to connect to this endpoint
             metrics.endpoints_contacted.inc();
         }
-        let (udp_addr, relay_url) = self.addr_for_send(have_ipv6, metrics);
+        let (udp_addr, relay_url, custom_addr) = self.addr_for_send(have_ipv6, metrics, events);
 
         let ping_msgs = if self.want_call_me_maybe(&now, have_ipv6) {
             self.send_call_me_maybe(now, SendCallMeMaybe::IfNoRecent)
@@ -1199,10 +1546,11 @@ impl EndpointState {
         trace!(
             ?udp_addr,
             ?relay_url,
+            ?custom_addr,
             pings = %ping_msgs.len(),
             "found send address",
         );
-        (udp_addr, relay_url, ping_msgs)
+        (udp_addr, relay_url, custom_addr, ping_msgs)
     }
 
     /// Get the IP addresses for this endpoint.
@@ -1210,6 +1558,11 @@ impl EndpointState {
         self.udp_paths.paths().keys().copied()
     }
 
+    /// Get the custom transport addresses for this endpoint.
+    pub(super) fn custom_addrs(&self) -> impl Iterator<Item = &CustomAddr> + '_ {
+        self.custom_paths.keys()
+    }
+
     #[cfg(test)]
     pub(super) fn ip_addr_states(&self) -> impl Iterator<Item = (&IpPort, &PathState)> + '_ {
         self.udp_paths.paths().iter()
@@ -1231,6 +1584,7 @@ impl From<RemoteInfo> for EndpointAddr {
         if let Some(url) = info.relay_url {
             addrs.insert(TransportAddr::Relay(url.into()));
         }
+        addrs.extend(info.custom_addrs.into_iter().map(TransportAddr::Custom));
 
         EndpointAddr {
             id: info.endpoint_id,
@@ -1264,13 +1618,59 @@ pub(super) struct PongReply {
 pub(super) struct SentPing {
     pub(super) to: SendAddr,
     pub(super) at: Instant,
-    #[allow(dead_code)]
     pub(super) purpose: DiscoPingPurpose,
     pub(super) _expiry_task: AbortOnDropHandle<()>,
 }
 
+/// Predicts addresses which a NAT with address-and-port-dependent mapping may have
+/// allocated for the remote endpoint.
+///
+/// For each public IPv4 address in `known` these are the ports within
+/// [`PortPrediction::range`] of its known ports, nearest first, followed by
+/// [`PortPrediction::random_ports`] random ports.  Known addresses are skipped and at most
+/// [`PortPrediction::MAX_PINGS`] addresses are returned.
+fn predict_addrs(
+    known: &BTreeSet<SocketAddr>,
+    config: &PortPrediction,
+    rng: &mut impl rand::Rng,
+) -> Vec<SocketAddr> {
+    let mut ports_by_ip: BTreeMap<Ipv4Addr, BTreeSet<u16>> = BTreeMap::new();
+    for addr in known {
+        if let SocketAddr::V4(addr) = addr {
+            let ip = *addr.ip();
+            if !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()) {
+                ports_by_ip.entry(ip).or_default().insert(addr.port());
+            }
+        }
+    }
+
+    let mut addrs = Vec::new();
+    let mut seen = BTreeSet::new();
+    let mut push = |addr: SocketAddr| {
+        if addrs.len() < PortPrediction::MAX_PINGS && !known.contains(&addr) && seen.insert(addr) {
+            addrs.push(addr);
+        }
+    };
+    for (ip, ports) in &ports_by_ip {
+        for delta in 1..=config.range() {
+            for port in ports {
+                for port in [port.checked_add(delta), port.checked_sub(delta)] {
+                    if let Some(port) = port.filter(|port| *port != 0) {
+                        push(SocketAddr::from((*ip, port)));
+                    }
+                }
+            }
+        }
+        for _ in 0..config.random_ports() {
+            push(SocketAddr::from((*ip, rng.random_range(1024..=u16::MAX))));
+        }
+    }
+    addrs
+}
+
 /// The reason why a discovery ping message was sent.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum DiscoPingPurpose {
     /// The purpose of a ping was to see if a path was valid.
     Discovery,
@@ -1281,6 +1681,10 @@ pub enum DiscoPingPurpose {
     /// When a ping was received we suspect a direct connection is possible.  If we do not
     /// yet have one that triggers a ping, indicated with this reason.
     PingBack,
+    /// A ping to a predicted address of the endpoint, see [`PortPrediction`].
+    ///
+    /// [`PortPrediction`]: crate::endpoint::PortPrediction
+    PortPrediction,
 }
 
 /// The type of control message we have received.
@@ -1310,6 +1714,11 @@ pub struct DirectAddrInfo {
     ///
     /// If there has never been any connectivity via this address no latency will be known.
     pub latency: Option<Duration>,
+    /// The latencies measured by the most recent pongs received on this network path.
+    ///
+    /// Only a small number of recent measurements are kept, ordered from oldest to newest.
+    /// The last entry is the same as [`DirectAddrInfo::latency`].
+    pub recent_latencies: Vec<Duration>,
     /// Last control message received by this endpoint about this address.
     ///
     /// This contains the elapsed duration since the control message was received and the
@@ -1377,12 +1786,16 @@ impl From<RelayUrlInfo> for RelayUrl {
 ///
 /// Having details of an endpoint does not mean it can be connected to, nor that it has ever been
 /// connected to in the past. There are various reasons an endpoint might be known: it could have
-/// been manually added via [`Endpoint::add_endpoint_addr`], it could have been added by some
-/// discovery mechanism, the endpoint could have contacted this endpoint, etc.
+/// been passed to [`Endpoint::connect`], it could have been added by some discovery
+/// mechanism, the endpoint could have contacted this endpoint, etc.
+///
+/// This is returned by [`Endpoint::remote_info`] and [`Endpoint::remote_infos`].
 ///
-/// [`Endpoint::add_endpoint_addr`]: crate::endpoint::Endpoint::add_endpoint_addr
+/// [`Endpoint::connect`]: crate::endpoint::Endpoint::connect
+/// [`Endpoint::remote_info`]: crate::endpoint::Endpoint::remote_info
+/// [`Endpoint::remote_infos`]: crate::endpoint::Endpoint::remote_infos
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
-pub(crate) struct RemoteInfo {
+pub struct RemoteInfo {
     /// The globally unique identifier for this endpoint.
     pub endpoint_id: EndpointId,
     /// Relay server information, if available.
@@ -1392,6 +1805,8 @@ pub(crate) struct RemoteInfo {
     /// Some of these addresses might only be valid for networks we are not part of, but the remote
     /// endpoint might be a part of.
     pub addrs: Vec<DirectAddrInfo>,
+    /// The custom transport addresses at which this endpoint might be reachable.
+    pub custom_addrs: Vec<CustomAddr>,
     /// The type of connection we have to the endpoint, either direct or over relay.
     pub conn_type: ConnectionType,
     /// The latency of the current network path to the remote endpoint.
@@ -1407,7 +1822,7 @@ pub(crate) struct RemoteInfo {
 impl RemoteInfo {
     /// Get the duration since the last activity we received from this endpoint
     /// on any of its direct addresses.
-    pub(crate) fn last_received(&self) -> Option<Duration> {
+    pub fn last_received(&self) -> Option<Duration> {
         self.addrs
             .iter()
             .filter_map(|addr| addr.last_control.map(|x| x.0).min(addr.last_payload))
@@ -1418,13 +1833,54 @@ impl RemoteInfo {
     ///
     /// Note that this does not provide any guarantees of whether any network path is
     /// usable.
-    pub(crate) fn has_send_address(&self) -> bool {
-        self.relay_url.is_some() || !self.addrs.is_empty()
+    pub fn has_send_address(&self) -> bool {
+        self.relay_url.is_some() || !self.addrs.is_empty() || !self.custom_addrs.is_empty()
+    }
+}
+
+/// The network path currently used to send data to a remote endpoint.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct PathInfo {
+    /// The path, holding the relay URL and/or the socket address used.
+    pub conn_type: ConnectionType,
+    /// The latency of the path, if known.
+    pub latency: Option<Duration>,
+    /// When this path started being used.
+    pub since: Instant,
+}
+
+impl Default for PathInfo {
+    fn default() -> Self {
+        Self {
+            conn_type: ConnectionType::None,
+            latency: None,
+            since: Instant::now(),
+        }
+    }
+}
+
+impl PathInfo {
+    /// Whether data is sent directly to the remote endpoint, without a relay server.
+    pub fn is_direct(&self) -> bool {
+        matches!(
+            self.conn_type,
+            ConnectionType::Direct(_) | ConnectionType::Custom(_)
+        )
+    }
+
+    /// Whether data is sent via a relay server, possibly in addition to a direct path.
+    pub fn is_relay(&self) -> bool {
+        matches!(
+            self.conn_type,
+            ConnectionType::Relay(_) | ConnectionType::Mixed(_, _)
+        )
     }
 }
 
 /// The type of connection we have to the endpoint.
 #[derive(derive_more::Display, Default, Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum ConnectionType {
     /// Direct UDP connection
     #[display("direct({_0})")]
@@ -1438,6 +1894,9 @@ pub enum ConnectionType {
     /// the address works.
     #[display("mixed(udp: {_0}, relay: {_1})")]
     Mixed(SocketAddr, RelayUrl),
+    /// Connection over a custom transport
+    #[display("custom({_0})")]
+    Custom(CustomAddr),
     /// We have no verified connection to this PublicKey
     #[default]
     #[display("none")]
@@ -1509,13 +1968,17 @@ mod tests {
                         endpoint_state,
                         UdpSendAddr::Valid(ip_port.into()),
                     ),
+                    custom_paths: BTreeMap::new(),
                     sent_pings: HashMap::new(),
                     last_used: Some(now),
                     last_call_me_maybe: None,
                     conn_type: Watchable::new(ConnectionType::Direct(ip_port.into())),
+                    path_info: Watchable::new(PathInfo::default()),
                     has_been_direct: AtomicBool::new(true),
-                    #[cfg(any(test, feature = "test-utils"))]
                     path_selection: PathSelection::default(),
+                    holepunch_log: Default::default(),
+                    port_prediction: None,
+                    last_port_prediction: None,
                 },
                 ip_port.into(),
             )
@@ -1531,13 +1994,17 @@ mod tests {
                 last_full_ping: None,
                 relay_url: relay_and_state(key.public(), send_addr.clone()),
                 udp_paths: EndpointUdpPaths::new(),
+                custom_paths: BTreeMap::new(),
                 sent_pings: HashMap::new(),
                 last_used: Some(now),
                 last_call_me_maybe: None,
                 conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
+                path_info: Watchable::new(PathInfo::default()),
                 has_been_direct: AtomicBool::new(false),
-                #[cfg(any(test, feature = "test-utils"))]
                 path_selection: PathSelection::default(),
+                holepunch_log: Default::default(),
+                port_prediction: None,
+                last_port_prediction: None,
             }
         };
 
@@ -1560,13 +2027,17 @@ mod tests {
                     ),
                 )),
                 udp_paths: EndpointUdpPaths::new(),
+                custom_paths: BTreeMap::new(),
                 sent_pings: HashMap::new(),
                 last_used: Some(now),
                 last_call_me_maybe: None,
                 conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
+                path_info: Watchable::new(PathInfo::default()),
                 has_been_direct: AtomicBool::new(false),
-                #[cfg(any(test, feature = "test-utils"))]
                 path_selection: PathSelection::default(),
+                holepunch_log: Default::default(),
+                port_prediction: None,
+                last_port_prediction: None,
             }
         };
 
@@ -1598,6 +2069,7 @@ mod tests {
                         endpoint_state,
                         UdpSendAddr::Outdated(socket_addr),
                     ),
+                    custom_paths: BTreeMap::new(),
                     sent_pings: HashMap::new(),
                     last_used: Some(now),
                     last_call_me_maybe: None,
@@ -1605,9 +2077,12 @@ mod tests {
                         socket_addr,
                         send_addr.clone(),
                     )),
+                    path_info: Watchable::new(PathInfo::default()),
                     has_been_direct: AtomicBool::new(false),
-                    #[cfg(any(test, feature = "test-utils"))]
                     path_selection: PathSelection::default(),
+                    holepunch_log: Default::default(),
+                    port_prediction: None,
+                    last_port_prediction: None,
                 },
                 socket_addr,
             )
@@ -1620,11 +2095,13 @@ mod tests {
                 addrs: Vec::from([DirectAddrInfo {
                     addr: a_socket_addr,
                     latency: Some(latency),
+                    recent_latencies: vec![latency],
                     last_control: Some((elapsed, ControlMsg::Pong)),
                     last_payload: None,
                     last_alive: Some(elapsed),
                     sources: HashMap::new(),
                 }]),
+                custom_addrs: Vec::new(),
                 conn_type: ConnectionType::Direct(a_socket_addr),
                 latency: Some(latency),
                 last_used: Some(elapsed),
@@ -1637,6 +2114,7 @@ mod tests {
                     latency: Some(latency),
                 }),
                 addrs: Vec::new(),
+                custom_addrs: Vec::new(),
                 conn_type: ConnectionType::Relay(send_addr.clone()),
                 latency: Some(latency),
                 last_used: Some(elapsed),
@@ -1649,6 +2127,7 @@ mod tests {
                     latency: None,
                 }),
                 addrs: Vec::new(),
+                custom_addrs: Vec::new(),
                 conn_type: ConnectionType::Relay(send_addr.clone()),
                 latency: None,
                 last_used: Some(elapsed),
@@ -1663,11 +2142,13 @@ mod tests {
                 addrs: Vec::from([DirectAddrInfo {
                     addr: d_socket_addr,
                     latency: Some(latency),
+                    recent_latencies: vec![latency],
                     last_control: Some((elapsed, ControlMsg::Pong)),
                     last_payload: None,
                     last_alive: Some(elapsed),
                     sources: HashMap::new(),
                 }]),
+                custom_addrs: Vec::new(),
                 conn_type: ConnectionType::Mixed(d_socket_addr, send_addr.clone()),
                 latency: Some(Duration::from_millis(50)),
                 last_used: Some(elapsed),
@@ -1685,6 +2166,7 @@ mod tests {
                 (a_socket_addr.into(), a_endpoint.id),
                 (d_socket_addr.into(), d_endpoint.id),
             ]),
+            by_custom_addr: HashMap::new(),
             by_quic_mapped_addr: HashMap::from([
                 (a_endpoint.quic_mapped_addr, a_endpoint.id),
                 (b_endpoint.quic_mapped_addr, b_endpoint.id),
@@ -1699,6 +2181,8 @@ mod tests {
             ]),
             next_id: 5,
             path_selection: PathSelection::default(),
+            path_selection_overrides: HashMap::new(),
+            port_prediction: None,
         });
         let mut got = endpoint_map.list_remote_infos(later);
         got.sort_by_key(|p| p.endpoint_id);
@@ -1709,8 +2193,8 @@ mod tests {
 
     fn remove_non_deterministic_fields(infos: &mut [RemoteInfo]) {
         for info in infos.iter_mut() {
-            if info.relay_url.is_some() {
-                info.relay_url.as_mut().unwrap().last_alive = None;
+            if let Some(relay_url) = info.relay_url.as_mut() {
+                relay_url.last_alive = None;
             }
         }
     }
@@ -1730,6 +2214,7 @@ mod tests {
                 name: "test".into(),
             },
             path_selection: PathSelection::default(),
+            port_prediction: None,
         };
         let mut ep = EndpointState::new(0, opts);
 
@@ -1746,4 +2231,162 @@ mod tests {
         // number of pings as direct addresses in the call-me-maybe.
         assert_eq!(ping_messages.len(), my_numbers_count as usize);
     }
+
+    #[test]
+    fn test_predict_addrs() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0u64);
+        let known = BTreeSet::from([
+            SocketAddr::from(([203, 0, 113, 5], 1000)),
+            SocketAddr::from(([203, 0, 113, 5], 1001)),
+            SocketAddr::from(([192, 168, 1, 2], 5000)),
+        ]);
+        let config = PortPrediction::default().with_range(2).with_random_ports(3);
+
+        let addrs = predict_addrs(&known, &config, &mut rng);
+        let ports: Vec<_> = addrs.iter().take(4).map(|addr| addr.port()).collect();
+        assert_eq!(ports, [999, 1002, 998, 1003]);
+        assert_eq!(addrs.len(), 7);
+        assert!(
+            addrs
+                .iter()
+                .all(|addr| addr.ip() == Ipv4Addr::new(203, 0, 113, 5))
+        );
+
+        let config = PortPrediction::default().with_range(u16::MAX);
+        let addrs = predict_addrs(&known, &config, &mut rng);
+        assert_eq!(addrs.len(), PortPrediction::MAX_PINGS);
+    }
+
+    #[tokio::test]
+    async fn test_port_prediction_round() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0u64);
+        let key = SecretKey::generate(&mut rng);
+        let opts = Options {
+            endpoint_id: key.public(),
+            relay_url: None,
+            active: true,
+            source: crate::magicsock::Source::NamedApp {
+                name: "test".into(),
+            },
+            path_selection: PathSelection::default(),
+            port_prediction: Some(PortPrediction::default().with_range(4).with_random_ports(0)),
+        };
+        let mut ep = EndpointState::new(0, opts);
+        let metrics = MagicsockMetrics::default();
+        let known = SocketAddr::from(([203, 0, 113, 5], 1000));
+        let pings = ep.handle_call_me_maybe(
+            disco::CallMeMaybe {
+                my_numbers: vec![known],
+            },
+            &metrics,
+        );
+        assert_eq!(
+            pings.len(),
+            1,
+            "no prediction before regular holepunching failed"
+        );
+
+        // Pretend the regular pings were not answered.
+        let now = Instant::now();
+        ep.last_full_ping = Some(now - PING_TIMEOUT_DURATION);
+        ep.udp_paths
+            .access_mut(now)
+            .paths()
+            .values_mut()
+            .for_each(|path| {
+                path.last_ping = None;
+            });
+        let predicted: Vec<_> = ep
+            .send_pings(now)
+            .into_iter()
+            .filter_map(|action| match action {
+                PingAction::SendPing(ping) if ping.purpose == DiscoPingPurpose::PortPrediction => {
+                    Some(ping)
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(predicted.len(), 8);
+        assert!(
+            ep.send_pings(now + PING_TIMEOUT_DURATION)
+                .iter()
+                .all(|action| !matches!(
+                    action,
+                    PingAction::SendPing(ping) if ping.purpose == DiscoPingPurpose::PortPrediction
+                )),
+            "rounds are rate limited"
+        );
+
+        // A pong from a predicted address makes it a path.
+        let (sender, _receiver) = mpsc::channel(8);
+        let ping = &predicted[0];
+        ep.ping_sent(ping.dst.clone(), ping.tx_id, ping.purpose, sender, &metrics);
+        let pong = disco::Pong {
+            tx_id: ping.tx_id,
+            ping_observed_addr: SendAddr::Udp(SocketAddr::from(([198, 51, 100, 1], 2000))),
+        };
+        let inserted = ep.handle_pong(&pong, ping.dst.clone(), &metrics);
+        let SendAddr::Udp(predicted_addr) = ping.dst else {
+            panic!("predicted addresses are UDP");
+        };
+        assert_eq!(inserted, Some((predicted_addr, key.public())));
+        assert!(ep.udp_paths.paths().contains_key(&predicted_addr.into()));
+        assert_eq!(metrics.port_prediction_pings.get(), 1);
+        assert_eq!(metrics.port_prediction_successes.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_not_traced() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0u64);
+        let key = SecretKey::generate(&mut rng);
+        let opts = Options {
+            endpoint_id: key.public(),
+            relay_url: None,
+            active: true,
+            source: crate::magicsock::Source::NamedApp {
+                name: "test".into(),
+            },
+            path_selection: PathSelection::default(),
+            port_prediction: None,
+        };
+        let mut ep = EndpointState::new(0, opts);
+        let metrics = MagicsockMetrics::default();
+        let addr = SocketAddr::from(([203, 0, 113, 5], 1000));
+        ep.handle_call_me_maybe(
+            disco::CallMeMaybe {
+                my_numbers: vec![addr],
+            },
+            &metrics,
+        );
+        let (sender, _receiver) = mpsc::channel(8);
+        let ping_pong = |ep: &mut EndpointState, purpose| {
+            let tx_id = TransactionId::default();
+            ep.ping_sent(
+                SendAddr::Udp(addr),
+                tx_id,
+                purpose,
+                sender.clone(),
+                &metrics,
+            );
+            let pong = disco::Pong {
+                tx_id,
+                ping_observed_addr: SendAddr::Udp(SocketAddr::from(([198, 51, 100, 1], 2000))),
+            };
+            ep.handle_pong(&pong, SendAddr::Udp(addr), &metrics);
+        };
+        let trace_len = |ep: &EndpointState| ep.holepunch_trace(Instant::now()).events.len();
+
+        // The call-me-maybe and the discovery ping and pong are recorded.
+        ping_pong(&mut ep, DiscoPingPurpose::Discovery);
+        assert_eq!(trace_len(&ep), 3);
+        assert!(matches!(
+            ep.udp_paths.send_addr(true),
+            UdpSendAddr::Valid(best) if *best == addr
+        ));
+
+        // Heartbeats in both directions on the established path are not.
+        ping_pong(&mut ep, DiscoPingPurpose::StayinAlive);
+        ep.handle_ping(SendAddr::Udp(addr), TransactionId::default());
+        assert_eq!(trace_len(&ep), 3);
+    }
 }
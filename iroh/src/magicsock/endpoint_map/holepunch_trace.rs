@@ -0,0 +1,175 @@
+//! A bounded history of the holepunching activity towards a single remote endpoint.
+//!
+//! The [`EndpointState`] records every DISCO message it sends or handles, together with
+//! the changes of the path used to send to the remote endpoint.  The history is exposed as
+//! a [`HolepunchTrace`] by [`Endpoint::holepunch_trace`], to understand why an endpoint
+//! stays on the relay.
+//!
+//! [`EndpointState`]: super::endpoint_state::EndpointState
+//! [`Endpoint::holepunch_trace`]: crate::Endpoint::holepunch_trace
+
+use std::{collections::VecDeque, net::SocketAddr};
+
+use iroh_base::{EndpointId, RelayUrl, TransportAddr};
+use n0_future::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+use super::endpoint_state::{ConnectionType, DiscoPingPurpose};
+
+/// The number of events kept per remote endpoint, older events are dropped.
+const MAX_EVENTS: usize = 256;
+
+/// The holepunching history of a remote endpoint, as returned by
+/// [`Endpoint::holepunch_trace`].
+///
+/// Serializes to JSON with `serde_json`, which makes it easy to attach to bug reports.
+///
+/// [`Endpoint::holepunch_trace`]: crate::Endpoint::holepunch_trace
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HolepunchTrace {
+    /// The remote endpoint.
+    pub endpoint_id: EndpointId,
+    /// The recorded events, ordered from oldest to newest.
+    pub events: Vec<HolepunchEvent>,
+    /// The number of older events which were dropped to bound the history.
+    pub dropped: u64,
+}
+
+/// A single event in a [`HolepunchTrace`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HolepunchEvent {
+    /// Elapsed time since the event, at the time the trace was taken.
+    pub elapsed: Duration,
+    /// What happened.
+    pub kind: HolepunchEventKind,
+}
+
+/// The kinds of events in a [`HolepunchTrace`].
+///
+/// The heartbeat pings keeping an established direct path alive, and their pongs, are not
+/// recorded.  Only their timeouts are, as they explain why a direct path is abandoned.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum HolepunchEventKind {
+    /// A call-me-maybe was queued for sending to the remote endpoint via its relay.
+    CallMeMaybeSent {
+        /// The relay server the call-me-maybe is sent through.
+        relay_url: RelayUrl,
+    },
+    /// A call-me-maybe was received from the remote endpoint.
+    CallMeMaybeReceived {
+        /// The direct addresses the remote endpoint asked us to ping.
+        addrs: Vec<SocketAddr>,
+    },
+    /// A ping was sent to one of the candidate addresses of the remote endpoint.
+    PingSent {
+        /// The address the ping was sent to.
+        dst: TransportAddr,
+        /// Why the ping was sent.
+        purpose: DiscoPingPurpose,
+    },
+    /// A ping was received from the remote endpoint.
+    PingReceived {
+        /// The address the ping was received from.
+        src: TransportAddr,
+    },
+    /// A pong was received in reply to one of our pings.
+    PongReceived {
+        /// The address the pong was received from.
+        src: TransportAddr,
+        /// The address the ping was sent to.
+        ping_dst: TransportAddr,
+        /// Our address as observed by the remote endpoint when it received the ping.
+        observed_addr: TransportAddr,
+        /// The round trip time of the ping.
+        latency: Duration,
+    },
+    /// A round of pings to predicted addresses of the remote endpoint was started.
+    ///
+    /// The individual pings of the round are not recorded, see [`PortPrediction`].
+    ///
+    /// [`PortPrediction`]: crate::endpoint::PortPrediction
+    PortPredictionStarted {
+        /// The number of predicted addresses pinged.
+        pings: usize,
+    },
+    /// No pong was received for a ping in time.
+    PingTimeout {
+        /// The address the ping was sent to.
+        dst: TransportAddr,
+    },
+    /// The path used to send to the remote endpoint changed.
+    ///
+    /// This includes changes of the best direct address, which is part of the
+    /// [`ConnectionType`].
+    PathChanged {
+        /// The path used before.
+        previous: ConnectionType,
+        /// The path used now.
+        current: ConnectionType,
+    },
+}
+
+/// The bounded list of events recorded by the [`EndpointState`].
+///
+/// [`EndpointState`]: super::endpoint_state::EndpointState
+#[derive(Debug, Default)]
+pub(super) struct HolepunchLog {
+    events: VecDeque<(Instant, HolepunchEventKind)>,
+    dropped: u64,
+}
+
+impl HolepunchLog {
+    pub(super) fn record(&mut self, kind: HolepunchEventKind) {
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+        self.events.push_back((Instant::now(), kind));
+    }
+
+    pub(super) fn trace(&self, endpoint_id: EndpointId, now: Instant) -> HolepunchTrace {
+        HolepunchTrace {
+            endpoint_id,
+            events: self
+                .events
+                .iter()
+                .map(|(at, kind)| HolepunchEvent {
+                    elapsed: now.saturating_duration_since(*at),
+                    kind: kind.clone(),
+                })
+                .collect(),
+            dropped: self.dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn test_holepunch_log_bounded() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0u64);
+        let endpoint_id = SecretKey::generate(&mut rng).public();
+        let mut log = HolepunchLog::default();
+        for port in 0..MAX_EVENTS as u16 + 10 {
+            log.record(HolepunchEventKind::PingTimeout {
+                dst: TransportAddr::Ip(SocketAddr::from(([192, 0, 2, 1], port))),
+            });
+        }
+
+        let trace = log.trace(endpoint_id, Instant::now());
+        assert_eq!(trace.events.len(), MAX_EVENTS);
+        assert_eq!(trace.dropped, 10);
+        assert_eq!(
+            trace.events[0].kind,
+            HolepunchEventKind::PingTimeout {
+                dst: TransportAddr::Ip(SocketAddr::from(([192, 0, 2, 1], 10))),
+            }
+        );
+    }
+}
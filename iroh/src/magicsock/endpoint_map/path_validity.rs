@@ -54,6 +54,12 @@ impl CongestionMetrics {
         self.pings_sent = self.pings_sent.saturating_add(1);
     }
 
+    /// Returns the recorded latency samples, oldest first.
+    fn latency_history(&self) -> Vec<Duration> {
+        let (newer, older) = self.latency_samples.split_at(self.sample_index);
+        older.iter().chain(newer).filter_map(|&s| s).collect()
+    }
+
     /// Calculate packet loss rate (0.0 to 1.0).
     fn packet_loss_rate(&self) -> f64 {
         if self.pings_sent == 0 {
@@ -233,6 +239,14 @@ impl PathValidity {
         }
     }
 
+    /// Returns the latencies of the most recent pongs on this path, oldest first.
+    pub(super) fn latency_history(&self) -> Vec<Duration> {
+        self.0
+            .as_ref()
+            .map(|state| state.congestion_metrics.latency_history())
+            .unwrap_or_default()
+    }
+
     /// Get the path quality score (0.0 = worst, 1.0 = best).
     #[cfg(test)]
     pub(super) fn quality_score(&self) -> f64 {
@@ -395,4 +409,20 @@ mod tests {
         let quality = validity.quality_score();
         assert!(quality < 0.9); // Should be penalized
     }
+
+    #[tokio::test]
+    async fn test_latency_history_order() {
+        let mut validity = PathValidity::new(Instant::now(), Duration::from_millis(1));
+        assert_eq!(validity.latency_history(), vec![Duration::from_millis(1)]);
+
+        // Wrap around the sample buffer, the oldest samples are overwritten.
+        for i in 2..=10 {
+            validity.update_pong(Instant::now(), Duration::from_millis(i));
+        }
+        let history = validity.latency_history();
+        let expected: Vec<_> = (3..=10).map(Duration::from_millis).collect();
+        assert_eq!(history, expected);
+
+        assert!(PathValidity::empty().latency_history().is_empty());
+    }
 }
@@ -15,13 +15,28 @@ pub struct Metrics {
     pub send_ipv6: Counter,
     pub send_relay: Counter,
     pub send_relay_error: Counter,
+    /// Number of bytes sent on custom transports.
+    pub send_custom: Counter,
 
     // Data packets (non-disco)
     pub send_data: Counter,
     pub send_data_network_down: Counter,
+    /// Number of bytes of data delayed or dropped by bandwidth limits when sending.
+    pub send_data_throttled: Counter,
+    /// Number of bytes of data dropped by bandwidth limits when receiving.
+    pub recv_data_throttled: Counter,
+    /// Number of application datagrams sent while the send buffer was full.
+    ///
+    /// This approximates the datagrams dropped by `DatagramDropPolicy::DropOldest`: each
+    /// of these sends may have dropped any number of older datagrams, including none.
+    pub send_datagrams_buffer_full: Counter,
+    /// Number of application datagrams discarded because the send buffer was full.
+    pub send_datagrams_rejected: Counter,
     pub recv_data_relay: Counter,
     pub recv_data_ipv4: Counter,
     pub recv_data_ipv6: Counter,
+    /// Number of bytes of data received on custom transports.
+    pub recv_data_custom: Counter,
     /// Number of QUIC datagrams received.
     pub recv_datagrams: Counter,
     /// Number of datagrams received using GRO
@@ -77,6 +92,10 @@ pub struct Metrics {
     pub connection_handshake_success: Counter,
     /// Number of connections with a successful handshake that became direct.
     pub connection_became_direct: Counter,
+    /// Number of pings sent to predicted addresses of remote endpoints.
+    pub port_prediction_pings: Counter,
+    /// Number of direct paths found by pinging predicted addresses.
+    pub port_prediction_successes: Counter,
 
     /*
      * Path Congestion Metrics
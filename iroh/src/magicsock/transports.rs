@@ -6,21 +6,29 @@ use std::{
     task::{Context, Poll},
 };
 
-use iroh_base::{EndpointId, RelayUrl};
+use iroh_base::{CustomAddr, EndpointId, RelayUrl};
+use n0_future::time;
 use n0_watcher::Watcher;
 use relay::{RelayNetworkChangeSender, RelaySender};
 use smallvec::SmallVec;
 use tracing::{error, trace, warn};
 
+mod custom;
 #[cfg(not(wasm_browser))]
 mod ip;
+mod rate_limit;
 mod relay;
 
+use self::custom::CustomSender;
 #[cfg(not(wasm_browser))]
 pub(crate) use self::ip::IpTransport;
 #[cfg(not(wasm_browser))]
 use self::ip::{IpNetworkChangeSender, IpSender};
-pub(crate) use self::relay::{RelayActorConfig, RelayTransport};
+pub(crate) use self::{
+    custom::CustomTransport,
+    rate_limit::{Direction, RateLimiter},
+    relay::{RelayActorConfig, RelayTransport},
+};
 use super::MagicSock;
 use crate::net_report::Report;
 
@@ -31,10 +39,13 @@ pub(crate) struct Transports {
     #[cfg(not(wasm_browser))]
     ip: Vec<IpTransport>,
     relay: Vec<RelayTransport>,
+    custom: Vec<CustomTransport>,
 
     poll_recv_counter: AtomicUsize,
 }
 
+type CustomAddrsWatch = n0_watcher::Join<Vec<CustomAddr>, n0_watcher::Direct<Vec<CustomAddr>>>;
+
 #[cfg(not(wasm_browser))]
 pub(crate) type LocalAddrsWatch = n0_watcher::Map<
     (
@@ -43,16 +54,20 @@ pub(crate) type LocalAddrsWatch = n0_watcher::Map<
             Option<(RelayUrl, EndpointId)>,
             n0_watcher::Map<n0_watcher::Direct<Option<RelayUrl>>, Option<(RelayUrl, EndpointId)>>,
         >,
+        CustomAddrsWatch,
     ),
     Vec<Addr>,
 >;
 
 #[cfg(wasm_browser)]
 pub(crate) type LocalAddrsWatch = n0_watcher::Map<
-    n0_watcher::Join<
-        Option<(RelayUrl, EndpointId)>,
-        n0_watcher::Map<n0_watcher::Direct<Option<RelayUrl>>, Option<(RelayUrl, EndpointId)>>,
-    >,
+    (
+        n0_watcher::Join<
+            Option<(RelayUrl, EndpointId)>,
+            n0_watcher::Map<n0_watcher::Direct<Option<RelayUrl>>, Option<(RelayUrl, EndpointId)>>,
+        >,
+        CustomAddrsWatch,
+    ),
     Vec<Addr>,
 >;
 
@@ -61,11 +76,13 @@ impl Transports {
     pub(crate) fn new(
         #[cfg(not(wasm_browser))] ip: Vec<IpTransport>,
         relay: Vec<RelayTransport>,
+        custom: Vec<CustomTransport>,
     ) -> Self {
         Self {
             #[cfg(not(wasm_browser))]
             ip,
             relay,
+            custom,
             poll_recv_counter: Default::default(),
         }
     }
@@ -127,7 +144,13 @@ impl Transports {
             for transport in &mut self.relay {
                 poll_transport!(transport);
             }
+            for transport in &mut self.custom {
+                poll_transport!(transport);
+            }
         } else {
+            for transport in self.custom.iter_mut().rev() {
+                poll_transport!(transport);
+            }
             for transport in self.relay.iter_mut().rev() {
                 poll_transport!(transport);
             }
@@ -143,7 +166,8 @@ impl Transports {
     /// Returns a list of all currently known local addresses.
     ///
     /// For IP based transports this is the [`SocketAddr`] of the socket,
-    /// for relay transports, this is the home relay.
+    /// for relay transports, this is the home relay, and custom transports
+    /// report their own addresses.
     pub(crate) fn local_addrs(&self) -> Vec<Addr> {
         self.local_addrs_watch().get()
     }
@@ -153,8 +177,9 @@ impl Transports {
     pub(crate) fn local_addrs_watch(&self) -> LocalAddrsWatch {
         let ips = n0_watcher::Join::new(self.ip.iter().map(|t| t.local_addr_watch()));
         let relays = n0_watcher::Join::new(self.relay.iter().map(|t| t.local_addr_watch()));
+        let custom = self.custom_addrs_watch();
 
-        (ips, relays).map(|(ips, relays)| {
+        (ips, relays, custom).map(|(ips, relays, custom)| {
             ips.into_iter()
                 .map(Addr::from)
                 .chain(
@@ -163,15 +188,27 @@ impl Transports {
                         .flatten()
                         .map(|(relay_url, endpoint_id)| Addr::Relay(relay_url, endpoint_id)),
                 )
+                .chain(custom.into_iter().flatten().map(Addr::Custom))
                 .collect()
         })
     }
 
     #[cfg(wasm_browser)]
     pub(crate) fn local_addrs_watch(&self) -> LocalAddrsWatch {
-        let relays = self.relay.iter().map(|t| t.local_addr_watch());
-        n0_watcher::Join::new(relays)
-            .map(|relays| relays.into_iter().flatten().map(Addr::from).collect())
+        let relays = n0_watcher::Join::new(self.relay.iter().map(|t| t.local_addr_watch()));
+        let custom = self.custom_addrs_watch();
+        (relays, custom).map(|(relays, custom)| {
+            relays
+                .into_iter()
+                .flatten()
+                .map(Addr::from)
+                .chain(custom.into_iter().flatten().map(Addr::Custom))
+                .collect()
+        })
+    }
+
+    fn custom_addrs_watch(&self) -> CustomAddrsWatch {
+        n0_watcher::Join::new(self.custom.iter().map(|t| t.local_addr_watch()))
     }
 
     /// Returns the bound addresses for IP based transports
@@ -223,6 +260,7 @@ impl Transports {
         #[cfg(not(wasm_browser))]
         let ip = self.ip.iter().map(|t| t.create_sender()).collect();
         let relay = self.relay.iter().map(|t| t.create_sender()).collect();
+        let custom = self.custom.iter().map(|t| t.create_sender()).collect();
         let max_transmit_segments = self.max_transmit_segments();
 
         UdpSender {
@@ -230,7 +268,9 @@ impl Transports {
             ip,
             msock,
             relay,
+            custom,
             max_transmit_segments,
+            throttled: None,
         }
     }
 
@@ -291,6 +331,18 @@ impl NetworkChangeSender {
         }
         res
     }
+
+    /// Hands all datagrams queued for relay servers to the relay connections.
+    pub(crate) fn flush_relay_datagrams(&self) -> impl Future<Output = ()> + use<> {
+        let flushed: Vec<_> = self
+            .relay
+            .iter()
+            .map(|transport| transport.flush_datagrams())
+            .collect();
+        async move {
+            n0_future::join_all(flushed).await;
+        }
+    }
 }
 
 /// An outgoing packet
@@ -305,6 +357,7 @@ pub(crate) struct Transmit<'a> {
 pub(crate) enum Addr {
     Ip(SocketAddr),
     Relay(RelayUrl, EndpointId),
+    Custom(CustomAddr),
 }
 
 impl Default for Addr {
@@ -339,7 +392,7 @@ impl Addr {
     pub(crate) fn into_socket_addr(self) -> Option<SocketAddr> {
         match self {
             Self::Ip(ip) => Some(ip),
-            Self::Relay(..) => None,
+            Self::Relay(..) | Self::Custom(_) => None,
         }
     }
 }
@@ -350,7 +403,35 @@ pub(crate) struct UdpSender {
     #[cfg(not(wasm_browser))]
     ip: Vec<IpSender>,
     relay: Vec<RelaySender>,
+    custom: Vec<CustomSender>,
     max_transmit_segments: usize,
+    /// The transmit currently delayed by the bandwidth limits.
+    throttled: Option<Throttled>,
+}
+
+/// A transmit delayed by the bandwidth limits.
+///
+/// QUIC polls the same transmit again until it is sent.  The prepared send is kept so
+/// that polling a delayed transmit does not prepare it again, which would update the path
+/// state and metrics every time.
+#[derive(Debug)]
+struct Throttled {
+    /// Wakes up once the bandwidth limits might allow the transmit.
+    sleep: Pin<Box<time::Sleep>>,
+    /// The destination of the transmit.
+    destination: SocketAddr,
+    /// The length of the transmit.
+    len: usize,
+    /// The remote endpoint, as returned by [`MagicSock::prepare_send`].
+    remote: Option<EndpointId>,
+    /// The paths to send on, as returned by [`MagicSock::prepare_send`].
+    paths: SmallVec<[Addr; 3]>,
+}
+
+impl Throttled {
+    fn is_for(&self, transmit: &quinn_udp::Transmit) -> bool {
+        self.destination == transmit.destination && self.len == transmit.contents.len()
+    }
 }
 
 impl UdpSender {
@@ -397,6 +478,21 @@ impl UdpSender {
                     }
                 }
             }
+            Addr::Custom(addr) => {
+                for sender in &self.custom {
+                    if sender.is_valid_send_addr(addr) {
+                        any_match = true;
+                        match sender.send(addr, transmit).await {
+                            Ok(()) => {
+                                return Ok(());
+                            }
+                            Err(err) => {
+                                warn!("custom transport failed to send: {:?}", err);
+                            }
+                        }
+                    }
+                }
+            }
         }
         if any_match {
             Err(io::Error::other("all available transports failed"))
@@ -440,6 +536,90 @@ impl UdpSender {
                     }
                 }
             }
+            Addr::Custom(addr) => {
+                for sender in &self.custom {
+                    if sender.is_valid_send_addr(addr) {
+                        match sender.poll_send(cx, addr, transmit) {
+                            Poll::Pending => {}
+                            Poll::Ready(res) => return Poll::Ready(res),
+                        }
+                    }
+                }
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Applies the bandwidth limits to a transmit about to be sent on `paths`.
+    ///
+    /// Returns the paths which may be used now.  If the limits allow none of them, returns
+    /// [`Poll::Pending`] and wakes up once the limits allow the transmit again, remembering
+    /// the prepared send in [`UdpSender::throttled`].  Paths which are throttled while others
+    /// are not are dropped, leaving it to QUIC to recover.
+    fn poll_rate_limit(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        remote: Option<EndpointId>,
+        mut paths: SmallVec<[Addr; 3]>,
+        transmit: &quinn_udp::Transmit,
+    ) -> Poll<SmallVec<[Addr; 3]>> {
+        let limiter = &self.msock.rate_limiter;
+        if !limiter.is_enabled() || paths.is_empty() {
+            return Poll::Ready(paths);
+        }
+
+        let len = transmit.contents.len();
+        let prepared = paths.clone();
+        let mut wait = None;
+        let mut throttled = 0;
+        paths.retain(
+            |path| match limiter.try_acquire(Direction::Send, path, remote, len) {
+                Ok(()) => true,
+                Err(duration) => {
+                    trace!(dst = ?path, len, ?duration, "transmit throttled");
+                    wait = wait.max(Some(duration));
+                    throttled += 1;
+                    false
+                }
+            },
+        );
+
+        let Some(wait) = wait.filter(|_| paths.is_empty()) else {
+            if throttled > 0 {
+                self.msock
+                    .metrics
+                    .magicsock
+                    .send_data_throttled
+                    .inc_by((throttled * len) as _);
+            }
+            self.throttled = None;
+            return Poll::Ready(paths);
+        };
+
+        // Only count the transmit once, no matter how often it is polled while delayed.
+        let deadline = time::Instant::now() + wait;
+        match self.throttled {
+            Some(ref mut throttled) if throttled.is_for(transmit) => {
+                throttled.sleep.as_mut().reset(deadline)
+            }
+            _ => {
+                self.msock
+                    .metrics
+                    .magicsock
+                    .send_data_throttled
+                    .inc_by(len as _);
+                self.throttled = Some(Throttled {
+                    sleep: Box::pin(time::sleep_until(deadline)),
+                    destination: transmit.destination,
+                    len,
+                    remote,
+                    paths: prepared,
+                });
+            }
+        }
+        let throttled = self.throttled.as_mut().expect("just set");
+        if throttled.sleep.as_mut().poll(cx).is_ready() {
+            cx.waker().wake_by_ref();
         }
         Poll::Pending
     }
@@ -481,6 +661,18 @@ impl UdpSender {
                     }
                 }
             }
+            Addr::Custom(addr) => {
+                for transport in &self.custom {
+                    if transport.is_valid_send_addr(addr) {
+                        match transport.try_send(addr, transmit) {
+                            Ok(()) => return Ok(()),
+                            Err(_err) => {
+                                continue;
+                            }
+                        }
+                    }
+                }
+            }
         }
         Err(io::Error::new(
             io::ErrorKind::WouldBlock,
@@ -495,7 +687,22 @@ impl quinn::UdpSender for UdpSender {
         transmit: &quinn_udp::Transmit,
         cx: &mut Context,
     ) -> Poll<io::Result<()>> {
-        let active_paths = self.msock.prepare_send(&self, transmit)?;
+        let prepared = self
+            .throttled
+            .as_ref()
+            .filter(|throttled| throttled.is_for(transmit))
+            .map(|throttled| (throttled.remote, throttled.paths.clone()));
+        let (remote, active_paths) = match prepared {
+            Some(prepared) => prepared,
+            None => self.msock.prepare_send(&self, transmit)?,
+        };
+        let active_paths = match self
+            .as_mut()
+            .poll_rate_limit(cx, remote, active_paths, transmit)
+        {
+            Poll::Ready(active_paths) => active_paths,
+            Poll::Pending => return Poll::Pending,
+        };
 
         if active_paths.is_empty() {
             // Returning Ok here means we let QUIC timeout.
@@ -547,7 +754,32 @@ impl quinn::UdpSender for UdpSender {
     }
 
     fn try_send(self: Pin<&mut Self>, transmit: &quinn_udp::Transmit) -> io::Result<()> {
-        let active_paths = self.msock.prepare_send(&self, transmit)?;
+        let (remote, mut active_paths) = self.msock.prepare_send(&self, transmit)?;
+        if self.msock.rate_limiter.is_enabled() && !active_paths.is_empty() {
+            let len = transmit.contents.len();
+            active_paths.retain(|path| {
+                let allowed = self
+                    .msock
+                    .rate_limiter
+                    .try_acquire(Direction::Send, path, remote, len)
+                    .is_ok();
+                if !allowed {
+                    trace!(dst = ?path, len, "transmit throttled");
+                    self.msock
+                        .metrics
+                        .magicsock
+                        .send_data_throttled
+                        .inc_by(len as _);
+                }
+                allowed
+            });
+            if active_paths.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "bandwidth limit reached",
+                ));
+            }
+        }
         if active_paths.is_empty() {
             // Returning Ok here means we let QUIC timeout.
             // Returning an error would immediately fail a connection.
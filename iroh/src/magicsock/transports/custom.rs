@@ -0,0 +1,149 @@
+//! Adapts the user provided [`Transport`]s to the magicsock.
+
+use std::{
+    io,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+use iroh_base::CustomAddr;
+use tracing::{trace, warn};
+
+use super::{Addr, Transmit};
+use crate::{endpoint::transport::Transport, magicsock::Metrics as MagicsockMetrics};
+
+#[derive(Debug)]
+pub(crate) struct CustomTransport {
+    transport: Arc<dyn Transport>,
+    metrics: Arc<MagicsockMetrics>,
+}
+
+impl CustomTransport {
+    pub(crate) fn new(transport: Arc<dyn Transport>, metrics: Arc<MagicsockMetrics>) -> Self {
+        Self { transport, metrics }
+    }
+
+    pub(super) fn poll_recv(
+        &mut self,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        metas: &mut [quinn_udp::RecvMeta],
+        source_addrs: &mut [Addr],
+    ) -> Poll<io::Result<usize>> {
+        let mut num_msgs = 0;
+        for ((buf_out, meta_out), addr) in bufs
+            .iter_mut()
+            .zip(metas.iter_mut())
+            .zip(source_addrs.iter_mut())
+        {
+            let (len, src) = match self.transport.poll_recv(cx, buf_out) {
+                Poll::Ready(Ok(recv)) => recv,
+                Poll::Ready(Err(err)) => {
+                    if num_msgs > 0 {
+                        // Report the datagrams received so far, the error will come up again.
+                        break;
+                    }
+                    return Poll::Ready(Err(err));
+                }
+                Poll::Pending => break,
+            };
+            trace!(%src, len, "custom recv");
+
+            meta_out.len = len;
+            meta_out.stride = len;
+            meta_out.ecn = None;
+            meta_out.dst_ip = None;
+
+            *addr = Addr::Custom(src);
+            num_msgs += 1;
+        }
+
+        if num_msgs > 0 {
+            Poll::Ready(Ok(num_msgs))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    pub(super) fn local_addr_watch(&self) -> n0_watcher::Direct<Vec<CustomAddr>> {
+        self.transport.local_addrs()
+    }
+
+    pub(super) fn create_sender(&self) -> CustomSender {
+        CustomSender {
+            transport: self.transport.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CustomSender {
+    transport: Arc<dyn Transport>,
+    metrics: Arc<MagicsockMetrics>,
+}
+
+impl CustomSender {
+    pub(super) fn is_valid_send_addr(&self, dst: &CustomAddr) -> bool {
+        dst.id() == self.transport.id()
+    }
+
+    pub(super) async fn send(&self, dst: &CustomAddr, transmit: &Transmit<'_>) -> io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_send(cx, dst, transmit)).await
+    }
+
+    /// Sends the datagrams of `transmit` one by one.
+    ///
+    /// Backpressure only applies to the first datagram: once it was sent, the remaining
+    /// datagrams the transport is not ready for are dropped, as the transmit can not be
+    /// retried partially.
+    pub(super) fn poll_send(
+        &self,
+        cx: &mut Context,
+        dst: &CustomAddr,
+        transmit: &Transmit<'_>,
+    ) -> Poll<io::Result<()>> {
+        let segment_size = transmit
+            .segment_size
+            .unwrap_or(transmit.contents.len())
+            .max(1);
+        let max_datagram_size = self.transport.max_datagram_size();
+        for (i, datagram) in transmit.contents.chunks(segment_size).enumerate() {
+            if datagram.len() > max_datagram_size {
+                warn!(
+                    %dst,
+                    len = datagram.len(),
+                    max_datagram_size,
+                    "dropping datagram: too large for custom transport"
+                );
+                continue;
+            }
+            match self.transport.poll_send(cx, dst, datagram) {
+                Poll::Ready(Ok(())) => {
+                    self.metrics.send_custom.inc_by(datagram.len() as _);
+                }
+                Poll::Ready(Err(err)) if i == 0 => return Poll::Ready(Err(err)),
+                Poll::Ready(Err(err)) => {
+                    warn!(%dst, "custom transport failed to send: {err:#}");
+                }
+                Poll::Pending if i == 0 => return Poll::Pending,
+                Poll::Pending => {
+                    trace!(%dst, "custom transport busy, dropping remaining datagrams");
+                    break;
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    pub(super) fn try_send(&self, dst: &CustomAddr, transmit: &Transmit<'_>) -> io::Result<()> {
+        let mut cx = Context::from_waker(Waker::noop());
+        match self.poll_send(&mut cx, dst, transmit) {
+            Poll::Ready(res) => res,
+            Poll::Pending => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "custom transport not ready",
+            )),
+        }
+    }
+}
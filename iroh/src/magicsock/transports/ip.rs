@@ -13,15 +13,26 @@ use tracing::trace;
 
 use super::{Addr, Transmit};
 use crate::metrics::MagicsockMetrics;
+#[cfg(any(test, feature = "test-utils"))]
+use crate::test_utils::sim::VirtualHost;
 
 #[derive(Debug)]
 pub(crate) struct IpTransport {
     bind_addr: SocketAddr,
-    socket: Arc<UdpSocket>,
+    socket: IpSocket,
     local_addr: Watchable<SocketAddr>,
     metrics: Arc<MagicsockMetrics>,
 }
 
+/// The socket of an [`IpTransport`].
+#[derive(Debug, Clone)]
+enum IpSocket {
+    Udp(Arc<UdpSocket>),
+    /// A host on a simulated network, see [`crate::test_utils::sim`].
+    #[cfg(any(test, feature = "test-utils"))]
+    Virtual(VirtualHost),
+}
+
 impl IpTransport {
     pub(crate) fn new(
         bind_addr: SocketAddr,
@@ -34,12 +45,26 @@ impl IpTransport {
 
         Self {
             bind_addr,
-            socket,
+            socket: IpSocket::Udp(socket),
             local_addr,
             metrics,
         }
     }
 
+    /// Creates a transport sending and receiving on a simulated host.
+    ///
+    /// The transport is bound to the private address of the host.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub(crate) fn new_virtual(host: VirtualHost, metrics: Arc<MagicsockMetrics>) -> Self {
+        let bind_addr = SocketAddr::V4(host.private_socket_addr());
+        Self {
+            bind_addr,
+            socket: IpSocket::Virtual(host),
+            local_addr: Watchable::new(bind_addr),
+            metrics,
+        }
+    }
+
     /// NOTE: Receiving on a closed socket will return [`Poll::Pending`] indefinitely.
     pub(super) fn poll_recv(
         &mut self,
@@ -48,15 +73,62 @@ impl IpTransport {
         metas: &mut [quinn_udp::RecvMeta],
         source_addrs: &mut [Addr],
     ) -> Poll<io::Result<usize>> {
-        match self.socket.poll_recv_quinn(cx, bufs, metas) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Ok(n)) => {
-                for (addr, el) in source_addrs.iter_mut().zip(metas.iter()).take(n) {
-                    *addr = el.addr.into();
+        match self.socket {
+            IpSocket::Udp(ref socket) => match socket.poll_recv_quinn(cx, bufs, metas) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(Ok(n)) => {
+                    for (addr, el) in source_addrs.iter_mut().zip(metas.iter()).take(n) {
+                        *addr = el.addr.into();
+                    }
+                    Poll::Ready(Ok(n))
                 }
-                Poll::Ready(Ok(n))
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            },
+            #[cfg(any(test, feature = "test-utils"))]
+            IpSocket::Virtual(ref host) => {
+                Self::poll_recv_virtual(host, cx, bufs, metas, source_addrs)
             }
-            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+        }
+    }
+
+    #[cfg(any(test, feature = "test-utils"))]
+    fn poll_recv_virtual(
+        host: &VirtualHost,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        metas: &mut [quinn_udp::RecvMeta],
+        source_addrs: &mut [Addr],
+    ) -> Poll<io::Result<usize>> {
+        let mut num_msgs = 0;
+        for ((buf_out, meta_out), addr) in bufs
+            .iter_mut()
+            .zip(metas.iter_mut())
+            .zip(source_addrs.iter_mut())
+        {
+            let (len, src) = match host.poll_recv_from(cx, buf_out) {
+                Poll::Ready(Ok(recv)) => recv,
+                Poll::Ready(Err(err)) => {
+                    if num_msgs > 0 {
+                        break;
+                    }
+                    return Poll::Ready(Err(err));
+                }
+                Poll::Pending => break,
+            };
+            meta_out.addr = SocketAddr::V4(src);
+            meta_out.len = len;
+            meta_out.stride = len;
+            meta_out.ecn = None;
+            meta_out.dst_ip = None;
+
+            *addr = meta_out.addr.into();
+            num_msgs += 1;
+        }
+
+        if num_msgs > 0 {
+            Poll::Ready(Ok(num_msgs))
+        } else {
+            Poll::Pending
         }
     }
 
@@ -65,15 +137,27 @@ impl IpTransport {
     }
 
     pub(super) fn max_transmit_segments(&self) -> usize {
-        self.socket.max_gso_segments()
+        match self.socket {
+            IpSocket::Udp(ref socket) => socket.max_gso_segments(),
+            #[cfg(any(test, feature = "test-utils"))]
+            IpSocket::Virtual(_) => 1,
+        }
     }
 
     pub(super) fn max_receive_segments(&self) -> usize {
-        self.socket.gro_segments()
+        match self.socket {
+            IpSocket::Udp(ref socket) => socket.gro_segments(),
+            #[cfg(any(test, feature = "test-utils"))]
+            IpSocket::Virtual(_) => 1,
+        }
     }
 
     pub(super) fn may_fragment(&self) -> bool {
-        self.socket.may_fragment()
+        match self.socket {
+            IpSocket::Udp(ref socket) => socket.may_fragment(),
+            #[cfg(any(test, feature = "test-utils"))]
+            IpSocket::Virtual(_) => false,
+        }
     }
 
     pub(crate) fn bind_addr(&self) -> SocketAddr {
@@ -88,7 +172,11 @@ impl IpTransport {
     }
 
     pub(super) fn create_sender(&self) -> IpSender {
-        let sender = self.socket.clone().create_sender();
+        let sender = match self.socket {
+            IpSocket::Udp(ref socket) => Sender::Udp(socket.clone().create_sender()),
+            #[cfg(any(test, feature = "test-utils"))]
+            IpSocket::Virtual(ref host) => Sender::Virtual(host.clone()),
+        };
         IpSender {
             bind_addr: self.bind_addr,
             sender,
@@ -99,17 +187,24 @@ impl IpTransport {
 
 #[derive(Debug)]
 pub(super) struct IpNetworkChangeSender {
-    socket: Arc<UdpSocket>,
+    socket: IpSocket,
     local_addr: Watchable<SocketAddr>,
 }
 
 impl IpNetworkChangeSender {
     pub(super) fn rebind(&self) -> io::Result<()> {
-        let old_addr = self.local_addr.get();
-        self.socket.rebind()?;
-        let addr = self.socket.local_addr()?;
-        self.local_addr.set(addr).ok();
-        trace!("rebound from {} to {}", old_addr, addr);
+        match self.socket {
+            IpSocket::Udp(ref socket) => {
+                let old_addr = self.local_addr.get();
+                socket.rebind()?;
+                let addr = socket.local_addr()?;
+                self.local_addr.set(addr).ok();
+                trace!("rebound from {} to {}", old_addr, addr);
+            }
+            // A simulated host can not lose its address.
+            #[cfg(any(test, feature = "test-utils"))]
+            IpSocket::Virtual(_) => {}
+        }
 
         Ok(())
     }
@@ -124,10 +219,17 @@ impl IpNetworkChangeSender {
 pub(super) struct IpSender {
     bind_addr: SocketAddr,
     #[pin]
-    sender: UdpSender,
+    sender: Sender,
     metrics: Arc<MagicsockMetrics>,
 }
 
+#[derive(Debug)]
+enum Sender {
+    Udp(UdpSender),
+    #[cfg(any(test, feature = "test-utils"))]
+    Virtual(VirtualHost),
+}
+
 impl IpSender {
     pub(super) fn is_valid_send_addr(&self, dst: &SocketAddr) -> bool {
         // Our net-tools crate binds sockets to their specific family.  This means an IPv6
@@ -151,16 +253,24 @@ impl IpSender {
     ) -> io::Result<()> {
         trace!("sending to {}", destination);
         let total_bytes = transmit.contents.len() as u64;
-        let res = self
-            .sender
-            .send(&quinn_udp::Transmit {
-                destination: Self::canonical_addr(destination),
-                ecn: transmit.ecn,
-                contents: transmit.contents,
-                segment_size: transmit.segment_size,
-                src_ip: src,
-            })
-            .await;
+        let res = match self.sender {
+            Sender::Udp(ref sender) => {
+                sender
+                    .send(&quinn_udp::Transmit {
+                        destination: Self::canonical_addr(destination),
+                        ecn: transmit.ecn,
+                        contents: transmit.contents,
+                        segment_size: transmit.segment_size,
+                        src_ip: src,
+                    })
+                    .await
+            }
+            #[cfg(any(test, feature = "test-utils"))]
+            Sender::Virtual(ref host) => {
+                Self::send_virtual(host, destination, transmit);
+                Ok(())
+            }
+        };
         trace!("send res: {:?}", res);
 
         match res {
@@ -189,6 +299,22 @@ impl IpSender {
         SocketAddr::new(addr.ip().to_canonical(), addr.port())
     }
 
+    /// Sends the datagrams of `transmit` on a simulated host, which never blocks.
+    #[cfg(any(test, feature = "test-utils"))]
+    fn send_virtual(host: &VirtualHost, destination: SocketAddr, transmit: &Transmit<'_>) {
+        // The simulated network only has IPv4 addresses.
+        let SocketAddr::V4(dst) = Self::canonical_addr(destination) else {
+            return;
+        };
+        let segment_size = transmit
+            .segment_size
+            .unwrap_or(transmit.contents.len())
+            .max(1);
+        for datagram in transmit.contents.chunks(segment_size) {
+            host.send_to(dst, datagram);
+        }
+    }
+
     pub(super) fn poll_send(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context,
@@ -198,16 +324,23 @@ impl IpSender {
     ) -> Poll<io::Result<()>> {
         trace!("sending to {}", destination);
         let total_bytes = transmit.contents.len() as u64;
-        let res = Pin::new(&mut self.sender).poll_send(
-            &quinn_udp::Transmit {
-                destination: Self::canonical_addr(destination),
-                ecn: transmit.ecn,
-                contents: transmit.contents,
-                segment_size: transmit.segment_size,
-                src_ip: src,
-            },
-            cx,
-        );
+        let res = match self.sender {
+            Sender::Udp(ref mut sender) => Pin::new(sender).poll_send(
+                &quinn_udp::Transmit {
+                    destination: Self::canonical_addr(destination),
+                    ecn: transmit.ecn,
+                    contents: transmit.contents,
+                    segment_size: transmit.segment_size,
+                    src_ip: src,
+                },
+                cx,
+            ),
+            #[cfg(any(test, feature = "test-utils"))]
+            Sender::Virtual(ref host) => {
+                Self::send_virtual(host, destination, transmit);
+                Poll::Ready(Ok(()))
+            }
+        };
         trace!("send res: {:?}", res);
 
         match res {
@@ -235,13 +368,20 @@ impl IpSender {
     ) -> io::Result<()> {
         trace!("sending to {}", destination);
         let total_bytes = transmit.contents.len() as u64;
-        let res = self.sender.try_send(&quinn_udp::Transmit {
-            destination,
-            ecn: transmit.ecn,
-            contents: transmit.contents,
-            segment_size: transmit.segment_size,
-            src_ip: src,
-        });
+        let res = match self.sender {
+            Sender::Udp(ref sender) => sender.try_send(&quinn_udp::Transmit {
+                destination,
+                ecn: transmit.ecn,
+                contents: transmit.contents,
+                segment_size: transmit.segment_size,
+                src_ip: src,
+            }),
+            #[cfg(any(test, feature = "test-utils"))]
+            Sender::Virtual(ref host) => {
+                Self::send_virtual(host, destination, transmit);
+                Ok(())
+            }
+        };
         trace!("send res: {:?}", res);
 
         match res {
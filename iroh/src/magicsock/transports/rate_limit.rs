@@ -0,0 +1,286 @@
+//! Enforces the [`BandwidthLimits`] of an endpoint.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use iroh_base::EndpointId;
+use n0_future::time::{Duration, Instant};
+
+use super::Addr;
+use crate::endpoint::bandwidth::{BandwidthLimits, RateLimit, TrafficLimits};
+
+/// The direction of the traffic to limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Send,
+    Recv,
+}
+
+/// Token buckets for all configured bandwidth limits.
+///
+/// Does not lock anything if no limits are configured.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    buckets: Option<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: &BandwidthLimits) -> Self {
+        if limits.is_unlimited() {
+            return Self { buckets: None };
+        }
+        let now = Instant::now();
+        let buckets = Buckets {
+            total: DirectionBuckets::new(limits.total(), now),
+            direct: DirectionBuckets::new(limits.direct(), now),
+            relay: DirectionBuckets::new(limits.relay(), now),
+            remotes: limits
+                .remotes()
+                .map(|(id, limits)| (*id, DirectionBuckets::new(*limits, now)))
+                .collect(),
+        };
+        Self {
+            buckets: Some(Mutex::new(buckets)),
+        }
+    }
+
+    /// Returns `true` if any traffic is limited.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.buckets.is_some()
+    }
+
+    /// Takes `len` bytes from all buckets applying to a packet.
+    ///
+    /// If any of the buckets does not have enough tokens nothing is taken, and the time
+    /// until all buckets will have refilled enough is returned as error.
+    pub(crate) fn try_acquire(
+        &self,
+        direction: Direction,
+        addr: &Addr,
+        remote: Option<EndpointId>,
+        len: usize,
+    ) -> Result<(), Duration> {
+        match self.buckets {
+            None => Ok(()),
+            Some(ref buckets) => buckets.lock().expect("poisoned").try_acquire(
+                direction,
+                addr,
+                remote,
+                len,
+                Instant::now(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    total: DirectionBuckets,
+    direct: DirectionBuckets,
+    relay: DirectionBuckets,
+    remotes: HashMap<EndpointId, DirectionBuckets>,
+}
+
+impl Buckets {
+    fn try_acquire(
+        &mut self,
+        direction: Direction,
+        addr: &Addr,
+        remote: Option<EndpointId>,
+        len: usize,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let Self {
+            total,
+            direct,
+            relay,
+            remotes,
+        } = self;
+        let path = match addr {
+            Addr::Ip(_) | Addr::Custom(_) => direct,
+            Addr::Relay(..) => relay,
+        };
+        let remote = remote.and_then(|id| remotes.get_mut(&id));
+        let mut buckets = [Some(total), Some(path), remote]
+            .into_iter()
+            .flatten()
+            .filter_map(|buckets| buckets.get_mut(direction))
+            .collect::<Vec<_>>();
+
+        let mut wait = None;
+        for bucket in buckets.iter_mut() {
+            bucket.refill(now);
+            if let Some(duration) = bucket.wait_time(len) {
+                wait = wait.max(Some(duration));
+            }
+        }
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+        for bucket in buckets {
+            bucket.consume(len);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct DirectionBuckets {
+    send: Option<TokenBucket>,
+    recv: Option<TokenBucket>,
+}
+
+impl DirectionBuckets {
+    fn new(limits: TrafficLimits, now: Instant) -> Self {
+        Self {
+            send: limits.upload().map(|limit| TokenBucket::new(limit, now)),
+            recv: limits.download().map(|limit| TokenBucket::new(limit, now)),
+        }
+    }
+
+    fn get_mut(&mut self, direction: Direction) -> Option<&mut TokenBucket> {
+        match direction {
+            Direction::Send => self.send.as_mut(),
+            Direction::Recv => self.recv.as_mut(),
+        }
+    }
+}
+
+/// A token bucket, holding up to `capacity` bytes and refilling at `rate` bytes per second.
+///
+/// Packets larger than the capacity are admitted when the bucket is full, leaving the
+/// bucket in debt until it refilled.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            rate: limit.rate() as f64,
+            capacity: limit.burst() as f64,
+            tokens: limit.burst() as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Returns how long to wait until `len` bytes may pass, or `None` if they may pass now.
+    fn wait_time(&self, len: usize) -> Option<Duration> {
+        let needed = (len as f64).min(self.capacity);
+        if self.tokens >= needed {
+            None
+        } else {
+            Some(Duration::from_secs_f64((needed - self.tokens) / self.rate))
+        }
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.tokens -= len as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use iroh_base::SecretKey;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn ip_addr() -> Addr {
+        Addr::Ip(SocketAddr::from((Ipv4Addr::LOCALHOST, 1234)))
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let limit = RateLimit::bytes_per_second(1000).with_burst(500);
+        let mut bucket = TokenBucket::new(limit, now);
+
+        assert_eq!(bucket.wait_time(500), None);
+        bucket.consume(500);
+        assert_eq!(bucket.wait_time(100), Some(Duration::from_millis(100)));
+
+        bucket.refill(now + Duration::from_millis(100));
+        assert_eq!(bucket.wait_time(100), None);
+
+        // Never fills beyond the burst size.
+        bucket.refill(now + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, 500.0);
+
+        // Oversized packets pass once the bucket is full, leaving it in debt.
+        assert_eq!(bucket.wait_time(2000), None);
+        bucket.consume(2000);
+        assert_eq!(bucket.wait_time(1), Some(Duration::from_millis(1501)));
+    }
+
+    #[test]
+    fn test_limits_apply_together() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0);
+        let remote = SecretKey::generate(&mut rng).public();
+        let other = SecretKey::generate(&mut rng).public();
+        let relay_addr = Addr::Relay("https://relay.example".parse().unwrap(), remote);
+
+        let limit = |rate| RateLimit::bytes_per_second(rate).with_burst(rate);
+        let limits = BandwidthLimits::new()
+            .with_total(TrafficLimits::new().with_upload(limit(3000)))
+            .with_relay(TrafficLimits::new().with_upload(limit(1000)))
+            .with_remote(remote, TrafficLimits::new().with_download(limit(500)));
+        let limiter = RateLimiter::new(&limits);
+        assert!(limiter.is_enabled());
+        let mut buckets = limiter.buckets.unwrap().into_inner().unwrap();
+        let now = Instant::now();
+
+        // The relay limit is hit before the total limit.
+        assert!(
+            buckets
+                .try_acquire(Direction::Send, &relay_addr, Some(remote), 1000, now)
+                .is_ok()
+        );
+        assert!(
+            buckets
+                .try_acquire(Direction::Send, &relay_addr, Some(remote), 1000, now)
+                .is_err()
+        );
+        // Direct traffic only counts against the total limit.
+        assert!(
+            buckets
+                .try_acquire(Direction::Send, &ip_addr(), Some(remote), 2000, now)
+                .is_ok()
+        );
+        assert!(
+            buckets
+                .try_acquire(Direction::Send, &ip_addr(), Some(remote), 1, now)
+                .is_err()
+        );
+
+        // Downloads are only limited for the configured remote.
+        assert!(
+            buckets
+                .try_acquire(Direction::Recv, &ip_addr(), Some(remote), 500, now)
+                .is_ok()
+        );
+        assert!(
+            buckets
+                .try_acquire(Direction::Recv, &ip_addr(), Some(remote), 500, now)
+                .is_err()
+        );
+        assert!(
+            buckets
+                .try_acquire(Direction::Recv, &ip_addr(), Some(other), 5000, now)
+                .is_ok()
+        );
+
+        assert!(!RateLimiter::new(&BandwidthLimits::new()).is_enabled());
+    }
+}
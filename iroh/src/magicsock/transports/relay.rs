@@ -12,11 +12,13 @@ use n0_future::{
     task::{self, AbortOnDropHandle},
 };
 use n0_watcher::{Watchable, Watcher as _};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio_util::sync::{CancellationToken, PollSender};
 use tracing::{Instrument, error, info_span, trace, warn};
 
 use super::{Addr, Transmit};
+#[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+use crate::test_utils::sim::VirtualHost;
 
 mod actor;
 
@@ -70,6 +72,69 @@ impl RelayTransport {
         }
     }
 
+    /// Creates a transport using the relay server of a simulated network.
+    ///
+    /// The relay server is the home relay right away.  Datagrams are sent to the remote
+    /// endpoint through the simulated relay server no matter which relay URL they are
+    /// addressed to.
+    #[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+    pub(crate) fn new_virtual(
+        host: VirtualHost,
+        my_endpoint_id: EndpointId,
+        my_relay: Watchable<Option<RelayUrl>>,
+        cancel_token: CancellationToken,
+    ) -> Self {
+        let (relay_datagram_send_tx, mut relay_datagram_send_rx) =
+            mpsc::channel::<RelaySendItem>(256);
+        let (relay_datagram_recv_tx, relay_datagram_recv_rx) = mpsc::channel(512);
+        let (actor_sender, mut actor_receiver) = mpsc::channel(256);
+
+        let url = host.relay_url();
+        my_relay.set(Some(url.clone())).ok();
+        let mut inbox = host.connect_relay(my_endpoint_id);
+
+        let actor_handle = AbortOnDropHandle::new(task::spawn(
+            async move {
+                loop {
+                    tokio::select! {
+                        biased;
+                        _ = cancel_token.cancelled() => break,
+                        Some(item) = relay_datagram_send_rx.recv() => {
+                            host.send_relay(my_endpoint_id, item.remote_endpoint, item.datagrams);
+                        }
+                        Some((src, datagrams)) = inbox.recv() => {
+                            let datagram = RelayRecvDatagram {
+                                url: url.clone(),
+                                src,
+                                datagrams,
+                            };
+                            if relay_datagram_recv_tx.send(datagram).await.is_err() {
+                                break;
+                            }
+                        }
+                        Some(msg) = actor_receiver.recv() => {
+                            if let RelayActorMessage::FlushDatagrams { done } = msg {
+                                done.send(()).ok();
+                            }
+                        }
+                        else => break,
+                    }
+                }
+            }
+            .instrument(info_span!("virtual-relay")),
+        ));
+
+        Self {
+            relay_datagram_recv_queue: relay_datagram_recv_rx,
+            relay_datagram_send_channel: relay_datagram_send_tx,
+            pending_item: None,
+            actor_sender,
+            _actor_handle: actor_handle,
+            my_relay,
+            my_endpoint_id,
+        }
+    }
+
     pub(crate) fn create_sender(&self) -> RelaySender {
         RelaySender {
             sender: PollSender::new(self.relay_datagram_send_channel.clone()),
@@ -218,6 +283,16 @@ impl RelayNetworkChangeSender {
         Ok(())
     }
 
+    /// Asks the relay actor to hand all queued datagrams to the relay connections.
+    ///
+    /// The returned receiver completes once this is done, or errors if the relay actor is
+    /// gone.
+    pub(super) fn flush_datagrams(&self) -> oneshot::Receiver<()> {
+        let (done, rx) = oneshot::channel();
+        self.send_relay_actor(RelayActorMessage::FlushDatagrams { done });
+        rx
+    }
+
     fn send_relay_actor(&self, msg: RelayActorMessage) {
         match self.sender.try_send(msg) {
             Ok(_) => {}
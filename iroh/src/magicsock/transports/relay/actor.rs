@@ -59,7 +59,12 @@ use url::Url;
 
 #[cfg(not(wasm_browser))]
 use crate::dns::DnsResolver;
-use crate::{magicsock::Metrics as MagicsockMetrics, net_report::Report, util::MaybeFuture};
+use crate::{
+    endpoint::{EndpointEvent, EventSender},
+    magicsock::Metrics as MagicsockMetrics,
+    net_report::Report,
+    util::MaybeFuture,
+};
 
 /// How long a non-home relay connection needs to be idle (last written to) before we close it.
 const RELAY_INACTIVE_CLEANUP_TIME: Duration = Duration::from_secs(60);
@@ -92,6 +97,9 @@ const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 /// This value is set to 3 times the QUIC initial Probe Timeout (PTO).
 const UNDELIVERABLE_DATAGRAM_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// How often to check whether an [`ActiveRelayActor`] emptied its send queue when flushing.
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 /// An actor which handles the connection to a single relay server.
 ///
 /// It is responsible for maintaining the connection to the relay server and handling all
@@ -809,7 +817,16 @@ impl ConnectedRelayState {
 
 pub(super) enum RelayActorMessage {
     MaybeCloseRelaysOnRebind,
-    NetworkChange { report: Report },
+    NetworkChange {
+        report: Report,
+    },
+    /// Hands all queued datagrams to the relay connections.
+    ///
+    /// Completes `done` once the [`ActiveRelayActor`]s took all datagrams queued
+    /// before this message from their send queues.
+    FlushDatagrams {
+        done: oneshot::Sender<()>,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -849,6 +866,7 @@ pub struct Config {
     #[cfg(any(test, feature = "test-utils"))]
     pub insecure_skip_relay_cert_verify: bool,
     pub metrics: Arc<MagicsockMetrics>,
+    pub events: EventSender,
 }
 
 impl RelayActor {
@@ -901,6 +919,21 @@ impl RelayActor {
                         break;
                     };
                     let cancel_token = self.cancel_token.child_token();
+                    if matches!(msg, RelayActorMessage::FlushDatagrams { .. }) {
+                        // Datagrams queued before the flush need to reach the
+                        // ActiveRelayActors first.
+                        cancel_token.run_until_cancelled(async {
+                            if datagram_send_fut.is_some() {
+                                (&mut datagram_send_fut).await;
+                                datagram_send_fut.as_mut().set_none();
+                            }
+                            while let Ok(item) = datagram_send_channel.try_recv() {
+                                if let Some(fut) = self.try_send_datagram(item).await {
+                                    fut.await;
+                                }
+                            }
+                        }).await;
+                    }
                     cancel_token.run_until_cancelled(self.handle_msg(msg)).await;
                 }
                 // Only poll for new datagrams if we are not blocked on sending them.
@@ -940,9 +973,25 @@ impl RelayActor {
             RelayActorMessage::MaybeCloseRelaysOnRebind => {
                 self.maybe_close_relays_on_rebind().await;
             }
+            RelayActorMessage::FlushDatagrams { done } => {
+                self.flush_active_relays().await;
+                done.send(()).ok();
+            }
         }
     }
 
+    /// Waits until all [`ActiveRelayActor`]s took the datagrams from their send queues.
+    async fn flush_active_relays(&self) {
+        let flush_futs = self.active_relays.iter().map(|(url, handle)| async move {
+            let queue = &handle.datagrams_send_queue;
+            while !queue.is_closed() && queue.capacity() < queue.max_capacity() {
+                time::sleep(FLUSH_POLL_INTERVAL).await;
+            }
+            trace!(%url, "flushed relay datagrams");
+        });
+        n0_future::join_all(flush_futs).await;
+    }
+
     /// Sends datagrams to the correct [`ActiveRelayActor`], or returns a future.
     ///
     /// If the datagram can not be sent immediately, because the destination channel is
@@ -985,6 +1034,10 @@ impl RelayActor {
             .my_relay
             .set(report.preferred_relay.clone())
             .unwrap_or_else(|e| e);
+        self.config.events.send(EndpointEvent::HomeRelayChanged {
+            previous: old_relay.clone(),
+            current: report.preferred_relay.clone(),
+        });
 
         if let Some(relay_url) = report.preferred_relay {
             self.config.metrics.relay_home_change.inc();
@@ -48,7 +48,7 @@ use tracing::{debug, trace, warn};
 
 #[cfg(not(wasm_browser))]
 use self::reportgen::QadProbeReport;
-use self::reportgen::{ProbeFinished, ProbeReport};
+use self::reportgen::{ProbeFinished, ProbeReport, ProbesError};
 
 mod defaults;
 mod ip_mapped_addrs;
@@ -104,7 +104,7 @@ pub use self::{
     metrics::Metrics,
     options::Options,
     probes::Probe,
-    report::{RelayLatencies, Report},
+    report::{DiagnosticReport, MappingBehavior, NatType, ProbeResult, RelayLatencies, Report},
     reportgen::QuicConfig,
 };
 
@@ -112,8 +112,13 @@ const FULL_REPORT_INTERVAL: Duration = Duration::from_secs(5 * 60);
 const ENOUGH_ENDPOINTS: usize = 3;
 
 /// Client to run net_reports.
+///
+/// Endpoints run net reports continuously.  Use [`Client::run_once`] to check the network
+/// conditions without an [`Endpoint`].
+///
+/// [`Endpoint`]: crate::Endpoint
 #[derive(Debug)]
-pub(crate) struct Client {
+pub struct Client {
     #[cfg(not(wasm_browser))]
     socket_state: SocketState,
     metrics: Arc<Metrics>,
@@ -236,6 +241,12 @@ impl Client {
         #[cfg(any(test, feature = "test-utils"))]
         let insecure_skip_relay_cert_verify = opts.insecure_skip_relay_cert_verify;
 
+        #[cfg(not(wasm_browser))]
+        let hairpin_client_config = opts
+            .quic_config
+            .as_ref()
+            .filter(|c| opts.hairpinning && c.ipv4)
+            .map(|c| c.client_config.clone());
         #[cfg(not(wasm_browser))]
         let quic_client = opts
             .quic_config
@@ -244,6 +255,7 @@ impl Client {
         #[cfg(not(wasm_browser))]
         let socket_state = SocketState {
             quic_client,
+            hairpin_client_config,
             dns_resolver,
             ip_mapped_addrs,
         };
@@ -262,10 +274,77 @@ impl Client {
         }
     }
 
+    /// Runs a single full net report against the relays in `relay_map`.
+    ///
+    /// This does not need an [`Endpoint`]: the probes use their own sockets.  QUIC address
+    /// discovery only runs if [`Options::quic_config`] is set, see [`QuicConfig::bind`].
+    /// Port mapping protocols are probed too, unless disabled with [`Options::portmapper`].
+    /// Enable [`Options::hairpinning`] to also check whether our NAT supports hairpinning.
+    ///
+    /// Returns the [`Report`] together with the raw results of all probes which finished.
+    ///
+    /// [`Endpoint`]: crate::Endpoint
+    pub async fn run_once(relay_map: RelayMap, opts: Options) -> DiagnosticReport {
+        #[cfg(not(wasm_browser))]
+        let portmap = opts.portmapper.then(|| {
+            let client = ::portmapper::Client::default();
+            let probe = client.probe();
+            (client, probe)
+        });
+        #[cfg(not(wasm_browser))]
+        let if_state = IfStateDetails::from(netwatch::netmon::State::new().await);
+        #[cfg(wasm_browser)]
+        let if_state = IfStateDetails::default();
+
+        let mut client = Client::new(
+            #[cfg(not(wasm_browser))]
+            DnsResolver::new(),
+            #[cfg(not(wasm_browser))]
+            None,
+            relay_map,
+            opts,
+            Default::default(),
+        );
+        let (report, mut probes) = client.get_report_with_probes(if_state, true).await;
+        #[cfg(not(wasm_browser))]
+        client.qad_conns.clear();
+
+        #[cfg(not(wasm_browser))]
+        if let Some((_client, probe)) = portmap {
+            probes.push(match time::timeout(PROBES_TIMEOUT, probe).await {
+                Ok(Ok(Ok(output))) => ProbeResult::Portmap {
+                    upnp: output.upnp,
+                    pcp: output.pcp,
+                    nat_pmp: output.nat_pmp,
+                },
+                Ok(Ok(Err(err))) => ProbeResult::PortmapFailed {
+                    error: format!("{err:#}"),
+                },
+                Ok(Err(_)) => ProbeResult::PortmapFailed {
+                    error: "port mapper is gone".to_string(),
+                },
+                Err(time::Elapsed { .. }) => ProbeResult::PortmapFailed {
+                    error: "timed out".to_string(),
+                },
+            });
+        }
+
+        DiagnosticReport { report, probes }
+    }
+
     /// Generates a [`Report`].
     ///
     /// Look at [`Options`] for the different configuration options.
     pub(crate) async fn get_report(&mut self, if_state: IfStateDetails, is_major: bool) -> Report {
+        self.get_report_with_probes(if_state, is_major).await.0
+    }
+
+    /// Generates a [`Report`], also returning the results of the individual probes.
+    async fn get_report_with_probes(
+        &mut self,
+        if_state: IfStateDetails,
+        is_major: bool,
+    ) -> (Report, Vec<ProbeResult>) {
         let now = Instant::now();
 
         let mut do_full = is_major
@@ -303,6 +382,7 @@ impl Client {
         };
 
         let mut report = Report::default();
+        let mut probes = Vec::new();
 
         // Start the reportgen client to start any needed probes
         let (actor, mut probe_rx) = reportgen::Client::new(
@@ -318,12 +398,18 @@ impl Client {
 
         #[cfg(not(wasm_browser))]
         let reports = self
-            .spawn_qad_probes(&if_state, enough_relays, do_full)
+            .spawn_qad_probes(&if_state, enough_relays, do_full, &mut probes)
             .await;
 
+        #[cfg(not(wasm_browser))]
+        let hairpin_check = do_full
+            .then(|| self.spawn_hairpin_check(&reports))
+            .flatten();
+
         #[cfg(not(wasm_browser))]
         for r in reports {
             report.update(&r);
+            probes.push(ProbeResult::from(&r));
         }
 
         if self.have_enough_reports(&if_state, do_full, num_relays, &report) {
@@ -349,7 +435,9 @@ impl Client {
                         #[cfg(not(wasm_browser))]
                         {
                             trace!(?r, "new report from QAD V4");
-                            report.update(&ProbeReport::QadIpv4(r));
+                            let r = ProbeReport::QadIpv4(r);
+                            report.update(&r);
+                            probes.push(ProbeResult::from(&r));
                         }
                     }
 
@@ -357,7 +445,9 @@ impl Client {
                         #[cfg(not(wasm_browser))]
                         {
                             trace!(?r, "new report from QAD V6");
-                            report.update(&ProbeReport::QadIpv6(r));
+                            let r = ProbeReport::QadIpv6(r);
+                            report.update(&r);
+                            probes.push(ProbeResult::from(&r));
                         }
                     }
 
@@ -369,6 +459,7 @@ impl Client {
                             ProbeFinished::Regular(probe) => match probe {
                                 Ok(probe) => {
                                     report.update(&probe);
+                                    probes.push(ProbeResult::from(&probe));
                                     if self.have_enough_reports(&if_state, do_full, num_relays, &report) {
                                         trace!("have enough probe reports, aborting further probes");
                                         // shuts down the probes
@@ -378,17 +469,42 @@ impl Client {
                                 }
                                 Err(err) => {
                                     trace!("probe failed: {:?}", err);
+                                    match err {
+                                        ProbesError::ProbeFailure { probe, relay, source, .. } => {
+                                            probes.push(ProbeResult::Failed {
+                                                probe,
+                                                relay,
+                                                error: format!("{source:#}"),
+                                            });
+                                        }
+                                        ProbesError::Timeout { probe, relay, .. } => {
+                                            probes.push(ProbeResult::Failed {
+                                                probe,
+                                                relay,
+                                                error: "timed out".to_string(),
+                                            });
+                                        }
+                                        _ => {}
+                                    }
                                 }
                             },
                             #[cfg(not(wasm_browser))]
                             ProbeFinished::CaptivePortal(portal) => {
                                 report.captive_portal = portal;
+                                probes.push(ProbeResult::CaptivePortal { found: portal });
                             }
                         }
                     }
                 }
             }
         }
+        #[cfg(not(wasm_browser))]
+        if let Some(hairpin_check) = hairpin_check {
+            let works = hairpin_check.await.ok().flatten();
+            report.update_hairpinning(works);
+            probes.push(ProbeResult::Hairpinning { works });
+        }
+
         self.add_report_history_and_set_preferred_relay(&mut report);
         debug!(
             ?report,
@@ -396,7 +512,7 @@ impl Client {
             now.elapsed().as_millis()
         );
 
-        report
+        (report, probes)
     }
 
     #[cfg(not(wasm_browser))]
@@ -405,6 +521,7 @@ impl Client {
         if_state: &IfStateDetails,
         enough_relays: usize,
         do_full: bool,
+        failures: &mut Vec<ProbeResult>,
     ) -> Vec<ProbeReport> {
         use tracing::{Instrument, warn_span};
 
@@ -469,15 +586,14 @@ impl Client {
                 let dns_resolver = self.socket_state.dns_resolver.clone();
                 let quic_client = quic_client.clone();
                 let relay_url = relay.url.clone();
-                v4_buf.spawn(
-                    cancel_v4
-                        .child_token()
-                        .run_until_cancelled_owned(time::timeout(
-                            PROBES_TIMEOUT,
-                            run_probe_v4(ip_mapped_addrs, relay, quic_client, dns_resolver),
-                        ))
-                        .instrument(warn_span!("QAD-IPv4", %relay_url)),
-                );
+                let probe = cancel_v4
+                    .child_token()
+                    .run_until_cancelled_owned(time::timeout(
+                        PROBES_TIMEOUT,
+                        run_probe_v4(ip_mapped_addrs, relay, quic_client, dns_resolver),
+                    ))
+                    .instrument(warn_span!("QAD-IPv4", %relay_url));
+                v4_buf.spawn(async move { (relay_url, probe.await) });
             }
 
             if if_state.have_v6 && needs_v6_probe {
@@ -487,15 +603,14 @@ impl Client {
                 let dns_resolver = self.socket_state.dns_resolver.clone();
                 let quic_client = quic_client.clone();
                 let relay_url = relay.url.clone();
-                v6_buf.spawn(
-                    cancel_v6
-                        .child_token()
-                        .run_until_cancelled_owned(time::timeout(
-                            PROBES_TIMEOUT,
-                            run_probe_v6(ip_mapped_addrs, relay, quic_client, dns_resolver),
-                        ))
-                        .instrument(warn_span!("QAD-IPv6", %relay_url)),
-                );
+                let probe = cancel_v6
+                    .child_token()
+                    .run_until_cancelled_owned(time::timeout(
+                        PROBES_TIMEOUT,
+                        run_probe_v6(ip_mapped_addrs, relay, quic_client, dns_resolver),
+                    ))
+                    .instrument(warn_span!("QAD-IPv6", %relay_url));
+                v6_buf.spawn(async move { (relay_url, probe.await) });
             }
         }
 
@@ -522,7 +637,7 @@ impl Client {
                 val = v4_buf.join_next(), if !v4_buf.is_empty() => {
                     ipv4_pending = false;
                     match val {
-                        Some(Ok(Some(Ok(res)))) => {
+                        Some(Ok((relay_url, Some(Ok(res))))) => {
                             match res {
                                 Ok((r, conn)) => {
                                     debug!(?r, "got v4 QAD conn");
@@ -536,6 +651,11 @@ impl Client {
                                 }
                                 Err(err) => {
                                     debug!("probe v4 failed: {err:?}");
+                                    failures.push(ProbeResult::Failed {
+                                        probe: Probe::QadIpv4,
+                                        relay: relay_url,
+                                        error: format!("{err:#}"),
+                                    });
                                 }
                             }
                         }
@@ -545,11 +665,16 @@ impl Client {
                             }
                             warn!("probe v4 failed: {err:?}");
                         }
-                        Some(Ok(None)) => {
+                        Some(Ok((_, None))) => {
                             debug!("probe v4 canceled");
                         }
-                        Some(Ok(Some(Err(time::Elapsed { .. })))) => {
+                        Some(Ok((relay_url, Some(Err(time::Elapsed { .. }))))) => {
                             debug!("probe v4 timed out");
+                            failures.push(ProbeResult::Failed {
+                                probe: Probe::QadIpv4,
+                                relay: relay_url,
+                                error: "timed out".to_string(),
+                            });
                         }
                         None => {}
                     }
@@ -557,7 +682,7 @@ impl Client {
                 val = v6_buf.join_next(), if !v6_buf.is_empty() => {
                     ipv6_pending = false;
                     match val {
-                        Some(Ok(Some(Ok(res)))) => {
+                        Some(Ok((relay_url, Some(Ok(res))))) => {
                             match res {
                                 Ok((r, conn)) => {
                                     debug!(?r, "got v6 QAD conn");
@@ -571,6 +696,11 @@ impl Client {
                                 }
                                 Err(err) => {
                                     debug!("probe v6 failed: {err:?}");
+                                    failures.push(ProbeResult::Failed {
+                                        probe: Probe::QadIpv6,
+                                        relay: relay_url,
+                                        error: format!("{err:#}"),
+                                    });
                                 }
                             }
                         }
@@ -580,11 +710,16 @@ impl Client {
                             }
                             warn!("probe v6 failed: {err:?}");
                         }
-                        Some(Ok(None)) => {
+                        Some(Ok((_, None))) => {
                             debug!("probe v6 canceled");
                         }
-                        Some(Ok(Some(Err(time::Elapsed { .. })))) => {
+                        Some(Ok((relay_url, Some(Err(time::Elapsed { .. }))))) => {
                             debug!("probe v6 timed out");
+                            failures.push(ProbeResult::Failed {
+                                probe: Probe::QadIpv6,
+                                relay: relay_url,
+                                error: "timed out".to_string(),
+                            });
                         }
                         None => {}
                     }
@@ -598,6 +733,50 @@ impl Client {
         reports
     }
 
+    /// Spawns the hairpinning check, using the relay of the first IPv4 QAD report.
+    ///
+    /// The relay is known to support QUIC address discovery, which the check needs.  The
+    /// task resolves to `None` if the check failed or timed out.
+    #[cfg(not(wasm_browser))]
+    fn spawn_hairpin_check(
+        &self,
+        reports: &[ProbeReport],
+    ) -> Option<AbortOnDropHandle<Option<bool>>> {
+        use tracing::{Instrument, warn_span};
+
+        let client_config = self.socket_state.hairpin_client_config.clone()?;
+        let relay = reports.iter().find_map(|r| match r {
+            ProbeReport::QadIpv4(r) => self.relay_map.get(&r.relay),
+            _ => None,
+        })?;
+        let dns_resolver = self.socket_state.dns_resolver.clone();
+        let task = task::spawn(
+            async move {
+                match time::timeout(
+                    PROBES_TIMEOUT,
+                    reportgen::check_hairpinning(&dns_resolver, &relay, client_config),
+                )
+                .await
+                {
+                    Ok(Ok(works)) => {
+                        debug!(%works, "hairpinning check finished");
+                        Some(works)
+                    }
+                    Ok(Err(err)) => {
+                        debug!("hairpinning check failed: {err:#}");
+                        None
+                    }
+                    Err(time::Elapsed { .. }) => {
+                        debug!("hairpinning check timed out");
+                        None
+                    }
+                }
+            }
+            .instrument(warn_span!("hairpin-check")),
+        );
+        Some(AbortOnDropHandle::new(task))
+    }
+
     /// Check if we have enough information to consider the current report "good enough".
     fn have_enough_reports(
         &self,
@@ -699,6 +878,15 @@ impl Client {
             if r.mapping_varies_by_dest_ipv6.is_none() {
                 r.mapping_varies_by_dest_ipv6 = last.mapping_varies_by_dest_ipv6;
             }
+            if r.mapping_behavior_ipv4.is_none() {
+                r.mapping_behavior_ipv4 = last.mapping_behavior_ipv4;
+            }
+            if r.mapping_behavior_ipv6.is_none() {
+                r.mapping_behavior_ipv6 = last.mapping_behavior_ipv6;
+            }
+            if r.hairpinning.is_none() {
+                r.hairpinning = last.hairpinning;
+            }
         }
 
         let now = Instant::now();
@@ -795,6 +983,7 @@ async fn run_probe_v4(
         .expect("known");
     let report = QadProbeReport {
         relay: relay.url.clone(),
+        relay_addr: relay_addr_orig.into(),
         addr: SocketAddr::new(addr.ip().to_canonical(), addr.port()),
         latency: conn.rtt(),
     };
@@ -815,6 +1004,7 @@ async fn run_probe_v4(
                 observer
                     .set(val.map(|addr| QadProbeReport {
                         relay: endpoint.clone(),
+                        relay_addr: relay_addr_orig.into(),
                         addr,
                         latency,
                     }))
@@ -869,6 +1059,7 @@ async fn run_probe_v6(
         .expect("known");
     let report = QadProbeReport {
         relay: relay.url.clone(),
+        relay_addr: relay_addr_orig.into(),
         addr: SocketAddr::new(addr.ip().to_canonical(), addr.port()),
         latency: conn.rtt(),
     };
@@ -889,6 +1080,7 @@ async fn run_probe_v6(
                 observer
                     .set(val.map(|addr| QadProbeReport {
                         relay: endpoint.clone(),
+                        relay_addr: relay_addr_orig.into(),
                         addr,
                         latency,
                     }))
@@ -1018,6 +1210,51 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    #[traced_test]
+    async fn test_run_once() -> Result<()> {
+        let (server, relay) = test_utils::relay().await;
+        let relay_url = relay.url.clone();
+        let ep =
+            quinn::Endpoint::client(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).anyerr()?;
+        let quic_config = QuicConfig {
+            ep: ep.clone(),
+            client_config: iroh_relay::client::make_dangerous_client_config(),
+            ipv4: true,
+            ipv6: false,
+        };
+        let opts = Options::default()
+            .quic_config(Some(quic_config))
+            .portmapper(false)
+            .hairpinning(true)
+            .insecure_skip_relay_cert_verify(true);
+
+        let diagnostic = Client::run_once(RelayMap::from(relay), opts).await;
+
+        assert!(diagnostic.report.udp_v4, "want UDP");
+        let global_v4 = diagnostic.report.global_v4.expect("expected globalV4 set");
+        assert!(diagnostic.probes.iter().any(|probe| matches!(
+            probe,
+            ProbeResult::QadIpv4 { relay, addr, .. }
+                if *relay == relay_url && *addr == SocketAddr::V4(global_v4)
+        )));
+
+        assert_eq!(diagnostic.report.hairpinning, Some(true));
+        assert!(
+            diagnostic
+                .probes
+                .contains(&ProbeResult::Hairpinning { works: Some(true) })
+        );
+
+        let json = serde_json::to_string(&diagnostic).anyerr()?;
+        let decoded: DiagnosticReport = serde_json::from_str(&json).anyerr()?;
+        assert_eq!(decoded, diagnostic);
+
+        ep.wait_idle().await;
+        server.shutdown().await?;
+        Ok(())
+    }
+
     #[tokio::test(flavor = "current_thread", start_paused = true)]
     async fn test_add_report_history_set_preferred_relay() -> Result {
         fn relay_url(i: u16) -> RelayUrl {
@@ -35,5 +35,9 @@ pub(crate) mod timeouts {
     /// [`CAPTIVE_PORTAL_DELAY`].
     pub(crate) const CAPTIVE_PORTAL_TIMEOUT: Duration = Duration::from_secs(2);
 
+    /// How long to wait for a connection to our own public address in the hairpinning
+    /// check, once that address is known.
+    pub(crate) const HAIRPIN_TIMEOUT: Duration = Duration::from_secs(1);
+
     pub(crate) const DNS_TIMEOUT: Duration = Duration::from_secs(3);
 }
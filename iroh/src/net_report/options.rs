@@ -23,6 +23,18 @@ mod imp {
         ///
         /// On by default
         pub(crate) https: bool,
+        /// Enable the port mapping probe of [`Client::run_once`].
+        ///
+        /// On by default.  Endpoints probe the port mapping protocols with their own port
+        /// mapper, so this is not used by them.
+        ///
+        /// [`Client::run_once`]: crate::net_report::Client::run_once
+        pub(crate) portmapper: bool,
+        /// Enable the hairpinning check on full reports.
+        ///
+        /// Off by default, as the check binds additional sockets and delays full reports.
+        /// This needs QUIC address discovery, see [`Options::quic_config`].
+        pub(crate) hairpinning: bool,
 
         #[cfg(any(test, feature = "test-utils"))]
         pub(crate) insecure_skip_relay_cert_verify: bool,
@@ -33,6 +45,8 @@ mod imp {
             Self {
                 quic_config: None,
                 https: true,
+                portmapper: true,
+                hairpinning: false,
                 #[cfg(any(test, feature = "test-utils"))]
                 insecure_skip_relay_cert_verify: false,
             }
@@ -45,6 +59,8 @@ mod imp {
             Self {
                 quic_config: None,
                 https: false,
+                portmapper: false,
+                hairpinning: false,
                 #[cfg(any(test, feature = "test-utils"))]
                 insecure_skip_relay_cert_verify: false,
             }
@@ -62,6 +78,30 @@ mod imp {
             self
         }
 
+        /// Enable or disable the port mapping probe of [`Client::run_once`].
+        ///
+        /// [`Client::run_once`]: crate::net_report::Client::run_once
+        pub fn portmapper(mut self, enable: bool) -> Self {
+            self.portmapper = enable;
+            self
+        }
+
+        /// Enable or disable the hairpinning check.
+        ///
+        /// The check binds two additional sockets for the duration of a full report, to
+        /// learn whether our NAT forwards packets sent to our own public address back to
+        /// us.  See [`Report::hairpinning`].
+        ///
+        /// Off by default.  Meant for diagnostics with [`Client::run_once`], endpoints do
+        /// not enable it.
+        ///
+        /// [`Report::hairpinning`]: crate::net_report::Report::hairpinning
+        /// [`Client::run_once`]: crate::net_report::Client::run_once
+        pub fn hairpinning(mut self, enable: bool) -> Self {
+            self.hairpinning = enable;
+            self
+        }
+
         /// Skip cert verification
         #[cfg(any(test, feature = "test-utils"))]
         pub fn insecure_skip_relay_cert_verify(mut self, skip: bool) -> Self {
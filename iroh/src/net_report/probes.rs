@@ -8,6 +8,7 @@ use std::{collections::BTreeSet, fmt, sync::Arc};
 
 use iroh_relay::{RelayConfig, RelayMap};
 use n0_future::time::Duration;
+use serde::{Deserialize, Serialize};
 
 use crate::net_report::Report;
 
@@ -18,7 +19,9 @@ const DEFAULT_INITIAL_RETRANSMIT: Duration = Duration::from_millis(100);
 const HTTPS_OFFSET: Duration = Duration::from_millis(200);
 
 /// The protocol used to time an endpoint's latency.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, derive_more::Display)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, derive_more::Display, Serialize, Deserialize,
+)]
 #[repr(u8)]
 pub enum Probe {
     /// HTTPS
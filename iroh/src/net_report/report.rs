@@ -34,6 +34,66 @@ pub struct Report {
     /// CaptivePortal is set when we think there's a captive portal that is
     /// intercepting HTTP traffic.
     pub captive_portal: Option<bool>,
+    /// Our public address as observed by each QUIC address discovery server, keyed by the
+    /// address of the server.
+    pub observed_addrs: BTreeMap<SocketAddr, SocketAddr>,
+    /// The NAT mapping behaviour on IPv4, `None` if it could not be determined.
+    pub mapping_behavior_ipv4: Option<MappingBehavior>,
+    /// The NAT mapping behaviour on IPv6, `None` if it could not be determined.
+    pub mapping_behavior_ipv6: Option<MappingBehavior>,
+    /// Whether our NAT forwards packets sent to our own public IPv4 address back to us.
+    ///
+    /// `None` if the check did not run or was inconclusive.
+    pub hairpinning: Option<bool>,
+}
+
+/// The NAT mapping behaviour, as defined in [RFC 4787, section 4.1].
+///
+/// This describes when the NAT reuses the same public address and port for packets sent
+/// from the same local address and port.
+///
+/// [RFC 4787, section 4.1]: https://www.rfc-editor.org/rfc/rfc4787#section-4.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum MappingBehavior {
+    /// The same public address is used for all destinations.
+    EndpointIndependent,
+    /// The same public address is used for all destinations with the same IP address.
+    ///
+    /// This is also reported when the public address depends on the destination, but no
+    /// two servers on the same IP address were probed to tell whether it depends on the
+    /// port as well.
+    AddressDependent,
+    /// A different public address is used for every destination IP address and port.
+    AddressAndPortDependent,
+}
+
+/// A summary of the NAT situation, see [`Report::nat_type`].
+///
+/// Only the mapping behaviour of the NAT is known, net reports can not learn its filtering
+/// behaviour as defined in [RFC 4787, section 5]: that needs a server answering from a
+/// different address than the one probed, which relay servers do not.  So a NAT which
+/// forwards packets from any source is not told apart from a more restrictive one.
+///
+/// [RFC 4787, section 5]: https://www.rfc-editor.org/rfc/rfc4787#section-5
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum NatType {
+    /// No QUIC address discovery probe succeeded, while the relay servers were reachable.
+    ///
+    /// Only relayed connections are likely to work.
+    UdpBlocked,
+    /// Endpoint-independent mapping, or no NAT at all.
+    ///
+    /// Holepunching is very likely to succeed.
+    Cone,
+    /// The mapping depends on the destination.
+    ///
+    /// Holepunching is unlikely to succeed unless the remote endpoint is behind a
+    /// [`NatType::Cone`] NAT, expect to use the relay.
+    Symmetric,
+    /// Not enough information was gathered to classify the NAT.
+    Unknown,
 }
 
 impl fmt::Display for Report {
@@ -61,6 +121,41 @@ impl Report {
         }
     }
 
+    /// Classifies the NAT we are behind.
+    ///
+    /// This is based on the IPv4 mapping behaviour, or the IPv6 one if the former is not
+    /// known.
+    pub fn nat_type(&self) -> NatType {
+        match self.mapping_behavior_ipv4.or(self.mapping_behavior_ipv6) {
+            Some(MappingBehavior::EndpointIndependent) => NatType::Cone,
+            Some(_) => NatType::Symmetric,
+            None if !self.has_udp() && !self.relay_latency.is_empty() => NatType::UdpBlocked,
+            None => NatType::Unknown,
+        }
+    }
+
+    /// Records the results of a hairpinning check.
+    pub(super) fn update_hairpinning(&mut self, hairpinning: Option<bool>) {
+        self.hairpinning = hairpinning;
+    }
+
+    /// Records our public address as observed by the server at `relay_addr`.
+    #[cfg(not(wasm_browser))]
+    fn update_observed_addr(&mut self, relay_addr: SocketAddr, addr: SocketAddr) {
+        self.observed_addrs.insert(relay_addr, addr);
+        let observed = self
+            .observed_addrs
+            .iter()
+            .filter(|(relay_addr, _)| relay_addr.is_ipv4() == addr.is_ipv4())
+            .map(|(relay_addr, addr)| (*relay_addr, *addr));
+        let behavior = classify_mapping(observed);
+        if addr.is_ipv4() {
+            self.mapping_behavior_ipv4 = behavior;
+        } else {
+            self.mapping_behavior_ipv6 = behavior;
+        }
+    }
+
     /// Updates a net_report [`Report`] with a new [`ProbeReport`].
     pub(super) fn update(&mut self, report: &ProbeReport) {
         match report {
@@ -81,6 +176,7 @@ impl Report {
                 };
 
                 self.udp_v4 = true;
+                self.update_observed_addr(report.relay_addr, report.addr);
 
                 tracing::debug!(?self.global_v4, ?self.mapping_varies_by_dest_ipv4, %ipp,"got");
                 if let Some(global) = self.global_v4 {
@@ -109,6 +205,7 @@ impl Report {
                 };
 
                 self.udp_v6 = true;
+                self.update_observed_addr(report.relay_addr, report.addr);
                 tracing::debug!(?self.global_v6, ?self.mapping_varies_by_dest_ipv6, %ipp,"got");
                 if let Some(global) = self.global_v6 {
                     if global == ipp {
@@ -127,6 +224,36 @@ impl Report {
     }
 }
 
+/// Classifies the mapping behaviour from pairs of server addresses and our public address
+/// as observed by those servers.
+///
+/// Endpoint-independent mapping needs observations from at least two server IP addresses.
+/// Telling address-dependent from address-and-port-dependent mapping needs two servers on
+/// the same IP address, which is rare.  Without those only address-dependent mapping is
+/// reported, as that much is certain.
+fn classify_mapping(
+    observed: impl IntoIterator<Item = (SocketAddr, SocketAddr)>,
+) -> Option<MappingBehavior> {
+    let observed: Vec<_> = observed.into_iter().collect();
+    let (_, first) = observed.first()?;
+    if observed.iter().all(|(_, addr)| addr == first) {
+        let mut ips = observed.iter().map(|(relay_addr, _)| relay_addr.ip());
+        let first_ip = ips.next()?;
+        return ips
+            .any(|ip| ip != first_ip)
+            .then_some(MappingBehavior::EndpointIndependent);
+    }
+
+    for (i, (relay_a, addr_a)) in observed.iter().enumerate() {
+        for (relay_b, addr_b) in &observed[i + 1..] {
+            if relay_a.ip() == relay_b.ip() && addr_a != addr_b {
+                return Some(MappingBehavior::AddressAndPortDependent);
+            }
+        }
+    }
+    Some(MappingBehavior::AddressDependent)
+}
+
 /// Latencies per relay endpoint.
 #[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct RelayLatencies {
@@ -213,3 +340,178 @@ impl RelayLatencies {
         list.into_iter().min()
     }
 }
+
+/// A [`Report`] together with the raw results of the probes it was created from.
+///
+/// Returned by [`Client::run_once`], this is meant for diagnosing the network conditions
+/// of a host.  It is serializable, e.g. to JSON for attaching it to bug reports.
+///
+/// [`Client::run_once`]: super::Client::run_once
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiagnosticReport {
+    /// The report, as an endpoint would generate it.
+    pub report: Report,
+    /// The results of the individual probes, in the order they finished.
+    pub probes: Vec<ProbeResult>,
+}
+
+/// The raw result of a single probe.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum ProbeResult {
+    /// QUIC address discovery over IPv4 learned our public address.
+    QadIpv4 {
+        /// The relay server which was probed.
+        relay: RelayUrl,
+        /// The round trip time to the relay server.
+        latency: Duration,
+        /// Our public address, as observed by the relay server.
+        addr: SocketAddr,
+    },
+    /// QUIC address discovery over IPv6 learned our public address.
+    QadIpv6 {
+        /// The relay server which was probed.
+        relay: RelayUrl,
+        /// The round trip time to the relay server.
+        latency: Duration,
+        /// Our public address, as observed by the relay server.
+        addr: SocketAddr,
+    },
+    /// The relay server answered an HTTPS request.
+    Https {
+        /// The relay server which was probed.
+        relay: RelayUrl,
+        /// The time it took to get the response.
+        latency: Duration,
+    },
+    /// A probe to a relay server failed or timed out.
+    Failed {
+        /// The kind of probe.
+        probe: Probe,
+        /// The relay server which was probed.
+        relay: RelayUrl,
+        /// Why the probe failed.
+        error: String,
+    },
+    /// The captive portal check finished.
+    CaptivePortal {
+        /// Whether a captive portal was found, `None` if the check was inconclusive.
+        found: Option<bool>,
+    },
+    /// The port mapping protocols on the LAN were probed.
+    Portmap {
+        /// Whether UPnP is available.
+        upnp: bool,
+        /// Whether PCP is available.
+        pcp: bool,
+        /// Whether NAT-PMP is available.
+        nat_pmp: bool,
+    },
+    /// Probing the port mapping protocols failed.
+    PortmapFailed {
+        /// Why the probe failed.
+        error: String,
+    },
+    /// The hairpinning check finished.
+    Hairpinning {
+        /// Whether our NAT supports hairpinning, `None` if the check was inconclusive.
+        works: Option<bool>,
+    },
+}
+
+impl From<&ProbeReport> for ProbeResult {
+    fn from(report: &ProbeReport) -> Self {
+        match report {
+            ProbeReport::Https(report) => ProbeResult::Https {
+                relay: report.relay.clone(),
+                latency: report.latency,
+            },
+            #[cfg(not(wasm_browser))]
+            ProbeReport::QadIpv4(report) => ProbeResult::QadIpv4 {
+                relay: report.relay.clone(),
+                latency: report.latency,
+                addr: report.addr,
+            },
+            #[cfg(not(wasm_browser))]
+            ProbeReport::QadIpv6(report) => ProbeResult::QadIpv6 {
+                relay: report.relay.clone(),
+                latency: report.latency,
+                addr: report.addr,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observed(pairs: &[(&str, &str)]) -> Vec<(SocketAddr, SocketAddr)> {
+        pairs
+            .iter()
+            .map(|(relay, addr)| (relay.parse().unwrap(), addr.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_classify_mapping() {
+        // A single server tells us nothing.
+        assert_eq!(
+            classify_mapping(observed(&[("192.0.2.1:7842", "198.51.100.1:1000")])),
+            None
+        );
+        // Neither do several ports of a single server IP.
+        assert_eq!(
+            classify_mapping(observed(&[
+                ("192.0.2.1:7842", "198.51.100.1:1000"),
+                ("192.0.2.1:7843", "198.51.100.1:1000"),
+            ])),
+            None
+        );
+        assert_eq!(
+            classify_mapping(observed(&[
+                ("192.0.2.1:7842", "198.51.100.1:1000"),
+                ("192.0.2.2:7842", "198.51.100.1:1000"),
+            ])),
+            Some(MappingBehavior::EndpointIndependent)
+        );
+        assert_eq!(
+            classify_mapping(observed(&[
+                ("192.0.2.1:7842", "198.51.100.1:1000"),
+                ("192.0.2.1:7843", "198.51.100.1:1000"),
+                ("192.0.2.2:7842", "198.51.100.1:1001"),
+            ])),
+            Some(MappingBehavior::AddressDependent)
+        );
+        assert_eq!(
+            classify_mapping(observed(&[
+                ("192.0.2.1:7842", "198.51.100.1:1000"),
+                ("192.0.2.1:7843", "198.51.100.1:1001"),
+            ])),
+            Some(MappingBehavior::AddressAndPortDependent)
+        );
+        // Without two ports on one server IP only the address dependency is certain.
+        assert_eq!(
+            classify_mapping(observed(&[
+                ("192.0.2.1:7842", "198.51.100.1:1000"),
+                ("192.0.2.2:7842", "198.51.100.1:1001"),
+            ])),
+            Some(MappingBehavior::AddressDependent)
+        );
+    }
+
+    #[test]
+    fn test_nat_type() {
+        let mut report = Report::default();
+        assert_eq!(report.nat_type(), NatType::Unknown);
+
+        report.mapping_behavior_ipv4 = Some(MappingBehavior::EndpointIndependent);
+        assert_eq!(report.nat_type(), NatType::Cone);
+        // Hairpinning says nothing about the filtering behaviour.
+        report.update_hairpinning(Some(true));
+        assert_eq!(report.nat_type(), NatType::Cone);
+
+        report.mapping_behavior_ipv4 = Some(MappingBehavior::AddressAndPortDependent);
+        assert_eq!(report.nat_type(), NatType::Symmetric);
+    }
+}
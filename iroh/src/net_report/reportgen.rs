@@ -16,7 +16,7 @@
 //! - Sends the completed report to the net_report actor.
 
 #[cfg(not(wasm_browser))]
-use std::net::{SocketAddrV4, SocketAddrV6};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 use std::{
     collections::BTreeSet,
     net::{IpAddr, SocketAddr},
@@ -31,7 +31,7 @@ use iroh_relay::{
 #[cfg(not(wasm_browser))]
 use iroh_relay::{
     dns::{DnsError, DnsResolver, StaggeredError},
-    quic::QuicClient,
+    quic::{QUIC_ADDR_DISC_CLOSE_CODE, QUIC_ADDR_DISC_CLOSE_REASON, QuicClient},
 };
 use n0_error::{e, stack_error};
 #[cfg(wasm_browser)]
@@ -54,7 +54,10 @@ use super::{
     probes::{Probe, ProbePlan},
 };
 #[cfg(not(wasm_browser))]
-use super::{defaults::timeouts::DNS_TIMEOUT, ip_mapped_addrs::IpMappedAddresses};
+use super::{
+    defaults::timeouts::{DNS_TIMEOUT, HAIRPIN_TIMEOUT},
+    ip_mapped_addrs::IpMappedAddresses,
+};
 #[cfg(not(wasm_browser))]
 use crate::discovery::dns::DNS_STAGGERING_MS;
 use crate::{
@@ -108,6 +111,8 @@ impl From<netwatch::netmon::State> for IfStateDetails {
 pub(crate) struct SocketState {
     /// QUIC client to do QUIC address Discovery
     pub(crate) quic_client: Option<QuicClient>,
+    /// TLS client config for the hairpinning check, `None` if it is disabled.
+    pub(crate) hairpin_client_config: Option<rustls::ClientConfig>,
     /// The DNS resolver to use for probes that need to resolve DNS records.
     pub(crate) dns_resolver: DnsResolver,
     /// Optional [`IpMappedAddresses`] used to enable QAD in iroh
@@ -179,14 +184,18 @@ struct Actor {
 #[stack_error(derive, add_meta)]
 #[non_exhaustive]
 pub(super) enum ProbesError {
-    #[error("Probe failed")]
-    ProbeFailure { source: ProbeError },
+    #[error("Probe {probe} to {relay} failed")]
+    ProbeFailure {
+        probe: Probe,
+        relay: RelayUrl,
+        source: ProbeError,
+    },
     #[error("All probes failed")]
     AllProbesFailed,
     #[error("Probe cancelled")]
     Cancelled,
-    #[error("Probe timed out")]
-    Timeout,
+    #[error("Probe {probe} to {relay} timed out")]
+    Timeout { probe: Probe, relay: RelayUrl },
 }
 
 #[derive(Debug)]
@@ -377,6 +386,7 @@ impl Actor {
                         self.insecure_skip_relay_cert_verify,
                     ),
                 ));
+                let relay_url = relay.url.clone();
                 probes.spawn(
                     async move {
                         let res = fut.await;
@@ -384,9 +394,16 @@ impl Actor {
                             Some(Ok(Ok(report))) => Ok(report),
                             Some(Ok(Err(err))) => {
                                 warn!("probe failed: {:#}", err);
-                                Err(e!(ProbesError::ProbeFailure, err))
+                                Err(e!(ProbesError::ProbeFailure {
+                                    probe: proto,
+                                    relay: relay_url,
+                                    source: err
+                                }))
                             }
-                            Some(Err(time::Elapsed { .. })) => Err(e!(ProbesError::Timeout)),
+                            Some(Err(time::Elapsed { .. })) => Err(e!(ProbesError::Timeout {
+                                probe: proto,
+                                relay: relay_url
+                            })),
                             None => Err(e!(ProbesError::Cancelled)),
                         };
                         ProbeFinished::Regular(res)
@@ -432,6 +449,8 @@ impl ProbeReport {
 pub(super) struct QadProbeReport {
     /// The relay that was probed
     pub(super) relay: RelayUrl,
+    /// The address of the relay that was probed.
+    pub(super) relay_addr: SocketAddr,
     /// The latency to the relay.
     pub(super) latency: Duration,
     /// The discovered public address.
@@ -482,6 +501,35 @@ pub struct QuicConfig {
     pub ipv6: bool,
 }
 
+#[cfg(not(wasm_browser))]
+impl QuicConfig {
+    /// Binds a new QUIC endpoint for QUIC address discovery probes.
+    ///
+    /// This is for running net reports without an [`Endpoint`], see
+    /// [`Client::run_once`].  A dual-stack socket is used if possible, otherwise only IPv4
+    /// probes are enabled.  Relay servers are verified using the webpki root certificates.
+    ///
+    /// [`Endpoint`]: crate::Endpoint
+    /// [`Client::run_once`]: super::Client::run_once
+    pub fn bind() -> std::io::Result<Self> {
+        let (ep, ipv6) = match quinn::Endpoint::client(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))
+        {
+            Ok(ep) => (ep, true),
+            Err(err) => {
+                debug!("failed to bind dual-stack QAD socket, using IPv4 only: {err:#}");
+                let ep = quinn::Endpoint::client(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?;
+                (ep, false)
+            }
+        };
+        Ok(Self {
+            ep,
+            client_config: crate::magicsock::default_quic_client_config(),
+            ipv4: true,
+            ipv6,
+        })
+    }
+}
+
 impl Probe {
     /// Executes this particular [`Probe`], including using a delayed start if needed.
     async fn run(
@@ -634,6 +682,110 @@ async fn check_captive_portal(
     Ok(has_captive)
 }
 
+/// The ALPN used by the hairpinning check, connecting to ourselves.
+#[cfg(not(wasm_browser))]
+const HAIRPIN_ALPN: &[u8] = b"/iroh/net-report/hairpin/0";
+
+#[cfg(not(wasm_browser))]
+#[allow(missing_docs)]
+#[stack_error(derive, add_meta)]
+#[non_exhaustive]
+pub(super) enum HairpinError {
+    #[error("Failed to resolve relay address")]
+    GetRelayAddr { source: GetRelayAddrError },
+    #[error("Missing host in relay URL")]
+    MissingHost,
+    #[error("Failed to bind socket")]
+    Bind {
+        #[error(std_err)]
+        source: std::io::Error,
+    },
+    #[error("QUIC address discovery failed")]
+    Quic { source: iroh_relay::quic::Error },
+    #[error("No public address observed")]
+    NoObservedAddr,
+}
+
+/// Reports whether our NAT supports hairpinning, on IPv4.
+///
+/// This binds a fresh QUIC endpoint and learns its public address using QUIC address
+/// discovery with `relay`.  A second endpoint then connects to this public address, which
+/// only works if the NAT forwards packets sent to one of its own public addresses back
+/// into the local network.
+///
+/// The connection is attempted for [`HAIRPIN_TIMEOUT`], `false` is returned if it did not
+/// succeed by then.
+#[cfg(not(wasm_browser))]
+pub(super) async fn check_hairpinning(
+    dns_resolver: &DnsResolver,
+    relay: &RelayConfig,
+    client_config: rustls::ClientConfig,
+) -> Result<bool, HairpinError> {
+    let relay_addr = get_relay_addr_ipv4(dns_resolver, relay)
+        .await
+        .map_err(|source| e!(HairpinError::GetRelayAddr { source }))?;
+    let host = relay
+        .url
+        .host_str()
+        .ok_or_else(|| e!(HairpinError::MissingHost))?;
+
+    let secret_key = iroh_base::SecretKey::generate(&mut rand::rng());
+    let endpoint_id = secret_key.public();
+    let tls_config = crate::tls::TlsConfig::new(secret_key, 0);
+    let server_config = quinn::ServerConfig::with_crypto(Arc::new(
+        tls_config.make_server_config(vec![HAIRPIN_ALPN.to_vec()], false),
+    ));
+    let server = quinn::Endpoint::server(server_config, (Ipv4Addr::UNSPECIFIED, 0).into())
+        .map_err(|err| e!(HairpinError::Bind, err))?;
+
+    let qad_conn = QuicClient::new(server.clone(), client_config)
+        .create_conn(relay_addr.into(), host)
+        .await
+        .map_err(|source| e!(HairpinError::Quic { source }))?;
+    let public_addr = qad_conn
+        .observed_external_addr()
+        .wait_for(|addr| addr.is_some())
+        .await
+        .ok()
+        .and_then(|addr| *addr)
+        .map(|addr| SocketAddr::new(addr.ip().to_canonical(), addr.port()));
+    qad_conn.close(QUIC_ADDR_DISC_CLOSE_CODE, QUIC_ADDR_DISC_CLOSE_REASON);
+    let public_addr = public_addr.ok_or_else(|| e!(HairpinError::NoObservedAddr))?;
+    debug!(%public_addr, "checking hairpinning");
+
+    let client = quinn::Endpoint::client((Ipv4Addr::UNSPECIFIED, 0).into())
+        .map_err(|err| e!(HairpinError::Bind, err))?;
+    let client_config = quinn::ClientConfig::new(Arc::new(
+        tls_config.make_client_config(vec![HAIRPIN_ALPN.to_vec()], false),
+    ));
+    let accept = async {
+        let incoming = server.accept().await?;
+        incoming.await.ok()
+    };
+    let connect = async {
+        client
+            .connect_with(
+                client_config,
+                public_addr,
+                &crate::tls::name::encode(endpoint_id),
+            )
+            .ok()?
+            .await
+            .ok()
+    };
+    let works = match time::timeout(HAIRPIN_TIMEOUT, async { tokio::join!(accept, connect) }).await
+    {
+        Ok((_, Some(conn))) => {
+            conn.close(0u32.into(), b"");
+            true
+        }
+        Ok((_, None)) | Err(time::Elapsed { .. }) => false,
+    };
+    server.close(0u32.into(), b"");
+    client.close(0u32.into(), b"");
+    Ok(works)
+}
+
 /// Returns the proper port based on the protocol of the probe.
 #[cfg(not(wasm_browser))]
 fn get_quic_port(relay: &RelayConfig) -> Option<u16> {
@@ -899,4 +1051,19 @@ mod tests {
         server.shutdown().await?;
         Ok(())
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_check_hairpinning() -> Result {
+        let (server, relay) = test_utils::relay().await;
+        let client_config = iroh_relay::client::make_dangerous_client_config();
+        let dns_resolver = DnsResolver::default();
+
+        // Without a NAT our public address is local, which always works.
+        let works = check_hairpinning(&dns_resolver, &relay, client_config).await?;
+        assert!(works);
+
+        server.shutdown().await?;
+        Ok(())
+    }
 }
@@ -36,26 +36,41 @@
 //! }
 //! ```
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
     future::Future,
     pin::Pin,
     sync::{Arc, Mutex},
 };
 
-use iroh_base::EndpointId;
+use iroh_base::{EndpointId, PublicKey};
+use iroh_metrics::{Counter, MetricsGroup};
 use n0_error::{AnyError, e, stack_error};
 use n0_future::{
     join_all,
     task::{self, AbortOnDropHandle, JoinSet},
+    time::{self, Duration},
 };
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio_util::sync::CancellationToken;
-use tracing::{Instrument, error, field::Empty, info_span, trace, warn};
+use tracing::{Instrument, debug, error, field::Empty, info_span, trace, warn};
 
 use crate::{
     Endpoint,
-    endpoint::{Accepting, Connection, RemoteEndpointIdError},
+    endpoint::{Accepting, Connection, Incoming, RemoteEndpointIdError},
 };
 
+pub mod capability;
+pub mod layer;
+
+/// The reason sent to the remote when closing a connection which exceeds a limit of the
+/// [`Router`], see [`LimitPolicy::Refuse`].
+const LIMIT_EXCEEDED_REASON: &[u8] = b"connection limit exceeded";
+
+/// How long a connection waits for the ALPN or remote endpoint limit, see
+/// [`LimitPolicy::Queue`].
+const MAX_QUEUE_WAIT: Duration = Duration::from_secs(10);
+
 /// The built router.
 ///
 /// Construct this using [`Router::builder`].
@@ -92,6 +107,8 @@ pub struct Router {
     // `Router` needs to be `Clone + Send`, and we need to `task.await` in its `shutdown()` impl.
     task: Arc<Mutex<Option<AbortOnDropHandle<()>>>>,
     cancel_token: CancellationToken,
+    protocols: Arc<Mutex<ProtocolMap>>,
+    metrics: Arc<Metrics>,
 }
 
 /// Builder for creating a [`Router`] for accepting protocols.
@@ -99,6 +116,63 @@ pub struct Router {
 pub struct RouterBuilder {
     endpoint: Endpoint,
     protocols: ProtocolMap,
+    limits: ConnectionLimits,
+}
+
+/// Metrics of a [`Router`].
+#[derive(Debug, Serialize, Deserialize, MetricsGroup)]
+#[non_exhaustive]
+#[metrics(name = "router", default)]
+pub struct Metrics {
+    /// Number of connections rejected because the overall connection limit was reached.
+    pub rejected_max_connections: Counter,
+    /// Number of connections rejected because the connection limit of their ALPN was reached.
+    pub rejected_max_connections_per_alpn: Counter,
+    /// Number of connections rejected because the connection limit of their remote endpoint
+    /// was reached.
+    pub rejected_max_connections_per_endpoint: Counter,
+    /// Number of connections which had to wait for the ALPN or remote endpoint limit.
+    pub connections_queued: Counter,
+}
+
+/// What a [`Router`] does with connections exceeding one of its connection limits.
+///
+/// See [`RouterBuilder::max_connections`], [`RouterBuilder::max_connections_per_alpn`] and
+/// [`RouterBuilder::max_connections_per_endpoint`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LimitPolicy {
+    /// Rejects the connection.
+    ///
+    /// Connections over the overall limit are refused with [`Incoming::refuse`], before
+    /// the handshake.  The ALPN and the remote endpoint of a connection are only known
+    /// during the handshake.  Connections over the ALPN limit are closed as soon as the
+    /// ALPN is known, without finishing the handshake, those over the remote endpoint limit
+    /// once the handshake is done.  Both are closed with error code `0` and reason
+    /// `connection limit exceeded`.
+    #[default]
+    Refuse,
+    /// Holds the connection until it fits within the limits.
+    ///
+    /// While the overall limit is reached, the router stops accepting connections from the
+    /// endpoint, leaving them queued in the endpoint.  Connections over the ALPN or remote
+    /// endpoint limits are held during or after the handshake, before being passed to
+    /// [`ProtocolHandler::accept`].  They do not count against the overall limit while
+    /// held.
+    ///
+    /// A connection is held for at most 10 seconds, and at most as many connections of a
+    /// single remote endpoint are held as its limit allows to be handled.  Connections
+    /// exceeding these bounds are rejected as with [`LimitPolicy::Refuse`].
+    Queue,
+}
+
+/// The connection limits configured on a [`RouterBuilder`].
+#[derive(Debug, Default)]
+struct ConnectionLimits {
+    max_connections: Option<usize>,
+    max_connections_per_alpn: BTreeMap<Vec<u8>, usize>,
+    max_connections_per_endpoint: Option<usize>,
+    policy: LimitPolicy,
 }
 
 #[allow(missing_docs)]
@@ -117,6 +191,12 @@ pub enum AcceptError {
     MissingRemoteEndpointId { source: RemoteEndpointIdError },
     #[error("Not allowed.")]
     NotAllowed {},
+    #[error("Timed out")]
+    Timeout {},
+    #[error("Rate limited")]
+    RateLimited {},
+    #[error("Protocol handler panicked: {message}")]
+    Panicked { message: String },
     #[error(transparent)]
     User { source: AnyError },
 }
@@ -213,6 +293,18 @@ pub trait ProtocolHandler: Send + Sync + std::fmt::Debug + 'static {
     fn shutdown(&self) -> impl Future<Output = ()> + Send {
         async move {}
     }
+
+    /// Wraps this handler with a [`Layer`].
+    ///
+    /// See the [`layer`] module for the available layers.
+    ///
+    /// [`Layer`]: layer::Layer
+    fn layer<L: layer::Layer<Self>>(self, layer: L) -> L::Handler
+    where
+        Self: Sized,
+    {
+        layer.layer(self)
+    }
 }
 
 impl<T: ProtocolHandler> ProtocolHandler for Arc<T> {
@@ -303,30 +395,73 @@ impl<P: ProtocolHandler> DynProtocolHandler for P {
 
 /// A typed map of protocol handlers, mapping them from ALPNs.
 #[derive(Debug, Default)]
-pub(crate) struct ProtocolMap(BTreeMap<Vec<u8>, Box<dyn DynProtocolHandler>>);
+pub(crate) struct ProtocolMap {
+    handlers: BTreeMap<Vec<u8>, Arc<dyn DynProtocolHandler>>,
+    /// The registered ALPNs, in the order the endpoint prefers them.
+    alpns: Vec<Vec<u8>>,
+}
 
 impl ProtocolMap {
     /// Returns the registered protocol handler for an ALPN as a [`Arc<dyn ProtocolHandler>`].
-    pub(crate) fn get(&self, alpn: &[u8]) -> Option<&dyn DynProtocolHandler> {
-        self.0.get(alpn).map(|p| &**p)
+    pub(crate) fn get(&self, alpn: &[u8]) -> Option<Arc<dyn DynProtocolHandler>> {
+        self.handlers.get(alpn).cloned()
+    }
+
+    /// Inserts a protocol handler for all of `alpns`, returning the replaced handlers.
+    ///
+    /// The `alpns` are preferred in the given order, after all previously inserted ALPNs.
+    pub(crate) fn insert(
+        &mut self,
+        alpns: Vec<Vec<u8>>,
+        handler: Arc<dyn DynProtocolHandler>,
+    ) -> Vec<Arc<dyn DynProtocolHandler>> {
+        let mut replaced: Vec<Arc<dyn DynProtocolHandler>> = Vec::new();
+        for alpn in alpns {
+            self.alpns.retain(|a| a != &alpn);
+            self.alpns.push(alpn.clone());
+            if let Some(old) = self.handlers.insert(alpn, handler.clone()) {
+                if !replaced.iter().any(|h| Arc::ptr_eq(h, &old)) {
+                    replaced.push(old);
+                }
+            }
+        }
+        replaced
     }
 
-    /// Inserts a protocol handler.
-    pub(crate) fn insert(&mut self, alpn: Vec<u8>, handler: Box<dyn DynProtocolHandler>) {
-        self.0.insert(alpn, handler);
+    /// Removes and returns the protocol handler for an ALPN.
+    pub(crate) fn remove(&mut self, alpn: &[u8]) -> Option<Arc<dyn DynProtocolHandler>> {
+        self.alpns.retain(|a| a != alpn);
+        self.handlers.remove(alpn)
     }
 
-    /// Returns an iterator of all registered ALPN protocol identifiers.
+    /// Returns whether `handler` is still registered for any ALPN.
+    fn contains_handler(&self, handler: &Arc<dyn DynProtocolHandler>) -> bool {
+        self.handlers.values().any(|h| Arc::ptr_eq(h, handler))
+    }
+
+    /// Returns an iterator of all registered ALPN protocol identifiers, most preferred first.
     pub(crate) fn alpns(&self) -> impl Iterator<Item = &Vec<u8>> {
-        self.0.keys()
+        self.alpns.iter()
     }
 
     /// Shuts down all protocol handlers.
     ///
     /// Calls and awaits [`ProtocolHandler::shutdown`] for all registered handlers concurrently.
-    pub(crate) async fn shutdown(&self) {
-        let handlers = self.0.values().map(|p| p.shutdown());
-        join_all(handlers).await;
+    pub(crate) async fn shutdown(protocols: &Mutex<Self>) {
+        // Don't hold the lock while the handlers shut down.
+        let mut handlers: Vec<Arc<dyn DynProtocolHandler>> = Vec::new();
+        for handler in protocols.lock().expect("poisoned").handlers.values() {
+            // Handlers registered for several ALPNs are only shut down once.
+            if !handlers.iter().any(|h| Arc::ptr_eq(h, handler)) {
+                handlers.push(handler.clone());
+            }
+        }
+        join_all(handlers.iter().map(|p| p.shutdown())).await;
+    }
+
+    /// Updates the ALPNs accepted by the endpoint to the registered protocols.
+    fn apply_alpns(&self, endpoint: &Endpoint) {
+        endpoint.set_alpns(self.alpns().cloned().collect());
     }
 }
 
@@ -341,6 +476,75 @@ impl Router {
         &self.endpoint
     }
 
+    /// Registers the [`ProtocolHandler`] for `alpn` on the running router.
+    ///
+    /// The endpoint accepts connections for `alpn` from now on.  If a handler was
+    /// registered for `alpn` already, it is replaced and [`ProtocolHandler::shutdown`] is
+    /// called on the replaced handler.  Connections already passed to the replaced handler
+    /// are not affected.
+    ///
+    /// Has no effect if the router is shut down.
+    pub async fn add_protocol(
+        &self,
+        alpn: impl AsRef<[u8]>,
+        handler: impl Into<Box<dyn DynProtocolHandler>>,
+    ) {
+        self.add_protocol_versions([alpn], handler).await
+    }
+
+    /// Registers the [`ProtocolHandler`] for several versions of a protocol on the running
+    /// router.
+    ///
+    /// Like [`Self::add_protocol`], see [`RouterBuilder::accept_versions`] for details.
+    pub async fn add_protocol_versions(
+        &self,
+        alpns: impl IntoIterator<Item = impl AsRef<[u8]>>,
+        handler: impl Into<Box<dyn DynProtocolHandler>>,
+    ) {
+        if self.is_shutdown() {
+            return;
+        }
+        let alpns = alpns.into_iter().map(|a| a.as_ref().to_vec()).collect();
+        let unused = {
+            let mut protocols = self.protocols.lock().expect("poisoned");
+            let replaced = protocols.insert(alpns, handler.into().into());
+            protocols.apply_alpns(&self.endpoint);
+            replaced
+                .into_iter()
+                .filter(|handler| !protocols.contains_handler(handler))
+                .collect::<Vec<_>>()
+        };
+        join_all(unused.iter().map(|handler| handler.shutdown())).await;
+    }
+
+    /// Unregisters the [`ProtocolHandler`] for `alpn` from the running router.
+    ///
+    /// The endpoint no longer accepts connections for `alpn`, and
+    /// [`ProtocolHandler::shutdown`] is called on the removed handler, unless it is still
+    /// registered for other versions of its protocol.  Connections already passed to the
+    /// handler are not affected.
+    ///
+    /// Returns `false` if no handler was registered for `alpn`.
+    pub async fn remove_protocol(&self, alpn: impl AsRef<[u8]>) -> bool {
+        let removed = {
+            let mut protocols = self.protocols.lock().expect("poisoned");
+            let Some(removed) = protocols.remove(alpn.as_ref()) else {
+                return false;
+            };
+            protocols.apply_alpns(&self.endpoint);
+            (!protocols.contains_handler(&removed)).then_some(removed)
+        };
+        if let Some(handler) = removed {
+            handler.shutdown().await;
+        }
+        true
+    }
+
+    /// Returns the metrics of this router.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
     /// Checks if the router is already shutdown.
     pub fn is_shutdown(&self) -> bool {
         self.cancel_token.is_cancelled()
@@ -381,6 +585,7 @@ impl RouterBuilder {
         Self {
             endpoint,
             protocols: ProtocolMap::default(),
+            limits: ConnectionLimits::default(),
         }
     }
 
@@ -392,12 +597,69 @@ impl RouterBuilder {
     ///
     /// [`Box<dyn DynProtocolHandler>`]: DynProtocolHandler
     pub fn accept(
-        mut self,
+        self,
         alpn: impl AsRef<[u8]>,
         handler: impl Into<Box<dyn DynProtocolHandler>>,
     ) -> Self {
-        self.protocols
-            .insert(alpn.as_ref().to_vec(), handler.into());
+        self.accept_versions([alpn], handler)
+    }
+
+    /// Configures the router to accept the [`ProtocolHandler`] for several versions of a
+    /// protocol, e.g. `/my/proto/2` and `/my/proto/1`.
+    ///
+    /// The `alpns` are listed in order of preference: when the connecting side offers
+    /// several of them, e.g. using [`Endpoint::connect_with_alpns`], the first one in this
+    /// list is negotiated.  The handler learns the negotiated version from
+    /// [`Connection::alpn`].
+    ///
+    /// All versions need to be listed explicitly, as the ALPN is negotiated during the TLS
+    /// handshake against the exact identifiers accepted by the endpoint.
+    pub fn accept_versions(
+        mut self,
+        alpns: impl IntoIterator<Item = impl AsRef<[u8]>>,
+        handler: impl Into<Box<dyn DynProtocolHandler>>,
+    ) -> Self {
+        let alpns = alpns.into_iter().map(|a| a.as_ref().to_vec()).collect();
+        self.protocols.insert(alpns, handler.into().into());
+        self
+    }
+
+    /// Sets the maximum number of connections handled concurrently.
+    ///
+    /// A connection counts against the limits from the moment it is accepted until
+    /// [`ProtocolHandler::accept`] returns.  What happens to connections over the limit is
+    /// set with [`Self::limit_policy`].
+    ///
+    /// By default the number of connections is not limited.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.limits.max_connections = Some(max);
+        self
+    }
+
+    /// Sets the maximum number of connections handled concurrently for this `alpn`.
+    ///
+    /// See [`Self::max_connections`].
+    pub fn max_connections_per_alpn(mut self, alpn: impl AsRef<[u8]>, max: usize) -> Self {
+        self.limits
+            .max_connections_per_alpn
+            .insert(alpn.as_ref().to_vec(), max);
+        self
+    }
+
+    /// Sets the maximum number of connections handled concurrently for a single remote
+    /// endpoint, across all ALPNs.
+    ///
+    /// See [`Self::max_connections`].
+    pub fn max_connections_per_endpoint(mut self, max: usize) -> Self {
+        self.limits.max_connections_per_endpoint = Some(max);
+        self
+    }
+
+    /// Sets what happens to connections exceeding one of the connection limits.
+    ///
+    /// Defaults to [`LimitPolicy::Refuse`].
+    pub fn limit_policy(mut self, policy: LimitPolicy) -> Self {
+        self.limits.policy = policy;
         self
     }
 
@@ -409,14 +671,11 @@ impl RouterBuilder {
     /// Spawns an accept loop and returns a handle to it encapsulated as the [`Router`].
     pub fn spawn(self) -> Router {
         // Update the endpoint with our alpns.
-        let alpns = self
-            .protocols
-            .alpns()
-            .map(|alpn| alpn.to_vec())
-            .collect::<Vec<_>>();
-
-        let protocols = Arc::new(self.protocols);
-        self.endpoint.set_alpns(alpns);
+        self.protocols.apply_alpns(&self.endpoint);
+        let protocols = Arc::new(Mutex::new(self.protocols));
+        let router_protocols = protocols.clone();
+        let metrics = Arc::new(Metrics::default());
+        let limiter = Arc::new(Limiter::new(self.limits, metrics.clone()));
 
         let mut join_set = JoinSet::new();
         let endpoint = self.endpoint.clone();
@@ -462,23 +721,38 @@ impl RouterBuilder {
                     },
 
                     // handle incoming p2p connections.
-                    incoming = endpoint.accept() => {
+                    // When queueing, we only accept once another connection released its permit.
+                    (incoming, queued_permit) = async {
+                        let permit = limiter.acquire_total_queued().await;
+                        (endpoint.accept().await, permit)
+                    } => {
                         let Some(incoming) = incoming else {
                             break; // Endpoint is closed.
                         };
+                        let permit = match queued_permit {
+                            Some(permit) => Some(Some(permit)),
+                            None => limiter.try_acquire_total(),
+                        };
+                        let Some(permit) = permit else {
+                            debug!(remote_addr = %incoming.remote_address(), "Refusing connection: connection limit reached");
+                            limiter.metrics.rejected_max_connections.inc();
+                            incoming.refuse();
+                            continue;
+                        };
 
                         let protocols = protocols.clone();
+                        let limiter = limiter.clone();
                         let token = handler_cancel_token.child_token();
                         let span = info_span!("router.accept", me=%endpoint.id().fmt_short(), remote=Empty, alpn=Empty);
                         join_set.spawn(async move {
-                            token.run_until_cancelled(handle_connection(incoming, protocols)).await
+                            token.run_until_cancelled(handle_connection(incoming, protocols, limiter, permit)).await
                         }.instrument(span));
                     },
                 }
             }
 
             // We first shutdown the protocol handlers to give them a chance to close connections gracefully.
-            protocols.shutdown().await;
+            ProtocolMap::shutdown(&protocols).await;
             // We now cancel the remaining `ProtocolHandler::accept` futures.
             handler_cancel_token.cancel();
             // Now we close the endpoint. This will force-close all connections that are not yet closed.
@@ -501,11 +775,18 @@ impl RouterBuilder {
             endpoint: self.endpoint,
             task: Arc::new(Mutex::new(Some(task))),
             cancel_token: cancel,
+            protocols: router_protocols,
+            metrics,
         }
     }
 }
 
-async fn handle_connection(incoming: crate::endpoint::Incoming, protocols: Arc<ProtocolMap>) {
+async fn handle_connection(
+    incoming: Incoming,
+    protocols: Arc<Mutex<ProtocolMap>>,
+    limiter: Arc<Limiter>,
+    mut total_permit: Option<OwnedSemaphorePermit>,
+) {
     let mut accepting = match incoming.accept() {
         Ok(conn) => conn,
         Err(err) => {
@@ -521,16 +802,33 @@ async fn handle_connection(incoming: crate::endpoint::Incoming, protocols: Arc<P
         }
     };
     tracing::Span::current().record("alpn", String::from_utf8_lossy(&alpn).to_string());
-    let Some(handler) = protocols.get(&alpn) else {
+    let handler = protocols.lock().expect("poisoned").get(&alpn);
+    let Some(handler) = handler else {
         warn!("Ignoring connection: unsupported ALPN protocol");
         return;
     };
+    let Some(_alpn_permit) = limiter.acquire_alpn(&alpn, &mut total_permit).await else {
+        debug!("Rejecting connection: ALPN connection limit reached");
+        limiter.metrics.rejected_max_connections_per_alpn.inc();
+        // Closes the connection without waiting for the handshake to finish.
+        accepting
+            .into_0rtt()
+            .close(0u32.into(), LIMIT_EXCEEDED_REASON);
+        return;
+    };
     match handler.on_accepting(accepting).await {
         Ok(connection) => {
-            tracing::Span::current().record(
-                "remote",
-                tracing::field::display(connection.remote_id().fmt_short()),
-            );
+            let remote_id = connection.remote_id();
+            tracing::Span::current()
+                .record("remote", tracing::field::display(remote_id.fmt_short()));
+            let Some(_endpoint_permit) =
+                limiter.acquire_endpoint(remote_id, &mut total_permit).await
+            else {
+                debug!("Rejecting connection: endpoint connection limit reached");
+                limiter.metrics.rejected_max_connections_per_endpoint.inc();
+                connection.close(0u32.into(), LIMIT_EXCEEDED_REASON);
+                return;
+            };
             if let Err(err) = handler.accept(connection).await {
                 warn!("Handling incoming connection ended with error: {err}");
             }
@@ -541,15 +839,216 @@ async fn handle_connection(incoming: crate::endpoint::Incoming, protocols: Arc<P
     }
 }
 
+/// Enforces the [`ConnectionLimits`] of a [`Router`].
+#[derive(Debug)]
+struct Limiter {
+    policy: LimitPolicy,
+    total: Option<Arc<Semaphore>>,
+    per_alpn: BTreeMap<Vec<u8>, Arc<Semaphore>>,
+    per_endpoint: Option<Arc<EndpointLimiter>>,
+    metrics: Arc<Metrics>,
+}
+
+/// Tracks the connections of each remote endpoint.
+///
+/// Only endpoints with active or waiting connections have an entry.
+#[derive(Debug)]
+struct EndpointLimiter {
+    max: usize,
+    active: Mutex<HashMap<EndpointId, Arc<EndpointSlots>>>,
+}
+
+/// The connections of a single remote endpoint in the [`EndpointLimiter`].
+#[derive(Debug)]
+struct EndpointSlots {
+    /// Permits for the connections being handled.
+    connections: Arc<Semaphore>,
+    /// Permits for the connections waiting for one of `connections`.
+    queue: Arc<Semaphore>,
+}
+
+/// A slot of a remote endpoint in the [`EndpointLimiter`].
+///
+/// Removes the entry of the endpoint once no connection uses it anymore.
+#[derive(Debug)]
+struct EndpointPermit {
+    endpoint_id: EndpointId,
+    slots: Option<Arc<EndpointSlots>>,
+    permit: Option<OwnedSemaphorePermit>,
+    limiter: Arc<EndpointLimiter>,
+}
+
+impl Drop for EndpointPermit {
+    fn drop(&mut self) {
+        let mut active = self.limiter.active.lock().expect("poisoned");
+        self.permit.take();
+        self.slots.take();
+        if active
+            .get(&self.endpoint_id)
+            .is_some_and(|slots| Arc::strong_count(slots) == 1)
+        {
+            active.remove(&self.endpoint_id);
+        }
+    }
+}
+
+impl Limiter {
+    fn new(limits: ConnectionLimits, metrics: Arc<Metrics>) -> Self {
+        Self {
+            policy: limits.policy,
+            total: limits
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            per_alpn: limits
+                .max_connections_per_alpn
+                .into_iter()
+                .map(|(alpn, max)| (alpn, Arc::new(Semaphore::new(max))))
+                .collect(),
+            per_endpoint: limits.max_connections_per_endpoint.map(|max| {
+                Arc::new(EndpointLimiter {
+                    max,
+                    active: Default::default(),
+                })
+            }),
+            metrics,
+        }
+    }
+
+    /// Waits for a permit of the overall limit when queueing.
+    ///
+    /// The accept loop only takes the next incoming connection once it has the permit.
+    /// Returns `None` if connections are not queued or not limited, see
+    /// [`Self::try_acquire_total`].
+    async fn acquire_total_queued(&self) -> Option<OwnedSemaphorePermit> {
+        match (&self.total, self.policy) {
+            // The semaphores are never closed.
+            (Some(total), LimitPolicy::Queue) => total.clone().acquire_owned().await.ok(),
+            _ => None,
+        }
+    }
+
+    /// Acquires a permit of the overall limit, or returns `None` if it is reached.
+    fn try_acquire_total(&self) -> Option<Option<OwnedSemaphorePermit>> {
+        match &self.total {
+            Some(total) => total.clone().try_acquire_owned().ok().map(Some),
+            None => Some(None),
+        }
+    }
+
+    /// Acquires a permit of the limit for `alpn`.
+    ///
+    /// Returns `None` if the limit is reached and the connection is rejected.  See
+    /// [`Self::acquire`] for `total_permit`.
+    async fn acquire_alpn(
+        &self,
+        alpn: &[u8],
+        total_permit: &mut Option<OwnedSemaphorePermit>,
+    ) -> Option<Option<OwnedSemaphorePermit>> {
+        match self.per_alpn.get(alpn) {
+            Some(semaphore) => self.acquire(semaphore, None, total_permit).await.map(Some),
+            None => Some(None),
+        }
+    }
+
+    /// Acquires a permit of the limit for the remote endpoint.
+    ///
+    /// Returns `None` if the limit is reached and the connection is rejected.  See
+    /// [`Self::acquire`] for `total_permit`.
+    async fn acquire_endpoint(
+        &self,
+        endpoint_id: EndpointId,
+        total_permit: &mut Option<OwnedSemaphorePermit>,
+    ) -> Option<Option<EndpointPermit>> {
+        let Some(limiter) = &self.per_endpoint else {
+            return Some(None);
+        };
+        let slots = limiter
+            .active
+            .lock()
+            .expect("poisoned")
+            .entry(endpoint_id)
+            .or_insert_with(|| {
+                Arc::new(EndpointSlots {
+                    connections: Arc::new(Semaphore::new(limiter.max)),
+                    queue: Arc::new(Semaphore::new(limiter.max)),
+                })
+            })
+            .clone();
+        // Holds the only extra reference to the slots, so that dropping it on refusal
+        // cleans up the entry.
+        let mut permit = EndpointPermit {
+            endpoint_id,
+            slots: Some(slots),
+            permit: None,
+            limiter: limiter.clone(),
+        };
+        let slots = permit.slots.as_ref().expect("just set");
+        permit.permit = Some(
+            self.acquire(&slots.connections, Some(&slots.queue), total_permit)
+                .await?,
+        );
+        Some(Some(permit))
+    }
+
+    /// Acquires a permit of `semaphore`, waiting for it if the policy is to queue.
+    ///
+    /// Waiting requires a permit of `queue`, if given, and is bounded by
+    /// [`MAX_QUEUE_WAIT`].  The `total_permit` of the overall limit is released while
+    /// waiting, so that other connections can be handled, and acquired again afterwards.
+    async fn acquire(
+        &self,
+        semaphore: &Arc<Semaphore>,
+        queue: Option<&Semaphore>,
+        total_permit: &mut Option<OwnedSemaphorePermit>,
+    ) -> Option<OwnedSemaphorePermit> {
+        match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) if self.policy == LimitPolicy::Queue => {
+                let _queued = match queue {
+                    Some(queue) => Some(queue.try_acquire().ok()?),
+                    None => None,
+                };
+                self.metrics.connections_queued.inc();
+                let total = total_permit.take().and(self.total.as_ref());
+                let wait = async {
+                    // The semaphores are never closed.
+                    let permit = semaphore.clone().acquire_owned().await.ok()?;
+                    if let Some(total) = total {
+                        *total_permit = Some(total.clone().acquire_owned().await.ok()?);
+                    }
+                    Some(permit)
+                };
+                time::timeout(MAX_QUEUE_WAIT, wait).await.ok().flatten()
+            }
+            Err(_) => None,
+        }
+    }
+}
+
 /// Wraps an existing protocol, limiting its access,
 /// based on the provided function.
 ///
+/// The function is either synchronous, see [`AccessLimit::new`], or asynchronous, see
+/// [`AccessLimit::new_async`].  [`AccessLimit::with_capability_tokens`] only allows
+/// endpoints presenting a valid [`CapabilityToken`].
+///
 /// Any refused connection will be closed with an error code of `0` and reason `not allowed`.
+///
+/// [`CapabilityToken`]: capability::CapabilityToken
 #[derive(derive_more::Debug, Clone)]
 pub struct AccessLimit<P: ProtocolHandler + Clone> {
     proto: P,
     #[debug("limiter")]
-    limiter: Arc<dyn Fn(EndpointId) -> bool + Send + Sync + 'static>,
+    limiter: Limit,
+}
+
+/// The future returned by an asynchronous [`AccessLimit`] function.
+type BoxedAuthorization = Pin<Box<dyn Future<Output = bool> + Send + 'static>>;
+
+#[derive(Clone)]
+enum Limit {
+    Sync(Arc<dyn Fn(EndpointId) -> bool + Send + Sync + 'static>),
+    Async(Arc<dyn Fn(Connection) -> BoxedAuthorization + Send + Sync + 'static>),
 }
 
 impl<P: ProtocolHandler + Clone> AccessLimit<P> {
@@ -563,9 +1062,51 @@ impl<P: ProtocolHandler + Clone> AccessLimit<P> {
     {
         Self {
             proto,
-            limiter: Arc::new(limiter),
+            limiter: Limit::Sync(Arc::new(limiter)),
+        }
+    }
+
+    /// Create a new `AccessLimit` with an asynchronous function.
+    ///
+    /// The function is called with a clone of each incoming connection, before it is passed
+    /// to the wrapped protocol.  Its future should resolve to `true` for connections that
+    /// are allowed, and `false` otherwise.  Besides consulting e.g. a database about the
+    /// remote endpoint, it can read credentials from a stream of the connection.
+    pub fn new_async<F, Fut>(proto: P, limiter: F) -> Self
+    where
+        F: Fn(Connection) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        Self {
+            proto,
+            limiter: Limit::Async(Arc::new(move |conn| Box::pin(limiter(conn)))),
         }
     }
+
+    /// Create a new `AccessLimit` only allowing endpoints with a [`CapabilityToken`].
+    ///
+    /// The remote must present a token issued by one of the `issuers`, for itself and the
+    /// ALPN of the connection, with [`CapabilityToken::present`].  The token is read from
+    /// the first unidirectional stream of the connection, which is not passed on to the
+    /// wrapped protocol.
+    ///
+    /// [`CapabilityToken`]: capability::CapabilityToken
+    /// [`CapabilityToken::present`]: capability::CapabilityToken::present
+    pub fn with_capability_tokens(proto: P, issuers: impl IntoIterator<Item = PublicKey>) -> Self {
+        let issuers: Arc<[PublicKey]> = issuers.into_iter().collect();
+        Self::new_async(proto, move |conn| {
+            let issuers = issuers.clone();
+            async move {
+                match capability::authorize(&conn, &issuers).await {
+                    Ok(_) => true,
+                    Err(err) => {
+                        debug!("Rejecting capability token: {err:#}");
+                        false
+                    }
+                }
+            }
+        })
+    }
 }
 
 impl<P: ProtocolHandler + Clone> ProtocolHandler for AccessLimit<P> {
@@ -577,8 +1118,10 @@ impl<P: ProtocolHandler + Clone> ProtocolHandler for AccessLimit<P> {
     }
 
     async fn accept(&self, conn: Connection) -> Result<(), AcceptError> {
-        let remote = conn.remote_id();
-        let is_allowed = (self.limiter)(remote);
+        let is_allowed = match &self.limiter {
+            Limit::Sync(limiter) => limiter(conn.remote_id()),
+            Limit::Async(limiter) => limiter(conn.clone()).await,
+        };
         if !is_allowed {
             conn.close(0u32.into(), b"not allowed");
             return Err(e!(AcceptError::NotAllowed));
@@ -594,13 +1137,19 @@ impl<P: ProtocolHandler + Clone> ProtocolHandler for AccessLimit<P> {
 
 #[cfg(test)]
 mod tests {
-    use std::{sync::Mutex, time::Duration};
+    use std::{
+        sync::{
+            Mutex,
+            atomic::{AtomicBool, Ordering},
+        },
+        time::Duration,
+    };
 
     use n0_error::{Result, StdResultExt};
     use quinn::ApplicationClose;
 
     use super::*;
-    use crate::{RelayMode, endpoint::ConnectionError};
+    use crate::{EndpointAddr, RelayMode, endpoint::ConnectionError};
 
     #[tokio::test]
     async fn test_shutdown() -> Result {
@@ -619,7 +1168,7 @@ mod tests {
     }
 
     // The protocol definition:
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, Default)]
     struct Echo;
 
     const ECHO_ALPN: &[u8] = b"/iroh/echo/1";
@@ -664,6 +1213,350 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_max_connections_per_endpoint() -> Result {
+        let e1 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .max_connections_per_endpoint(1)
+            .spawn();
+        let addr1 = r1.endpoint().addr();
+        let e2 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+
+        let conn1 = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let (mut send, mut recv) = conn1.open_bi().await.anyerr()?;
+        send.write_all(b"hello").await.anyerr()?;
+        send.finish().anyerr()?;
+        assert_eq!(recv.read_to_end(100).await.anyerr()?, b"hello");
+
+        // The first connection is still being handled, the second one is rejected.
+        let conn2 = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let reason = conn2.closed().await;
+        assert!(format!("{reason:?}").contains("connection limit exceeded"));
+        assert_eq!(r1.metrics().rejected_max_connections_per_endpoint.get(), 1);
+
+        // Once the first connection is done, the remote can connect again.
+        conn1.close(0u32.into(), b"done");
+        conn1.closed().await;
+        let conn3 = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+                let (mut send, mut recv) = conn.open_bi().await.anyerr()?;
+                send.write_all(b"again").await.anyerr()?;
+                send.finish().anyerr()?;
+                if let Ok(data) = recv.read_to_end(100).await {
+                    assert_eq!(data, b"again");
+                    break n0_error::Ok(conn);
+                }
+            }
+        })
+        .await
+        .anyerr()??;
+
+        conn3.close(0u32.into(), b"done");
+        r1.shutdown().await.anyerr()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_queue() -> Result {
+        let e1 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .max_connections(1)
+            .limit_policy(LimitPolicy::Queue)
+            .spawn();
+        let addr1 = r1.endpoint().addr();
+        let e2 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+
+        let conn1 = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let (mut send1, mut recv1) = conn1.open_bi().await.anyerr()?;
+        send1.write_all(b"first").await.anyerr()?;
+        send1.finish().anyerr()?;
+        assert_eq!(recv1.read_to_end(100).await.anyerr()?, b"first");
+
+        // The second connection is only accepted once the first one is closed.
+        let second = tokio::spawn({
+            let e2 = e2.clone();
+            async move {
+                let conn = e2.connect(addr1, ECHO_ALPN).await?;
+                let (mut send, mut recv) = conn.open_bi().await.anyerr()?;
+                send.write_all(b"second").await.anyerr()?;
+                send.finish().anyerr()?;
+                let data = recv.read_to_end(100).await.anyerr()?;
+                conn.close(0u32.into(), b"done");
+                n0_error::Ok(data)
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!second.is_finished());
+
+        conn1.close(0u32.into(), b"done");
+        let data = tokio::time::timeout(Duration::from_secs(5), second)
+            .await
+            .anyerr()?
+            .anyerr()??;
+        assert_eq!(data, b"second");
+        assert_eq!(r1.metrics().rejected_max_connections.get(), 0);
+
+        r1.shutdown().await.anyerr()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_per_alpn() -> Result {
+        let e1 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .max_connections_per_alpn(ECHO_ALPN, 1)
+            .spawn();
+        let addr1 = r1.endpoint().addr();
+        let e2 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+
+        let conn1 = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let (mut send, mut recv) = conn1.open_bi().await.anyerr()?;
+        send.write_all(b"hello").await.anyerr()?;
+        send.finish().anyerr()?;
+        assert_eq!(recv.read_to_end(100).await.anyerr()?, b"hello");
+
+        // The second connection is closed during the handshake, it may still complete on
+        // our side before the close arrives.
+        if let Ok(conn2) = e2.connect(addr1, ECHO_ALPN).await {
+            tokio::time::timeout(Duration::from_secs(5), conn2.closed())
+                .await
+                .anyerr()?;
+        }
+        assert_eq!(r1.metrics().rejected_max_connections_per_alpn.get(), 1);
+
+        conn1.close(0u32.into(), b"done");
+        r1.shutdown().await.anyerr()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_per_endpoint_queue() -> Result {
+        let e1 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .max_connections(2)
+            .max_connections_per_endpoint(1)
+            .limit_policy(LimitPolicy::Queue)
+            .spawn();
+        let addr1 = r1.endpoint().addr();
+        let e2 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let e3 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+
+        async fn echo(conn: &Connection, data: &[u8]) -> Result<Vec<u8>> {
+            let (mut send, mut recv) = conn.open_bi().await.anyerr()?;
+            send.write_all(data).await.anyerr()?;
+            send.finish().anyerr()?;
+            recv.read_to_end(100).await.anyerr()
+        }
+
+        let conn1 = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        assert_eq!(echo(&conn1, b"first").await?, b"first");
+
+        // The second connection waits for the first one.
+        let second = tokio::spawn({
+            let e2 = e2.clone();
+            let addr1 = addr1.clone();
+            async move {
+                let conn = e2.connect(addr1, ECHO_ALPN).await?;
+                let data = echo(&conn, b"second").await?;
+                conn.close(0u32.into(), b"done");
+                n0_error::Ok(data)
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!second.is_finished());
+
+        // The queue of the remote endpoint is full, the third connection is rejected.
+        let conn3 = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let reason = conn3.closed().await;
+        assert!(format!("{reason:?}").contains("connection limit exceeded"));
+        assert_eq!(r1.metrics().rejected_max_connections_per_endpoint.get(), 1);
+
+        // The waiting connection does not count against the overall limit.
+        let conn4 = e3.connect(addr1, ECHO_ALPN).await?;
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(5), echo(&conn4, b"other"))
+                .await
+                .anyerr()??,
+            b"other"
+        );
+        conn4.close(0u32.into(), b"done");
+
+        conn1.close(0u32.into(), b"done");
+        let data = tokio::time::timeout(Duration::from_secs(5), second)
+            .await
+            .anyerr()?
+            .anyerr()??;
+        assert_eq!(data, b"second");
+
+        r1.shutdown().await.anyerr()?;
+        e2.close().await;
+        e3.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_remove_protocol() -> Result {
+        #[derive(Debug, Clone, Default)]
+        struct Tracked {
+            echo: Echo,
+            shutdown: Arc<AtomicBool>,
+        }
+
+        impl ProtocolHandler for Tracked {
+            async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+                ProtocolHandler::accept(&self.echo, connection).await
+            }
+
+            async fn shutdown(&self) {
+                self.shutdown.store(true, Ordering::Relaxed);
+            }
+        }
+
+        let e1 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let r1 = Router::builder(e1).spawn();
+        let addr1 = r1.endpoint().addr();
+        let e2 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+
+        // Not registered yet.
+        assert!(e2.connect(addr1.clone(), ECHO_ALPN).await.is_err());
+
+        let handler = Tracked::default();
+        r1.add_protocol(ECHO_ALPN, handler.clone()).await;
+        let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await.anyerr()?;
+        send.write_all(b"hello").await.anyerr()?;
+        send.finish().anyerr()?;
+        assert_eq!(recv.read_to_end(100).await.anyerr()?, b"hello");
+        conn.close(0u32.into(), b"done");
+
+        assert!(r1.remove_protocol(ECHO_ALPN).await);
+        assert!(handler.shutdown.load(Ordering::Relaxed));
+        assert!(!r1.remove_protocol(ECHO_ALPN).await);
+        assert!(e2.connect(addr1, ECHO_ALPN).await.is_err());
+
+        r1.shutdown().await.anyerr()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_versions() -> Result {
+        /// Replies with the negotiated ALPN.
+        #[derive(Debug, Clone, Default)]
+        struct Version {
+            shutdown: Arc<AtomicBool>,
+        }
+
+        impl ProtocolHandler for Version {
+            async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+                let mut send = connection.open_uni().await?;
+                send.write_all(connection.alpn())
+                    .await
+                    .map_err(AcceptError::from_err)?;
+                send.finish()?;
+                connection.closed().await;
+                Ok(())
+            }
+
+            async fn shutdown(&self) {
+                self.shutdown.store(true, Ordering::Relaxed);
+            }
+        }
+
+        const V1: &[u8] = b"/iroh/version/1";
+        const V2: &[u8] = b"/iroh/version/2";
+        const V3: &[u8] = b"/iroh/version/3";
+
+        let handler = Version::default();
+        let e1 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .accept_versions([V2, V1], handler.clone())
+            .spawn();
+        let addr1 = r1.endpoint().addr();
+        let e2 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+
+        async fn negotiate(e2: &Endpoint, addr: EndpointAddr, alpns: &[&[u8]]) -> Result<Vec<u8>> {
+            let conn = e2
+                .connect_with_alpns(addr, alpns, Default::default())
+                .await?;
+            let mut recv = conn.accept_uni().await.anyerr()?;
+            let alpn = recv.read_to_end(100).await.anyerr()?;
+            assert_eq!(alpn, conn.alpn());
+            conn.close(0u32.into(), b"done");
+            Ok(alpn)
+        }
+
+        // The router's preference wins.
+        assert_eq!(negotiate(&e2, addr1.clone(), &[V3, V1, V2]).await?, V2);
+        assert_eq!(negotiate(&e2, addr1.clone(), &[V3, V1]).await?, V1);
+        assert!(
+            e2.connect_with_alpns(addr1.clone(), &[V3], Default::default())
+                .await
+                .is_err()
+        );
+        assert!(matches!(
+            e2.connect_with_alpns(addr1.clone(), &[], Default::default())
+                .await,
+            Err(crate::endpoint::ConnectError::NoAlpn { .. })
+        ));
+
+        // The handler is only shut down once it is removed for all versions.
+        assert!(r1.remove_protocol(V2).await);
+        assert!(!handler.shutdown.load(Ordering::Relaxed));
+        assert_eq!(negotiate(&e2, addr1.clone(), &[V2, V1]).await?, V1);
+        assert!(r1.remove_protocol(V1).await);
+        assert!(handler.shutdown.load(Ordering::Relaxed));
+
+        r1.shutdown().await.anyerr()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_capability_tokens() -> Result {
+        use n0_future::time::SystemTime;
+
+        use crate::{SecretKey, protocol::capability::CapabilityToken};
+
+        let issuer = SecretKey::generate(&mut rand::rng());
+        let e1 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let proto = AccessLimit::with_capability_tokens(Echo, [issuer.public()]);
+        let r1 = Router::builder(e1).accept(ECHO_ALPN, proto).spawn();
+        let addr1 = r1.endpoint().addr();
+        let e2 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+
+        // A valid token grants access.
+        let token = CapabilityToken::issue(&issuer, e2.id(), ECHO_ALPN, expires_at);
+        let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        token.present(&conn).await?;
+        let (mut send, mut recv) = conn.open_bi().await.anyerr()?;
+        send.write_all(b"hello").await.anyerr()?;
+        send.finish().anyerr()?;
+        assert_eq!(recv.read_to_end(100).await.anyerr()?, b"hello");
+        conn.close(0u32.into(), b"done");
+
+        // A token for another ALPN is refused.
+        let token = CapabilityToken::issue(&issuer, e2.id(), b"/iroh/other/1", expires_at);
+        let conn = e2.connect(addr1, ECHO_ALPN).await?;
+        token.present(&conn).await?;
+        let reason = conn.closed().await;
+        assert!(format!("{reason:?}").contains("not allowed"));
+
+        r1.shutdown().await.anyerr()?;
+        e2.close().await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_graceful_shutdown() -> Result {
         #[derive(Debug, Clone, Default)]
@@ -0,0 +1,325 @@
+//! Capability tokens granting access to a protocol.
+//!
+//! A [`CapabilityToken`] is issued by the holder of an issuer [`SecretKey`].  It grants a
+//! single remote endpoint access to the protocol with a given ALPN, until it expires.
+//!
+//! The remote presents the token with [`CapabilityToken::present`] on the first
+//! unidirectional stream of the connection.  The accepting side wraps its protocol handler
+//! with [`AccessLimit::with_capability_tokens`], which verifies the token before passing
+//! the connection to the wrapped handler.
+//!
+//! [`AccessLimit::with_capability_tokens`]: super::AccessLimit::with_capability_tokens
+
+use iroh_base::{EndpointId, KeyParsingError, PublicKey, SecretKey, Signature, SignatureError};
+use n0_error::{e, ensure, stack_error};
+use n0_future::time::{self, Duration, SystemTime};
+
+use crate::endpoint::{ClosedStream, Connection, ConnectionError, ReadToEndError, WriteError};
+
+/// Prefix of the signed message, to not confuse token signatures with other signatures.
+const SIGNATURE_DOMAIN: &[u8] = b"iroh-capability-token-v1";
+
+/// The length of a token without its ALPN.
+const FIXED_LEN: usize = PublicKey::LENGTH * 2 + 8 + Signature::LENGTH;
+
+/// The maximum length of an encoded token, ALPNs are at most 255 bytes long.
+const MAX_LEN: usize = FIXED_LEN + 255;
+
+/// How long the accepting side waits for the remote to present its token.
+const PRESENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A token granting an endpoint access to a protocol, signed by an issuer.
+///
+/// See the [module documentation](self) for how tokens are used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapabilityToken {
+    issuer: PublicKey,
+    subject: EndpointId,
+    alpn: Vec<u8>,
+    /// Expiry, in seconds since the UNIX epoch.
+    expires_at: u64,
+    signature: Signature,
+}
+
+/// Errors of decoding or verifying a [`CapabilityToken`].
+#[allow(missing_docs)]
+#[stack_error(derive, add_meta)]
+#[non_exhaustive]
+pub enum TokenError {
+    #[error("Invalid token length")]
+    InvalidLength {},
+    #[error("Token expiry is out of range")]
+    InvalidExpiry {},
+    #[error("Invalid issuer key")]
+    InvalidIssuer { source: KeyParsingError },
+    #[error("Invalid subject key")]
+    InvalidSubject { source: KeyParsingError },
+    #[error("Token issuer is not trusted")]
+    UntrustedIssuer {},
+    #[error("Invalid token signature")]
+    InvalidSignature { source: SignatureError },
+    #[error("Token was issued for another endpoint")]
+    WrongSubject {},
+    #[error("Token was issued for another ALPN")]
+    WrongAlpn {},
+    #[error("Token expired")]
+    Expired {},
+    #[error("Failed to read the token")]
+    Read {
+        #[error(std_err)]
+        source: ReadToEndError,
+    },
+    #[error("Failed to accept the token stream")]
+    Connection {
+        #[error(std_err)]
+        source: ConnectionError,
+    },
+    #[error("Timed out waiting for the token")]
+    Timeout {},
+}
+
+/// Errors of [`CapabilityToken::present`].
+#[allow(missing_docs)]
+#[stack_error(derive, add_meta, from_sources)]
+#[non_exhaustive]
+pub enum PresentError {
+    #[error(transparent)]
+    Connection {
+        #[error(std_err)]
+        source: ConnectionError,
+    },
+    #[error(transparent)]
+    Write {
+        #[error(std_err)]
+        source: WriteError,
+    },
+    #[error(transparent)]
+    ClosedStream {
+        #[error(std_err)]
+        source: ClosedStream,
+    },
+}
+
+impl CapabilityToken {
+    /// Issues a token granting `subject` access to the protocol `alpn` until `expires_at`.
+    pub fn issue(
+        issuer: &SecretKey,
+        subject: EndpointId,
+        alpn: impl AsRef<[u8]>,
+        expires_at: SystemTime,
+    ) -> Self {
+        let alpn = alpn.as_ref().to_vec();
+        let expires_at = expires_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let signature = issuer.sign(&signed_message(subject, &alpn, expires_at));
+        Self {
+            issuer: issuer.public(),
+            subject,
+            alpn,
+            expires_at,
+            signature,
+        }
+    }
+
+    /// Returns the public key of the issuer.
+    pub fn issuer(&self) -> PublicKey {
+        self.issuer
+    }
+
+    /// Returns the endpoint this token was issued for.
+    pub fn subject(&self) -> EndpointId {
+        self.subject
+    }
+
+    /// Returns the ALPN of the protocol this token grants access to.
+    pub fn alpn(&self) -> &[u8] {
+        &self.alpn
+    }
+
+    /// Returns when this token expires.
+    pub fn expires_at(&self) -> SystemTime {
+        expiry(self.expires_at).expect("checked when decoding")
+    }
+
+    /// Encodes the token.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(FIXED_LEN + self.alpn.len());
+        bytes.extend_from_slice(self.issuer.as_bytes());
+        bytes.extend_from_slice(self.subject.as_bytes());
+        bytes.extend_from_slice(&self.expires_at.to_be_bytes());
+        bytes.extend_from_slice(&self.signature.to_bytes());
+        bytes.extend_from_slice(&self.alpn);
+        bytes
+    }
+
+    /// Decodes a token encoded with [`Self::to_bytes`].
+    ///
+    /// This does not verify the token, see [`Self::verify`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TokenError> {
+        ensure!(
+            (FIXED_LEN..=MAX_LEN).contains(&bytes.len()),
+            TokenError::InvalidLength
+        );
+        let (issuer, rest) = bytes.split_at(PublicKey::LENGTH);
+        let (subject, rest) = rest.split_at(PublicKey::LENGTH);
+        let (expires_at, rest) = rest.split_at(8);
+        let (signature, alpn) = rest.split_at(Signature::LENGTH);
+        let issuer = PublicKey::from_bytes(issuer.try_into().expect("checked length"))
+            .map_err(|err| e!(TokenError::InvalidIssuer, err))?;
+        let subject = PublicKey::from_bytes(subject.try_into().expect("checked length"))
+            .map_err(|err| e!(TokenError::InvalidSubject, err))?;
+        let expires_at = u64::from_be_bytes(expires_at.try_into().expect("checked length"));
+        ensure!(expiry(expires_at).is_some(), TokenError::InvalidExpiry);
+        Ok(Self {
+            issuer,
+            subject,
+            alpn: alpn.to_vec(),
+            expires_at,
+            signature: Signature::from_bytes(signature.try_into().expect("checked length")),
+        })
+    }
+
+    /// Verifies the token grants `subject` access to the protocol `alpn` at `now`.
+    ///
+    /// The token must be signed by one of the trusted `issuers`.
+    pub fn verify(
+        &self,
+        issuers: &[PublicKey],
+        subject: EndpointId,
+        alpn: &[u8],
+        now: SystemTime,
+    ) -> Result<(), TokenError> {
+        ensure!(issuers.contains(&self.issuer), TokenError::UntrustedIssuer);
+        self.issuer
+            .verify(
+                &signed_message(self.subject, &self.alpn, self.expires_at),
+                &self.signature,
+            )
+            .map_err(|err| e!(TokenError::InvalidSignature, err))?;
+        ensure!(self.subject == subject, TokenError::WrongSubject);
+        ensure!(self.alpn == alpn, TokenError::WrongAlpn);
+        let expires_at = expiry(self.expires_at).ok_or_else(|| e!(TokenError::InvalidExpiry))?;
+        ensure!(now < expires_at, TokenError::Expired);
+        Ok(())
+    }
+
+    /// Presents the token to the remote on a new unidirectional stream.
+    ///
+    /// This must be the first unidirectional stream opened on the connection.
+    pub async fn present(&self, connection: &Connection) -> Result<(), PresentError> {
+        let mut send = connection.open_uni().await?;
+        send.write_all(&self.to_bytes()).await?;
+        send.finish()?;
+        Ok(())
+    }
+}
+
+/// Converts an expiry in seconds since the UNIX epoch, `None` if it is not representable.
+fn expiry(secs: u64) -> Option<SystemTime> {
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+fn signed_message(subject: EndpointId, alpn: &[u8], expires_at: u64) -> Vec<u8> {
+    let mut message = Vec::with_capacity(SIGNATURE_DOMAIN.len() + 40 + alpn.len());
+    message.extend_from_slice(SIGNATURE_DOMAIN);
+    message.extend_from_slice(subject.as_bytes());
+    message.extend_from_slice(&expires_at.to_be_bytes());
+    message.extend_from_slice(alpn);
+    message
+}
+
+/// Reads the token presented by the remote of `connection` and verifies it.
+pub(super) async fn authorize(
+    connection: &Connection,
+    issuers: &[PublicKey],
+) -> Result<CapabilityToken, TokenError> {
+    let read = async {
+        let mut recv = connection
+            .accept_uni()
+            .await
+            .map_err(|err| e!(TokenError::Connection, err))?;
+        recv.read_to_end(MAX_LEN)
+            .await
+            .map_err(|err| e!(TokenError::Read, err))
+    };
+    let bytes = time::timeout(PRESENT_TIMEOUT, read)
+        .await
+        .map_err(|_| e!(TokenError::Timeout))??;
+    let token = CapabilityToken::from_bytes(&bytes)?;
+    token.verify(
+        issuers,
+        connection.remote_id(),
+        connection.alpn(),
+        SystemTime::now(),
+    )?;
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn test_token_roundtrip_verify() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0u64);
+        let issuer = SecretKey::generate(&mut rng);
+        let other_issuer = SecretKey::generate(&mut rng);
+        let subject = SecretKey::generate(&mut rng).public();
+        let other_subject = SecretKey::generate(&mut rng).public();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let expires_at = now + Duration::from_secs(60);
+
+        let token = CapabilityToken::issue(&issuer, subject, b"/iroh/test/1", expires_at);
+        let decoded = CapabilityToken::from_bytes(&token.to_bytes()).unwrap();
+        assert_eq!(decoded, token);
+        assert_eq!(decoded.expires_at(), expires_at);
+
+        let issuers = [issuer.public()];
+        decoded
+            .verify(&issuers, subject, b"/iroh/test/1", now)
+            .unwrap();
+        assert!(matches!(
+            decoded.verify(&[other_issuer.public()], subject, b"/iroh/test/1", now),
+            Err(TokenError::UntrustedIssuer { .. })
+        ));
+        assert!(matches!(
+            decoded.verify(&issuers, other_subject, b"/iroh/test/1", now),
+            Err(TokenError::WrongSubject { .. })
+        ));
+        assert!(matches!(
+            decoded.verify(&issuers, subject, b"/iroh/test/2", now),
+            Err(TokenError::WrongAlpn { .. })
+        ));
+        assert!(matches!(
+            decoded.verify(&issuers, subject, b"/iroh/test/1", expires_at),
+            Err(TokenError::Expired { .. })
+        ));
+
+        // Tampering with the token invalidates the signature.
+        let mut bytes = token.to_bytes();
+        *bytes.last_mut().unwrap() = b'2';
+        let tampered = CapabilityToken::from_bytes(&bytes).unwrap();
+        assert!(matches!(
+            tampered.verify(&issuers, subject, b"/iroh/test/2", now),
+            Err(TokenError::InvalidSignature { .. })
+        ));
+
+        assert!(matches!(
+            CapabilityToken::from_bytes(&bytes[..FIXED_LEN - 1]),
+            Err(TokenError::InvalidLength { .. })
+        ));
+
+        // An expiry which does not fit a `SystemTime` is rejected instead of panicking.
+        let mut bytes = token.to_bytes();
+        let offset = PublicKey::LENGTH * 2;
+        bytes[offset..offset + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            CapabilityToken::from_bytes(&bytes),
+            Err(TokenError::InvalidExpiry { .. })
+        ));
+    }
+}
@@ -0,0 +1,529 @@
+//! Composable middleware for [`ProtocolHandler`]s.
+//!
+//! A [`Layer`] wraps a protocol handler into a new protocol handler, adding behaviour
+//! around its [`ProtocolHandler::on_accepting`] and [`ProtocolHandler::accept`].  Layers
+//! are applied with [`ProtocolHandler::layer`] and can be stacked, the layer applied last
+//! is the outermost one:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use iroh::{
+//! #     endpoint::{BindError, Connection},
+//! #     protocol::{
+//! #         AcceptError, ProtocolHandler, Router,
+//! #         layer::{CatchPanicLayer, TimeoutLayer, TraceLayer},
+//! #     },
+//! #     Endpoint,
+//! # };
+//! # #[derive(Debug, Clone)]
+//! # struct Echo;
+//! # impl ProtocolHandler for Echo {
+//! #     async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+//! #         Ok(())
+//! #     }
+//! # }
+//! # async fn test_compile() -> Result<(), BindError> {
+//! let endpoint = Endpoint::bind().await?;
+//!
+//! let handler = Echo
+//!     .layer(TimeoutLayer::new(Duration::from_secs(60)))
+//!     .layer(CatchPanicLayer::new())
+//!     .layer(TraceLayer::new("echo"));
+//! let router = Router::builder(endpoint).accept(b"/my/alpn", handler).spawn();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A tuple of two layers is a layer as well, applying the first layer before the second
+//! one.  This allows to reuse a stack of layers for several handlers.
+
+use std::{
+    collections::HashMap,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+};
+
+use iroh_base::EndpointId;
+use iroh_metrics::{Counter, Gauge, MetricsGroup};
+use n0_error::e;
+use n0_future::{
+    FutureExt,
+    time::{self, Duration, Instant},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{Instrument, debug, debug_span, error, warn};
+
+use super::{AcceptError, ProtocolHandler};
+use crate::endpoint::{Accepting, Connection};
+
+/// Wraps a [`ProtocolHandler`] into another [`ProtocolHandler`].
+///
+/// See the [module documentation](self) for how layers are used.
+pub trait Layer<P: ProtocolHandler> {
+    /// The wrapping protocol handler.
+    type Handler: ProtocolHandler;
+
+    /// Wraps `inner` into the protocol handler of this layer.
+    fn layer(&self, inner: P) -> Self::Handler;
+}
+
+impl<P, Inner, Outer> Layer<P> for (Inner, Outer)
+where
+    P: ProtocolHandler,
+    Inner: Layer<P>,
+    Outer: Layer<Inner::Handler>,
+{
+    type Handler = Outer::Handler;
+
+    fn layer(&self, inner: P) -> Self::Handler {
+        self.1.layer(self.0.layer(inner))
+    }
+}
+
+/// A [`Layer`] running the protocol handler in a tracing span, and logging the outcome of
+/// each connection.
+#[derive(Debug, Clone)]
+pub struct TraceLayer {
+    name: Arc<str>,
+}
+
+impl TraceLayer {
+    /// Creates a new tracing layer, naming the handler `name` in the span.
+    pub fn new(name: impl Into<Arc<str>>) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl<P: ProtocolHandler> Layer<P> for TraceLayer {
+    type Handler = Trace<P>;
+
+    fn layer(&self, inner: P) -> Self::Handler {
+        Trace {
+            inner,
+            name: self.name.clone(),
+        }
+    }
+}
+
+/// The protocol handler of the [`TraceLayer`].
+#[derive(Debug, Clone)]
+pub struct Trace<P> {
+    inner: P,
+    name: Arc<str>,
+}
+
+impl<P: ProtocolHandler> ProtocolHandler for Trace<P> {
+    async fn on_accepting(&self, accepting: Accepting) -> Result<Connection, AcceptError> {
+        self.inner
+            .on_accepting(accepting)
+            .instrument(debug_span!("handler", name = %self.name))
+            .await
+    }
+
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let span = debug_span!("handler", name = %self.name);
+        async {
+            debug!("Handling connection");
+            let start = Instant::now();
+            let res = self.inner.accept(connection).await;
+            match &res {
+                Ok(()) => debug!(elapsed = ?start.elapsed(), "Connection handled"),
+                Err(err) => {
+                    warn!(elapsed = ?start.elapsed(), "Handling connection failed: {err:#}")
+                }
+            }
+            res
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn shutdown(&self) {
+        self.inner.shutdown().await
+    }
+}
+
+/// A [`Layer`] limiting the time the protocol handler may spend in
+/// [`ProtocolHandler::accept`].
+///
+/// When the timeout elapses, the connection is closed with error code `0` and reason
+/// `timeout`, and the handler fails with [`AcceptError::Timeout`].
+#[derive(Debug, Clone)]
+pub struct TimeoutLayer {
+    timeout: Duration,
+}
+
+impl TimeoutLayer {
+    /// Creates a new timeout layer.
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl<P: ProtocolHandler> Layer<P> for TimeoutLayer {
+    type Handler = Timeout<P>;
+
+    fn layer(&self, inner: P) -> Self::Handler {
+        Timeout {
+            inner,
+            timeout: self.timeout,
+        }
+    }
+}
+
+/// The protocol handler of the [`TimeoutLayer`].
+#[derive(Debug, Clone)]
+pub struct Timeout<P> {
+    inner: P,
+    timeout: Duration,
+}
+
+impl<P: ProtocolHandler> ProtocolHandler for Timeout<P> {
+    async fn on_accepting(&self, accepting: Accepting) -> Result<Connection, AcceptError> {
+        self.inner.on_accepting(accepting).await
+    }
+
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        match time::timeout(self.timeout, self.inner.accept(connection.clone())).await {
+            Ok(res) => res,
+            Err(_) => {
+                connection.close(0u32.into(), b"timeout");
+                Err(e!(AcceptError::Timeout))
+            }
+        }
+    }
+
+    async fn shutdown(&self) {
+        self.inner.shutdown().await
+    }
+}
+
+/// A [`Layer`] limiting the rate of connections per remote endpoint.
+///
+/// Each remote endpoint may open up to `max` connections per `period`.  Connections over
+/// the limit are closed with error code `0` and reason `rate limited`, without calling
+/// [`ProtocolHandler::accept`] of the wrapped handler.
+///
+/// To limit the number of concurrent connections instead, see
+/// [`RouterBuilder::max_connections_per_endpoint`].
+///
+/// [`RouterBuilder::max_connections_per_endpoint`]: super::RouterBuilder::max_connections_per_endpoint
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    max: u32,
+    period: Duration,
+}
+
+impl RateLimitLayer {
+    /// Creates a new rate limiting layer, allowing `max` connections per `period`.
+    pub fn new(max: u32, period: Duration) -> Self {
+        Self { max, period }
+    }
+}
+
+impl<P: ProtocolHandler> Layer<P> for RateLimitLayer {
+    type Handler = RateLimit<P>;
+
+    fn layer(&self, inner: P) -> Self::Handler {
+        RateLimit {
+            inner,
+            max: self.max,
+            period: self.period,
+            windows: Default::default(),
+        }
+    }
+}
+
+/// The protocol handler of the [`RateLimitLayer`].
+#[derive(Debug, Clone)]
+pub struct RateLimit<P> {
+    inner: P,
+    max: u32,
+    period: Duration,
+    /// The start of the current window of each remote endpoint, and the connections in it.
+    windows: Arc<Mutex<HashMap<EndpointId, (Instant, u32)>>>,
+}
+
+impl<P> RateLimit<P> {
+    /// Counts a connection of `remote`, returning whether it is within the limit.
+    fn check(&self, remote: EndpointId, now: Instant) -> bool {
+        let mut windows = self.windows.lock().expect("poisoned");
+        windows.retain(|_, (start, _)| now.duration_since(*start) < self.period);
+        let (_, count) = windows.entry(remote).or_insert((now, 0));
+        if *count >= self.max {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+impl<P: ProtocolHandler> ProtocolHandler for RateLimit<P> {
+    async fn on_accepting(&self, accepting: Accepting) -> Result<Connection, AcceptError> {
+        self.inner.on_accepting(accepting).await
+    }
+
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        if !self.check(connection.remote_id(), Instant::now()) {
+            connection.close(0u32.into(), b"rate limited");
+            return Err(e!(AcceptError::RateLimited));
+        }
+        self.inner.accept(connection).await
+    }
+
+    async fn shutdown(&self) {
+        self.inner.shutdown().await
+    }
+}
+
+/// A [`Layer`] turning panics of the protocol handler into errors.
+///
+/// A panic in a protocol handler otherwise shuts down the whole [`Router`].  With this
+/// layer, the connection is closed with error code `0` and reason `internal error`, and the
+/// handler fails with [`AcceptError::Panicked`].
+///
+/// [`Router`]: super::Router
+#[derive(Debug, Clone, Default)]
+pub struct CatchPanicLayer;
+
+impl CatchPanicLayer {
+    /// Creates a new panic isolation layer.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl<P: ProtocolHandler> Layer<P> for CatchPanicLayer {
+    type Handler = CatchPanic<P>;
+
+    fn layer(&self, inner: P) -> Self::Handler {
+        CatchPanic { inner }
+    }
+}
+
+/// The protocol handler of the [`CatchPanicLayer`].
+#[derive(Debug, Clone)]
+pub struct CatchPanic<P> {
+    inner: P,
+}
+
+impl<P: ProtocolHandler> ProtocolHandler for CatchPanic<P> {
+    async fn on_accepting(&self, accepting: Accepting) -> Result<Connection, AcceptError> {
+        match AssertUnwindSafe(self.inner.on_accepting(accepting))
+            .catch_unwind()
+            .await
+        {
+            Ok(res) => res,
+            Err(payload) => Err(panicked(payload)),
+        }
+    }
+
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        match AssertUnwindSafe(self.inner.accept(connection.clone()))
+            .catch_unwind()
+            .await
+        {
+            Ok(res) => res,
+            Err(payload) => {
+                connection.close(0u32.into(), b"internal error");
+                Err(panicked(payload))
+            }
+        }
+    }
+
+    async fn shutdown(&self) {
+        if let Err(payload) = AssertUnwindSafe(self.inner.shutdown()).catch_unwind().await {
+            panicked(payload);
+        }
+    }
+}
+
+/// Logs a caught panic and turns it into an [`AcceptError`].
+fn panicked(payload: Box<dyn std::any::Any + Send>) -> AcceptError {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    };
+    error!("Protocol handler panicked: {message}");
+    e!(AcceptError::Panicked { message })
+}
+
+/// Metrics of a protocol handler wrapped with the [`MetricsLayer`].
+#[derive(Debug, Serialize, Deserialize, MetricsGroup)]
+#[non_exhaustive]
+#[metrics(name = "protocol_handler", default)]
+pub struct HandlerMetrics {
+    /// Number of connections passed to the handler.
+    pub connections_accepted: Counter,
+    /// Number of connections the handler finished successfully.
+    pub connections_completed: Counter,
+    /// Number of connections the handler failed on.
+    pub connections_failed: Counter,
+    /// Number of connections currently handled.
+    pub connections_active: Gauge,
+}
+
+/// A [`Layer`] recording [`HandlerMetrics`] of the protocol handler.
+///
+/// All handlers wrapped by the same layer record into the same metrics.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsLayer {
+    metrics: Arc<HandlerMetrics>,
+}
+
+impl MetricsLayer {
+    /// Creates a new metrics layer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the metrics recorded by this layer.
+    pub fn metrics(&self) -> &Arc<HandlerMetrics> {
+        &self.metrics
+    }
+}
+
+impl<P: ProtocolHandler> Layer<P> for MetricsLayer {
+    type Handler = Metered<P>;
+
+    fn layer(&self, inner: P) -> Self::Handler {
+        Metered {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+/// The protocol handler of the [`MetricsLayer`].
+#[derive(Debug, Clone)]
+pub struct Metered<P> {
+    inner: P,
+    metrics: Arc<HandlerMetrics>,
+}
+
+impl<P: ProtocolHandler> ProtocolHandler for Metered<P> {
+    async fn on_accepting(&self, accepting: Accepting) -> Result<Connection, AcceptError> {
+        self.inner.on_accepting(accepting).await
+    }
+
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        self.metrics.connections_accepted.inc();
+        self.metrics.connections_active.inc();
+        // Decrements the active connections also if the future is dropped.
+        let _active = ActiveGuard(&self.metrics);
+        let res = self.inner.accept(connection).await;
+        match res {
+            Ok(()) => self.metrics.connections_completed.inc(),
+            Err(_) => self.metrics.connections_failed.inc(),
+        };
+        res
+    }
+
+    async fn shutdown(&self) {
+        self.inner.shutdown().await
+    }
+}
+
+struct ActiveGuard<'a>(&'a HandlerMetrics);
+
+impl Drop for ActiveGuard<'_> {
+    fn drop(&mut self) {
+        self.0.connections_active.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use n0_error::{Result, StdResultExt};
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{Endpoint, RelayMode, endpoint::ConnectionError, protocol::Router};
+
+    const TEST_ALPN: &[u8] = b"/iroh/test/layer";
+
+    /// Panics on the first connection and waits for the remote on all others.
+    #[derive(Debug, Clone, Default)]
+    struct Flaky {
+        calls: Arc<Mutex<u32>>,
+    }
+
+    impl ProtocolHandler for Flaky {
+        async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+            let call = {
+                let mut calls = self.calls.lock().expect("poisoned");
+                *calls += 1;
+                *calls
+            };
+            if call == 1 {
+                panic!("flaky");
+            }
+            connection.closed().await;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_check() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0u64);
+        let a = iroh_base::SecretKey::generate(&mut rng).public();
+        let b = iroh_base::SecretKey::generate(&mut rng).public();
+        let limit = RateLimitLayer::new(2, Duration::from_secs(10)).layer(Flaky::default());
+
+        let now = Instant::now();
+        assert!(limit.check(a, now));
+        assert!(limit.check(a, now));
+        assert!(!limit.check(a, now));
+        assert!(limit.check(b, now));
+
+        // A new window starts once the period elapsed.
+        let later = now + Duration::from_secs(10);
+        assert!(limit.check(a, later));
+        assert_eq!(limit.windows.lock().expect("poisoned").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_layers() -> Result {
+        let metrics = MetricsLayer::new();
+        let stack = (
+            (
+                TimeoutLayer::new(Duration::from_millis(200)),
+                CatchPanicLayer,
+            ),
+            (metrics.clone(), TraceLayer::new("flaky")),
+        );
+        let e1 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+        let r1 = Router::builder(e1)
+            .accept(TEST_ALPN, Flaky::default().layer(stack))
+            .spawn();
+        let addr1 = r1.endpoint().addr();
+        let e2 = Endpoint::empty_builder(RelayMode::Disabled).bind().await?;
+
+        // The panic closes the connection, without taking down the router.
+        let conn = e2.connect(addr1.clone(), TEST_ALPN).await?;
+        let reason = conn.closed().await;
+        assert!(
+            matches!(reason, ConnectionError::ApplicationClosed(close) if &close.reason[..] == b"internal error")
+        );
+
+        // The second connection runs into the timeout.
+        let conn = e2.connect(addr1, TEST_ALPN).await?;
+        let reason = conn.closed().await;
+        assert!(
+            matches!(reason, ConnectionError::ApplicationClosed(close) if &close.reason[..] == b"timeout")
+        );
+
+        assert!(!r1.is_shutdown());
+        let metrics = metrics.metrics();
+        assert_eq!(metrics.connections_accepted.get(), 2);
+        assert_eq!(metrics.connections_failed.get(), 2);
+        assert_eq!(metrics.connections_active.get(), 0);
+
+        r1.shutdown().await.anyerr()?;
+        e2.close().await;
+        Ok(())
+    }
+}
@@ -12,6 +12,8 @@ use iroh_relay::{
 };
 use tokio::sync::oneshot;
 
+pub mod sim;
+
 /// A drop guard to clean up test infrastructure.
 ///
 /// After dropping the test infrastructure will asynchronously shutdown and release its
@@ -0,0 +1,891 @@
+//! An in-process network simulator for deterministic tests.
+//!
+//! A [`VirtualNetwork`] connects any number of [`VirtualHost`]s, so no sockets, root
+//! privileges or network namespaces are needed.  All timers are tokio timers, so tests can
+//! run on paused time.
+//!
+//! Hosts either sit directly on the network, or each behind its own [`Nat`] which
+//! translates and filters their traffic like a home router would.  The network can add
+//! latency, drop packets and partition hosts from each other, and all of this can be
+//! changed while endpoints are running to exercise holepunching and failover.  Packet loss
+//! uses a seeded random number generator, see [`VirtualNetwork::with_seed`].
+//!
+//! An endpoint uses a host in one of two ways:
+//!
+//! - [`Builder::virtual_host`] binds the endpoint onto the host instead of UDP sockets and
+//!   relay servers.  Virtual addresses are IPv4 socket addresses, which the endpoint
+//!   discovers, publishes and holepunches like on a real network.  The network has a relay
+//!   server, see [`VirtualNetwork::relay_url`], which all such endpoints use as their home
+//!   relay, so they can be dialed by their [`EndpointId`] and relay URL alone.
+//! - [`Builder::add_custom_transport`] adds the host as a custom [`Transport`].  Endpoints
+//!   then only learn virtual addresses from [`EndpointAddr`]s and from incoming packets,
+//!   there is no holepunching on custom transports.
+//!
+//! ```no_run
+//! # async fn wrapper() -> n0_error::Result<()> {
+//! use iroh::{
+//!     Endpoint, EndpointAddr, RelayMode,
+//!     test_utils::sim::{Nat, VirtualNetwork},
+//! };
+//!
+//! let network = VirtualNetwork::with_seed(42);
+//! let server = Endpoint::empty_builder(RelayMode::Disabled)
+//!     .alpns(vec![b"my-alpn".to_vec()])
+//!     .virtual_host(network.add_host(Nat::PortRestricted))
+//!     .bind()
+//!     .await?;
+//! let client = Endpoint::empty_builder(RelayMode::Disabled)
+//!     .virtual_host(network.add_host(Nat::PortRestricted))
+//!     .bind()
+//!     .await?;
+//!
+//! let addr = EndpointAddr::new(server.id()).with_relay_url(network.relay_url());
+//! let conn = client.connect(addr, b"my-alpn").await?;
+//! # let _ = conn;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`Transport`]: crate::endpoint::transport::Transport
+//! [`Builder::virtual_host`]: crate::endpoint::Builder::virtual_host
+//! [`Builder::add_custom_transport`]: crate::endpoint::Builder::add_custom_transport
+//! [`EndpointAddr`]: crate::EndpointAddr
+//! [`EndpointId`]: crate::EndpointId
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use iroh_base::{CustomAddr, EndpointId, RelayUrl};
+use iroh_relay::protos::relay::Datagrams;
+use n0_watcher::Watchable;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tokio::sync::mpsc;
+use tracing::trace;
+
+use crate::{endpoint::transport::Transport, net_report::Report};
+
+/// The transport id used by all [`VirtualHost`]s.
+pub const TRANSPORT_ID: u64 = 0x6e65_7473_696d;
+
+/// The port hosts use on their private address.
+const HOST_PORT: u16 = 7000;
+/// The first port a [`Nat`] maps outgoing traffic to.
+const FIRST_MAPPED_PORT: u16 = 40000;
+/// The maximum datagram size of the virtual network.
+const MAX_DATAGRAM_SIZE: usize = 1500;
+/// The URL of the relay server of every network.
+const RELAY_URL: &str = "https://relay.sim.invalid./";
+
+type Datagram = (Vec<u8>, CustomAddr);
+/// Datagrams relayed from the remote endpoint with the given id.
+pub(crate) type RelayDatagram = (EndpointId, Datagrams);
+
+/// Returns the virtual socket address a [`CustomAddr`] of the network refers to.
+///
+/// Returns `None` if the address does not belong to a [`VirtualNetwork`].
+pub fn socket_addr(addr: &CustomAddr) -> Option<SocketAddrV4> {
+    if addr.id() != TRANSPORT_ID {
+        return None;
+    }
+    let data: [u8; 6] = addr.data().try_into().ok()?;
+    let ip = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+    let port = u16::from_be_bytes([data[4], data[5]]);
+    Some(SocketAddrV4::new(ip, port))
+}
+
+/// Returns the [`CustomAddr`] for a virtual socket address.
+pub fn custom_addr(addr: SocketAddrV4) -> CustomAddr {
+    let mut data = addr.ip().octets().to_vec();
+    data.extend_from_slice(&addr.port().to_be_bytes());
+    CustomAddr::new(TRANSPORT_ID, data)
+}
+
+/// The behaviour of the NAT a [`VirtualHost`] sits behind.
+///
+/// The names follow [RFC 3489].  Mappings and filtering permissions never expire.
+///
+/// [RFC 3489]: https://datatracker.ietf.org/doc/html/rfc3489#section-5
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Nat {
+    /// The host is directly reachable at its address.
+    None,
+    /// All traffic of the host uses the same public port, and anyone can send to it.
+    FullCone,
+    /// All traffic of the host uses the same public port, but only addresses the host sent
+    /// to before can send to it.
+    PortRestricted,
+    /// Each destination of the host gets its own public port, and only this destination can
+    /// send to it.
+    Symmetric,
+}
+
+impl Nat {
+    /// Whether the mapping is the same for all destinations.
+    fn is_endpoint_independent(&self) -> bool {
+        matches!(self, Self::FullCone | Self::PortRestricted)
+    }
+}
+
+/// A simulated network connecting [`VirtualHost`]s.
+///
+/// Cloning the network returns another handle to the same network.
+#[derive(Debug, Clone)]
+pub struct VirtualNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl Default for VirtualNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+struct NetworkState {
+    hosts: Vec<HostState>,
+    latency: Duration,
+    loss: f64,
+    rng: StdRng,
+    /// Pairs of host indices which can not reach each other, the lower index first.
+    partitions: HashSet<(usize, usize)>,
+}
+
+#[derive(Debug)]
+struct HostState {
+    nat: Nat,
+    private_addr: SocketAddrV4,
+    /// The address of the NAT, or the address of the host if it is not behind a NAT.
+    public_ip: Ipv4Addr,
+    inbox: mpsc::UnboundedSender<Datagram>,
+    /// The endpoint connected to the relay server from this host, and its inbox.
+    relay: Option<(EndpointId, mpsc::UnboundedSender<RelayDatagram>)>,
+    next_port: u16,
+    /// The public ports of the NAT by destination, `None` for endpoint independent mappings.
+    mappings: HashMap<Option<SocketAddrV4>, u16>,
+    /// The destinations the host sent to, which may send back through a restricted NAT.
+    permissions: HashSet<SocketAddrV4>,
+}
+
+impl HostState {
+    /// Returns the source address of a packet from this host to `dst` as seen by `dst`.
+    fn map_outbound(&mut self, dst: SocketAddrV4) -> SocketAddrV4 {
+        if self.nat == Nat::None {
+            return self.private_addr;
+        }
+        let key = (!self.nat.is_endpoint_independent()).then_some(dst);
+        let port = match self.mappings.get(&key) {
+            Some(port) => *port,
+            None => {
+                let port = self.next_port;
+                self.next_port += 1;
+                self.mappings.insert(key, port);
+                port
+            }
+        };
+        self.permissions.insert(dst);
+        SocketAddrV4::new(self.public_ip, port)
+    }
+
+    /// Whether a packet from `src` to the public address `dst` is let through.
+    fn accepts_inbound(&self, src: SocketAddrV4, dst: SocketAddrV4) -> bool {
+        match self.nat {
+            Nat::None => dst == self.private_addr,
+            Nat::FullCone => self.mappings.get(&None) == Some(&dst.port()),
+            Nat::PortRestricted => {
+                self.mappings.get(&None) == Some(&dst.port()) && self.permissions.contains(&src)
+            }
+            Nat::Symmetric => self.mappings.get(&Some(src)) == Some(&dst.port()),
+        }
+    }
+
+    /// The public address of the host, if it is the same for all destinations.
+    fn public_addr(&self) -> Option<SocketAddrV4> {
+        match self.nat {
+            Nat::None => Some(self.private_addr),
+            _ => self
+                .mappings
+                .get(&None)
+                .map(|port| SocketAddrV4::new(self.public_ip, *port)),
+        }
+    }
+}
+
+impl VirtualNetwork {
+    /// Creates a new network without latency or packet loss.
+    pub fn new() -> Self {
+        Self::with_seed(0)
+    }
+
+    /// Creates a new network, seeding the random number generator used for packet loss.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(NetworkState {
+                hosts: Vec::new(),
+                latency: Duration::ZERO,
+                loss: 0.0,
+                rng: StdRng::seed_from_u64(seed),
+                partitions: HashSet::new(),
+            })),
+        }
+    }
+
+    /// Adds a new host to the network.
+    ///
+    /// Hosts behind a [`Nat`] each get their own NAT.  With an endpoint independent
+    /// mapping the public address of the host is known right away, like it would be
+    /// after a STUN request.
+    pub fn add_host(&self, nat: Nat) -> VirtualHost {
+        let mut state = self.state.lock().expect("poisoned");
+        let idx = state.hosts.len();
+        let n = u32::try_from(idx + 1).expect("too many hosts");
+        let (private_ip, public_ip) = match nat {
+            Nat::None => {
+                let ip = Ipv4Addr::from(u32::from(Ipv4Addr::new(1, 0, 0, 0)) + n);
+                (ip, ip)
+            }
+            _ => (
+                Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 0)) + n),
+                Ipv4Addr::from(u32::from(Ipv4Addr::new(2, 0, 0, 0)) + n),
+            ),
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut host = HostState {
+            nat,
+            private_addr: SocketAddrV4::new(private_ip, HOST_PORT),
+            public_ip,
+            inbox: tx,
+            relay: None,
+            next_port: FIRST_MAPPED_PORT,
+            mappings: HashMap::new(),
+            permissions: HashSet::new(),
+        };
+        if nat.is_endpoint_independent() {
+            host.mappings.insert(None, host.next_port);
+            host.next_port += 1;
+        }
+        let mut local_addrs = vec![custom_addr(host.private_addr)];
+        if let Some(addr) = host.public_addr().filter(|addr| *addr != host.private_addr) {
+            local_addrs.push(custom_addr(addr));
+        }
+        state.hosts.push(host);
+        VirtualHost {
+            idx,
+            network: self.clone(),
+            local_addrs: Watchable::new(local_addrs),
+            inbox: Arc::new(Mutex::new(rx)),
+        }
+    }
+
+    /// Sets the one-way latency of all packets.
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().expect("poisoned").latency = latency;
+    }
+
+    /// Sets the probability of a packet being dropped, between `0.0` and `1.0`.
+    pub fn set_loss(&self, loss: f64) {
+        assert!((0.0..=1.0).contains(&loss), "loss must be a probability");
+        self.state.lock().expect("poisoned").loss = loss;
+    }
+
+    /// Returns the URL of the relay server of the network.
+    ///
+    /// Endpoints bound onto a host with [`Builder::virtual_host`] use it as their home
+    /// relay.  Relayed packets travel twice the latency of the network.
+    ///
+    /// [`Builder::virtual_host`]: crate::endpoint::Builder::virtual_host
+    pub fn relay_url(&self) -> RelayUrl {
+        RELAY_URL.parse().expect("valid url")
+    }
+
+    /// Drops all direct packets between the two hosts, in both directions.
+    ///
+    /// Packets relayed by the relay server of the network still get through.
+    pub fn partition(&self, a: &VirtualHost, b: &VirtualHost) {
+        self.state
+            .lock()
+            .expect("poisoned")
+            .partitions
+            .insert(link(a.idx, b.idx));
+    }
+
+    /// Removes a partition between the two hosts created by [`Self::partition`].
+    pub fn heal(&self, a: &VirtualHost, b: &VirtualHost) {
+        self.state
+            .lock()
+            .expect("poisoned")
+            .partitions
+            .remove(&link(a.idx, b.idx));
+    }
+
+    /// Removes all partitions.
+    pub fn heal_all(&self) {
+        self.state.lock().expect("poisoned").partitions.clear();
+    }
+
+    fn send(&self, from: usize, dst: SocketAddrV4, datagram: &[u8]) {
+        let mut state = self.state.lock().expect("poisoned");
+        let src = state.hosts[from].map_outbound(dst);
+        let Some(to) = state
+            .hosts
+            .iter()
+            .position(|host| host.public_ip == *dst.ip() && host.accepts_inbound(src, dst))
+        else {
+            trace!(%src, %dst, "sim: dropping packet, not routable");
+            return;
+        };
+        if state.partitions.contains(&link(from, to)) {
+            trace!(%src, %dst, "sim: dropping packet, partitioned");
+            return;
+        }
+        let loss = state.loss;
+        if loss > 0.0 && state.rng.random_bool(loss) {
+            trace!(%src, %dst, "sim: dropping packet, lost");
+            return;
+        }
+        let inbox = state.hosts[to].inbox.clone();
+        let latency = state.latency;
+        drop(state);
+
+        deliver(inbox, (datagram.to_vec(), custom_addr(src)), latency);
+    }
+
+    fn send_relay(&self, src: EndpointId, dst: EndpointId, datagrams: Datagrams) {
+        let mut state = self.state.lock().expect("poisoned");
+        let Some(inbox) = state.hosts.iter().find_map(|host| match host.relay {
+            Some((id, ref inbox)) if id == dst => Some(inbox.clone()),
+            _ => None,
+        }) else {
+            trace!(src = %src.fmt_short(), dst = %dst.fmt_short(), "sim: dropping relayed packet, not connected");
+            return;
+        };
+        let loss = state.loss;
+        if loss > 0.0 && state.rng.random_bool(loss) {
+            trace!(src = %src.fmt_short(), dst = %dst.fmt_short(), "sim: dropping relayed packet, lost");
+            return;
+        }
+        let latency = state.latency * 2;
+        drop(state);
+
+        deliver(inbox, (src, datagrams), latency);
+    }
+}
+
+fn deliver<T: Send + 'static>(inbox: mpsc::UnboundedSender<T>, item: T, latency: Duration) {
+    if latency.is_zero() {
+        inbox.send(item).ok();
+    } else {
+        n0_future::task::spawn(async move {
+            n0_future::time::sleep(latency).await;
+            inbox.send(item).ok();
+        });
+    }
+}
+
+fn link(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+/// A host on a [`VirtualNetwork`].
+///
+/// Bind an endpoint onto the host by passing it to [`Builder::virtual_host`] or
+/// [`Builder::add_custom_transport`].  Only one endpoint may use a host at a time, the
+/// host is kept as a handle to refer to it in [`VirtualNetwork::partition`].
+///
+/// [`Builder::virtual_host`]: crate::endpoint::Builder::virtual_host
+/// [`Builder::add_custom_transport`]: crate::endpoint::Builder::add_custom_transport
+#[derive(Debug, Clone)]
+pub struct VirtualHost {
+    idx: usize,
+    network: VirtualNetwork,
+    local_addrs: Watchable<Vec<CustomAddr>>,
+    inbox: Arc<Mutex<mpsc::UnboundedReceiver<Datagram>>>,
+}
+
+impl VirtualHost {
+    /// Returns the address of the host on its private network.
+    ///
+    /// For hosts which are not behind a NAT this is also their public address.
+    pub fn private_addr(&self) -> CustomAddr {
+        let state = self.network.state.lock().expect("poisoned");
+        custom_addr(state.hosts[self.idx].private_addr)
+    }
+
+    /// Returns the address other hosts can reach this host at.
+    ///
+    /// Returns `None` for hosts behind a [`Nat::Symmetric`], whose address depends on the
+    /// destination.
+    pub fn public_addr(&self) -> Option<CustomAddr> {
+        let state = self.network.state.lock().expect("poisoned");
+        state.hosts[self.idx].public_addr().map(custom_addr)
+    }
+
+    /// Returns the address of the host on its private network as a socket address.
+    pub(crate) fn private_socket_addr(&self) -> SocketAddrV4 {
+        let state = self.network.state.lock().expect("poisoned");
+        state.hosts[self.idx].private_addr
+    }
+
+    /// Sends a datagram to a virtual socket address.
+    pub(crate) fn send_to(&self, dst: SocketAddrV4, datagram: &[u8]) {
+        self.network.send(self.idx, dst, datagram);
+    }
+
+    /// Receives a datagram and the virtual socket address it came from.
+    pub(crate) fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddrV4)>> {
+        let mut inbox = self.inbox.lock().expect("poisoned");
+        match inbox.poll_recv(cx) {
+            Poll::Ready(Some((datagram, src))) => {
+                let len = datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                let src = socket_addr(&src).expect("sent by the network");
+                Poll::Ready(Ok((len, src)))
+            }
+            Poll::Ready(None) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Connects the endpoint to the relay server of the network.
+    ///
+    /// Returns the datagrams relayed to the endpoint.  A later connection from the same
+    /// host replaces this one.
+    pub(crate) fn connect_relay(
+        &self,
+        endpoint_id: EndpointId,
+    ) -> mpsc::UnboundedReceiver<RelayDatagram> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut state = self.network.state.lock().expect("poisoned");
+        state.hosts[self.idx].relay = Some((endpoint_id, tx));
+        rx
+    }
+
+    /// Sends datagrams from the endpoint `src` to the endpoint `dst` via the relay server.
+    pub(crate) fn send_relay(&self, src: EndpointId, dst: EndpointId, datagrams: Datagrams) {
+        self.network.send_relay(src, dst, datagrams);
+    }
+
+    /// Returns the URL of the relay server of the network.
+    pub(crate) fn relay_url(&self) -> RelayUrl {
+        self.network.relay_url()
+    }
+
+    /// Returns the report the host would get from probing the relay server.
+    ///
+    /// The reported public address is the one other hosts can reach the host at, if there
+    /// is one.
+    pub(crate) fn net_report(&self) -> Report {
+        let state = self.network.state.lock().expect("poisoned");
+        let host = &state.hosts[self.idx];
+        Report {
+            udp_v4: true,
+            mapping_varies_by_dest_ipv4: Some(host.nat == Nat::Symmetric),
+            preferred_relay: Some(self.network.relay_url()),
+            global_v4: host.public_addr(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Transport for VirtualHost {
+    fn id(&self) -> u64 {
+        TRANSPORT_ID
+    }
+
+    fn local_addrs(&self) -> n0_watcher::Direct<Vec<CustomAddr>> {
+        self.local_addrs.watch()
+    }
+
+    fn max_datagram_size(&self) -> usize {
+        MAX_DATAGRAM_SIZE
+    }
+
+    fn poll_send(
+        &self,
+        _cx: &mut Context<'_>,
+        dst: &CustomAddr,
+        datagram: &[u8],
+    ) -> Poll<io::Result<()>> {
+        if let Some(dst) = socket_addr(dst) {
+            self.network.send(self.idx, dst, datagram);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, CustomAddr)>> {
+        self.poll_recv_from(cx, buf)
+            .map_ok(|(len, src)| (len, custom_addr(src)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::poll_fn, task::Waker};
+
+    use n0_error::{Result, StdResultExt};
+    use n0_future::StreamExt;
+    use n0_watcher::Watcher;
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::{
+        Endpoint, EndpointAddr, RelayMode,
+        endpoint::{Connection, ConnectionType},
+    };
+
+    const TEST_ALPN: &[u8] = b"n0/iroh/test";
+    /// How long to wait for a connection type, in paused time.
+    const CONN_TYPE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Binds an endpoint onto `host`, echoing all streams of incoming connections.
+    async fn echo_endpoint(host: VirtualHost) -> Result<Endpoint> {
+        let ep = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .virtual_host(host)
+            .bind()
+            .await?;
+        let accept_ep = ep.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = accept_ep.accept().await {
+                let Ok(conn) = incoming.await else {
+                    continue;
+                };
+                tokio::spawn(async move {
+                    while let Ok((mut send, mut recv)) = conn.accept_bi().await {
+                        let Ok(data) = recv.read_to_end(100).await else {
+                            break;
+                        };
+                        send.write_all(&data).await.ok();
+                        send.finish().ok();
+                    }
+                });
+            }
+        });
+        Ok(ep)
+    }
+
+    async fn bind_client(host: VirtualHost) -> Result<Endpoint> {
+        Ok(Endpoint::empty_builder(RelayMode::Disabled)
+            .virtual_host(host)
+            .bind()
+            .await?)
+    }
+
+    async fn assert_echo(conn: &Connection, data: &[u8]) -> Result {
+        let (mut send, mut recv) = conn.open_bi().await.anyerr()?;
+        send.write_all(data).await.anyerr()?;
+        send.finish().anyerr()?;
+        assert_eq!(recv.read_to_end(100).await.anyerr()?, data);
+        Ok(())
+    }
+
+    /// Waits until the connection type to `remote` matches `f`, returning it.
+    async fn wait_conn_type(
+        ep: &Endpoint,
+        remote: &Endpoint,
+        f: impl Fn(&ConnectionType) -> bool,
+    ) -> Result<ConnectionType> {
+        let mut conn_type = ep.conn_type(remote.id()).expect("known").stream();
+        let conn_type = tokio::time::timeout(CONN_TYPE_TIMEOUT, async {
+            while let Some(conn_type) = conn_type.next().await {
+                if f(&conn_type) {
+                    return Some(conn_type);
+                }
+            }
+            None
+        })
+        .await
+        .anyerr()?;
+        Ok(conn_type.expect("watcher closed"))
+    }
+
+    /// Like [`wait_conn_type`], but sends traffic on `conn` while waiting.
+    ///
+    /// A broken path is only noticed while the connection is in use.
+    async fn wait_conn_type_busy(
+        conn: &Connection,
+        ep: &Endpoint,
+        remote: &Endpoint,
+        f: impl Fn(&ConnectionType) -> bool,
+    ) -> Result<ConnectionType> {
+        let traffic = async {
+            loop {
+                if let Err(err) = assert_echo(conn, b"ping").await {
+                    return err;
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        };
+        tokio::select! {
+            conn_type = wait_conn_type(ep, remote, f) => conn_type,
+            err = traffic => Err(err),
+        }
+    }
+
+    fn is_direct(conn_type: &ConnectionType) -> bool {
+        matches!(conn_type, ConnectionType::Direct(_))
+    }
+
+    /// Whether the connection uses the relay, possibly with unconfirmed direct paths.
+    fn is_relayed(conn_type: &ConnectionType) -> bool {
+        matches!(
+            conn_type,
+            ConnectionType::Relay(_) | ConnectionType::Mixed(..)
+        )
+    }
+
+    fn send(from: &VirtualHost, to: &CustomAddr, datagram: &[u8]) {
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(from.poll_send(&mut cx, to, datagram).is_ready());
+    }
+
+    fn try_recv(host: &VirtualHost) -> Option<(Vec<u8>, CustomAddr)> {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        match host.poll_recv(&mut cx, &mut buf) {
+            Poll::Ready(Ok((len, src))) => Some((buf[..len].to_vec(), src)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_custom_addr_roundtrip() {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 3), 7000);
+        assert_eq!(socket_addr(&custom_addr(addr)), Some(addr));
+        assert_eq!(socket_addr(&CustomAddr::new(1, [0u8; 6])), None);
+    }
+
+    #[tokio::test]
+    async fn test_nat_filtering() {
+        let network = VirtualNetwork::new();
+        let public = network.add_host(Nat::None);
+        let full_cone = network.add_host(Nat::FullCone);
+        let restricted = network.add_host(Nat::PortRestricted);
+        let symmetric = network.add_host(Nat::Symmetric);
+        let public_addr = public.public_addr().unwrap();
+
+        // Private addresses are not routable.
+        send(&public, &full_cone.private_addr(), b"private");
+        assert!(try_recv(&full_cone).is_none());
+
+        // A full cone NAT lets in unsolicited packets.
+        send(&public, &full_cone.public_addr().unwrap(), b"unsolicited");
+        assert_eq!(try_recv(&full_cone).unwrap().0, b"unsolicited");
+
+        // A port restricted NAT only after the host sent to the source.
+        let restricted_addr = restricted.public_addr().unwrap();
+        send(&public, &restricted_addr, b"unsolicited");
+        assert!(try_recv(&restricted).is_none());
+        send(&restricted, &public_addr, b"hello");
+        let (data, src) = try_recv(&public).unwrap();
+        assert_eq!(
+            (data.as_slice(), &src),
+            (b"hello".as_slice(), &restricted_addr)
+        );
+        send(&public, &restricted_addr, b"reply");
+        assert_eq!(try_recv(&restricted).unwrap().0, b"reply");
+
+        // A symmetric NAT uses a different mapping for each destination.
+        assert!(symmetric.public_addr().is_none());
+        send(&symmetric, &public_addr, b"hello");
+        let (_, src_public) = try_recv(&public).unwrap();
+        send(&symmetric, &full_cone.public_addr().unwrap(), b"hello");
+        let (_, src_full_cone) = try_recv(&full_cone).unwrap();
+        assert_ne!(src_public, src_full_cone);
+        send(&public, &src_public, b"reply");
+        assert_eq!(try_recv(&symmetric).unwrap().0, b"reply");
+        send(&full_cone, &src_public, b"not for you");
+        assert!(try_recv(&symmetric).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_partition_and_loss() {
+        let network = VirtualNetwork::with_seed(7);
+        let a = network.add_host(Nat::None);
+        let b = network.add_host(Nat::None);
+        let b_addr = b.public_addr().unwrap();
+
+        network.partition(&a, &b);
+        send(&a, &b_addr, b"partitioned");
+        assert!(try_recv(&b).is_none());
+        network.heal(&a, &b);
+        send(&a, &b_addr, b"healed");
+        assert_eq!(try_recv(&b).unwrap().0, b"healed");
+
+        network.set_loss(0.5);
+        let received = (0..100)
+            .filter(|_| {
+                send(&a, &b_addr, b"maybe");
+                try_recv(&b).is_some()
+            })
+            .count();
+        assert!((20..80).contains(&received), "{received}");
+    }
+
+    #[tokio::test]
+    async fn test_latency() -> Result {
+        let network = VirtualNetwork::new();
+        let a = network.add_host(Nat::None);
+        let b = network.add_host(Nat::None);
+        network.set_latency(Duration::from_millis(50));
+
+        let start = n0_future::time::Instant::now();
+        send(&a, &b.public_addr().unwrap(), b"slow");
+        assert!(try_recv(&b).is_none());
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let (len, _src) = poll_fn(|cx| b.poll_recv(cx, &mut buf)).await.anyerr()?;
+        assert_eq!(&buf[..len], b"slow");
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_endpoint_through_nat() -> Result {
+        const TIMEOUT: Duration = Duration::from_secs(10);
+        let network = VirtualNetwork::with_seed(1);
+        network.set_latency(Duration::from_millis(5));
+        let server_host = network.add_host(Nat::FullCone);
+        let server_addr = server_host.public_addr().unwrap();
+        let server = Endpoint::empty_builder(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .add_custom_transport(server_host)
+            .bind()
+            .await?;
+        let client = Endpoint::empty_builder(RelayMode::Disabled)
+            .add_custom_transport(network.add_host(Nat::Symmetric))
+            .bind()
+            .await?;
+
+        let server_id = server.id();
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.anyerr()?;
+            let conn = incoming.await.anyerr()?;
+            let (mut send, mut recv) = conn.accept_bi().await.anyerr()?;
+            let data = recv.read_to_end(100).await.anyerr()?;
+            send.write_all(&data).await.anyerr()?;
+            send.finish().anyerr()?;
+            conn.closed().await;
+            server.close().await;
+            Ok::<_, n0_error::AnyError>(())
+        });
+
+        let addr = EndpointAddr::new(server_id).with_custom_addr(server_addr);
+        let conn = tokio::time::timeout(TIMEOUT, client.connect(addr, TEST_ALPN))
+            .await
+            .anyerr()??;
+        let (mut send, mut recv) = conn.open_bi().await.anyerr()?;
+        send.write_all(b"through the nat").await.anyerr()?;
+        send.finish().anyerr()?;
+        assert_eq!(recv.read_to_end(100).await.anyerr()?, b"through the nat");
+
+        let mut conn_type = client.conn_type(server_id).expect("known").stream();
+        tokio::time::timeout(TIMEOUT, async {
+            while let Some(conn_type) = conn_type.next().await {
+                if matches!(conn_type, ConnectionType::Custom(_)) {
+                    break;
+                }
+            }
+        })
+        .await
+        .anyerr()?;
+
+        conn.close(0u32.into(), b"done");
+        server_task.await.anyerr()??;
+        client.close().await;
+        Ok(())
+    }
+
+    /// Two endpoints behind port restricted NATs, knowing nothing but each others relay,
+    /// holepunch a direct path.
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_holepunch_port_restricted() -> Result {
+        let network = VirtualNetwork::with_seed(2);
+        network.set_latency(Duration::from_millis(20));
+        let server_host = network.add_host(Nat::PortRestricted);
+        let server_addr = socket_addr(&server_host.public_addr().unwrap()).unwrap();
+        let server = echo_endpoint(server_host).await?;
+        let client = bind_client(network.add_host(Nat::PortRestricted)).await?;
+
+        let addr = EndpointAddr::new(server.id()).with_relay_url(network.relay_url());
+        let conn = client.connect(addr, TEST_ALPN).await?;
+        assert_echo(&conn, b"hello").await?;
+
+        let conn_type = wait_conn_type(&client, &server, is_direct).await?;
+        assert_eq!(conn_type, ConnectionType::Direct(server_addr.into()));
+        assert_echo(&conn, b"direct").await?;
+
+        conn.close(0u32.into(), b"done");
+        tokio::join!(client.close(), server.close());
+        Ok(())
+    }
+
+    /// Endpoints behind symmetric NATs can not holepunch and stay on the relay.
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_holepunch_symmetric_stays_relayed() -> Result {
+        let network = VirtualNetwork::with_seed(3);
+        network.set_latency(Duration::from_millis(20));
+        let server = echo_endpoint(network.add_host(Nat::Symmetric)).await?;
+        let client = bind_client(network.add_host(Nat::Symmetric)).await?;
+
+        let addr = EndpointAddr::new(server.id()).with_relay_url(network.relay_url());
+        let conn = client.connect(addr, TEST_ALPN).await?;
+        assert_echo(&conn, b"hello").await?;
+
+        let res = wait_conn_type(&client, &server, is_direct).await;
+        assert!(res.is_err(), "holepunched through symmetric NATs: {res:?}");
+        assert!(is_relayed(&client.conn_type(server.id()).unwrap().get()));
+        assert_echo(&conn, b"relayed").await?;
+
+        conn.close(0u32.into(), b"done");
+        tokio::join!(client.close(), server.close());
+        Ok(())
+    }
+
+    /// A connection starts on the relay, moves to the direct path once holepunching
+    /// becomes possible, and falls back to the relay when the direct path breaks.
+    #[tokio::test(start_paused = true)]
+    #[traced_test]
+    async fn test_failover_relay_direct_relay() -> Result {
+        let network = VirtualNetwork::with_seed(4);
+        network.set_latency(Duration::from_millis(20));
+        let server_host = network.add_host(Nat::PortRestricted);
+        let client_host = network.add_host(Nat::PortRestricted);
+        network.partition(&server_host, &client_host);
+        let server = echo_endpoint(server_host.clone()).await?;
+        let client = bind_client(client_host.clone()).await?;
+
+        let addr = EndpointAddr::new(server.id()).with_relay_url(network.relay_url());
+        let conn = client.connect(addr, TEST_ALPN).await?;
+        assert_echo(&conn, b"relayed").await?;
+        wait_conn_type(&client, &server, is_relayed).await?;
+
+        network.heal(&server_host, &client_host);
+        wait_conn_type(&client, &server, is_direct).await?;
+        assert_echo(&conn, b"direct").await?;
+
+        network.partition(&server_host, &client_host);
+        wait_conn_type_busy(&conn, &client, &server, is_relayed).await?;
+        assert_echo(&conn, b"relayed again").await?;
+
+        conn.close(0u32.into(), b"done");
+        tokio::join!(client.close(), server.close());
+        Ok(())
+    }
+}
@@ -12,6 +12,7 @@ use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
 use tracing::warn;
 
 use self::resolver::AlwaysResolvesCert;
+pub(crate) use self::verifier::IncomingFilter;
 
 pub(crate) mod name;
 mod resolver;
@@ -41,6 +42,8 @@ pub(crate) struct TlsConfig {
     server_verifier: Arc<verifier::ServerCertificateVerifier>,
     client_verifier: Arc<verifier::ClientCertificateVerifier>,
     session_store: Arc<dyn rustls::client::ClientSessionStore>,
+    /// Whether incoming connections may resume earlier TLS sessions and send 0-RTT data.
+    server_resumption: bool,
 }
 
 impl TlsConfig {
@@ -52,13 +55,33 @@ impl TlsConfig {
             secret_key,
             cert_resolver,
             server_verifier: Arc::new(verifier::ServerCertificateVerifier),
-            client_verifier: Arc::new(verifier::ClientCertificateVerifier),
+            client_verifier: Arc::new(verifier::ClientCertificateVerifier::default()),
             session_store: Arc::new(rustls::client::ClientSessionMemoryCache::new(
                 max_tls_tickets,
             )),
+            server_resumption: true,
         }
     }
 
+    /// Refuses incoming connections from endpoints rejected by `filter` during the handshake.
+    ///
+    /// Resumed TLS sessions skip the client certificate verification, so this also disables
+    /// session resumption and 0-RTT for incoming connections.
+    pub(crate) fn with_incoming_filter(mut self, filter: IncomingFilter) -> Self {
+        self.client_verifier = Arc::new(verifier::ClientCertificateVerifier::new(Some(filter)));
+        self.server_resumption = false;
+        self
+    }
+
+    /// Keeps the TLS session state of outgoing connections in `store`.
+    pub(crate) fn with_session_store(
+        mut self,
+        store: Arc<dyn rustls::client::ClientSessionStore>,
+    ) -> Self {
+        self.session_store = store;
+        self
+    }
+
     /// Create a TLS client configuration.
     ///
     /// If *keylog* is `true` this will enable logging of the pre-master key to the file in the
@@ -116,9 +139,15 @@ impl TlsConfig {
             crypto.key_log = Arc::new(rustls::KeyLogFile::new());
         }
 
-        // must be u32::MAX or 0 (the default). Any other value panics with QUIC
-        // This is specified in RFC 9001: https://www.rfc-editor.org/rfc/rfc9001#section-4.6.1
-        crypto.max_early_data_size = u32::MAX;
+        if self.server_resumption {
+            // must be u32::MAX or 0 (the default). Any other value panics with QUIC
+            // This is specified in RFC 9001: https://www.rfc-editor.org/rfc/rfc9001#section-4.6.1
+            crypto.max_early_data_size = u32::MAX;
+        } else {
+            // Every handshake must verify the client certificate.
+            crypto.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
+            crypto.send_tls13_tickets = 0;
+        }
         crypto
             .try_into()
             .expect("expected to have a TLS1.3-compatible crypto provider set (hardcoded)")
@@ -3,8 +3,13 @@
 //! This module handles a verification of a client/server certificate chain
 //! and signatures allegedly by the given certificates, or using raw public keys.
 
-use ed25519_dalek::pkcs8::EncodePublicKey;
-use iroh_base::PublicKey;
+use std::sync::Arc;
+
+use ed25519_dalek::{
+    VerifyingKey,
+    pkcs8::{DecodePublicKey, EncodePublicKey},
+};
+use iroh_base::{EndpointId, PublicKey};
 use rustls::{
     CertificateError, DigitallySignedStruct, DistinguishedName, SignatureScheme,
     SupportedProtocolVersion,
@@ -13,6 +18,7 @@ use rustls::{
     pki_types::CertificateDer as Certificate,
     server::danger::{ClientCertVerified, ClientCertVerifier},
 };
+use tracing::debug;
 use webpki::ring as webpki_algs;
 use webpki_types::SubjectPublicKeyInfoDer;
 
@@ -136,11 +142,38 @@ impl ServerCertVerifier for ServerCertificateVerifier {
     }
 }
 
+/// Decides whether a remote endpoint is allowed to connect.
+///
+/// Returns `true` for endpoints that are allowed to connect, and `false` otherwise.
+#[derive(Clone, derive_more::Debug)]
+#[debug("IncomingFilter")]
+pub(crate) struct IncomingFilter(Arc<dyn Fn(EndpointId) -> bool + Send + Sync + 'static>);
+
+impl IncomingFilter {
+    pub(crate) fn new(filter: impl Fn(EndpointId) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(filter))
+    }
+
+    fn is_allowed(&self, endpoint_id: EndpointId) -> bool {
+        (self.0)(endpoint_id)
+    }
+}
+
 /// Implementation of the `rustls` certificate verification traits.
 ///
 /// Only TLS 1.3 is supported. TLS 1.2 should be disabled in the configuration of `rustls`.
+///
+/// If an [`IncomingFilter`] is set, clients it refuses fail the handshake.
 #[derive(Default, Debug)]
-pub(super) struct ClientCertificateVerifier;
+pub(super) struct ClientCertificateVerifier {
+    filter: Option<IncomingFilter>,
+}
+
+impl ClientCertificateVerifier {
+    pub(super) fn new(filter: Option<IncomingFilter>) -> Self {
+        Self { filter }
+    }
+}
 
 /// We requires either following of X.509 client certificate chains:
 ///
@@ -152,7 +185,7 @@ impl ClientCertVerifier for ClientCertificateVerifier {
 
     fn verify_client_cert(
         &self,
-        _end_entity: &Certificate,
+        end_entity: &Certificate,
         intermediates: &[Certificate],
         _now: rustls::pki_types::UnixTime,
     ) -> Result<ClientCertVerified, rustls::Error> {
@@ -162,6 +195,21 @@ impl ClientCertVerifier for ClientCertificateVerifier {
             ));
         }
 
+        if let Some(filter) = &self.filter {
+            let Ok(key) = VerifyingKey::from_public_key_der(end_entity.as_ref()) else {
+                return Err(rustls::Error::InvalidCertificate(
+                    CertificateError::BadEncoding,
+                ));
+            };
+            let endpoint_id = EndpointId::from_verifying_key(key);
+            if !filter.is_allowed(endpoint_id) {
+                debug!(remote = %endpoint_id.fmt_short(), "incoming connection refused");
+                return Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ));
+            }
+        }
+
         Ok(ClientCertVerified::assertion())
     }
 